@@ -0,0 +1,134 @@
+use anyhow::{anyhow, Context, Result};
+use chrono::Utc;
+use log::{debug, warn};
+use reqwest::{Client, Url};
+use serde_json::{json, Map, Value};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use crate::charging::ChargingType;
+use crate::secrets::{self, Secret};
+use crate::BatteryData;
+
+pub struct AbrpOptions {
+    pub url: String,
+    pub token: Secret,
+    pub api_key: Option<Secret>,
+    pub car_model: Option<String>,
+}
+
+// Sink sending samples to the ABRP (A Better Route Planner) telemetry API.
+// Requests run in the background, so an ABRP outage never delays the posts to
+// aa-proxy-rs.
+pub struct AbrpSink {
+    client: Client,
+    url: Url,
+    token: Secret,
+    api_key: Option<Secret>,
+    car_model: Option<String>,
+    pending: Arc<AtomicBool>,
+}
+
+impl AbrpSink {
+    pub fn new(options: AbrpOptions, client: Client) -> Result<Self> {
+        let url = Url::parse(&options.url)
+            .with_context(|| format!("Invalid ABRP url '{}'", secrets::redact_url(&options.url)))?;
+        Ok(Self {
+            client,
+            url,
+            token: options.token,
+            api_key: options.api_key,
+            car_model: options.car_model,
+            pending: Arc::new(AtomicBool::new(false)),
+        })
+    }
+
+    pub fn send(&self, sample: &BatteryData) {
+        let tlm = self.telemetry(sample);
+        if tlm.get("soc").is_none() {
+            debug!("Not sending a sample without a SOC to ABRP.");
+            return;
+        }
+        // Requests to an unreachable ABRP would otherwise pile up until the
+        // client times them out
+        if self.pending.swap(true, Ordering::AcqRel) {
+            warn!("Skipping ABRP update, the previous one is still pending.");
+            return;
+        }
+
+        let mut request = self.client.post(self.url.clone()).query(&[
+            ("token", self.token.expose()),
+            ("tlm", &Value::Object(tlm).to_string()),
+        ]);
+        if let Some(api_key) = &self.api_key {
+            request = request.header("Authorization", format!("APIKEY {}", api_key.expose()));
+        }
+        let pending = self.pending.clone();
+        tokio::spawn(async move {
+            match post(request).await {
+                Ok(()) => debug!("Sent sample to ABRP."),
+                Err(e) => warn!("Failed to update ABRP: {:#}", e),
+            }
+            pending.store(false, Ordering::Release);
+        });
+    }
+
+    // The sample under ABRP's field names, leaving out the ones the vehicle
+    // didn't report
+    fn telemetry(&self, sample: &BatteryData) -> Map<String, Value> {
+        let mut tlm = Map::new();
+        let utc = sample.timestamp.unwrap_or_else(Utc::now).timestamp();
+        tlm.insert("utc".to_string(), json!(utc));
+        let mut set = |key: &str, value: Option<Value>| {
+            if let Some(value) = value {
+                tlm.insert(key.to_string(), value);
+            }
+        };
+        set("soc", sample.battery_level_percentage.map(|v| json!(v)));
+        // Like the vehicle, ABRP takes the power as positive while driving and
+        // negative while charging
+        set("power", sample.battery_power_kw.map(|v| json!(v)));
+        set("speed", sample.speed_kmh.map(|v| json!(v)));
+        set("is_charging", Some(json!(sample.is_charging())));
+        set(
+            "is_dcfc",
+            sample
+                .charging_type
+                .map(|charging_type| json!(charging_type == ChargingType::Dc)),
+        );
+        set("ext_temp", sample.external_temp_celsius.map(|v| json!(v)));
+        set("batt_temp", sample.battery_temp_celsius.map(|v| json!(v)));
+        set("odometer", sample.odometer_km.map(|v| json!(v)));
+        set("soh", sample.state_of_health_percentage.map(|v| json!(v)));
+        set(
+            "capacity",
+            sample
+                .battery_capacity_wh
+                .map(|wh| json!(wh as f32 / 1000.0)),
+        );
+        set("car_model", self.car_model.as_ref().map(|v| json!(v)));
+        tlm
+    }
+}
+
+async fn post(request: reqwest::RequestBuilder) -> Result<()> {
+    let response = request.send().await.context("Could not reach ABRP")?;
+    let status = response.status();
+    let body = response.text().await.unwrap_or_default();
+    if !status.is_success() {
+        return Err(anyhow!("ABRP returned {}: {}", status, body.trim()));
+    }
+    // ABRP answers errors such as an unknown token with a 200 and the status
+    // in the body
+    let reply: Value = serde_json::from_str(&body).unwrap_or_default();
+    match reply.get("status").and_then(Value::as_str) {
+        Some("ok") | None => Ok(()),
+        Some(_) => Err(anyhow!(
+            "ABRP rejected the sample: {}",
+            reply
+                .get("error")
+                .and_then(Value::as_str)
+                .unwrap_or(body.trim())
+        )),
+    }
+}
@@ -0,0 +1,114 @@
+use crate::control::CONTROL;
+use crate::BatteryData;
+use log::info;
+use std::fmt;
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+
+static ADAPTIVE: OnceLock<AdaptivePolling> = OnceLock::new();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VehicleState {
+    Charging,
+    Driving,
+    Parked,
+}
+
+impl fmt::Display for VehicleState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VehicleState::Charging => write!(f, "charging"),
+            VehicleState::Driving => write!(f, "driving"),
+            VehicleState::Parked => write!(f, "parked"),
+        }
+    }
+}
+
+impl VehicleState {
+    fn of(sample: &BatteryData) -> Self {
+        if sample.is_charging() {
+            VehicleState::Charging
+        } else if sample.is_moving() {
+            VehicleState::Driving
+        } else {
+            VehicleState::Parked
+        }
+    }
+}
+
+pub struct AdaptiveOptions {
+    pub charging: Duration,
+    pub driving: Duration,
+    pub parked: Duration,
+    // Consecutive failed polls after which the vehicle is taken as parked and
+    // asleep
+    pub parked_after_failures: u32,
+}
+
+// Polls at an interval chosen by what the vehicle is doing, replacing the
+// update frequency
+struct AdaptivePolling {
+    options: AdaptiveOptions,
+    state: Mutex<AdaptiveState>,
+}
+
+#[derive(Default)]
+struct AdaptiveState {
+    vehicle: Option<VehicleState>,
+    failures: u32,
+}
+
+impl AdaptivePolling {
+    fn interval(&self, state: VehicleState) -> Duration {
+        match state {
+            VehicleState::Charging => self.options.charging,
+            VehicleState::Driving => self.options.driving,
+            VehicleState::Parked => self.options.parked,
+        }
+    }
+
+    fn switch(&self, current: &mut AdaptiveState, vehicle: VehicleState, reason: &str) {
+        if current.vehicle.replace(vehicle) != Some(vehicle) {
+            let interval = self.interval(vehicle);
+            info!(
+                "The vehicle is {}{}, polling every {:?}.",
+                vehicle, reason, interval
+            );
+            CONTROL.set_state_interval(Some(interval));
+        }
+    }
+}
+
+pub fn configure(options: AdaptiveOptions) {
+    let _ = ADAPTIVE.set(AdaptivePolling {
+        options,
+        state: Mutex::new(AdaptiveState::default()),
+    });
+}
+
+pub fn is_enabled() -> bool {
+    ADAPTIVE.get().is_some()
+}
+
+// Pick the interval for the state a sample shows the vehicle in
+pub fn record_sample(sample: &BatteryData) {
+    let Some(adaptive) = ADAPTIVE.get() else {
+        return;
+    };
+    let mut state = adaptive.state.lock().unwrap();
+    state.failures = 0;
+    adaptive.switch(&mut state, VehicleState::of(sample), "");
+}
+
+// Count a poll that got no reply, as a parked vehicle's ECUs go to sleep and
+// stop answering
+pub fn record_failure() {
+    let Some(adaptive) = ADAPTIVE.get() else {
+        return;
+    };
+    let mut state = adaptive.state.lock().unwrap();
+    state.failures = state.failures.saturating_add(1);
+    if state.failures >= adaptive.options.parked_after_failures {
+        adaptive.switch(&mut state, VehicleState::Parked, " and not answering");
+    }
+}
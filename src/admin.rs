@@ -0,0 +1,187 @@
+use anyhow::{Context, Result};
+use axum::extract::{Request, State};
+use axum::http::{header, StatusCode};
+use axum::middleware::{self, Next};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::net::TcpListener;
+
+use crate::api::ApiClient;
+use crate::control::CONTROL;
+use crate::cost;
+use crate::departure;
+use crate::secrets::Secret;
+use crate::stats::STATS;
+
+#[derive(Clone)]
+struct AdminState {
+    token: Arc<Secret>,
+    api: Arc<ApiClient>,
+}
+
+#[derive(Serialize)]
+struct PollingStatus {
+    update_frequency_minutes: u8,
+    paused: bool,
+}
+
+#[derive(Deserialize)]
+struct IntervalRequest {
+    minutes: u8,
+}
+
+// Bind the admin listener, done before dropping privileges so low ports work
+pub async fn bind(address: SocketAddr) -> Result<TcpListener> {
+    TcpListener::bind(address)
+        .await
+        .with_context(|| format!("Failed to listen for admin requests on {}", address))
+}
+
+// Serve the admin API until the process exits
+pub async fn serve(listener: TcpListener, token: Secret, api: Arc<ApiClient>) {
+    let state = AdminState {
+        token: Arc::new(token),
+        api,
+    };
+    let app = Router::new()
+        .route("/admin/polling", get(polling_status))
+        .route("/admin/polling/interval", post(set_interval))
+        .route("/admin/polling/pause", post(pause))
+        .route("/admin/polling/resume", post(resume))
+        .route("/admin/polling/poll-now", post(poll_now))
+        .route("/admin/queue", get(queue).delete(clear_queue))
+        .route("/admin/sample", get(last_sample))
+        .route("/admin/departure", get(departure_prediction))
+        .route("/admin/charging", get(charging_costs))
+        .route_layer(middleware::from_fn_with_state(state.clone(), authenticate))
+        .with_state(state);
+
+    if let Ok(address) = listener.local_addr() {
+        info!("Admin API listening on {}", address);
+    }
+    if let Err(e) = axum::serve(listener, app).await {
+        error!("Admin API stopped: {}", e);
+    }
+}
+
+// Require "Authorization: Bearer <token>" on every request
+async fn authenticate(State(state): State<AdminState>, request: Request, next: Next) -> Response {
+    let authorized = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|token| constant_time_eq(token.as_bytes(), state.token.expose().as_bytes()));
+
+    if !authorized {
+        return (
+            StatusCode::UNAUTHORIZED,
+            Json(json!({ "error": "Missing or invalid bearer token" })),
+        )
+            .into_response();
+    }
+    next.run(request).await
+}
+
+// Compare without returning early, so timing doesn't reveal the token
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn polling_status_json() -> Json<PollingStatus> {
+    Json(PollingStatus {
+        update_frequency_minutes: CONTROL.update_frequency_minutes(),
+        paused: CONTROL.is_paused(),
+    })
+}
+
+async fn polling_status() -> Json<PollingStatus> {
+    polling_status_json()
+}
+
+async fn set_interval(Json(request): Json<IntervalRequest>) -> Response {
+    if request.minutes == 0 {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "error": "minutes must be at least 1" })),
+        )
+            .into_response();
+    }
+    if let Err(e) = CONTROL.set_update_frequency_minutes(request.minutes) {
+        return (
+            StatusCode::CONFLICT,
+            Json(json!({ "error": format!("{:#}", e) })),
+        )
+            .into_response();
+    }
+    polling_status_json().into_response()
+}
+
+async fn pause() -> Json<PollingStatus> {
+    CONTROL.pause();
+    polling_status_json()
+}
+
+async fn resume() -> Json<PollingStatus> {
+    CONTROL.resume();
+    polling_status_json()
+}
+
+async fn poll_now() -> StatusCode {
+    CONTROL.poll_now();
+    StatusCode::ACCEPTED
+}
+
+async fn queue(State(state): State<AdminState>) -> impl IntoResponse {
+    Json(state.api.queued_samples())
+}
+
+async fn clear_queue(State(state): State<AdminState>) -> impl IntoResponse {
+    let cleared = state.api.clear_queue();
+    info!("Cleared {} queued sample(s) on admin request.", cleared);
+    Json(json!({ "cleared": cleared }))
+}
+
+async fn last_sample() -> Response {
+    match STATS.last_sample.lock().unwrap().clone() {
+        Some(sample) => Json(sample).into_response(),
+        None => (
+            StatusCode::NOT_FOUND,
+            Json(json!({ "error": "No sample has been read yet" })),
+        )
+            .into_response(),
+    }
+}
+
+async fn departure_prediction() -> Response {
+    match departure::latest() {
+        Some(prediction) => Json(prediction).into_response(),
+        None => (
+            StatusCode::NOT_FOUND,
+            Json(json!({ "error": "No departure prediction, set --departure-time and wait for a sample" })),
+        )
+            .into_response(),
+    }
+}
+
+async fn charging_costs() -> Response {
+    match cost::costs() {
+        Some(Ok(costs)) => Json(costs).into_response(),
+        Some(Err(e)) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({ "error": format!("{:#}", e) })),
+        )
+            .into_response(),
+        None => (
+            StatusCode::NOT_FOUND,
+            Json(json!({ "error": "Charging sessions aren't tracked, set --tariff or --carbon-intensity-region" })),
+        )
+            .into_response(),
+    }
+}
@@ -0,0 +1,858 @@
+use anyhow::{anyhow, Context, Result};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use chrono::{DateTime, Utc};
+use clap::CommandFactory;
+use hmac::{Hmac, Mac};
+use log::{debug, info, warn};
+use reqwest::header::{HeaderMap, HeaderValue, AUTHORIZATION, CONTENT_TYPE, RETRY_AFTER};
+#[cfg(any(feature = "rustls", feature = "native-tls"))]
+use reqwest::Certificate;
+use reqwest::{Client, ClientBuilder, Method, RequestBuilder, Response, StatusCode};
+use serde::Deserialize;
+use serde_json::Value;
+use sha2::Sha256;
+use std::collections::BTreeSet;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::{self, Instant};
+use uuid::Uuid;
+
+// Delay used when aa-proxy-rs rate limits us without saying for how long
+const DEFAULT_RETRY_AFTER: Duration = Duration::from_secs(60);
+
+use crate::queue::RetryQueue;
+use crate::secrets;
+use crate::stats::STATS;
+use crate::BatteryData;
+
+// Fields of an aa-proxy-rs response body that we understand
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct ApiResponse {
+    #[serde(alias = "api_version")]
+    version: Option<Value>,
+    status: Option<String>,
+    error: Option<String>,
+    warning: Option<String>,
+    message: Option<String>,
+}
+
+// Settings for the aa-proxy-rs API client
+pub struct ApiOptions {
+    // Tried in order until one can be reached
+    pub urls: Vec<String>,
+    pub expected_version: Option<String>,
+    pub send_timestamp: bool,
+    pub send_idempotency: bool,
+    pub send_link_quality: bool,
+    pub omit_fields: Vec<String>,
+    pub conditional_update: bool,
+    pub connect_timeout: Duration,
+    pub read_timeout: Duration,
+    pub user_agent: String,
+    pub resolve: Vec<HostOverride>,
+    pub pool_idle_timeout: Option<Duration>,
+    pub tcp_keepalive: Option<Duration>,
+    pub http2_prior_knowledge: bool,
+    pub http2_keep_alive_interval: Option<Duration>,
+    // Whole request limit, on top of the connect and read timeouts
+    pub timeout: Option<Duration>,
+    // Bearer token, or the user and password for basic authentication
+    pub token: Option<String>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    // PEM certificates trusted for aa-proxy-rs besides the system ones
+    pub ca_cert: Option<Vec<u8>>,
+    pub insecure_tls: bool,
+    pub retry_queue_size: usize,
+    // Queued samples read longer ago are dropped
+    pub retry_max_age: Option<Duration>,
+    // File the retry queue is kept in across restarts
+    pub retry_queue_file: Option<PathBuf>,
+    pub batch: bool,
+    // Log posts instead of sending them
+    pub dry_run: bool,
+    pub hmac_secret: Option<String>,
+    pub hmac_header: String,
+}
+
+// Name of a sample field that can be left out of the payload, as in the JSON
+pub fn parse_payload_field(value: &str) -> Result<String> {
+    let command = BatteryData::command();
+    let fields: Vec<&str> = command
+        .get_arguments()
+        .map(|arg| arg.get_id().as_str())
+        .collect();
+    if fields.contains(&value) {
+        Ok(value.to_string())
+    } else {
+        Err(anyhow!(
+            "Unknown field '{}', expected one of {}",
+            value,
+            fields.join(", ")
+        ))
+    }
+}
+
+// Static address for a host name, given as host:ip like curl's --resolve
+#[derive(Debug, Clone, PartialEq)]
+pub struct HostOverride {
+    pub host: String,
+    pub address: IpAddr,
+}
+
+impl FromStr for HostOverride {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (host, address) = s
+            .split_once(':')
+            .ok_or_else(|| anyhow!("'{}' is not in host:ip form", s))?;
+        if host.is_empty() {
+            return Err(anyhow!("'{}' has an empty host name", s));
+        }
+        let address = address
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .parse()
+            .with_context(|| format!("'{}' does not contain a valid IP address", s))?;
+
+        Ok(Self {
+            host: host.to_string(),
+            address,
+        })
+    }
+}
+
+// Client for the aa-proxy-rs battery API
+pub struct ApiClient {
+    client: Client,
+    // Whole request limit of posts and reads, but not of the events stream,
+    // which shares the client and stays open
+    timeout: Option<Duration>,
+    // Without the aa-proxy-rs credentials, for other services
+    shared_client: Client,
+    // Replaced when a reloaded config file changes them
+    urls: Mutex<Vec<String>>,
+    // Index of the url that last answered, used for requests other than posts
+    active: AtomicUsize,
+    expected_version: Option<String>,
+    send_timestamp: bool,
+    send_idempotency: bool,
+    send_link_quality: bool,
+    // Payload fields left out as configured
+    omit_fields: Vec<String>,
+    conditional_update: bool,
+    // Payload fields the API has rejected as unknown, omitted from later posts
+    rejected_fields: Mutex<BTreeSet<String>>,
+    // Posting is deferred until this time after aa-proxy-rs asks us to back off
+    retry_at: Mutex<Option<Instant>>,
+    // Samples waiting to be posted again after a failure
+    queue: Mutex<RetryQueue>,
+    // Held while posting, so queued samples replayed in the background are
+    // never posted after a newer sample
+    posting: tokio::sync::Mutex<()>,
+    batch: bool,
+    dry_run: bool,
+    // Shared secret and header used to sign request bodies
+    hmac_secret: Option<String>,
+    hmac_header: String,
+}
+
+// aa-proxy-rs asked us to retry later, which is not treated as a hard failure
+#[derive(Debug)]
+pub struct RateLimited {
+    pub retry_after: Duration,
+}
+
+impl fmt::Display for RateLimited {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "aa-proxy-rs asked us to retry after {:?}",
+            self.retry_after
+        )
+    }
+}
+
+impl std::error::Error for RateLimited {}
+
+// Client settings shared by the requests to aa-proxy-rs and to other services
+fn client_builder(options: &ApiOptions) -> ClientBuilder {
+    let mut builder = Client::builder()
+        .connect_timeout(options.connect_timeout)
+        .read_timeout(options.read_timeout)
+        .user_agent(&options.user_agent)
+        .pool_idle_timeout(options.pool_idle_timeout)
+        .pool_max_idle_per_host(1)
+        .tcp_keepalive(options.tcp_keepalive);
+    if options.http2_prior_knowledge {
+        builder = builder.http2_prior_knowledge();
+    }
+    if let Some(interval) = options.http2_keep_alive_interval {
+        builder = builder
+            .http2_keep_alive_interval(interval)
+            .http2_keep_alive_while_idle(true);
+    }
+    for host_override in &options.resolve {
+        // reqwest uses the port from the URL, so the port here is ignored
+        builder = builder.resolve(
+            &host_override.host,
+            SocketAddr::new(host_override.address, 0),
+        );
+    }
+    builder
+}
+
+// The credentials and certificates only aa-proxy-rs gets, kept out of the
+// client shared with other services so they never see the token or relaxed
+// certificate checks
+fn aa_proxy_client(options: &ApiOptions) -> Result<Client> {
+    let mut builder = client_builder(options);
+
+    let authorization = match (&options.token, &options.username) {
+        (Some(token), _) => Some(format!("Bearer {}", token)),
+        (None, Some(username)) => Some(format!(
+            "Basic {}",
+            BASE64.encode(format!(
+                "{}:{}",
+                username,
+                options.password.as_deref().unwrap_or_default()
+            ))
+        )),
+        (None, None) => None,
+    };
+    if let Some(authorization) = authorization {
+        let mut value = HeaderValue::from_str(&authorization)
+            .context("The aa-proxy-rs credentials can't be sent in a header")?;
+        value.set_sensitive(true);
+        builder = builder.default_headers(HeaderMap::from_iter([(AUTHORIZATION, value)]));
+    }
+
+    if options.ca_cert.is_some() || options.insecure_tls {
+        builder = with_tls_options(builder, options)?;
+    }
+    builder.build().context("Failed to create HTTP client")
+}
+
+#[cfg(any(feature = "rustls", feature = "native-tls"))]
+fn with_tls_options(mut builder: ClientBuilder, options: &ApiOptions) -> Result<ClientBuilder> {
+    if let Some(pem) = &options.ca_cert {
+        for certificate in Certificate::from_pem_bundle(pem)
+            .context("Failed to parse the aa-proxy-rs CA certificate")?
+        {
+            builder = builder.add_root_certificate(certificate);
+        }
+    }
+    if options.insecure_tls {
+        warn!("Not verifying the certificate of aa-proxy-rs.");
+        builder = builder.danger_accept_invalid_certs(true);
+    }
+    Ok(builder)
+}
+
+#[cfg(not(any(feature = "rustls", feature = "native-tls")))]
+fn with_tls_options(_builder: ClientBuilder, _options: &ApiOptions) -> Result<ClientBuilder> {
+    Err(anyhow!(
+        "--api-ca-cert and --api-insecure-tls need a build with the 'rustls' or 'native-tls' feature"
+    ))
+}
+
+impl ApiClient {
+    pub fn new(options: ApiOptions) -> Result<Self> {
+        for host_override in &options.resolve {
+            info!(
+                "Resolving {} to {} for aa-proxy-rs requests.",
+                host_override.host, host_override.address
+            );
+        }
+        let client = aa_proxy_client(&options)?;
+        let shared_client = client_builder(&options)
+            .build()
+            .context("Failed to create HTTP client")?;
+        let mut queue =
+            RetryQueue::new(options.retry_queue_size).with_max_age(options.retry_max_age);
+        if let Some(path) = options.retry_queue_file {
+            queue = queue.persisted(path);
+        }
+        STATS.set_queue_depth(queue.len());
+
+        Ok(Self {
+            client,
+            timeout: options.timeout,
+            shared_client,
+            urls: Mutex::new(options.urls),
+            active: AtomicUsize::new(0),
+            expected_version: options.expected_version,
+            send_timestamp: options.send_timestamp,
+            send_idempotency: options.send_idempotency,
+            send_link_quality: options.send_link_quality,
+            omit_fields: options.omit_fields,
+            conditional_update: options.conditional_update,
+            rejected_fields: Mutex::new(BTreeSet::new()),
+            retry_at: Mutex::new(None),
+            queue: Mutex::new(queue),
+            posting: tokio::sync::Mutex::new(()),
+            batch: options.batch,
+            dry_run: options.dry_run,
+            hmac_secret: options.hmac_secret,
+            hmac_header: options.hmac_header,
+        })
+    }
+
+    // The url that last answered, or the first before any has
+    fn url(&self) -> String {
+        let urls = self.urls();
+        urls[self.active.load(Ordering::Relaxed).min(urls.len() - 1)].clone()
+    }
+
+    fn urls(&self) -> Vec<String> {
+        self.urls.lock().unwrap().clone()
+    }
+
+    // Post to other urls from now on, starting again with the first
+    pub fn set_urls(&self, urls: Vec<String>) {
+        if urls.is_empty() {
+            return;
+        }
+        *self.urls.lock().unwrap() = urls;
+        self.active.store(0, Ordering::Relaxed);
+    }
+
+    // The configured HTTP client, for requests made to other services
+    pub fn http_client(&self) -> Client {
+        self.shared_client.clone()
+    }
+
+    // The HTTP client for other requests made to aa-proxy-rs, carrying its
+    // credentials
+    pub fn aa_proxy_client(&self) -> Client {
+        self.client.clone()
+    }
+
+    fn request(&self, method: Method, url: &str) -> RequestBuilder {
+        let request = self.client.request(method, url);
+        match self.timeout {
+            Some(timeout) => request.timeout(timeout),
+            None => request,
+        }
+    }
+
+    // Time left before aa-proxy-rs is willing to accept another post
+    pub fn retry_after(&self) -> Option<Duration> {
+        let retry_at = (*self.retry_at.lock().unwrap())?;
+        let remaining = retry_at.saturating_duration_since(Instant::now());
+        (!remaining.is_zero()).then_some(remaining)
+    }
+
+    // Number of samples waiting to be posted again
+    pub fn queue_depth(&self) -> usize {
+        self.queue.lock().unwrap().len()
+    }
+
+    pub fn queued_samples(&self) -> Vec<BatteryData> {
+        self.queue.lock().unwrap().samples()
+    }
+
+    // Drop every queued sample, returning how many were dropped
+    pub fn clear_queue(&self) -> usize {
+        let cleared = self.queue.lock().unwrap().take_all().len();
+        STATS.set_queue_depth(0);
+        cleared
+    }
+
+    // Post a new sample together with any samples queued after earlier failures,
+    // queueing everything that couldn't be posted for the next attempt
+    pub async fn submit(&self, data: BatteryData) -> Result<String> {
+        let _posting = self.posting.lock().await;
+        let result = self.submit_with_queue(data).await;
+        STATS.record_post(result.is_ok(), self.queue_depth());
+        if result.is_err() && self.queue_depth() > 0 {
+            info!(
+                "{} sample(s) queued to post on the next attempt.",
+                self.queue_depth()
+            );
+        }
+        result
+    }
+
+    // Post the queued samples while aa-proxy-rs would otherwise only see them
+    // with the next sample, which can be many minutes away
+    pub async fn replay_queued(self: Arc<Self>, interval: Duration) {
+        let mut ticks = time::interval(interval);
+        ticks.tick().await;
+        loop {
+            ticks.tick().await;
+            if self.queue_depth() == 0 || self.retry_after().is_some() {
+                continue;
+            }
+            let _posting = self.posting.lock().await;
+            let pending = self.queue.lock().unwrap().take_all();
+            if pending.is_empty() {
+                STATS.set_queue_depth(0);
+                continue;
+            }
+            let count = pending.len();
+            let result = self.post_pending(pending).await;
+            STATS.record_post(result.is_ok(), self.queue_depth());
+            match result {
+                Ok(_) => info!("Posted {} queued sample(s).", count),
+                Err(e) => debug!("Queued samples still can't be posted: {:#}", e),
+            }
+        }
+    }
+
+    async fn submit_with_queue(&self, data: BatteryData) -> Result<String> {
+        let mut pending = self.queue.lock().unwrap().take_all();
+        if pending.is_empty() {
+            return match self.post_battery_data(&data).await {
+                Ok(body) => Ok(body),
+                Err(e) => {
+                    self.queue.lock().unwrap().extend([data]);
+                    Err(e)
+                }
+            };
+        }
+        pending.push(data);
+        match self.batch {
+            true => info!("Posting {} queued samples in one batch.", pending.len()),
+            false => info!("Posting {} queued samples.", pending.len()),
+        }
+        self.post_pending(pending).await
+    }
+
+    // Post samples oldest first, queueing whatever couldn't be posted
+    async fn post_pending(&self, pending: Vec<BatteryData>) -> Result<String> {
+        if self.batch {
+            return match self.post_batch(&pending).await {
+                Ok(body) => Ok(body),
+                Err(e) => {
+                    self.queue.lock().unwrap().extend(pending);
+                    Err(e)
+                }
+            };
+        }
+
+        let mut samples = pending.into_iter();
+        let mut last_body = String::new();
+        while let Some(sample) = samples.next() {
+            match self.post_battery_data(&sample).await {
+                Ok(body) => last_body = body,
+                Err(e) => {
+                    let mut queue = self.queue.lock().unwrap();
+                    queue.extend([sample]);
+                    queue.extend(samples);
+                    return Err(e);
+                }
+            }
+        }
+        Ok(last_body)
+    }
+
+    // Post several samples as a JSON array in a single request
+    pub async fn post_batch(&self, batch: &[BatteryData]) -> Result<String> {
+        if let Some(retry_after) = self.retry_after() {
+            return Err(RateLimited { retry_after }.into());
+        }
+
+        info!(
+            "Sending a batch of {} samples to aa-proxy-rs at: {}",
+            batch.len(),
+            secrets::redact_url(&self.url())
+        );
+
+        let payload = batch
+            .iter()
+            .map(|data| self.payload(data))
+            .collect::<Result<Vec<_>>>()?;
+        self.post_payload(&Value::Array(payload), None)
+            .await
+            .map_err(Into::into)
+    }
+
+    // The JSON sent for a sample, without fields aa-proxy-rs shouldn't receive
+    pub fn payload(&self, data: &BatteryData) -> Result<Value> {
+        let mut payload = serde_json::to_value(data)?;
+        if let Some(object) = payload.as_object_mut() {
+            // A sample saved before a restart always carries the time it was read
+            if !self.send_timestamp && data.cached.is_none() {
+                object.remove("timestamp");
+            }
+            if !self.send_idempotency {
+                object.remove("sequence");
+                object.remove("idempotency_key");
+            }
+            if !self.send_link_quality {
+                object.remove("rssi_dbm");
+                object.remove("tx_power_dbm");
+            }
+            for field in &self.omit_fields {
+                object.remove(field);
+            }
+        }
+        self.remove_rejected_fields(&mut payload);
+        Ok(payload)
+    }
+
+    // Post battery data to aa-proxy-rs, returning the response body
+    pub async fn post_battery_data(&self, data: &BatteryData) -> Result<String> {
+        if let Some(retry_after) = self.retry_after() {
+            return Err(RateLimited { retry_after }.into());
+        }
+
+        if self.conditional_update && !self.dry_run {
+            if let Some(sample_time) = data.timestamp {
+                match self.current_timestamp().await {
+                    Ok(Some(stored_time)) if stored_time > sample_time => {
+                        info!(
+                            "aa-proxy-rs already has newer data from {} than our sample from {}. Skipping post.",
+                            stored_time, sample_time
+                        );
+                        return Ok(String::new());
+                    }
+                    Ok(_) => {}
+                    Err(e) => warn!(
+                        "Failed to read current battery state from aa-proxy-rs: {}. Posting anyway.",
+                        e
+                    ),
+                }
+            }
+        }
+
+        info!(
+            "Sending {:?} to aa-proxy-rs at: {}",
+            data,
+            secrets::redact_url(&self.url())
+        );
+
+        let mut payload = self.payload(data)?;
+
+        match self.post_payload(&payload, data.idempotency_key).await {
+            Err(PostError::UnknownField(field)) if payload.get(&field).is_some() => {
+                warn!(
+                    "aa-proxy-rs does not support the '{}' field. Omitting it from now on and retrying...",
+                    field
+                );
+                self.rejected_fields.lock().unwrap().insert(field);
+                self.remove_rejected_fields(&mut payload);
+                self.post_payload(&payload, data.idempotency_key)
+                    .await
+                    .map_err(Into::into)
+            }
+            result => result.map_err(Into::into),
+        }
+    }
+
+    // Status the first reachable aa-proxy-rs answers a GET of the battery
+    // endpoint with, to tell whether it is reachable without posting anything
+    pub async fn reachable(&self) -> Result<(String, StatusCode)> {
+        let mut error = None;
+        for url in self.urls() {
+            match self.request(Method::GET, &url).send().await {
+                Ok(res) => return Ok((url, res.status())),
+                Err(e) => {
+                    error = Some(
+                        anyhow!(e)
+                            .context(format!("Could not reach {}", secrets::redact_url(&url))),
+                    )
+                }
+            }
+        }
+        Err(error
+            .unwrap_or_else(|| anyhow!("No url"))
+            .context("Could not reach aa-proxy-rs"))
+    }
+
+    // Timestamp of the battery data currently stored in aa-proxy-rs, if it reports one
+    async fn current_timestamp(&self) -> Result<Option<DateTime<Utc>>> {
+        let res = self.request(Method::GET, &self.url()).send().await?;
+        if !res.status().is_success() {
+            return Err(anyhow!("Status: {}", res.status()));
+        }
+
+        let body: Value = res
+            .json()
+            .await
+            .context("Failed to parse battery state JSON")?;
+        debug!("aa-proxy-rs current battery state: {}", body);
+
+        Ok(body
+            .get("timestamp")
+            .and_then(|t| serde_json::from_value(t.clone()).ok()))
+    }
+
+    fn remove_rejected_fields(&self, payload: &mut Value) {
+        if let Some(object) = payload.as_object_mut() {
+            for field in self.rejected_fields.lock().unwrap().iter() {
+                object.remove(field);
+            }
+        }
+    }
+
+    async fn post_payload(
+        &self,
+        payload: &Value,
+        idempotency_key: Option<Uuid>,
+    ) -> Result<String, PostError> {
+        let body = serde_json::to_vec(payload).map_err(|e| PostError::Other(e.into()))?;
+        if self.dry_run {
+            info!(
+                "Dry run, not posting to aa-proxy-rs at: {}. Body: {}",
+                secrets::redact_url(&self.url()),
+                String::from_utf8_lossy(&body)
+            );
+            return Ok(String::new());
+        }
+        let (url, res) = self.send_post(body, idempotency_key).await?;
+
+        let status = res.status();
+        let retry_after = retry_after(status, res.headers());
+        let body = res.text().await.unwrap_or_default();
+        debug!("aa-proxy-rs response body: {}", body);
+
+        if let Some(retry_after) = retry_after {
+            warn!(
+                "aa-proxy-rs at: {} is rate limiting us. Status: {}. Deferring posts for {:?}.",
+                secrets::redact_url(&url),
+                status,
+                retry_after
+            );
+            *self.retry_at.lock().unwrap() = Some(Instant::now() + retry_after);
+            return Err(PostError::Other(RateLimited { retry_after }.into()));
+        }
+
+        self.interpret_response(&url, status, &body)?;
+
+        info!(
+            "Successfully posted to aa-proxy-rs at: {}. Status: {}",
+            secrets::redact_url(&url),
+            status
+        );
+        STATS.set_api_url(&url);
+        Ok(body)
+    }
+
+    // Send a post to the first url that can be reached, starting over from
+    // the first each time so a preferred receiver is used again once it's back
+    async fn send_post(
+        &self,
+        body: Vec<u8>,
+        idempotency_key: Option<Uuid>,
+    ) -> Result<(String, Response), PostError> {
+        let mut error = None;
+        let urls = self.urls();
+        for (index, url) in urls.iter().enumerate() {
+            let mut request = self
+                .request(Method::POST, url)
+                .header(CONTENT_TYPE, "application/json");
+            if let Some(secret) = &self.hmac_secret {
+                request = request.header(&self.hmac_header, sign(secret, &body));
+            }
+            if let Some(key) = idempotency_key.filter(|_| self.send_idempotency) {
+                request = request.header("Idempotency-Key", key.to_string());
+            }
+            match request.body(body.clone()).send().await {
+                Ok(res) => {
+                    if self.active.swap(index, Ordering::Relaxed) != index {
+                        info!("Posting to aa-proxy-rs at: {}", secrets::redact_url(url));
+                    }
+                    return Ok((url.clone(), res));
+                }
+                Err(e) => {
+                    // Only worth a warning when the url in use stops answering,
+                    // not on every post while falling back
+                    match urls.get(index + 1) {
+                        Some(next) if self.active.load(Ordering::Relaxed) == index => warn!(
+                            "Could not reach aa-proxy-rs at: {}: {}. Trying {}...",
+                            secrets::redact_url(url),
+                            e,
+                            secrets::redact_url(next)
+                        ),
+                        Some(next) => debug!(
+                            "Could not reach aa-proxy-rs at: {}: {}. Trying {}...",
+                            secrets::redact_url(url),
+                            e,
+                            secrets::redact_url(next)
+                        ),
+                        None => {}
+                    }
+                    error = Some(e);
+                }
+            }
+        }
+        Err(PostError::Other(error.map_or_else(
+            || anyhow!("No aa-proxy-rs url configured"),
+            Into::into,
+        )))
+    }
+
+    // Check the status code and any error, warning or version reported in the body
+    fn interpret_response(
+        &self,
+        url: &str,
+        status: StatusCode,
+        body: &str,
+    ) -> Result<(), PostError> {
+        let response: Option<ApiResponse> = serde_json::from_str(body).ok();
+
+        if !status.is_success() {
+            warn!(
+                "Failed to post to aa-proxy-rs at: {}. Status: {}",
+                secrets::redact_url(url),
+                status
+            );
+
+            if status.is_client_error() {
+                if let Some(field) = unknown_field(body) {
+                    return Err(PostError::UnknownField(field));
+                }
+            }
+
+            let detail = response
+                .as_ref()
+                .and_then(|r| r.error.clone().or_else(|| r.message.clone()))
+                .unwrap_or_else(|| body.trim().to_string());
+            return Err(PostError::Other(if detail.is_empty() {
+                anyhow!(
+                    "Failed to post to aa-proxy-rs at: {}. Status: {}",
+                    secrets::redact_url(url),
+                    status
+                )
+            } else {
+                anyhow!(
+                    "Failed to post to aa-proxy-rs at: {}. Status: {}. Error: {}",
+                    secrets::redact_url(url),
+                    status,
+                    detail
+                )
+            }));
+        }
+
+        let Some(response) = response else {
+            return Ok(());
+        };
+
+        if let Some(version) = &response.version {
+            let version = match version {
+                Value::String(version) => version.clone(),
+                other => other.to_string(),
+            };
+            debug!("aa-proxy-rs reports API version {}", version);
+            if let Some(expected) = &self.expected_version {
+                if &version != expected {
+                    warn!(
+                        "aa-proxy-rs reports API version {}, expected {}",
+                        version, expected
+                    );
+                }
+            }
+        }
+
+        if let Some(warning) = &response.warning {
+            warn!("aa-proxy-rs returned a warning: {}", warning);
+        }
+
+        let failed = response
+            .status
+            .as_deref()
+            .is_some_and(|s| matches!(s.to_lowercase().as_str(), "error" | "failed" | "fail"));
+        if let Some(error) = response.error.as_ref().filter(|e| !e.is_empty()) {
+            return Err(PostError::Other(anyhow!(
+                "aa-proxy-rs at: {} returned an error: {}",
+                secrets::redact_url(url),
+                error
+            )));
+        }
+        if failed {
+            return Err(PostError::Other(anyhow!(
+                "aa-proxy-rs at: {} reported failure: {}",
+                secrets::redact_url(url),
+                response.message.as_deref().unwrap_or(body)
+            )));
+        }
+
+        if let Some(message) = &response.message {
+            info!("aa-proxy-rs returned: {}", message);
+        }
+
+        Ok(())
+    }
+}
+
+enum PostError {
+    UnknownField(String),
+    Other(anyhow::Error),
+}
+
+impl From<PostError> for anyhow::Error {
+    fn from(e: PostError) -> Self {
+        match e {
+            PostError::UnknownField(field) => {
+                anyhow!("aa-proxy-rs rejected unknown field '{}'", field)
+            }
+            PostError::Other(e) => e,
+        }
+    }
+}
+
+// HMAC-SHA256 signature of a request body, as sent in the signature header
+fn sign(secret: &str, body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(body);
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+// How long to wait before retrying a 429, or a 503 that says when to retry
+fn retry_after(status: StatusCode, headers: &HeaderMap) -> Option<Duration> {
+    let header = headers
+        .get(RETRY_AFTER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(parse_retry_after);
+
+    match status {
+        StatusCode::TOO_MANY_REQUESTS => Some(header.unwrap_or(DEFAULT_RETRY_AFTER)),
+        StatusCode::SERVICE_UNAVAILABLE => header,
+        _ => None,
+    }
+}
+
+// Parse a Retry-After value given either in seconds or as an HTTP date
+fn parse_retry_after(value: &str) -> Option<Duration> {
+    if let Ok(seconds) = value.trim().parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+
+    let date = DateTime::parse_from_rfc2822(value.trim()).ok()?;
+    Some(
+        (date.with_timezone(&Utc) - Utc::now())
+            .to_std()
+            .unwrap_or_default(),
+    )
+}
+
+// Extract the field name from a serde "unknown field `name`" rejection
+fn unknown_field(body: &str) -> Option<String> {
+    let start = body.find("unknown field `")? + "unknown field `".len();
+    let end = body[start..].find('`')?;
+    Some(body[start..start + end].to_string())
+}
+
+// Post synthetic battery data supplied on the command line and report the response
+pub async fn test_post(api: &ApiClient, data: &BatteryData) -> Result<()> {
+    info!("Posting synthetic battery data to aa-proxy-rs...");
+
+    match api.post_battery_data(data).await {
+        Ok(body) => {
+            info!("Test post succeeded. Response: '{}'", body);
+            Ok(())
+        }
+        Err(e) => Err(anyhow!("Test post failed: {}", e)),
+    }
+}
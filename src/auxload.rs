@@ -0,0 +1,38 @@
+// Gravitational acceleration in m/s²
+const GRAVITY: f32 = 9.81;
+
+// Air density at sea level and 15 °C in kg/m³
+const AIR_DENSITY: f32 = 1.225;
+
+// Rough road load model of the vehicle, used to tell the power needed to move
+// it from what the pack delivers to everything else
+#[derive(Debug, Clone)]
+pub struct DriveModel {
+    pub mass_kg: f32,
+    // Drag coefficient times frontal area in m²
+    pub drag_area_m2: f32,
+    pub rolling_resistance: f32,
+    // Share of the pack power that reaches the wheels
+    pub drivetrain_efficiency: f32,
+}
+
+impl DriveModel {
+    // Power in kW needed to hold a speed on a flat road, ignoring wind,
+    // gradient and acceleration
+    pub fn drive_power_kw(&self, speed_kmh: f32) -> f32 {
+        let speed = speed_kmh.max(0.0) / 3.6;
+        let force = self.mass_kg * GRAVITY * self.rolling_resistance
+            + 0.5 * AIR_DENSITY * self.drag_area_m2 * speed * speed;
+        force * speed / self.drivetrain_efficiency.clamp(0.1, 1.0) / 1000.0
+    }
+
+    // Pack power not explained by driving, i.e. accessories, climate and the
+    // 12V system. Battery power is positive while discharging; nothing is
+    // estimated while charging or regenerating.
+    pub fn auxiliary_load_kw(&self, battery_power_kw: f32, speed_kmh: f32) -> Option<f32> {
+        if battery_power_kw <= 0.0 {
+            return None;
+        }
+        Some((battery_power_kw - self.drive_power_kw(speed_kmh)).max(0.0))
+    }
+}
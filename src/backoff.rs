@@ -0,0 +1,124 @@
+use anyhow::Result;
+use log::warn;
+use std::collections::hash_map::RandomState;
+use std::fmt;
+use std::future::Future;
+use std::hash::BuildHasher;
+use std::sync::OnceLock;
+use std::time::Duration;
+use tokio::time;
+
+static POLICY: OnceLock<RetryPolicy> = OnceLock::new();
+
+// Stages of reaching the WiCAN, each retried within its own budget
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    Discovery,
+    Pairing,
+    Connect,
+    Gatt,
+}
+
+impl fmt::Display for Stage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Stage::Discovery => write!(f, "Discovery"),
+            Stage::Pairing => write!(f, "Pairing"),
+            Stage::Connect => write!(f, "Connection"),
+            Stage::Gatt => write!(f, "Reading from the WiCAN"),
+        }
+    }
+}
+
+// An error retrying won't fix, such as a device that isn't a WiCAN
+#[derive(Debug)]
+pub struct Permanent(pub anyhow::Error);
+
+impl fmt::Display for Permanent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:#}", self.0)
+    }
+}
+
+impl std::error::Error for Permanent {}
+
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+    // Attempts per poll at each stage
+    pub discovery_attempts: u8,
+    pub pairing_attempts: u8,
+    pub connect_attempts: u8,
+    pub gatt_attempts: u8,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            initial_delay: Duration::from_secs(2),
+            max_delay: Duration::from_secs(60),
+            discovery_attempts: 1,
+            pairing_attempts: 3,
+            connect_attempts: 5,
+            gatt_attempts: 3,
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn attempts(&self, stage: Stage) -> u8 {
+        let attempts = match stage {
+            Stage::Discovery => self.discovery_attempts,
+            Stage::Pairing => self.pairing_attempts,
+            Stage::Connect => self.connect_attempts,
+            Stage::Gatt => self.gatt_attempts,
+        };
+        attempts.max(1)
+    }
+
+    // Wait before the retry following the given attempt, doubling from the
+    // initial delay up to the maximum. Half of it is random, so retries
+    // after a shared cause, like the car waking up, don't run in lockstep.
+    pub fn delay(&self, attempt: u8) -> Duration {
+        let doubled = self
+            .initial_delay
+            .saturating_mul(1 << attempt.saturating_sub(1).min(16));
+        let delay = doubled.min(self.max_delay);
+        let random = RandomState::new().hash_one(attempt) as f64 / u64::MAX as f64;
+        delay / 2 + delay.mul_f64(random / 2.0)
+    }
+
+    // Run a stage until it succeeds, fails permanently or its budget is used up
+    pub async fn retry<T, F, Fut>(&self, stage: Stage, mut run: F) -> Result<T>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        let attempts = self.attempts(stage);
+        let mut attempt = 1;
+        loop {
+            match run().await {
+                Ok(value) => return Ok(value),
+                Err(e) if attempt < attempts && !e.is::<Permanent>() => {
+                    let delay = self.delay(attempt);
+                    attempt += 1;
+                    warn!(
+                        "{} failed: {:#}. Retrying in {:.1?} (attempt {}/{})...",
+                        stage, e, delay, attempt, attempts
+                    );
+                    time::sleep(delay).await;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+pub fn configure(policy: RetryPolicy) {
+    let _ = POLICY.set(policy);
+}
+
+pub fn policy() -> &'static RetryPolicy {
+    POLICY.get_or_init(RetryPolicy::default)
+}
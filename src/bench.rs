@@ -0,0 +1,160 @@
+use crate::bluez::BluezSession;
+use crate::dongle::{Dongle, DongleKind};
+use crate::WriteType;
+use anyhow::{anyhow, Context, Result};
+use bluer::{Address, DeviceEvent, DeviceProperty};
+use futures_util::StreamExt;
+use log::{info, warn};
+use std::time::{Duration, Instant};
+use tokio::time;
+
+pub struct BenchOptions {
+    pub wican_mac_address: Address,
+    pub wican_passkey: u32,
+    pub wican_timeout: Duration,
+    pub response_timeout: Duration,
+    pub write_type: WriteType,
+    pub verify_service: bool,
+    pub dongle: DongleKind,
+    pub iterations: u16,
+}
+
+// Durations measured for one kind of operation
+#[derive(Default)]
+struct Samples {
+    durations: Vec<Duration>,
+    failures: u32,
+}
+
+impl Samples {
+    fn record(&mut self, name: &str, result: Result<Duration>) {
+        match result {
+            Ok(duration) => {
+                info!("{}: {:?}", name, duration);
+                self.durations.push(duration);
+            }
+            Err(e) => {
+                warn!("{} failed: {:#}", name, e);
+                self.failures += 1;
+            }
+        }
+    }
+
+    // Nearest-rank percentile
+    fn percentile(&self, sorted: &[Duration], percentile: f64) -> Duration {
+        let rank = ((percentile / 100.0) * sorted.len() as f64).ceil() as usize;
+        sorted[rank.clamp(1, sorted.len()) - 1]
+    }
+
+    fn print(&self, name: &str) {
+        if self.durations.is_empty() {
+            println!(
+                "{:<12} no successful measurements, {} failed",
+                name, self.failures
+            );
+            return;
+        }
+
+        let mut sorted = self.durations.clone();
+        sorted.sort();
+        println!(
+            "{:<12} n={:<4} failed={:<4} min={:>8.1?} p50={:>8.1?} p90={:>8.1?} p99={:>8.1?} max={:>8.1?}",
+            name,
+            sorted.len(),
+            self.failures,
+            sorted[0],
+            self.percentile(&sorted, 50.0),
+            self.percentile(&sorted, 90.0),
+            self.percentile(&sorted, 99.0),
+            sorted[sorted.len() - 1]
+        );
+    }
+}
+
+// Repeatedly disconnect, rediscover, reconnect and fetch autopid data from the
+// WiCAN, reporting percentiles of each step
+pub async fn bench(options: BenchOptions) -> Result<()> {
+    let bluez = BluezSession::open(options.wican_passkey).await?;
+    let adapter = &bluez.adapter;
+    let device = crate::find_device(adapter, options.wican_mac_address, options.wican_timeout)
+        .await
+        .context("Failed to find device")?;
+    crate::try_pair(&device, options.verify_service).await?;
+
+    let mut scan = Samples::default();
+    let mut connect = Samples::default();
+    let mut round_trip = Samples::default();
+    let mut detected_dongle = None;
+
+    for iteration in 1..=options.iterations {
+        info!("Benchmark iteration {}/{}", iteration, options.iterations);
+
+        if device.is_connected().await? {
+            device.disconnect().await.context("Failed to disconnect")?;
+        }
+
+        // Time until an advertisement from the device is seen
+        let started = Instant::now();
+        let result = async {
+            let _discovery = adapter.discover_devices().await?;
+            let mut events = device.events().await?;
+            time::timeout(options.wican_timeout, async {
+                while let Some(DeviceEvent::PropertyChanged(property)) = events.next().await {
+                    if let DeviceProperty::Rssi(_) = property {
+                        return Ok(started.elapsed());
+                    }
+                }
+                Err(anyhow!("Device event stream ended"))
+            })
+            .await
+            .map_err(|_| anyhow!("No advertisement within {:?}", options.wican_timeout))?
+        }
+        .await;
+        scan.record("Scan", result);
+
+        let started = Instant::now();
+        let result = device
+            .connect()
+            .await
+            .map(|_| started.elapsed())
+            .context("Failed to connect");
+        let connected = result.is_ok();
+        connect.record("Connect", result);
+        if !connected {
+            continue;
+        }
+
+        let result = async {
+            let dongle = match detected_dongle {
+                Some(dongle) => dongle,
+                None => *detected_dongle.insert(Dongle::detect(&device, options.dongle).await),
+            };
+            if dongle.is_elm327() {
+                return Err(anyhow!(
+                    "The benchmark needs an autopid dongle, not an {}",
+                    dongle
+                ));
+            }
+            let (notify_char, write_char) = dongle.characteristics(&device).await?;
+            let mut notifications = Box::pin(dongle.notifications(&notify_char).await?);
+            let started = Instant::now();
+            crate::write_command(&write_char, b"autopid -d\n", options.write_type).await?;
+            time::timeout(options.response_timeout, notifications.next())
+                .await
+                .map_err(|_| anyhow!("No reply within {:?}", options.response_timeout))?
+                .context("Notification stream ended")?;
+            Ok(started.elapsed())
+        }
+        .await;
+        round_trip.record("Autopid round trip", result);
+    }
+
+    println!(
+        "aa-proxy-wican benchmark of {} over {} iteration(s)",
+        options.wican_mac_address, options.iterations
+    );
+    scan.print("Scan");
+    connect.print("Connect");
+    round_trip.print("Autopid RTT");
+    Ok(())
+}
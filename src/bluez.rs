@@ -0,0 +1,547 @@
+use anyhow::{anyhow, Context, Result};
+use bluer::agent::{Agent, AgentHandle};
+use bluer::{Adapter, Address, Device, ErrorKind, Session};
+use clap::ValueEnum;
+use dbus::channel::MatchingReceiver;
+use dbus::message::MatchRule;
+use dbus_tokio::connection;
+use log::{error, info, warn};
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+use tokio::process::Command;
+use tokio::time::{self, Instant};
+
+use crate::rpa;
+
+// Places bluetoothd is installed to by the common distributions
+const BLUETOOTHD_PATHS: &[&str] = &[
+    "bluetoothd",
+    "/usr/libexec/bluetooth/bluetoothd",
+    "/usr/lib/bluetooth/bluetoothd",
+    "/usr/sbin/bluetoothd",
+];
+
+// First release without the quirks the legacy profile works around
+const CURRENT_VERSION: BluezVersion = BluezVersion {
+    major: 5,
+    minor: 66,
+};
+
+// How long to wait for the GATT services of a device to be resolved
+const SERVICES_RESOLVED_TIMEOUT: Duration = Duration::from_secs(10);
+
+// How long shutting down waits on each BlueZ call before giving up
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+// How long the adapter is left off when power-cycling it
+const POWER_CYCLE_OFF: Duration = Duration::from_secs(2);
+
+const BLUEZ_BUS_NAME: &str = "org.bluez";
+
+static QUIRKS: OnceLock<Quirks> = OnceLock::new();
+
+static SCAN_DUTY_CYCLE: OnceLock<ScanDutyCycle> = OnceLock::new();
+
+static ADAPTER: OnceLock<AdapterSelector> = OnceLock::new();
+
+// Passkeys of WiCANs that don't use the one the session is opened with
+static PASSKEYS: OnceLock<HashMap<Address, u32>> = OnceLock::new();
+
+// The WiCAN in use, so it can be disconnected on shutdown
+static WICAN: Mutex<Option<Device>> = Mutex::new(None);
+
+// Number of times bluetoothd has stopped or started since watching began
+static RESTARTS: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct BluezVersion {
+    major: u32,
+    minor: u32,
+}
+
+impl FromStr for BluezVersion {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (major, minor) = s
+            .trim()
+            .split_once('.')
+            .ok_or_else(|| anyhow!("'{}' is not a BlueZ version", s))?;
+        Ok(Self {
+            major: major.parse()?,
+            minor: minor.parse()?,
+        })
+    }
+}
+
+impl fmt::Display for BluezVersion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{}", self.major, self.minor)
+    }
+}
+
+// Quirk profile selected on the command line
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum QuirkProfile {
+    Auto,
+    Legacy,
+    Current,
+}
+
+impl fmt::Display for QuirkProfile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            QuirkProfile::Auto => write!(f, "auto"),
+            QuirkProfile::Legacy => write!(f, "legacy"),
+            QuirkProfile::Current => write!(f, "current"),
+        }
+    }
+}
+
+// Workarounds for behavior that differs between BlueZ releases. Before 5.66
+// pairing an LE device that isn't connected often fails, its advertised
+// service UUIDs are only filled in once connected, and its GATT services are
+// listed empty until resolution has finished.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Quirks {
+    pub connect_before_pair: bool,
+    pub wait_for_services: bool,
+}
+
+impl Quirks {
+    fn for_profile(profile: QuirkProfile) -> Self {
+        match profile {
+            QuirkProfile::Legacy => Self {
+                connect_before_pair: true,
+                wait_for_services: true,
+            },
+            QuirkProfile::Auto | QuirkProfile::Current => Self::default(),
+        }
+    }
+}
+
+// Bluetooth adapter to use instead of the default one, given by its name,
+// e.g. hci1, or its address
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AdapterSelector {
+    Name(String),
+    Address(Address),
+}
+
+impl FromStr for AdapterSelector {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let s = s.trim();
+        if s.is_empty() {
+            return Err(anyhow!(
+                "Expected an adapter name such as hci1 or its address"
+            ));
+        }
+        Ok(match s.parse() {
+            Ok(address) => AdapterSelector::Address(address),
+            Err(_) => AdapterSelector::Name(s.to_string()),
+        })
+    }
+}
+
+impl fmt::Display for AdapterSelector {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AdapterSelector::Name(name) => write!(f, "{}", name),
+            AdapterSelector::Address(address) => write!(f, "{}", address),
+        }
+    }
+}
+
+pub fn set_adapter(selector: AdapterSelector) {
+    let _ = ADAPTER.set(selector);
+}
+
+pub fn set_passkeys(passkeys: HashMap<Address, u32>) {
+    let _ = PASSKEYS.set(passkeys);
+}
+
+fn device_passkey(address: Address) -> Option<u32> {
+    PASSKEYS.get()?.get(&rpa::identity(address)).copied()
+}
+
+// The selected adapter, or the default one when none is selected
+pub async fn adapter(session: &Session) -> Result<Adapter> {
+    match ADAPTER.get() {
+        None => session
+            .default_adapter()
+            .await
+            .context("Failed to find a Bluetooth adapter"),
+        Some(AdapterSelector::Name(name)) => {
+            let names = session.adapter_names().await?;
+            if !names.contains(name) {
+                return Err(anyhow!(
+                    "There is no Bluetooth adapter {}, found {}",
+                    name,
+                    adapter_list(&names)
+                ));
+            }
+            Ok(session.adapter(name)?)
+        }
+        Some(AdapterSelector::Address(address)) => {
+            let names = session.adapter_names().await?;
+            for name in &names {
+                let adapter = session.adapter(name)?;
+                if adapter.address().await? == *address {
+                    return Ok(adapter);
+                }
+            }
+            Err(anyhow!(
+                "There is no Bluetooth adapter with address {}, found {}",
+                address,
+                adapter_list(&names)
+            ))
+        }
+    }
+}
+
+fn adapter_list(names: &[String]) -> String {
+    match names.is_empty() {
+        true => "none".to_string(),
+        false => names.join(", "),
+    }
+}
+
+// Whether a failure points at the adapter or bluetoothd rather than the
+// WiCAN, such as operations left in progress forever or the adapter not being
+// ready. A car out of range fails differently and doesn't count.
+pub fn is_adapter_failure(error: &anyhow::Error) -> bool {
+    error
+        .chain()
+        .filter_map(|cause| cause.downcast_ref::<bluer::Error>())
+        .any(|error| {
+            matches!(
+                error.kind,
+                ErrorKind::NotReady
+                    | ErrorKind::InProgress
+                    | ErrorKind::DiscoveryActive
+                    | ErrorKind::Internal(_)
+            )
+        })
+}
+
+// Turn the adapter off and on again, which clears most wedged controller and
+// bluetoothd states without restarting anything
+pub async fn power_cycle(adapter: &Adapter) -> Result<()> {
+    time::timeout(SHUTDOWN_TIMEOUT, adapter.set_powered(false))
+        .await
+        .context("Timed out powering off the adapter")?
+        .context("Failed to power off the adapter")?;
+    time::sleep(POWER_CYCLE_OFF).await;
+    time::timeout(SHUTDOWN_TIMEOUT, adapter.set_powered(true))
+        .await
+        .context("Timed out powering on the adapter")?
+        .context("Failed to power on the adapter")?;
+    Ok(())
+}
+
+// Discovery in short windows with idle gaps between them, leaving airtime to
+// the Wi-Fi side of combo chips such as the one carrying wireless Android Auto
+#[derive(Debug, Clone, Copy)]
+pub struct ScanDutyCycle {
+    pub window: Duration,
+    pub gap: Duration,
+}
+
+pub fn set_scan_duty_cycle(duty_cycle: ScanDutyCycle) {
+    let _ = SCAN_DUTY_CYCLE.set(duty_cycle);
+}
+
+// Duty cycle to discover with, None to discover continuously
+pub fn scan_duty_cycle() -> Option<ScanDutyCycle> {
+    SCAN_DUTY_CYCLE.get().copied()
+}
+
+// Version of the installed bluetoothd, None when it can't be run
+pub async fn version() -> Option<BluezVersion> {
+    for path in BLUETOOTHD_PATHS {
+        let Ok(output) = Command::new(path).arg("--version").output().await else {
+            continue;
+        };
+        if output.status.success() {
+            return String::from_utf8_lossy(&output.stdout).parse().ok();
+        }
+    }
+    None
+}
+
+// Profile matching a BlueZ version, assuming a current one when unknown
+pub fn profile_for(version: Option<BluezVersion>) -> QuirkProfile {
+    match version {
+        Some(version) if version < CURRENT_VERSION => QuirkProfile::Legacy,
+        _ => QuirkProfile::Current,
+    }
+}
+
+// Select the workarounds to use, detecting the BlueZ version for the auto
+// profile. bluetoothd is run to read its version, so call this before the
+// sandbox is applied.
+pub async fn select_quirks(profile: QuirkProfile) {
+    let profile = match profile {
+        QuirkProfile::Auto => {
+            let version = version().await;
+            let profile = profile_for(version);
+            match version {
+                Some(version) => info!(
+                    "Detected BlueZ {}, using the {} quirk profile.",
+                    version, profile
+                ),
+                None => warn!(
+                    "Could not detect the BlueZ version, using the {} quirk profile.",
+                    profile
+                ),
+            }
+            profile
+        }
+        profile => {
+            info!("Using the {} BlueZ quirk profile.", profile);
+            profile
+        }
+    };
+    let _ = QUIRKS.set(Quirks::for_profile(profile));
+}
+
+pub fn quirks() -> Quirks {
+    QUIRKS.get().copied().unwrap_or_default()
+}
+
+// Wait until BlueZ has resolved the GATT services of a connected device
+pub async fn wait_for_services(device: &Device) -> Result<()> {
+    let deadline = Instant::now() + SERVICES_RESOLVED_TIMEOUT;
+    while !device.is_services_resolved().await? {
+        if Instant::now() >= deadline {
+            return Err(anyhow!(
+                "The services of {} were not resolved within {:?}",
+                device.address(),
+                SERVICES_RESOLVED_TIMEOUT
+            ));
+        }
+        time::sleep(Duration::from_millis(250)).await;
+    }
+    Ok(())
+}
+
+// Whether an error means the device no longer accepts our bond, as happens
+// when its keys were wiped by a firmware update or reset. BlueZ reports this
+// as an authentication failure or a missing key depending on the release.
+pub fn is_bond_rejected(error: &bluer::Error) -> bool {
+    if matches!(
+        error.kind,
+        ErrorKind::AuthenticationFailed | ErrorKind::AuthenticationRejected
+    ) {
+        return true;
+    }
+    let message = error.message.to_ascii_lowercase();
+    [
+        "key-missing",
+        "key missing",
+        "authentication failed",
+        "insufficient authentication",
+        "insufficient encryption",
+    ]
+    .iter()
+    .any(|reason| message.contains(reason))
+}
+
+// A BlueZ session with the default adapter and the passkey agent registered
+// for as long as it lives
+pub struct BluezSession {
+    pub adapter: Adapter,
+    _agent: AgentHandle,
+    restarts: u64,
+}
+
+impl BluezSession {
+    pub async fn open(passkey: u32) -> Result<Self> {
+        let restarts = RESTARTS.load(Ordering::Relaxed);
+        let session = Session::new().await.context("Failed to connect to BlueZ")?;
+        let adapter = adapter(&session).await?;
+        // Left off by --power-off-adapter-on-shutdown or another program
+        if !adapter.is_powered().await.unwrap_or(true) {
+            info!("Powering on Bluetooth adapter {}.", adapter.name());
+            adapter
+                .set_powered(true)
+                .await
+                .context("Failed to power on the Bluetooth adapter")?;
+        }
+        let agent = Agent {
+            request_default: true,
+            request_passkey: Some(Box::new(move |request| {
+                let passkey = device_passkey(request.device).unwrap_or(passkey);
+                Box::pin(async move {
+                    info!(
+                        "A device requested a passkey code. We're providing '{}'.",
+                        passkey
+                    );
+                    Ok(passkey)
+                })
+            })),
+            ..Default::default()
+        };
+        let agent = session
+            .register_agent(agent)
+            .await
+            .context("Failed to register the pairing agent")?;
+        Ok(Self {
+            adapter,
+            _agent: agent,
+            restarts,
+        })
+    }
+
+    // Whether bluetoothd has not restarted since the session was opened and
+    // still answers over it
+    async fn is_alive(&self) -> bool {
+        self.restarts == RESTARTS.load(Ordering::Relaxed) && self.adapter.is_powered().await.is_ok()
+    }
+}
+
+// BlueZ session kept across polling cycles, rebuilt with a new agent when
+// bluetoothd restarts or the connection to it drops
+pub struct BluezConnection {
+    passkey: u32,
+    current: Option<BluezSession>,
+    // Adapter failures in a row after which the adapter is power-cycled, 0 to
+    // never
+    recover_after: u32,
+    adapter_failures: u32,
+}
+
+impl BluezConnection {
+    pub fn new(passkey: u32) -> Self {
+        Self {
+            passkey,
+            current: None,
+            recover_after: 0,
+            adapter_failures: 0,
+        }
+    }
+
+    pub fn with_recovery(mut self, after_failures: u32) -> Self {
+        self.recover_after = after_failures;
+        self
+    }
+
+    // Count a failed discovery or connection, power-cycling the adapter once
+    // enough of them in a row point at it
+    pub async fn record_failure(&mut self, error: &anyhow::Error) {
+        if !is_adapter_failure(error) {
+            return;
+        }
+        self.adapter_failures += 1;
+        if self.recover_after == 0 || self.adapter_failures < self.recover_after {
+            return;
+        }
+        self.adapter_failures = 0;
+        let Some(session) = &self.current else {
+            return;
+        };
+        warn!(
+            "{} failures in a row point at Bluetooth adapter {}. Power-cycling it...",
+            self.recover_after,
+            session.adapter.name()
+        );
+        match power_cycle(&session.adapter).await {
+            Ok(()) => info!("Bluetooth adapter {} power-cycled.", session.adapter.name()),
+            Err(e) => error!(
+                "Failed to power-cycle Bluetooth adapter {}: {:#}",
+                session.adapter.name(),
+                e
+            ),
+        }
+    }
+
+    pub fn record_success(&mut self) {
+        self.adapter_failures = 0;
+    }
+
+    pub async fn session(&mut self) -> Result<&BluezSession> {
+        if let Some(current) = self.current.take() {
+            if current.is_alive().await {
+                return Ok(self.current.insert(current));
+            }
+            warn!("Lost the connection to BlueZ. Opening a new session...");
+        }
+        let session = BluezSession::open(self.passkey).await?;
+        Ok(self.current.insert(session))
+    }
+}
+
+pub fn set_wican(device: &Device) {
+    *WICAN.lock().unwrap() = Some(device.clone());
+}
+
+// Disconnect the WiCAN when shutting down, as BlueZ otherwise keeps the link
+// up after the process is gone and the next start can't connect. The device
+// is set as soon as it's found, which covers a connection still being made
+// when the signal arrived. Returns the address of a connection that was
+// closed.
+pub async fn disconnect_on_shutdown(power_off_adapter: bool) -> Result<Option<Address>> {
+    let device = WICAN.lock().unwrap().take();
+    let mut disconnected = None;
+    if let Some(device) = device {
+        if device.is_connected().await.unwrap_or(false) {
+            info!("Disconnecting from {}...", device.address());
+            time::timeout(SHUTDOWN_TIMEOUT, device.disconnect())
+                .await
+                .context("Timed out disconnecting")?
+                .context("Failed to disconnect")?;
+            disconnected = Some(device.address());
+        }
+    }
+    if power_off_adapter {
+        let session = Session::new().await.context("Failed to connect to BlueZ")?;
+        let adapter = adapter(&session).await?;
+        info!("Powering off Bluetooth adapter {}.", adapter.name());
+        time::timeout(SHUTDOWN_TIMEOUT, adapter.set_powered(false))
+            .await
+            .context("Timed out powering off the adapter")?
+            .context("Failed to power off the adapter")?;
+    }
+    Ok(disconnected)
+}
+
+// Count bluetoothd restarts in the background from the owner changes of its
+// bus name, so sessions opened before a restart are replaced even when
+// bluetoothd came back before the next poll
+pub async fn watch_restarts() -> Result<()> {
+    let (resource, connection) =
+        connection::new_system_sync().context("Failed to connect to the system bus")?;
+    tokio::spawn(async move {
+        let e = resource.await;
+        error!("Lost the D-Bus connection watching bluetoothd: {}", e);
+    });
+
+    let rule = MatchRule::new_signal("org.freedesktop.DBus", "NameOwnerChanged")
+        .with_sender("org.freedesktop.DBus");
+    connection
+        .add_match_no_cb(&rule.match_str())
+        .await
+        .context("Failed to watch bluetoothd")?;
+    connection.start_receive(
+        rule,
+        Box::new(|message, _| {
+            if let Ok((name, _, new_owner)) = message.read3::<String, String, String>() {
+                if name == BLUEZ_BUS_NAME {
+                    match new_owner.is_empty() {
+                        true => warn!("bluetoothd stopped."),
+                        false => info!("bluetoothd started."),
+                    }
+                    RESTARTS.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+            true
+        }),
+    );
+    Ok(())
+}
@@ -0,0 +1,113 @@
+use anyhow::{anyhow, Context, Result};
+use log::{debug, warn};
+use std::fmt;
+
+// Upper bound for a line being reassembled, so a lost terminator can't grow
+// the buffer forever
+const MAX_LINE_LEN: usize = 1024;
+
+// A classic CAN frame as received from the bus
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CanFrame {
+    pub id: u32,
+    pub extended: bool,
+    pub data: Vec<u8>,
+}
+
+impl CanFrame {
+    // Parse an SLCAN frame, "tIIILDD.." for standard and "TIIIIIIIILDD.." for
+    // extended ids, ignoring a trailing timestamp
+    pub fn parse_slcan(line: &str) -> Result<Self> {
+        let (extended, rest) = match line.split_at_checked(1) {
+            Some(("t", rest)) => (false, rest),
+            Some(("T", rest)) => (true, rest),
+            _ => return Err(anyhow!("'{}' is not an SLCAN data frame", line)),
+        };
+        let id_len = if extended { 8 } else { 3 };
+        let (id, rest) = rest
+            .split_at_checked(id_len)
+            .ok_or_else(|| anyhow!("SLCAN frame '{}' is too short", line))?;
+        let id = u32::from_str_radix(id, 16)
+            .with_context(|| format!("Invalid id in SLCAN frame '{}'", line))?;
+        let (dlc, rest) = rest
+            .split_at_checked(1)
+            .ok_or_else(|| anyhow!("SLCAN frame '{}' has no length", line))?;
+        let dlc = dlc
+            .parse::<usize>()
+            .ok()
+            .filter(|dlc| *dlc <= 8)
+            .ok_or_else(|| anyhow!("Invalid length in SLCAN frame '{}'", line))?;
+        let data = rest
+            .get(..dlc * 2)
+            .ok_or_else(|| anyhow!("SLCAN frame '{}' is shorter than its length", line))?;
+        let data =
+            hex::decode(data).with_context(|| format!("Invalid data in SLCAN frame '{}'", line))?;
+
+        Ok(Self { id, extended, data })
+    }
+
+    // SLCAN command transmitting the frame, terminated by a carriage return
+    pub fn to_slcan(&self) -> String {
+        if self.extended {
+            format!(
+                "T{:08X}{}{}\r",
+                self.id,
+                self.data.len(),
+                hex::encode_upper(&self.data)
+            )
+        } else {
+            format!(
+                "t{:03X}{}{}\r",
+                self.id,
+                self.data.len(),
+                hex::encode_upper(&self.data)
+            )
+        }
+    }
+}
+
+// Formatted like candump, e.g. "123#DEADBEEF"
+impl fmt::Display for CanFrame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.extended {
+            write!(f, "{:08X}#{}", self.id, hex::encode_upper(&self.data))
+        } else {
+            write!(f, "{:03X}#{}", self.id, hex::encode_upper(&self.data))
+        }
+    }
+}
+
+// Splits notifications from a WiCAN in SLCAN mode into frames
+#[derive(Default)]
+pub struct SlcanReader {
+    buffer: Vec<u8>,
+}
+
+impl SlcanReader {
+    // Feed a notification, returning the frames it completed. Replies to
+    // commands and malformed lines are skipped.
+    pub fn push(&mut self, chunk: &[u8]) -> Vec<CanFrame> {
+        self.buffer.extend_from_slice(chunk);
+        let mut frames = Vec::new();
+        while let Some(end) = self.buffer.iter().position(|b| matches!(b, b'\r' | 0x07)) {
+            let line: Vec<u8> = self.buffer.drain(..=end).collect();
+            let line = String::from_utf8_lossy(&line[..line.len() - 1]);
+            let line = line.trim();
+            if line.starts_with(['t', 'T']) {
+                match CanFrame::parse_slcan(line) {
+                    Ok(frame) => frames.push(frame),
+                    Err(e) => debug!("Skipping SLCAN line: {}", e),
+                }
+            }
+        }
+
+        if self.buffer.len() > MAX_LINE_LEN {
+            warn!(
+                "Discarding {} bytes of SLCAN data without a terminator.",
+                self.buffer.len()
+            );
+            self.buffer.clear();
+        }
+        frames
+    }
+}
@@ -0,0 +1,121 @@
+use crate::can::CanFrame;
+use anyhow::{Context, Result};
+use chrono::{DateTime, Local, Utc};
+use log::{info, warn};
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+// Interface name written on every line, SavvyCAN shows it as the bus
+const INTERFACE: &str = "can0";
+
+// Log files are named like those of candump -l, e.g. candump-2024-05-01_120000.log
+const FILE_PREFIX: &str = "candump-";
+const FILE_SUFFIX: &str = ".log";
+
+// Received frames written to candump log files in a directory, starting a new
+// file once the current one reaches the size limit and removing the oldest
+// files beyond the file limit
+pub struct CanLog {
+    dir: PathBuf,
+    max_bytes: u64,
+    max_files: usize,
+    file: Option<(PathBuf, File)>,
+    written: u64,
+}
+
+impl CanLog {
+    pub fn new(dir: &Path, max_bytes: u64, max_files: usize) -> Self {
+        Self {
+            dir: dir.to_path_buf(),
+            max_bytes,
+            max_files,
+            file: None,
+            written: 0,
+        }
+    }
+
+    // Append a frame as "(SECONDS.MICROS) can0 123#DEADBEEF"
+    pub fn write(&mut self, frame: &CanFrame, timestamp: DateTime<Utc>) -> Result<()> {
+        let line = format!(
+            "({}.{:06}) {} {}\n",
+            timestamp.timestamp(),
+            timestamp.timestamp_subsec_micros(),
+            INTERFACE,
+            frame
+        );
+
+        if self.file.is_none() || (self.max_bytes > 0 && self.written >= self.max_bytes) {
+            self.rotate()?;
+        }
+        let Some((_, file)) = self.file.as_mut() else {
+            return Ok(());
+        };
+        if let Err(e) = file.write_all(line.as_bytes()) {
+            // Start over with a fresh file next time
+            self.file = None;
+            return Err(e).context("Failed to write to the CAN log");
+        }
+        self.written += line.len() as u64;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> Result<()> {
+        let name = format!(
+            "{}{}{}",
+            FILE_PREFIX,
+            Local::now().format("%Y-%m-%d_%H%M%S"),
+            FILE_SUFFIX
+        );
+        let path = self.dir.join(name);
+        // Already rotated within this second, keep writing to the same file
+        if self
+            .file
+            .as_ref()
+            .is_some_and(|(current, _)| *current == path)
+        {
+            self.written = 0;
+            return Ok(());
+        }
+
+        let file = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(&path)
+            .with_context(|| format!("Failed to open CAN log '{}'", path.display()))?;
+        self.written = file.metadata().map(|metadata| metadata.len()).unwrap_or(0);
+        self.file = Some((path.clone(), file));
+        info!("Logging CAN frames to {}.", path.display());
+
+        if let Err(e) = self.remove_old_files() {
+            warn!("Failed to remove old CAN logs: {:#}", e);
+        }
+        Ok(())
+    }
+
+    // The timestamped names sort chronologically, so keep the last ones
+    fn remove_old_files(&self) -> Result<()> {
+        if self.max_files == 0 {
+            return Ok(());
+        }
+
+        let mut logs: Vec<PathBuf> = std::fs::read_dir(&self.dir)
+            .with_context(|| format!("Failed to list '{}'", self.dir.display()))?
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| {
+                path.file_name()
+                    .and_then(|name| name.to_str())
+                    .is_some_and(|name| {
+                        name.starts_with(FILE_PREFIX) && name.ends_with(FILE_SUFFIX)
+                    })
+            })
+            .collect();
+        logs.sort();
+        let excess = logs.len().saturating_sub(self.max_files);
+        for path in &logs[..excess] {
+            std::fs::remove_file(path)
+                .with_context(|| format!("Failed to remove '{}'", path.display()))?;
+        }
+        Ok(())
+    }
+}
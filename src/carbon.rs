@@ -0,0 +1,139 @@
+use anyhow::{anyhow, Context, Result};
+use clap::ValueEnum;
+use log::{debug, info, warn};
+use reqwest::{Client, Url};
+use serde_json::Value;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::events::{self, Event};
+use crate::secrets::Secret;
+
+pub const ELECTRICITY_MAPS_URL: &str = "https://api.electricitymap.org/v3/carbon-intensity/latest";
+pub const UK_URL: &str = "https://api.carbonintensity.org.uk/regional/postcode/";
+
+// Both services update at most every half hour
+const REFRESH_INTERVAL: Duration = Duration::from_secs(15 * 60);
+
+// Readings older than this are no longer used for charging sessions
+const MAX_AGE: Duration = Duration::from_secs(2 * 60 * 60);
+
+// Latest reading in gCO2/kWh and when it was fetched
+static LATEST: Mutex<Option<(f64, Instant)>> = Mutex::new(None);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum CarbonProvider {
+    // api.electricitymap.org, by zone such as DE, with an API token
+    ElectricityMaps,
+    // api.carbonintensity.org.uk, by outward postcode such as RG10
+    Uk,
+}
+
+impl CarbonProvider {
+    pub fn url(&self) -> &'static str {
+        match self {
+            CarbonProvider::ElectricityMaps => ELECTRICITY_MAPS_URL,
+            CarbonProvider::Uk => UK_URL,
+        }
+    }
+}
+
+pub struct CarbonIntensityOptions {
+    pub provider: CarbonProvider,
+    pub region: String,
+    pub token: Option<Secret>,
+    pub threshold: Option<f64>,
+}
+
+// Follows the carbon intensity of the grid in a region, for pricing charging
+// sessions in CO2 and telling when it's a good time to charge
+pub struct CarbonIntensity {
+    client: Client,
+    url: Url,
+    token: Option<Secret>,
+    threshold: Option<f64>,
+}
+
+impl CarbonIntensity {
+    pub fn new(options: CarbonIntensityOptions, client: Client) -> Result<Self> {
+        let url = match options.provider {
+            CarbonProvider::ElectricityMaps => {
+                if options.token.is_none() {
+                    return Err(anyhow!(
+                        "Electricity Maps needs an API token, set --carbon-intensity-token"
+                    ));
+                }
+                Url::parse_with_params(ELECTRICITY_MAPS_URL, [("zone", &options.region)])?
+            }
+            CarbonProvider::Uk => Url::parse(UK_URL)?
+                .join(&options.region.replace(' ', ""))
+                .with_context(|| format!("Invalid postcode '{}'", options.region))?,
+        };
+        Ok(Self {
+            client,
+            url,
+            token: options.token,
+            threshold: options.threshold,
+        })
+    }
+
+    pub async fn run(self) {
+        info!("Following the grid carbon intensity from {}", self.url);
+        let mut below = false;
+        loop {
+            match self.fetch().await {
+                Ok(intensity) => {
+                    debug!("Grid carbon intensity is {:.0} gCO2/kWh", intensity);
+                    *LATEST.lock().unwrap() = Some((intensity, Instant::now()));
+                    if let Some(threshold) = self.threshold {
+                        if intensity < threshold && !below {
+                            info!(
+                                "Grid carbon intensity {:.0} gCO2/kWh is below {:.0} gCO2/kWh, a good time to charge.",
+                                intensity, threshold
+                            );
+                            events::emit(Event::LowCarbonIntensity {
+                                intensity,
+                                threshold,
+                            });
+                        }
+                        below = intensity < threshold;
+                    }
+                }
+                Err(e) => warn!("Failed to read the grid carbon intensity: {:#}", e),
+            }
+            tokio::time::sleep(REFRESH_INTERVAL).await;
+        }
+    }
+
+    async fn fetch(&self) -> Result<f64> {
+        let mut request = self.client.get(self.url.clone());
+        if let Some(token) = &self.token {
+            request = request.header("auth-token", token.expose());
+        }
+        let response = request
+            .send()
+            .await
+            .context("Could not reach the carbon intensity API")?;
+        let status = response.status();
+        if !status.is_success() {
+            let body = response.text().await.unwrap_or_default();
+            return Err(anyhow!("{}: {}", status, body.trim()));
+        }
+        let body: Value = response.json().await.context("Invalid response")?;
+        // Electricity Maps answers {"carbonIntensity": 302, ...} and the UK
+        // API {"data": [{"data": [{"intensity": {"forecast": 123, ...}}]}]}
+        body["carbonIntensity"]
+            .as_f64()
+            .or_else(|| body["data"][0]["data"][0]["intensity"]["forecast"].as_f64())
+            .ok_or_else(|| anyhow!("No carbon intensity in the response: {}", body))
+    }
+}
+
+// The latest carbon intensity in gCO2/kWh, unless it's out of date
+pub fn latest() -> Option<f64> {
+    LATEST
+        .lock()
+        .unwrap()
+        .filter(|(_, fetched)| fetched.elapsed() < MAX_AGE)
+        .map(|(intensity, _)| intensity)
+}
@@ -0,0 +1,71 @@
+use log::debug;
+use std::time::Duration;
+
+// Drop below the wake voltage needed before another rise counts as the car
+// waking, so a reading hovering around the threshold triggers once
+const VOLTAGE_HYSTERESIS: f32 = 0.2;
+
+// Signs in what the WiCAN sends between polls that the car just woke up or
+// started charging, each triggering an update straight away
+pub struct WakeOptions {
+    // 12V voltage at or above which the DC-DC converter is charging the
+    // auxiliary battery, as it does while the car is on or charging
+    pub voltage: Option<f32>,
+    // How often the voltage is read with ATRV
+    pub voltage_interval: Duration,
+    // Text in a notification that signals the WiCAN or the car waking
+    pub patterns: Vec<String>,
+}
+
+pub struct WakeDetector {
+    options: WakeOptions,
+    // Whether a rise to the wake voltage would count, false until the first
+    // reading below it
+    armed: bool,
+}
+
+impl WakeDetector {
+    pub fn new(options: WakeOptions) -> Self {
+        Self {
+            options,
+            armed: false,
+        }
+    }
+
+    pub fn voltage_interval(&self) -> Option<Duration> {
+        self.options.voltage.map(|_| self.options.voltage_interval)
+    }
+
+    // The reason to update now, if the frame shows the car waking
+    pub fn check(&mut self, frame: &[u8]) -> Option<String> {
+        let text = String::from_utf8_lossy(frame);
+        if let Some(pattern) = self
+            .options
+            .patterns
+            .iter()
+            .find(|pattern| text.contains(pattern.as_str()))
+        {
+            return Some(format!("The WiCAN sent '{}'", pattern));
+        }
+        let threshold = self.options.voltage?;
+        let voltage = parse_voltage(&text)?;
+        debug!("12V battery at {:.1} V", voltage);
+        if voltage < threshold - VOLTAGE_HYSTERESIS {
+            self.armed = true;
+        } else if voltage >= threshold && self.armed {
+            self.armed = false;
+            return Some(format!(
+                "The 12V battery rose to {:.1} V, the car is on or charging",
+                voltage
+            ));
+        }
+        None
+    }
+}
+
+// The reply to ATRV, e.g. "12.6V", on a line of its own
+fn parse_voltage(text: &str) -> Option<f32> {
+    text.lines()
+        .map(|line| line.trim().trim_end_matches('>').trim())
+        .find_map(|line| line.strip_suffix(['V', 'v'])?.trim().parse().ok())
+}
@@ -0,0 +1,151 @@
+use chrono::{DateTime, Utc};
+use clap::ValueEnum;
+use serde::de::{self, Deserializer, Unexpected, Visitor};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::Mutex;
+
+// Shortest time between the samples a charging power is estimated from, so
+// frames arriving together don't produce absurd power figures
+const MIN_ESTIMATE_SECONDS: i64 = 120;
+
+// Last SOC reading the charging power is estimated from, and the type and
+// power it gave, for each car by vehicle tag
+static ESTIMATES: Mutex<BTreeMap<Option<String>, Estimate>> = Mutex::new(BTreeMap::new());
+
+#[derive(Default)]
+struct Estimate {
+    reference: Option<(DateTime<Utc>, f32)>,
+    charging_type: Option<ChargingType>,
+    power_kw: Option<f32>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum ChargingType {
+    Ac,
+    Dc,
+}
+
+impl fmt::Display for ChargingType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ChargingType::Ac => write!(f, "AC"),
+            ChargingType::Dc => write!(f, "DC"),
+        }
+    }
+}
+
+// Guess the charging type from how fast the SOC rises, as the onboard charger
+// caps AC charging at the vehicle's maximum AC power. None while the SOC is
+// not rising.
+pub fn estimate(
+    vehicle_tag: Option<&str>,
+    soc: f32,
+    timestamp: DateTime<Utc>,
+    battery_capacity_wh: u32,
+    max_ac_power_kw: f32,
+) -> Option<ChargingType> {
+    let mut estimates = ESTIMATES.lock().unwrap();
+    let estimate = estimates
+        .entry(vehicle_tag.map(str::to_string))
+        .or_default();
+    let Some((since, previous)) = estimate.reference else {
+        estimate.reference = Some((timestamp, soc));
+        return None;
+    };
+    let seconds = (timestamp - since).num_seconds();
+    if seconds < MIN_ESTIMATE_SECONDS {
+        return estimate.charging_type;
+    }
+
+    let power_kw =
+        (soc - previous) / 100.0 * battery_capacity_wh as f32 / 1000.0 / (seconds as f32 / 3600.0);
+    estimate.reference = Some((timestamp, soc));
+    estimate.power_kw = (soc > previous).then_some(power_kw);
+    estimate.charging_type = estimate.power_kw.map(|power_kw| {
+        if power_kw > max_ac_power_kw {
+            ChargingType::Dc
+        } else {
+            ChargingType::Ac
+        }
+    });
+    estimate.charging_type
+}
+
+// Charging power from the car's latest estimate, None while the SOC is not
+// rising
+pub fn estimated_power_kw(vehicle_tag: Option<&str>) -> Option<f32> {
+    ESTIMATES
+        .lock()
+        .unwrap()
+        .get(&vehicle_tag.map(str::to_string))
+        .and_then(|estimate| estimate.power_kw)
+}
+
+struct TolerantChargingType;
+
+impl<'de> Visitor<'de> for TolerantChargingType {
+    type Value = Option<ChargingType>;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("\"AC\", \"DC\", a number or null")
+    }
+
+    fn visit_none<E: de::Error>(self) -> Result<Self::Value, E> {
+        Ok(None)
+    }
+
+    fn visit_unit<E: de::Error>(self) -> Result<Self::Value, E> {
+        Ok(None)
+    }
+
+    fn visit_some<D: Deserializer<'de>>(self, deserializer: D) -> Result<Self::Value, D::Error> {
+        deserializer.deserialize_any(self)
+    }
+
+    fn visit_u64<E: de::Error>(self, value: u64) -> Result<Self::Value, E> {
+        match value {
+            0 => Ok(None),
+            1 => Ok(Some(ChargingType::Ac)),
+            2 => Ok(Some(ChargingType::Dc)),
+            _ => Err(E::invalid_value(Unexpected::Unsigned(value), &self)),
+        }
+    }
+
+    fn visit_i64<E: de::Error>(self, value: i64) -> Result<Self::Value, E> {
+        match u64::try_from(value) {
+            Ok(value) => self.visit_u64(value),
+            Err(_) => Err(E::invalid_value(Unexpected::Signed(value), &self)),
+        }
+    }
+
+    fn visit_f64<E: de::Error>(self, value: f64) -> Result<Self::Value, E> {
+        if value.fract() == 0.0 && value >= 0.0 {
+            self.visit_u64(value as u64)
+        } else {
+            Err(E::invalid_value(Unexpected::Float(value), &self))
+        }
+    }
+
+    fn visit_str<E: de::Error>(self, value: &str) -> Result<Self::Value, E> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "" | "none" | "off" => Ok(None),
+            "ac" => Ok(Some(ChargingType::Ac)),
+            "dc" => Ok(Some(ChargingType::Dc)),
+            number => match number.parse::<u64>() {
+                Ok(number) => self.visit_u64(number),
+                Err(_) => Err(E::invalid_value(Unexpected::Str(value), &self)),
+            },
+        }
+    }
+}
+
+// Deserialize a charging type reported by the vehicle, either "AC" or "DC" or
+// a number where 0 is not charging, 1 is AC and 2 is DC
+pub fn tolerant_option_charging_type<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<ChargingType>, D::Error> {
+    deserializer.deserialize_option(TolerantChargingType)
+}
@@ -0,0 +1,316 @@
+use anyhow::{anyhow, Context, Result};
+use bluer::gatt::remote::{Characteristic, CharacteristicWriteRequest};
+use bluer::gatt::WriteOp;
+use bluer::{Adapter, AdapterEvent, Address, Device};
+use clap::ValueEnum;
+use futures_util::stream::{Stream, StreamExt};
+use log::{debug, info};
+use std::time::Duration;
+use tokio::time;
+
+use crate::backoff::{self, Permanent, Stage};
+use crate::bluez::{self, BluezSession};
+use crate::dongle::{Dongle, DongleKind};
+use crate::profile::AutopidKeys;
+use crate::telemetry::{self, WicanResponse};
+use crate::{rpa, throttle, trace};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum WriteType {
+    Auto,
+    WithResponse,
+    WithoutResponse,
+    Reliable,
+}
+
+// How to reach a WiCAN with WicanClient
+pub struct ClientOptions {
+    // Passkey the WiCAN is paired with
+    pub passkey: u32,
+    // Limit for finding the WiCAN in a scan
+    pub scan_timeout: Duration,
+    // Limit for the reply to an autopid request
+    pub response_timeout: Duration,
+    pub write_type: WriteType,
+    pub dongle: DongleKind,
+    // Check the device advertises a WiCAN service before pairing with it
+    pub verify_service: bool,
+    // Keys fields are read from when the autopid profile reports them under
+    // other names
+    pub autopid_keys: AutopidKeys,
+}
+
+impl Default for ClientOptions {
+    fn default() -> Self {
+        Self {
+            passkey: 123456,
+            scan_timeout: Duration::from_secs(10),
+            response_timeout: Duration::from_secs(10),
+            write_type: WriteType::Auto,
+            dongle: DongleKind::Auto,
+            verify_service: true,
+            autopid_keys: AutopidKeys::default(),
+        }
+    }
+}
+
+// A connected WiCAN, for programs that want the autopid values without the
+// rest of aa-proxy-wican. Each stage of connecting is retried within the
+// budgets of the backoff policy.
+pub struct WicanClient {
+    // Keeps the pairing agent registered for as long as the client lives
+    _session: BluezSession,
+    device: Device,
+    dongle: Dongle,
+    options: ClientOptions,
+}
+
+impl WicanClient {
+    // Find, pair with and connect to the WiCAN at an address
+    pub async fn connect(address: Address, options: ClientOptions) -> Result<Self> {
+        let session = BluezSession::open(options.passkey).await?;
+        let retry = backoff::policy();
+        let device = retry
+            .retry(Stage::Discovery, || {
+                find_device(&session.adapter, address, options.scan_timeout)
+            })
+            .await?;
+        retry
+            .retry(Stage::Pairing, || try_pair(&device, options.verify_service))
+            .await?;
+        if !device.is_connected().await? {
+            retry
+                .retry(Stage::Connect, || async {
+                    device.connect().await.context("Failed to connect")
+                })
+                .await?;
+        }
+
+        let dongle = Dongle::detect(&device, options.dongle).await;
+        if dongle.is_elm327() {
+            return Err(anyhow!(
+                "The {} is an ELM327 adapter, which does not answer autopid requests",
+                dongle
+            ));
+        }
+        Ok(Self {
+            _session: session,
+            device,
+            dongle,
+            options,
+        })
+    }
+
+    pub fn device(&self) -> &Device {
+        &self.device
+    }
+
+    pub fn dongle(&self) -> Dongle {
+        self.dongle
+    }
+
+    // Request the autopid values and wait for the reply
+    pub async fn query_autopid(&self) -> Result<WicanResponse> {
+        let (notify_char, write_char) = self
+            .dongle
+            .characteristics(&self.device)
+            .await
+            .context("Failed to find WiCAN characteristics")?;
+        let mut notifications = Box::pin(self.dongle.notifications(&notify_char).await?);
+        write_command(&write_char, b"autopid -d\n", self.options.write_type).await?;
+
+        let frame = time::timeout(self.options.response_timeout, notifications.next())
+            .await
+            .map_err(|_| {
+                anyhow!(
+                    "No reply from the WiCAN within {:?}",
+                    self.options.response_timeout
+                )
+            })?
+            .ok_or_else(|| anyhow!("Notification stream ended unexpectedly"))?;
+        telemetry::parse(&frame, &self.options.autopid_keys)
+    }
+
+    // Follow the autopid values the WiCAN broadcasts, until it disconnects
+    pub async fn subscribe(&self) -> Result<impl Stream<Item = Result<WicanResponse>>> {
+        let (notify_char, _) = self
+            .dongle
+            .characteristics(&self.device)
+            .await
+            .context("Failed to find WiCAN characteristics")?;
+        let notifications = self.dongle.notifications(&notify_char).await?;
+        let keys = self.options.autopid_keys.clone();
+        Ok(notifications.map(move |frame| telemetry::parse(&frame, &keys)))
+    }
+
+    pub async fn disconnect(self) -> Result<()> {
+        self.device
+            .disconnect()
+            .await
+            .context("Failed to disconnect")
+    }
+}
+
+// Write a command to the WiCAN using the configured write type
+pub async fn write_command(
+    characteristic: &Characteristic,
+    command: &[u8],
+    write_type: WriteType,
+) -> Result<()> {
+    let op_type = match write_type {
+        WriteType::WithResponse => WriteOp::Request,
+        WriteType::WithoutResponse => WriteOp::Command,
+        WriteType::Reliable => WriteOp::Reliable,
+        WriteType::Auto => {
+            let flags = characteristic.flags().await?;
+            if flags.write_without_response {
+                WriteOp::Command
+            } else if flags.write {
+                WriteOp::Request
+            } else if flags.reliable_write {
+                WriteOp::Reliable
+            } else {
+                return Err(anyhow!(
+                    "The WiCAN write characteristic does not support writing: {:?}",
+                    flags
+                ));
+            }
+        }
+    };
+    debug!(
+        "Writing {:?} to WiCAN using write type '{}'",
+        command, op_type
+    );
+
+    let request = CharacteristicWriteRequest {
+        op_type,
+        ..Default::default()
+    };
+    throttle::acquire().await?;
+    trace::frame(trace::Direction::Sent, command);
+    characteristic
+        .write_ext(command, &request)
+        .await
+        .with_context(|| format!("Failed to write to WiCAN using write type '{}'", op_type))
+}
+
+// Finds the target Bluetooth device by its MAC address during a discovery scan.
+pub async fn find_device(
+    adapter: &Adapter,
+    wican_mac_address: Address,
+    wican_timeout: Duration,
+) -> Result<Device> {
+    if adapter
+        .device(wican_mac_address)?
+        .is_services_resolved()
+        .await
+        .is_ok()
+    {
+        info!("Device {} is known and available.", wican_mac_address);
+        return Ok(adapter.device(wican_mac_address)?);
+    }
+
+    let duty_cycle = bluez::scan_duty_cycle();
+    match duty_cycle {
+        Some(duty_cycle) => info!(
+            "Starting device discovery to find {} for a maximum of {:?}, scanning for {:?} with {:?} gaps",
+            wican_mac_address, wican_timeout, duty_cycle.window, duty_cycle.gap
+        ),
+        None => info!(
+            "Starting device discovery to find {} for a maximum of {:?}",
+            wican_mac_address, wican_timeout
+        ),
+    }
+
+    let deadline = time::Instant::now() + wican_timeout;
+    loop {
+        let remaining = deadline.saturating_duration_since(time::Instant::now());
+        if remaining.is_zero() {
+            return Err(anyhow!("Scan timed out without finding device."));
+        }
+        let window = duty_cycle.map_or(remaining, |duty_cycle| duty_cycle.window.min(remaining));
+
+        // Discovery stops when the event stream is dropped at the end of the window
+        let mut device_events = adapter.discover_devices().await?;
+        let found = time::timeout(window, async {
+            while let Some(event) = device_events.next().await {
+                if let AdapterEvent::DeviceAdded(addr) = event {
+                    if rpa::matches(addr, wican_mac_address) {
+                        return Some(addr);
+                    }
+                }
+            }
+            None
+        })
+        .await;
+        drop(device_events);
+        match found {
+            Ok(Some(addr)) => {
+                info!("Found device with address: {}", addr);
+                return Ok(adapter.device(addr)?);
+            }
+            Ok(None) => return Err(anyhow!("Device discovery ended unexpectedly.")),
+            Err(_) => {}
+        }
+
+        if let Some(duty_cycle) = duty_cycle {
+            let gap = duty_cycle
+                .gap
+                .min(deadline.saturating_duration_since(time::Instant::now()));
+            debug!("Pausing discovery for {:?}", gap);
+            time::sleep(gap).await;
+        }
+    }
+}
+
+// Confirms the device advertises a WiCAN service, so a mistyped MAC address
+// doesn't result in pairing with an unrelated device.
+async fn verify_wican_service(device: &Device) -> Result<()> {
+    let uuids = device.uuids().await?.unwrap_or_default();
+    debug!(
+        "Device {} advertises services: {:?}",
+        device.address(),
+        uuids
+    );
+
+    match Dongle::from_services(&uuids) {
+        Some(dongle) => {
+            info!("Device {} advertises the {} service.", device.address(), dongle);
+            Ok(())
+        }
+        None => Err(Permanent(anyhow!(
+            "Device {} does not advertise a WiCAN service. Check the configured MAC address, or use --wican-skip-service-check if this really is your WiCAN.",
+            device.address()
+        ))
+        .into()),
+    }
+}
+
+// Attempts to pair with the device if it is not already paired, answering
+// passkey requests with the agent of the BlueZ session.
+pub async fn try_pair(device: &Device, verify_service: bool) -> Result<()> {
+    if device.is_paired().await? {
+        info!("Device is already paired. Skipping pairing.");
+        return Ok(());
+    }
+
+    // Older BlueZ releases only pair LE devices reliably once connected, and
+    // only know their services by then
+    if bluez::quirks().connect_before_pair && !device.is_connected().await? {
+        info!("Connecting before pairing...");
+        device
+            .connect()
+            .await
+            .context("Failed to connect before pairing")?;
+    }
+
+    if verify_service {
+        verify_wican_service(device).await?;
+    }
+
+    info!("Attempting to pair with device...");
+    device.pair().await.context("Failed to pair with device")?;
+
+    info!("Pairing successful!");
+    Ok(())
+}
@@ -0,0 +1,219 @@
+use anyhow::{anyhow, Context, Result};
+use clap::parser::ValueSource;
+use clap::{Arg, ArgAction, ArgMatches, Command};
+use log::{error, info};
+use nix::sys::inotify::AddWatchFlags;
+use serde_json::{json, Map, Value};
+use std::any::TypeId;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+use crate::filewatch::FileWatcher;
+
+// Command line options that make no sense in a config file
+const EXCLUDED_OPTIONS: &[&str] = &["help", "version", "config"];
+
+// JSON Schema for the config file, whose keys are the long command line
+// option names. Generated from the command line definition so the two can't
+// drift apart.
+pub fn schema(command: &Command) -> Value {
+    let mut properties = Map::new();
+    let mut required = Vec::new();
+    for arg in command.get_arguments() {
+        let Some(long) = arg.get_long() else {
+            continue;
+        };
+        if EXCLUDED_OPTIONS.contains(&long) {
+            continue;
+        }
+        if arg.is_required_set() {
+            required.push(long);
+        }
+        properties.insert(long.to_string(), property(arg));
+    }
+
+    json!({
+        "$schema": "https://json-schema.org/draft/2020-12/schema",
+        "title": "aa-proxy-wican configuration",
+        "type": "object",
+        "properties": properties,
+        "required": required,
+        "additionalProperties": false,
+    })
+}
+
+fn property(arg: &Arg) -> Value {
+    let mut property = Map::new();
+    if let Some(help) = arg.get_help() {
+        property.insert("description".to_string(), json!(help.to_string()));
+    }
+
+    let value_schema = match arg.get_action() {
+        ArgAction::SetTrue | ArgAction::SetFalse => json!({ "type": "boolean" }),
+        _ => value_schema(arg),
+    };
+    let defaults: Vec<Value> = arg
+        .get_default_values()
+        .iter()
+        .map(|default| typed_value(&value_schema, &default.to_string_lossy()))
+        .collect();
+
+    match arg.get_action() {
+        ArgAction::Append => {
+            property.insert("type".to_string(), json!("array"));
+            property.insert("items".to_string(), value_schema);
+        }
+        _ => {
+            if let Value::Object(schema) = value_schema {
+                property.extend(schema);
+            }
+            if let Some(default) = defaults.into_iter().next() {
+                property.insert("default".to_string(), default);
+            }
+        }
+    }
+
+    Value::Object(property)
+}
+
+// Schema of a single value, from the possible values or the parsed type
+fn value_schema(arg: &Arg) -> Value {
+    let possible_values: Vec<String> = arg
+        .get_possible_values()
+        .iter()
+        .filter(|value| !value.is_hide_set())
+        .map(|value| value.get_name().to_string())
+        .collect();
+    if !possible_values.is_empty() {
+        return json!({ "type": "string", "enum": possible_values });
+    }
+
+    let type_id = arg.get_value_parser().type_id();
+    let integer = |maximum: u64| json!({ "type": "integer", "minimum": 0, "maximum": maximum });
+    if type_id == TypeId::of::<u8>() {
+        integer(u8::MAX.into())
+    } else if type_id == TypeId::of::<u16>() {
+        integer(u16::MAX.into())
+    } else if type_id == TypeId::of::<u32>() {
+        integer(u32::MAX.into())
+    } else if type_id == TypeId::of::<u64>() || type_id == TypeId::of::<usize>() {
+        integer(u64::MAX)
+    } else if type_id == TypeId::of::<f32>() || type_id == TypeId::of::<f64>() {
+        json!({ "type": "number" })
+    } else {
+        json!({ "type": "string" })
+    }
+}
+
+// Convert a command line default value to the JSON type of its schema
+fn typed_value(schema: &Value, value: &str) -> Value {
+    let parsed = match schema["type"].as_str() {
+        Some("integer") => value.parse::<u64>().ok().map(Value::from),
+        Some("number") => value.parse::<f64>().ok().map(Value::from),
+        Some("boolean") => value.parse::<bool>().ok().map(Value::from),
+        _ => None,
+    };
+    parsed.unwrap_or_else(|| json!(value))
+}
+
+// Parse the command line together with the options set in the --config file.
+// The file's options are inserted before the command line ones, leaving out
+// any also given on the command line or in the environment so those win.
+pub fn matches(command: Command, args: &[OsString]) -> Result<ArgMatches> {
+    let given = command
+        .clone()
+        .ignore_errors(true)
+        .try_get_matches_from(args)
+        .ok();
+    let Some((given, path)) = given.and_then(|given| {
+        let path = given.get_one::<PathBuf>("config")?.clone();
+        Some((given, path))
+    }) else {
+        return Ok(command.try_get_matches_from(args)?);
+    };
+
+    let file_args = file_args(&path, &command, &given)?;
+    let mut merged: Vec<OsString> = args.first().cloned().into_iter().collect();
+    merged.extend(file_args);
+    merged.extend(args.iter().skip(1).cloned());
+    Ok(command.try_get_matches_from(merged)?)
+}
+
+// Command line arguments for the options in a TOML config file, keyed like
+// the schema by long option name
+fn file_args(path: &Path, command: &Command, given: &ArgMatches) -> Result<Vec<OsString>> {
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read config file '{}'", path.display()))?;
+    let table: toml::Table = contents
+        .parse()
+        .with_context(|| format!("Invalid config file '{}'", path.display()))?;
+
+    let mut args = Vec::new();
+    for (key, value) in table {
+        let arg = command
+            .get_arguments()
+            .find(|arg| arg.get_long() == Some(key.as_str()))
+            .filter(|_| !EXCLUDED_OPTIONS.contains(&key.as_str()))
+            .ok_or_else(|| anyhow!("Unknown option '{}' in '{}'", key, path.display()))?;
+        if matches!(
+            given.value_source(arg.get_id().as_str()),
+            Some(ValueSource::CommandLine | ValueSource::EnvVariable)
+        ) {
+            continue;
+        }
+
+        let invalid = || anyhow!("Invalid value for '{}' in '{}'", key, path.display());
+        match (arg.get_action(), value) {
+            (ArgAction::SetTrue, toml::Value::Boolean(set)) => {
+                if set {
+                    args.push(format!("--{}", key).into());
+                }
+            }
+            (ArgAction::SetTrue, _) => return Err(invalid()),
+            (ArgAction::Append, toml::Value::Array(values)) => {
+                for value in values {
+                    args.push(format!("--{}={}", key, scalar(value).ok_or_else(invalid)?).into());
+                }
+            }
+            (_, value) => {
+                args.push(format!("--{}={}", key, scalar(value).ok_or_else(invalid)?).into());
+            }
+        }
+    }
+    Ok(args)
+}
+
+// The command line form of a single TOML value
+fn scalar(value: toml::Value) -> Option<String> {
+    match value {
+        toml::Value::String(value) => Some(value),
+        toml::Value::Integer(value) => Some(value.to_string()),
+        toml::Value::Float(value) => Some(value.to_string()),
+        toml::Value::Boolean(value) => Some(value.to_string()),
+        _ => None,
+    }
+}
+
+// Watches the directory of the config file, as editors often replace the
+// file rather than write to it
+pub struct ConfigWatcher {
+    watcher: FileWatcher,
+}
+
+impl ConfigWatcher {
+    pub fn watch(path: &Path) -> Result<Self> {
+        let watcher = FileWatcher::watch(
+            path,
+            AddWatchFlags::IN_CLOSE_WRITE | AddWatchFlags::IN_MOVED_TO,
+        )?;
+        Ok(Self { watcher })
+    }
+
+    // Call reload whenever the config file has been written or replaced
+    pub async fn run(self, reload: impl FnMut()) {
+        info!("Watching {} for changes.", self.watcher.path().display());
+        if let Err(e) = self.watcher.run(reload).await {
+            error!("Stopped watching the config file: {}", e);
+        }
+    }
+}
@@ -0,0 +1,226 @@
+use anyhow::{anyhow, Result};
+use log::info;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering};
+use std::time::Duration;
+use tokio::sync::Notify;
+
+use crate::adaptive;
+
+// Polling settings that can be changed while running, shared between the
+// polling loop and the admin interfaces
+pub struct Control {
+    update_frequency_minutes: AtomicU8,
+    paused: AtomicBool,
+    // Counts the polls requested, so each polling loop can tell which it
+    // has answered
+    poll_requests: AtomicU64,
+    reconnect_requested: AtomicBool,
+    // Shorter intervals in seconds requested by each FastPoll reason, 0 when
+    // unused
+    fast_poll_seconds: [AtomicU64; 2],
+    // Interval in seconds for what the vehicle is doing, used instead of the
+    // update frequency with adaptive polling, 0 when unused
+    state_interval_seconds: AtomicU64,
+    // Counts the changes, so a change made while a loop isn't waiting is
+    // still seen
+    changes: AtomicU64,
+    changed: Notify,
+}
+
+// Reasons to sample more often than the update frequency
+#[derive(Debug, Clone, Copy)]
+pub enum FastPoll {
+    ChargingCurve,
+    SocRule,
+}
+
+impl Control {
+    pub const fn new() -> Self {
+        Self {
+            update_frequency_minutes: AtomicU8::new(1),
+            paused: AtomicBool::new(false),
+            poll_requests: AtomicU64::new(0),
+            reconnect_requested: AtomicBool::new(false),
+            fast_poll_seconds: [AtomicU64::new(0), AtomicU64::new(0)],
+            state_interval_seconds: AtomicU64::new(0),
+            changes: AtomicU64::new(0),
+            changed: Notify::const_new(),
+        }
+    }
+
+    // Set the configured update frequency at startup
+    pub fn configure(&self, update_frequency_minutes: u8) {
+        self.update_frequency_minutes
+            .store(update_frequency_minutes, Ordering::Relaxed);
+    }
+
+    pub fn update_frequency_minutes(&self) -> u8 {
+        self.update_frequency_minutes.load(Ordering::Relaxed)
+    }
+
+    // Change the update frequency, refused with adaptive polling, which picks
+    // the interval itself
+    pub fn set_update_frequency_minutes(&self, minutes: u8) -> Result<()> {
+        if adaptive::is_enabled() {
+            return Err(anyhow!(
+                "Adaptive polling is active, so the interval follows what the vehicle is doing"
+            ));
+        }
+        if self
+            .update_frequency_minutes
+            .swap(minutes, Ordering::Relaxed)
+            != minutes
+        {
+            info!("Update frequency changed to {} minute(s).", minutes);
+            self.notify();
+        }
+        Ok(())
+    }
+
+    pub fn state_interval(&self) -> Option<Duration> {
+        match self.state_interval_seconds.load(Ordering::Relaxed) {
+            0 => None,
+            seconds => Some(Duration::from_secs(seconds)),
+        }
+    }
+
+    pub fn set_state_interval(&self, interval: Option<Duration>) {
+        let seconds = interval.map_or(0, |interval| interval.as_secs().max(1));
+        if self.state_interval_seconds.swap(seconds, Ordering::Relaxed) != seconds {
+            self.notify();
+        }
+    }
+
+    // Time between polls: the interval for the vehicle's state or else the
+    // update frequency, shortened by any fast poll reason
+    pub fn poll_interval(&self) -> Duration {
+        let interval = self
+            .state_interval()
+            .unwrap_or_else(|| Duration::from_secs(self.update_frequency_minutes() as u64 * 60));
+        match self.fast_poll_interval() {
+            Some(fast_poll) => interval.min(fast_poll),
+            None => interval,
+        }
+    }
+
+    // The shortest interval any reason currently asks for
+    pub fn fast_poll_interval(&self) -> Option<Duration> {
+        self.fast_poll_seconds
+            .iter()
+            .map(|seconds| seconds.load(Ordering::Relaxed))
+            .filter(|seconds| *seconds > 0)
+            .min()
+            .map(Duration::from_secs)
+    }
+
+    pub fn fast_poll_interval_for(&self, reason: FastPoll) -> Option<Duration> {
+        match self.fast_poll_seconds[reason as usize].load(Ordering::Relaxed) {
+            0 => None,
+            seconds => Some(Duration::from_secs(seconds)),
+        }
+    }
+
+    pub fn set_fast_poll_interval(&self, reason: FastPoll, interval: Option<Duration>) {
+        let seconds = interval.map_or(0, |interval| interval.as_secs().max(1));
+        if self.fast_poll_seconds[reason as usize].swap(seconds, Ordering::Relaxed) != seconds {
+            self.notify();
+        }
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::Relaxed)
+    }
+
+    pub fn pause(&self) {
+        if !self.paused.swap(true, Ordering::Relaxed) {
+            info!("Polling paused.");
+            self.notify();
+        }
+    }
+
+    pub fn resume(&self) {
+        if self.paused.swap(false, Ordering::Relaxed) {
+            info!("Polling resumed.");
+            self.notify();
+        }
+    }
+
+    // Ask the polling loop to start a cycle now, even while paused
+    pub fn poll_now(&self) {
+        info!("Immediate poll requested.");
+        self.poll_requests.fetch_add(1, Ordering::Relaxed);
+        self.notify();
+    }
+
+    // Ask the polling loop to disconnect from the WiCAN and poll again over a
+    // new connection
+    pub fn reconnect(&self) {
+        info!("Reconnect requested.");
+        self.reconnect_requested.store(true, Ordering::Relaxed);
+        self.poll_requests.fetch_add(1, Ordering::Relaxed);
+        self.notify();
+    }
+
+    // Consume a pending reconnect request
+    pub fn take_reconnect_request(&self) -> bool {
+        self.reconnect_requested.swap(false, Ordering::Relaxed)
+    }
+
+    // Wake every polling loop waiting for a change
+    fn notify(&self) {
+        self.changes.fetch_add(1, Ordering::Relaxed);
+        self.changed.notify_waiters();
+    }
+
+    // A polling loop's view of the changes and poll requests, so each loop
+    // sees every one rather than only the loop that happens to wake first
+    pub fn listen(&self) -> Listener {
+        Listener {
+            changes_seen: self.changes.load(Ordering::Relaxed),
+            polls_seen: self.poll_requests.load(Ordering::Relaxed),
+        }
+    }
+}
+
+pub struct Listener {
+    changes_seen: u64,
+    polls_seen: u64,
+}
+
+impl Listener {
+    // Wait until any setting changes or a poll is requested
+    pub async fn changed(&mut self) {
+        loop {
+            let notified = CONTROL.changed.notified();
+            tokio::pin!(notified);
+            // Registered before checking, so a change in between still wakes
+            notified.as_mut().enable();
+            let changes = CONTROL.changes.load(Ordering::Relaxed);
+            if changes != self.changes_seen {
+                self.changes_seen = changes;
+                return;
+            }
+            notified.await;
+        }
+    }
+
+    // Consume the poll requests made since this loop last looked
+    pub fn take_poll_request(&mut self) -> bool {
+        let polls = CONTROL.poll_requests.load(Ordering::Relaxed);
+        let requested = polls != self.polls_seen;
+        self.polls_seen = polls;
+        requested
+    }
+
+    // Wait until a poll is requested, ignoring other changes
+    pub async fn poll_requested(&mut self) {
+        loop {
+            if self.take_poll_request() {
+                return;
+            }
+            self.changed().await;
+        }
+    }
+}
+
+pub static CONTROL: Control = Control::new();
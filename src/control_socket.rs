@@ -0,0 +1,204 @@
+use anyhow::{anyhow, Context, Result};
+use log::{debug, warn};
+use nix::sys::stat::{self, Mode};
+use serde_json::{json, Value};
+use std::io::ErrorKind;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::time;
+
+use crate::control::CONTROL;
+use crate::stats::STATS;
+use crate::status;
+use crate::Command;
+
+// Limit for a client to send its request or the service to answer
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+// Requests to the running service, sent as one line and answered with one
+// line of JSON
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ControlRequest {
+    Status,
+    FetchNow,
+    Reconnect,
+    SetInterval(u8),
+}
+
+impl ControlRequest {
+    // The request a subcommand sends, if it is one for the running service
+    pub fn from_command(command: &Command) -> Option<Self> {
+        match command {
+            Command::Status => Some(ControlRequest::Status),
+            Command::FetchNow => Some(ControlRequest::FetchNow),
+            Command::Reconnect => Some(ControlRequest::Reconnect),
+            Command::SetInterval { minutes } => Some(ControlRequest::SetInterval(*minutes)),
+            _ => None,
+        }
+    }
+
+    fn line(self) -> String {
+        match self {
+            ControlRequest::Status => "status".to_string(),
+            ControlRequest::FetchNow => "fetch-now".to_string(),
+            ControlRequest::Reconnect => "reconnect".to_string(),
+            ControlRequest::SetInterval(minutes) => format!("set-interval {}", minutes),
+        }
+    }
+
+    fn handle(self) -> Result<Value> {
+        match self {
+            ControlRequest::Status => {
+                let mut status = status::snapshot(&STATS);
+                status["update_frequency_minutes"] = json!(CONTROL.update_frequency_minutes());
+                status["paused"] = json!(CONTROL.is_paused());
+                Ok(status)
+            }
+            ControlRequest::FetchNow => {
+                CONTROL.poll_now();
+                Ok(json!({ "message": "Fetching battery data now." }))
+            }
+            ControlRequest::Reconnect => {
+                CONTROL.reconnect();
+                Ok(json!({ "message": "Reconnecting to the WiCAN." }))
+            }
+            ControlRequest::SetInterval(0) => {
+                Err(anyhow!("The interval must be at least 1 minute"))
+            }
+            ControlRequest::SetInterval(minutes) => {
+                CONTROL.set_update_frequency_minutes(minutes)?;
+                Ok(json!({
+                    "message": format!("Fetching battery data every {} minute(s).", minutes)
+                }))
+            }
+        }
+    }
+}
+
+impl FromStr for ControlRequest {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut words = s.split_whitespace();
+        let request = match (words.next(), words.next()) {
+            (Some("status"), None) => ControlRequest::Status,
+            (Some("fetch-now"), None) => ControlRequest::FetchNow,
+            (Some("reconnect"), None) => ControlRequest::Reconnect,
+            (Some("set-interval"), Some(minutes)) => ControlRequest::SetInterval(
+                minutes
+                    .parse()
+                    .with_context(|| format!("Invalid interval '{}'", minutes))?,
+            ),
+            _ => return Err(anyhow!("Unknown request '{}'", s.trim())),
+        };
+        match words.next() {
+            Some(extra) => Err(anyhow!("Unexpected '{}' after the request", extra)),
+            None => Ok(request),
+        }
+    }
+}
+
+// Listen on the control socket, done before dropping privileges so it can be
+// created under /run. A socket left behind by an instance that didn't shut
+// down cleanly is replaced, one still in use is not.
+pub fn bind(path: &Path) -> Result<UnixListener> {
+    if std::os::unix::net::UnixStream::connect(path).is_ok() {
+        return Err(anyhow!(
+            "Another instance is already listening on '{}'",
+            path.display()
+        ));
+    }
+    match std::fs::remove_file(path) {
+        Ok(()) => debug!("Removed stale control socket '{}'", path.display()),
+        Err(e) if e.kind() == ErrorKind::NotFound => {}
+        Err(e) => {
+            return Err(e).with_context(|| format!("Failed to remove '{}'", path.display()));
+        }
+    }
+    // Only the service's user may control it. The socket is created with
+    // these permissions rather than restricted afterwards, so no one can
+    // connect in between.
+    let umask = stat::umask(Mode::from_bits_truncate(0o177));
+    let listener = UnixListener::bind(path);
+    stat::umask(umask);
+    listener.with_context(|| format!("Failed to listen on '{}'", path.display()))
+}
+
+// Answer requests until the process exits
+pub async fn serve(listener: UnixListener) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                tokio::spawn(async move {
+                    if let Err(e) = time::timeout(TIMEOUT, answer(stream)).await {
+                        debug!("Control client took too long: {}", e);
+                    }
+                });
+            }
+            Err(e) => {
+                warn!("Failed to accept a control connection: {}", e);
+                time::sleep(Duration::from_secs(1)).await;
+            }
+        }
+    }
+}
+
+async fn answer(stream: UnixStream) {
+    let (reader, mut writer) = stream.into_split();
+    let mut line = String::new();
+    if let Err(e) = BufReader::new(reader).read_line(&mut line).await {
+        debug!("Failed to read a control request: {}", e);
+        return;
+    }
+    let reply = line
+        .parse::<ControlRequest>()
+        .and_then(|request| {
+            debug!("Control request: {}", request.line());
+            request.handle()
+        })
+        .unwrap_or_else(|e| json!({ "error": format!("{:#}", e) }));
+    let mut reply = reply.to_string();
+    reply.push('\n');
+    if let Err(e) = writer.write_all(reply.as_bytes()).await {
+        debug!("Failed to answer a control request: {}", e);
+    }
+}
+
+// Send a request to the running service and print its answer
+pub async fn request(path: &Path, request: ControlRequest) -> Result<()> {
+    let reply = time::timeout(TIMEOUT, exchange(path, request))
+        .await
+        .map_err(|_| anyhow!("The service did not answer on '{}'", path.display()))??;
+    let reply: Value = serde_json::from_str(&reply).context("Invalid answer from the service")?;
+    if let Some(error) = reply.get("error").and_then(Value::as_str) {
+        return Err(anyhow!("{}", error));
+    }
+    match reply.get("message").and_then(Value::as_str) {
+        Some(message) => println!("{}", message),
+        None => println!("{}", serde_json::to_string_pretty(&reply)?),
+    }
+    Ok(())
+}
+
+async fn exchange(path: &Path, request: ControlRequest) -> Result<String> {
+    let stream = UnixStream::connect(path).await.with_context(|| {
+        format!(
+            "Could not reach the service on '{}', is it running?",
+            path.display()
+        )
+    })?;
+    let (reader, mut writer) = stream.into_split();
+    writer
+        .write_all(format!("{}\n", request.line()).as_bytes())
+        .await
+        .context("Failed to send the request")?;
+    let mut reply = String::new();
+    BufReader::new(reader)
+        .read_line(&mut reply)
+        .await
+        .context("Failed to read the answer")?;
+    Ok(reply)
+}
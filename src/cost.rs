@@ -0,0 +1,394 @@
+use crate::carbon;
+use crate::charging::ChargingType;
+use crate::BatteryData;
+use anyhow::{anyhow, Context, Result};
+use chrono::{DateTime, Local, NaiveTime, Utc};
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Mutex, OnceLock};
+
+const SESSIONS_FILE: &str = "charging-sessions.jsonl";
+
+// Session in progress and where finished sessions are kept, served by the
+// admin API
+static CURRENT: Mutex<Option<ChargingSession>> = Mutex::new(None);
+static SESSIONS_PATH: OnceLock<PathBuf> = OnceLock::new();
+
+// Price per kWh, either flat or varying by local time of day, written as
+// comma separated HH:MM-HH:MM=PRICE windows with an optional bare price for
+// the times they don't cover, e.g. "0.30,00:00-07:00=0.12"
+#[derive(Debug, Clone, PartialEq)]
+pub struct Tariff {
+    default_price: Option<f64>,
+    windows: Vec<TariffWindow>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct TariffWindow {
+    start: NaiveTime,
+    end: NaiveTime,
+    price: f64,
+}
+
+impl TariffWindow {
+    // Windows ending before they start run past midnight
+    fn contains(&self, time: NaiveTime) -> bool {
+        if self.start < self.end {
+            self.start <= time && time < self.end
+        } else {
+            self.start <= time || time < self.end
+        }
+    }
+}
+
+impl Tariff {
+    // The price at a local time, the first matching window winning
+    pub fn price_at(&self, time: NaiveTime) -> Option<f64> {
+        self.windows
+            .iter()
+            .find(|window| window.contains(time))
+            .map(|window| window.price)
+            .or(self.default_price)
+    }
+}
+
+impl FromStr for Tariff {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut tariff = Tariff {
+            default_price: None,
+            windows: Vec::new(),
+        };
+        for item in s.split(',').map(str::trim) {
+            let Some((times, price)) = item.split_once('=') else {
+                if tariff.default_price.is_some() {
+                    return Err(anyhow!("A tariff can only have one price without a time"));
+                }
+                tariff.default_price = Some(parse_price(item)?);
+                continue;
+            };
+            let (start, end) = times
+                .split_once('-')
+                .ok_or_else(|| anyhow!("Window '{}' is not in HH:MM-HH:MM=PRICE form", item))?;
+            let time = |value: &str| {
+                NaiveTime::parse_from_str(value.trim(), "%H:%M")
+                    .map_err(|_| anyhow!("'{}' is not a time in HH:MM form", value))
+            };
+            let window = TariffWindow {
+                start: time(start)?,
+                end: time(end)?,
+                price: parse_price(price)?,
+            };
+            if window.start == window.end {
+                return Err(anyhow!("Window '{}' is empty", item));
+            }
+            tariff.windows.push(window);
+        }
+
+        if tariff.default_price.is_none() {
+            let uncovered = (0..24 * 60)
+                .filter_map(|minute| NaiveTime::from_hms_opt(minute / 60, minute % 60, 0))
+                .find(|time| tariff.price_at(*time).is_none());
+            if let Some(time) = uncovered {
+                return Err(anyhow!(
+                    "The tariff has no price at {}, add a price without a time for the rest of the day",
+                    time.format("%H:%M")
+                ));
+            }
+        }
+        Ok(tariff)
+    }
+}
+
+fn parse_price(value: &str) -> Result<f64> {
+    let price: f64 = value
+        .trim()
+        .parse()
+        .with_context(|| format!("Invalid price '{}'", value))?;
+    if !price.is_finite() || price < 0.0 {
+        return Err(anyhow!("Invalid price '{}'", value));
+    }
+    Ok(price)
+}
+
+// Summary of one charging session
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChargingSession {
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub charging_type: Option<ChargingType>,
+    pub start_soc: f32,
+    pub end_soc: f32,
+    pub energy_kwh: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cost: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub currency: Option<String>,
+    // Average over the energy added while the intensity was known
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub carbon_intensity_g_per_kwh: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub emissions_kg: Option<f64>,
+    // Energy the average intensity is taken over
+    #[serde(skip)]
+    carbon_energy_kwh: f64,
+}
+
+impl ChargingSession {
+    fn describe(&self) -> String {
+        let mut description = format!(
+            "{:.1}% -> {:.1}% in {} min, {:.2} kWh",
+            self.start_soc,
+            self.end_soc,
+            (self.end - self.start).num_minutes(),
+            self.energy_kwh
+        );
+        if let Some(cost) = self.cost {
+            description += &format!(" for {}", format_cost(cost, self.currency.as_deref()));
+        }
+        if let Some(intensity) = self.carbon_intensity_g_per_kwh {
+            description += &format!(" at {:.0} gCO2/kWh", intensity);
+        }
+        description
+    }
+
+    fn add_energy(&mut self, energy_kwh: f64, price: Option<f64>, intensity: Option<f64>) {
+        self.energy_kwh += energy_kwh;
+        if let Some(price) = price {
+            self.cost = Some(self.cost.unwrap_or_default() + energy_kwh * price);
+        }
+        if let Some(intensity) = intensity {
+            let emissions_kg =
+                self.emissions_kg.unwrap_or_default() + energy_kwh * intensity / 1000.0;
+            self.carbon_energy_kwh += energy_kwh;
+            self.emissions_kg = Some(emissions_kg);
+            if self.carbon_energy_kwh > 0.0 {
+                self.carbon_intensity_g_per_kwh =
+                    Some(emissions_kg * 1000.0 / self.carbon_energy_kwh);
+            }
+        }
+    }
+}
+
+fn format_cost(cost: f64, currency: Option<&str>) -> String {
+    match currency {
+        Some(currency) => format!("{:.2} {}", cost, currency),
+        None => format!("{:.2}", cost),
+    }
+}
+
+// Charging sessions so far, with the one in progress, served by the admin API
+#[derive(Debug, Serialize)]
+pub struct ChargingCosts {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub current: Option<ChargingSession>,
+    pub sessions: Vec<ChargingSession>,
+    pub total_energy_kwh: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub total_cost: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub total_emissions_kg: Option<f64>,
+    // Latest reading for the grid, when it's followed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub carbon_intensity_g_per_kwh: Option<f64>,
+}
+
+// The last sample of the session in progress, energy being counted between
+// consecutive samples
+struct Reading {
+    timestamp: DateTime<Utc>,
+    soc: f32,
+    power_kw: Option<f32>,
+}
+
+// Counts the energy added while charging, pricing it with the tariff and
+// the grid carbon intensity when they're known, and saves a summary of each
+// session in the state directory when it ends
+pub struct CostTracker {
+    path: PathBuf,
+    tariff: Option<Tariff>,
+    currency: Option<String>,
+    last: Mutex<Option<Reading>>,
+}
+
+impl CostTracker {
+    pub fn new(state_dir: &Path, tariff: Option<Tariff>, currency: Option<String>) -> Self {
+        let path = state_dir.join(SESSIONS_FILE);
+        let _ = SESSIONS_PATH.set(path.clone());
+        Self {
+            path,
+            tariff,
+            currency,
+            last: Mutex::new(None),
+        }
+    }
+
+    pub fn record(&self, sample: &BatteryData) -> Result<()> {
+        let mut last = self.last.lock().unwrap();
+        let mut current = CURRENT.lock().unwrap();
+        if !sample.is_charging() {
+            *last = None;
+            if let Some(session) = current.take() {
+                info!("Charging session ended: {}.", session.describe());
+                self.save(&session)?;
+            }
+            return Ok(());
+        }
+        let (Some(timestamp), Some(soc)) = (sample.timestamp, sample.battery_level_percentage)
+        else {
+            return Ok(());
+        };
+        let reading = Reading {
+            timestamp,
+            soc,
+            power_kw: sample.charging_power_kw(),
+        };
+
+        let session = current.get_or_insert_with(|| {
+            info!("Charging session started at {:.1}%.", soc);
+            ChargingSession {
+                start: timestamp,
+                end: timestamp,
+                charging_type: sample.charging_type,
+                start_soc: soc,
+                end_soc: soc,
+                energy_kwh: 0.0,
+                cost: self.tariff.as_ref().map(|_| 0.0),
+                currency: self.currency.clone(),
+                carbon_intensity_g_per_kwh: None,
+                emissions_kg: None,
+                carbon_energy_kwh: 0.0,
+            }
+        });
+        if let Some(previous) = last.as_ref() {
+            let energy_kwh = energy_added_kwh(previous, &reading, sample.battery_capacity_wh);
+            let time = previous.timestamp.with_timezone(&Local).time();
+            let price = self
+                .tariff
+                .as_ref()
+                .and_then(|tariff| tariff.price_at(time));
+            session.add_energy(energy_kwh, price, carbon::latest());
+        }
+        session.end = timestamp;
+        session.end_soc = soc;
+        session.charging_type = sample.charging_type;
+        *last = Some(reading);
+        Ok(())
+    }
+
+    fn save(&self, session: &ChargingSession) -> Result<()> {
+        let mut line = serde_json::to_vec(session)?;
+        line.push(b'\n');
+        OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(&self.path)
+            .and_then(|mut file| file.write_all(&line))
+            .with_context(|| format!("Failed to append to '{}'", self.path.display()))
+    }
+}
+
+// Energy added between two samples, from the average charging power when
+// both report it and otherwise from the rise in SOC
+fn energy_added_kwh(previous: &Reading, reading: &Reading, capacity_wh: Option<u32>) -> f64 {
+    let hours = (reading.timestamp - previous.timestamp).num_milliseconds() as f64 / 3_600_000.0;
+    if hours <= 0.0 {
+        return 0.0;
+    }
+    match (previous.power_kw, reading.power_kw) {
+        (Some(a), Some(b)) => f64::from(a + b) / 2.0 * hours,
+        _ => capacity_wh.map_or(0.0, |capacity_wh| {
+            f64::from((reading.soc - previous.soc).max(0.0)) * f64::from(capacity_wh) / 100_000.0
+        }),
+    }
+}
+
+// The sessions saved so far and the one in progress, None when costs aren't
+// being tracked
+pub fn costs() -> Option<Result<ChargingCosts>> {
+    let path = SESSIONS_PATH.get()?;
+    Some(read_sessions(path).map(|sessions| {
+        let current = CURRENT.lock().unwrap().clone();
+        let all = sessions.iter().chain(current.as_ref());
+        ChargingCosts {
+            total_energy_kwh: all.clone().map(|session| session.energy_kwh).sum(),
+            total_cost: total(all.clone().map(|session| session.cost)),
+            total_emissions_kg: total(all.map(|session| session.emissions_kg)),
+            carbon_intensity_g_per_kwh: carbon::latest(),
+            current,
+            sessions,
+        }
+    }))
+}
+
+// Sum of the values that are known, None when none are
+fn total(values: impl Iterator<Item = Option<f64>>) -> Option<f64> {
+    values.flatten().reduce(|a, b| a + b)
+}
+
+fn read_sessions(path: &Path) -> Result<Vec<ChargingSession>> {
+    let contents = match std::fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e).with_context(|| format!("Failed to read '{}'", path.display())),
+    };
+    contents
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(number, line)| {
+            serde_json::from_str(line)
+                .with_context(|| format!("{}: invalid line {}", path.display(), number + 1))
+        })
+        .collect()
+}
+
+// Print the saved sessions with their totals
+pub fn list(state_dir: &Path) -> Result<()> {
+    let sessions = read_sessions(&state_dir.join(SESSIONS_FILE))?;
+    if sessions.is_empty() {
+        println!("No charging sessions recorded yet.");
+        return Ok(());
+    }
+
+    for session in &sessions {
+        let start = session.start.with_timezone(&Local);
+        println!(
+            "{}  {}  {}",
+            start.format("%Y-%m-%d %H:%M"),
+            session
+                .charging_type
+                .map_or("--".to_string(), |charging_type| charging_type.to_string()),
+            session.describe()
+        );
+    }
+    let mut summary = format!(
+        "{} session(s), {:.2} kWh",
+        sessions.len(),
+        sessions
+            .iter()
+            .map(|session| session.energy_kwh)
+            .sum::<f64>()
+    );
+    // Sessions priced in different currencies can't be added up
+    let currency = sessions[0].currency.as_deref();
+    if let Some(cost) = total(sessions.iter().map(|session| session.cost)) {
+        if sessions
+            .iter()
+            .all(|session| session.currency.as_deref() == currency)
+        {
+            summary += &format!(" for {}", format_cost(cost, currency));
+        }
+    }
+    if let Some(emissions_kg) = total(sessions.iter().map(|session| session.emissions_kg)) {
+        summary += &format!(", {:.1} kgCO2", emissions_kg);
+    }
+    println!("{}", summary);
+    Ok(())
+}
@@ -0,0 +1,204 @@
+use crate::charging::{self, ChargingType};
+use crate::control::{FastPoll, CONTROL};
+use crate::BatteryData;
+use anyhow::{anyhow, Context, Result};
+use chrono::{DateTime, Utc};
+use clap::ValueEnum;
+use log::info;
+use serde::Serialize;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+
+const CURVES_DIR: &str = "charging-curves";
+const CSV_HEADER: &str = "timestamp,soc,power_kw,external_temp_celsius";
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum CurveFormat {
+    Csv,
+    Json,
+}
+
+// One point of a charging curve
+#[derive(Debug, Serialize)]
+struct CurvePoint {
+    timestamp: DateTime<Utc>,
+    soc: f32,
+    power_kw: Option<f32>,
+    external_temp_celsius: Option<f32>,
+}
+
+// Records SOC against charging power during DC charging sessions, one CSV
+// file per session in the state directory, sampling at the curve interval
+// while a session lasts
+pub struct CurveRecorder {
+    dir: PathBuf,
+    interval: Duration,
+    session: Mutex<Option<PathBuf>>,
+}
+
+impl CurveRecorder {
+    pub fn new(state_dir: &Path, interval: Duration) -> Self {
+        Self {
+            dir: state_dir.join(CURVES_DIR),
+            interval,
+            session: Mutex::new(None),
+        }
+    }
+
+    pub fn record(&self, sample: &BatteryData) -> Result<()> {
+        let mut session = self.session.lock().unwrap();
+        if !sample.is_charging() || sample.charging_type != Some(ChargingType::Dc) {
+            if let Some(path) = session.take() {
+                info!(
+                    "DC charging session ended, charging curve saved to {}.",
+                    path.display()
+                );
+                CONTROL.set_fast_poll_interval(FastPoll::ChargingCurve, None);
+            }
+            return Ok(());
+        }
+        let (Some(timestamp), Some(soc)) = (sample.timestamp, sample.battery_level_percentage)
+        else {
+            return Ok(());
+        };
+
+        let path = match session.as_ref() {
+            Some(path) => path.clone(),
+            None => {
+                std::fs::create_dir_all(&self.dir)
+                    .with_context(|| format!("Failed to create '{}'", self.dir.display()))?;
+                let path = self
+                    .dir
+                    .join(format!("{}.csv", timestamp.format("%Y%m%dT%H%M%SZ")));
+                std::fs::write(&path, format!("{}\n", CSV_HEADER))
+                    .with_context(|| format!("Failed to create '{}'", path.display()))?;
+                info!(
+                    "DC charging session started, recording the charging curve every {:?} to {}.",
+                    self.interval,
+                    path.display()
+                );
+                CONTROL.set_fast_poll_interval(FastPoll::ChargingCurve, Some(self.interval));
+                session.insert(path).clone()
+            }
+        };
+
+        let power_kw = sample
+            .charging_power_kw()
+            .or_else(|| charging::estimated_power_kw(sample.vehicle_tag.as_deref()));
+        let line = format!(
+            "{},{},{},{}\n",
+            timestamp.to_rfc3339(),
+            soc,
+            power_kw.map(|power| power.to_string()).unwrap_or_default(),
+            sample
+                .external_temp_celsius
+                .map(|temperature| temperature.to_string())
+                .unwrap_or_default()
+        );
+        OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .and_then(|mut file| file.write_all(line.as_bytes()))
+            .with_context(|| format!("Failed to append to '{}'", path.display()))
+    }
+}
+
+// Print the recorded sessions, or the curve of one session in a format
+pub fn export(state_dir: &Path, session: Option<&str>, format: CurveFormat) -> Result<()> {
+    let dir = state_dir.join(CURVES_DIR);
+    let Some(session) = session else {
+        return list(&dir);
+    };
+
+    let path = dir.join(format!("{}.csv", session.trim_end_matches(".csv")));
+    match format {
+        CurveFormat::Csv => {
+            let contents = std::fs::read_to_string(&path)
+                .with_context(|| format!("Failed to read '{}'", path.display()))?;
+            print!("{}", contents);
+        }
+        CurveFormat::Json => {
+            println!("{}", serde_json::to_string_pretty(&read_curve(&path)?)?);
+        }
+    }
+    Ok(())
+}
+
+fn list(dir: &Path) -> Result<()> {
+    let mut sessions: Vec<PathBuf> = match std::fs::read_dir(dir) {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| path.extension().is_some_and(|extension| extension == "csv"))
+            .collect(),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+        Err(e) => return Err(e).with_context(|| format!("Failed to list '{}'", dir.display())),
+    };
+    if sessions.is_empty() {
+        println!("No charging curves recorded yet.");
+        return Ok(());
+    }
+
+    sessions.sort();
+    for path in sessions {
+        let name = path.file_stem().unwrap_or_default().to_string_lossy();
+        let points = read_curve(&path)?;
+        let (Some(first), Some(last)) = (points.first(), points.last()) else {
+            println!("{}  no points", name);
+            continue;
+        };
+        let peak = points
+            .iter()
+            .filter_map(|point| point.power_kw)
+            .max_by(f32::total_cmp);
+        println!(
+            "{}  {} point(s)  {:.1}% -> {:.1}% in {} min  peak {}",
+            name,
+            points.len(),
+            first.soc,
+            last.soc,
+            (last.timestamp - first.timestamp).num_minutes(),
+            peak.map_or("unknown".to_string(), |peak| format!("{:.1} kW", peak))
+        );
+    }
+    Ok(())
+}
+
+fn read_curve(path: &Path) -> Result<Vec<CurvePoint>> {
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read '{}'", path.display()))?;
+    contents
+        .lines()
+        .enumerate()
+        .skip(1)
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(number, line)| {
+            parse_point(line)
+                .with_context(|| format!("{}: invalid line {}", path.display(), number + 1))
+        })
+        .collect()
+}
+
+fn parse_point(line: &str) -> Result<CurvePoint> {
+    let fields: Vec<&str> = line.split(',').map(str::trim).collect();
+    let [timestamp, soc, power_kw, external_temp_celsius] = fields[..] else {
+        return Err(anyhow!(
+            "expected {} columns",
+            CSV_HEADER.split(',').count()
+        ));
+    };
+    let optional = |value: &str| -> Result<Option<f32>> {
+        if value.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(value.parse()?))
+    };
+    Ok(CurvePoint {
+        timestamp: DateTime::parse_from_rfc3339(timestamp)?.with_timezone(&Utc),
+        soc: soc.parse()?,
+        power_kw: optional(power_kw)?,
+        external_temp_celsius: optional(external_temp_celsius)?,
+    })
+}
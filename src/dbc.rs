@@ -0,0 +1,256 @@
+use crate::can::CanFrame;
+use crate::WicanResponse;
+use anyhow::{anyhow, Context, Result};
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use std::path::Path;
+use std::str::FromStr;
+
+// Flag DBC files set on the ids of extended frames
+const EXTENDED_ID_FLAG: u32 = 0x8000_0000;
+
+// Autopid fields a signal can be mapped to
+const FIELDS: &[&str] = &[
+    "SOC",
+    "SOC_D",
+    "TMP_A",
+    "PRECOND",
+    "PLUG",
+    "CHG_PORT",
+    "CHG_TYPE",
+    "PWR",
+    "CELL_TMIN",
+    "CELL_TMAX",
+    "COOL_TMP",
+    "HVAC_PWR",
+    "SPEED",
+];
+
+// Messages and signals of a DBC file, keyed by CAN id and whether it is extended
+pub struct Dbc {
+    messages: HashMap<(u32, bool), Vec<Signal>>,
+}
+
+#[derive(Debug)]
+struct Signal {
+    name: String,
+    start: u32,
+    length: u32,
+    little_endian: bool,
+    signed: bool,
+    factor: f64,
+    offset: f64,
+    multiplex: Multiplex,
+}
+
+#[derive(Debug, PartialEq)]
+enum Multiplex {
+    None,
+    Multiplexor,
+    Multiplexed(u64),
+}
+
+impl Dbc {
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read DBC file {}", path.display()))?;
+        text.parse()
+            .with_context(|| format!("Failed to parse DBC file {}", path.display()))
+    }
+
+    pub fn signal_count(&self) -> usize {
+        self.messages.values().map(Vec::len).sum()
+    }
+
+    // Decode every signal of a frame's message into values, skipping
+    // multiplexed signals whose multiplexor value does not match
+    pub fn decode(&self, frame: &CanFrame) -> Vec<(&str, f64)> {
+        let Some(signals) = self.messages.get(&(frame.id, frame.extended)) else {
+            return Vec::new();
+        };
+
+        let multiplexor = signals
+            .iter()
+            .find(|signal| signal.multiplex == Multiplex::Multiplexor)
+            .and_then(|signal| signal.raw(&frame.data));
+        signals
+            .iter()
+            .filter(|signal| match signal.multiplex {
+                Multiplex::Multiplexed(value) => multiplexor == Some(value),
+                _ => true,
+            })
+            .filter_map(|signal| Some((signal.name.as_str(), signal.value(&frame.data)?)))
+            .collect()
+    }
+}
+
+impl FromStr for Dbc {
+    type Err = anyhow::Error;
+
+    fn from_str(text: &str) -> Result<Self> {
+        let mut messages: HashMap<(u32, bool), Vec<Signal>> = HashMap::new();
+        let mut current = None;
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if let Some(message) = line.strip_prefix("BO_ ") {
+                let id: u32 = message
+                    .split_whitespace()
+                    .next()
+                    .and_then(|id| id.parse().ok())
+                    .ok_or_else(|| anyhow!("Invalid message on line {}", number + 1))?;
+                let key = (id & !EXTENDED_ID_FLAG, id & EXTENDED_ID_FLAG != 0);
+                messages.entry(key).or_default();
+                current = Some(key);
+            } else if let Some(signal) = line.strip_prefix("SG_ ") {
+                let key = current
+                    .ok_or_else(|| anyhow!("Signal outside a message on line {}", number + 1))?;
+                let signal = parse_signal(signal)
+                    .with_context(|| format!("Invalid signal on line {}", number + 1))?;
+                messages.entry(key).or_default().push(signal);
+            }
+        }
+        Ok(Self { messages })
+    }
+}
+
+// Parse 'NAME [M|mN] : START|LENGTH@ORDERSIGN (FACTOR,OFFSET) [MIN|MAX] "UNIT" RECEIVERS'
+fn parse_signal(signal: &str) -> Result<Signal> {
+    let (head, layout) = signal
+        .split_once(':')
+        .ok_or_else(|| anyhow!("missing ':'"))?;
+    let mut head = head.split_whitespace();
+    let name = head.next().ok_or_else(|| anyhow!("missing name"))?;
+    let multiplex = match head.next() {
+        None => Multiplex::None,
+        Some("M") => Multiplex::Multiplexor,
+        Some(value) => Multiplex::Multiplexed(
+            // Extended multiplexing ("m3M") is treated as plain multiplexing
+            value
+                .trim_end_matches('M')
+                .strip_prefix('m')
+                .and_then(|value| value.parse().ok())
+                .ok_or_else(|| anyhow!("invalid multiplexer '{}'", value))?,
+        ),
+    };
+
+    let mut layout = layout.split_whitespace();
+    let position = layout.next().ok_or_else(|| anyhow!("missing bit layout"))?;
+    let (start, rest) = position
+        .split_once('|')
+        .ok_or_else(|| anyhow!("invalid bit layout '{}'", position))?;
+    let (length, format) = rest
+        .split_once('@')
+        .ok_or_else(|| anyhow!("invalid bit layout '{}'", position))?;
+    let scaling = layout.next().ok_or_else(|| anyhow!("missing scaling"))?;
+    let (factor, offset) = scaling
+        .trim_start_matches('(')
+        .trim_end_matches(')')
+        .split_once(',')
+        .ok_or_else(|| anyhow!("invalid scaling '{}'", scaling))?;
+
+    let length: u32 = length.parse().context("invalid length")?;
+    if !(1..=64).contains(&length) {
+        return Err(anyhow!("length {} is not between 1 and 64", length));
+    }
+    Ok(Signal {
+        name: name.to_string(),
+        start: start.parse().context("invalid start bit")?,
+        length,
+        little_endian: match format.get(..1) {
+            Some("1") => true,
+            Some("0") => false,
+            _ => return Err(anyhow!("invalid byte order in '{}'", position)),
+        },
+        signed: format.ends_with('-'),
+        factor: factor.parse().context("invalid factor")?,
+        offset: offset.parse().context("invalid offset")?,
+        multiplex,
+    })
+}
+
+impl Signal {
+    // Unscaled bits of the signal, None if it lies outside the frame
+    fn raw(&self, data: &[u8]) -> Option<u64> {
+        let mut bytes = [0u8; 8];
+        bytes[..data.len().min(8)].copy_from_slice(&data[..data.len().min(8)]);
+        let available = data.len().min(8) as u32 * 8;
+        let mask = u64::MAX >> (64 - self.length);
+
+        if self.little_endian {
+            if self.start + self.length > available {
+                return None;
+            }
+            Some((u64::from_le_bytes(bytes) >> self.start) & mask)
+        } else {
+            // Motorola start bits number the most significant bit, counting
+            // from the least significant bit of each byte
+            let msb = (self.start / 8) * 8 + (7 - self.start % 8);
+            let lsb = msb + self.length - 1;
+            if lsb >= available {
+                return None;
+            }
+            Some((u64::from_be_bytes(bytes) >> (63 - lsb)) & mask)
+        }
+    }
+
+    fn value(&self, data: &[u8]) -> Option<f64> {
+        let raw = self.raw(data)?;
+        let raw = if self.signed && self.length < 64 && (raw >> (self.length - 1)) & 1 == 1 {
+            (raw | !(u64::MAX >> (64 - self.length))) as i64 as f64
+        } else if self.signed {
+            raw as i64 as f64
+        } else {
+            raw as f64
+        };
+        Some(raw * self.factor + self.offset)
+    }
+}
+
+// Feeds a decoded signal into an autopid field, written as FIELD=SIGNAL,
+// e.g. "SOC=BMS_SOC"
+#[derive(Debug, Clone, PartialEq)]
+pub struct SignalMapping {
+    field: String,
+    signal: String,
+}
+
+impl FromStr for SignalMapping {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (field, signal) = s
+            .split_once('=')
+            .ok_or_else(|| anyhow!("Mapping '{}' is not in FIELD=SIGNAL form", s))?;
+        let field = field.trim().to_ascii_uppercase();
+        if !FIELDS.contains(&field.as_str()) {
+            return Err(anyhow!(
+                "Unknown field '{}', expected one of {}",
+                field,
+                FIELDS.join(", ")
+            ));
+        }
+        Ok(Self {
+            field,
+            signal: signal.trim().to_string(),
+        })
+    }
+}
+
+// Build an autopid style response from the latest signal values, None until
+// the signal mapped to the SOC has been received
+pub fn map_signals(
+    mappings: &[SignalMapping],
+    signals: &HashMap<String, f64>,
+) -> Result<Option<WicanResponse>> {
+    let fields: Map<String, Value> = mappings
+        .iter()
+        .filter_map(|mapping| {
+            let value = signals.get(&mapping.signal)?;
+            Some((mapping.field.clone(), Value::from(*value)))
+        })
+        .collect();
+    if !fields.contains_key("SOC") {
+        return Ok(None);
+    }
+    Ok(Some(serde_json::from_value(Value::Object(fields))?))
+}
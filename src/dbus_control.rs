@@ -0,0 +1,114 @@
+use anyhow::{Context, Result};
+use dbus::arg::{PropMap, RefArg, Variant};
+use dbus::channel::MatchingReceiver;
+use dbus::message::MatchRule;
+use dbus_crossroads::{Crossroads, IfaceBuilder};
+use dbus_tokio::connection;
+use log::{error, info};
+use std::sync::atomic::Ordering;
+
+use crate::control::CONTROL;
+use crate::stats::STATS;
+
+pub const BUS_NAME: &str = "io.github.ioniq3.AaProxyWican";
+const OBJECT_PATH: &str = "/io/github/ioniq3/AaProxyWican";
+const INTERFACE: &str = "io.github.ioniq3.AaProxyWican.Control";
+
+// Claim the bus name on the system bus and export the control object, which
+// is served in the background for as long as the process runs
+pub async fn serve() -> Result<()> {
+    let (resource, connection) =
+        connection::new_system_sync().context("Failed to connect to the system bus")?;
+    tokio::spawn(async move {
+        let e = resource.await;
+        error!("Lost the D-Bus control connection: {}", e);
+    });
+
+    connection
+        .request_name(BUS_NAME, false, true, true)
+        .await
+        .with_context(|| format!("Failed to claim D-Bus name {}", BUS_NAME))?;
+
+    let mut crossroads = Crossroads::new();
+    let interface = crossroads.register(INTERFACE, |builder: &mut IfaceBuilder<()>| {
+        builder.method("PollNow", (), (), |_, _, _: ()| {
+            CONTROL.poll_now();
+            Ok(())
+        });
+        builder.method("Pause", (), (), |_, _, _: ()| {
+            CONTROL.pause();
+            Ok(())
+        });
+        builder.method("Resume", (), (), |_, _, _: ()| {
+            CONTROL.resume();
+            Ok(())
+        });
+        builder.method("GetStatus", (), ("status",), |_, _, _: ()| Ok((status(),)));
+    });
+    crossroads.insert(OBJECT_PATH, &[interface], ());
+
+    connection.start_receive(
+        MatchRule::new_method_call(),
+        Box::new(move |message, connection| {
+            let _ = crossroads.handle_message(message, connection);
+            true
+        }),
+    );
+
+    info!("D-Bus control interface available as {}", BUS_NAME);
+    Ok(())
+}
+
+// Current polling and connection state as a{sv}
+fn status() -> PropMap {
+    let mut status = PropMap::new();
+    let mut insert = |key: &str, value: Box<dyn RefArg>| {
+        status.insert(key.to_string(), Variant(value));
+    };
+
+    insert(
+        "Connected",
+        Box::new(STATS.connected.load(Ordering::Relaxed)),
+    );
+    insert("Paused", Box::new(CONTROL.is_paused()));
+    insert(
+        "UpdateFrequencyMinutes",
+        Box::new(CONTROL.update_frequency_minutes()),
+    );
+    insert(
+        "SamplesReceived",
+        Box::new(STATS.samples_received.load(Ordering::Relaxed)),
+    );
+    insert(
+        "PostsSucceeded",
+        Box::new(STATS.posts_succeeded.load(Ordering::Relaxed)),
+    );
+    insert(
+        "PostsFailed",
+        Box::new(STATS.posts_failed.load(Ordering::Relaxed)),
+    );
+    insert(
+        "QueueDepth",
+        Box::new(STATS.queue_depth.load(Ordering::Relaxed) as u32),
+    );
+    if let Some(link_quality) = STATS.link_quality.lock().unwrap().as_ref() {
+        if let Some(rssi) = link_quality.rssi_dbm {
+            insert("RssiDbm", Box::new(rssi));
+        }
+        if let Some(tx_power) = link_quality.tx_power_dbm {
+            insert("TxPowerDbm", Box::new(tx_power));
+        }
+    }
+    if let Some(sample) = STATS.last_sample.lock().unwrap().as_ref() {
+        if let Some(level) = sample.battery_level_percentage {
+            insert("BatteryLevelPercentage", Box::new(level as f64));
+        }
+        if let Some(temperature) = sample.external_temp_celsius {
+            insert("ExternalTempCelsius", Box::new(temperature as f64));
+        }
+        if let Some(timestamp) = sample.timestamp {
+            insert("LastSampleTime", Box::new(timestamp.to_rfc3339()));
+        }
+    }
+    status
+}
@@ -0,0 +1,140 @@
+use serde::de::{self, Deserializer, Unexpected, Visitor};
+use std::fmt;
+
+// Parse a number written as a string, accepting a comma as the decimal separator
+fn parse_number<E: de::Error>(value: &str) -> Result<f32, E> {
+    value
+        .trim()
+        .replace(',', ".")
+        .parse()
+        .map_err(|_| E::invalid_value(Unexpected::Str(value), &"a number"))
+}
+
+struct TolerantF32;
+
+impl<'de> Visitor<'de> for TolerantF32 {
+    type Value = f32;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a number or a string containing a number")
+    }
+
+    fn visit_f64<E: de::Error>(self, value: f64) -> Result<f32, E> {
+        Ok(value as f32)
+    }
+
+    fn visit_i64<E: de::Error>(self, value: i64) -> Result<f32, E> {
+        Ok(value as f32)
+    }
+
+    fn visit_u64<E: de::Error>(self, value: u64) -> Result<f32, E> {
+        Ok(value as f32)
+    }
+
+    fn visit_str<E: de::Error>(self, value: &str) -> Result<f32, E> {
+        parse_number(value)
+    }
+}
+
+struct TolerantOptionF32;
+
+impl<'de> Visitor<'de> for TolerantOptionF32 {
+    type Value = Option<f32>;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a number, a string containing a number, or null")
+    }
+
+    fn visit_none<E: de::Error>(self) -> Result<Option<f32>, E> {
+        Ok(None)
+    }
+
+    fn visit_unit<E: de::Error>(self) -> Result<Option<f32>, E> {
+        Ok(None)
+    }
+
+    fn visit_some<D: Deserializer<'de>>(self, deserializer: D) -> Result<Option<f32>, D::Error> {
+        deserializer.deserialize_any(TolerantF32).map(Some)
+    }
+}
+
+// Parse a flag written as a string, e.g. "1", "true" or "on"
+fn parse_flag<E: de::Error>(value: &str) -> Result<bool, E> {
+    match value.trim().to_ascii_lowercase().as_str() {
+        "1" | "true" | "on" | "yes" => Ok(true),
+        "0" | "false" | "off" | "no" => Ok(false),
+        _ => Err(E::invalid_value(Unexpected::Str(value), &"a flag")),
+    }
+}
+
+struct TolerantBool;
+
+impl<'de> Visitor<'de> for TolerantBool {
+    type Value = bool;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a boolean, a number or a string containing a flag")
+    }
+
+    fn visit_bool<E: de::Error>(self, value: bool) -> Result<bool, E> {
+        Ok(value)
+    }
+
+    fn visit_f64<E: de::Error>(self, value: f64) -> Result<bool, E> {
+        Ok(value != 0.0)
+    }
+
+    fn visit_i64<E: de::Error>(self, value: i64) -> Result<bool, E> {
+        Ok(value != 0)
+    }
+
+    fn visit_u64<E: de::Error>(self, value: u64) -> Result<bool, E> {
+        Ok(value != 0)
+    }
+
+    fn visit_str<E: de::Error>(self, value: &str) -> Result<bool, E> {
+        parse_flag(value)
+    }
+}
+
+struct TolerantOptionBool;
+
+impl<'de> Visitor<'de> for TolerantOptionBool {
+    type Value = Option<bool>;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a boolean, a number, a string containing a flag, or null")
+    }
+
+    fn visit_none<E: de::Error>(self) -> Result<Option<bool>, E> {
+        Ok(None)
+    }
+
+    fn visit_unit<E: de::Error>(self) -> Result<Option<bool>, E> {
+        Ok(None)
+    }
+
+    fn visit_some<D: Deserializer<'de>>(self, deserializer: D) -> Result<Option<bool>, D::Error> {
+        deserializer.deserialize_any(TolerantBool).map(Some)
+    }
+}
+
+// Deserialize an f32 from a JSON number or a string such as "78.5" or "78,5"
+pub fn tolerant_f32<'de, D: Deserializer<'de>>(deserializer: D) -> Result<f32, D::Error> {
+    deserializer.deserialize_any(TolerantF32)
+}
+
+// Deserialize an optional f32, accepting the same forms as tolerant_f32
+pub fn tolerant_option_f32<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<f32>, D::Error> {
+    deserializer.deserialize_option(TolerantOptionF32)
+}
+
+// Deserialize an optional flag from a JSON boolean, a number where non-zero is
+// true, or a string such as "1", "true" or "on"
+pub fn tolerant_option_bool<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<bool>, D::Error> {
+    deserializer.deserialize_option(TolerantOptionBool)
+}
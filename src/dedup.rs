@@ -0,0 +1,120 @@
+use log::{Level, Log, Metadata, Record};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// A warning or error that has been logged before
+struct Repeat {
+    module_path: Option<&'static str>,
+    file: Option<&'static str>,
+    line: Option<u32>,
+    count: u64,
+    first_suppressed: Instant,
+    last_seen: Instant,
+}
+
+// Logger wrapper collapsing repeated identical warnings and errors into
+// periodic "repeated N times" summaries. Suppressed copies are still logged at
+// debug level.
+pub struct DedupLogger {
+    inner: Box<dyn Log>,
+    summary_interval: Duration,
+    repeats: Mutex<HashMap<(Level, String, String), Repeat>>,
+}
+
+impl DedupLogger {
+    pub fn new(inner: Box<dyn Log>, summary_interval: Duration) -> Self {
+        Self {
+            inner,
+            summary_interval,
+            repeats: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn log_summary(&self, level: Level, target: &str, message: &str, repeat: &Repeat) {
+        self.inner.log(
+            &Record::builder()
+                .level(level)
+                .target(target)
+                .module_path_static(repeat.module_path)
+                .file_static(repeat.file)
+                .line(repeat.line)
+                .args(format_args!(
+                    "Last message repeated {} times: {}",
+                    repeat.count, message
+                ))
+                .build(),
+        );
+    }
+}
+
+impl Log for DedupLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.inner.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if record.level() > Level::Warn {
+            self.inner.log(record);
+            return;
+        }
+
+        let message = record.args().to_string();
+        let key = (record.level(), record.target().to_string(), message);
+        let now = Instant::now();
+        let mut repeats = self.repeats.lock().unwrap();
+
+        // Forget messages that stopped repeating, reporting any outstanding count
+        let expiry = self.summary_interval * 2;
+        repeats.retain(|(level, target, message), repeat| {
+            let expired = now.duration_since(repeat.last_seen) >= expiry;
+            if expired && repeat.count > 0 {
+                self.log_summary(*level, target, message, repeat);
+            }
+            !expired
+        });
+
+        match repeats.get_mut(&key) {
+            Some(repeat) => {
+                repeat.last_seen = now;
+                if repeat.count == 0 {
+                    repeat.first_suppressed = now;
+                }
+                repeat.count += 1;
+                self.inner.log(
+                    &Record::builder()
+                        .level(Level::Debug)
+                        .target(record.target())
+                        .module_path(record.module_path())
+                        .file(record.file())
+                        .line(record.line())
+                        .args(*record.args())
+                        .build(),
+                );
+
+                if now.duration_since(repeat.first_suppressed) >= self.summary_interval {
+                    self.log_summary(key.0, &key.1, &key.2, repeat);
+                    repeat.count = 0;
+                }
+            }
+            None => {
+                self.inner.log(record);
+                repeats.insert(
+                    key,
+                    Repeat {
+                        module_path: record.module_path_static(),
+                        file: record.file_static(),
+                        line: record.line(),
+                        count: 0,
+                        first_suppressed: now,
+                        last_seen: now,
+                    },
+                );
+            }
+        }
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+    }
+}
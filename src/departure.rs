@@ -0,0 +1,116 @@
+use crate::charging;
+use crate::events::{self, Event};
+use crate::BatteryData;
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Days, Local, NaiveTime};
+use log::{info, warn};
+use serde::Serialize;
+use std::sync::Mutex;
+
+// Latest prediction, served by the admin API
+static PREDICTION: Mutex<Option<DeparturePrediction>> = Mutex::new(None);
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DeparturePrediction {
+    pub departure: DateTime<Local>,
+    pub soc: f32,
+    pub predicted_soc: f32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub target_soc: Option<f32>,
+    pub charging_power_kw: f32,
+}
+
+// Daily departure time the SOC is predicted for, warning once per departure
+// when charging at the current rate won't reach the target
+pub struct DeparturePlan {
+    time: NaiveTime,
+    target_soc: Option<f32>,
+    warned: Mutex<Option<DateTime<Local>>>,
+}
+
+impl DeparturePlan {
+    pub fn new(time: NaiveTime, target_soc: Option<f32>) -> Self {
+        Self {
+            time,
+            target_soc,
+            warned: Mutex::new(None),
+        }
+    }
+
+    pub fn update(&self, sample: &BatteryData) {
+        let (Some(soc), Some(capacity_wh)) =
+            (sample.battery_level_percentage, sample.battery_capacity_wh)
+        else {
+            return;
+        };
+        let now = Local::now();
+        let Some(departure) = self.next_departure(now) else {
+            return;
+        };
+
+        let charging_power_kw = match sample.is_charging() {
+            true => sample
+                .charging_power_kw()
+                .filter(|power| *power > 0.0)
+                .or_else(|| charging::estimated_power_kw(sample.vehicle_tag.as_deref()))
+                .unwrap_or(0.0),
+            false => 0.0,
+        };
+        let hours = (departure - now).num_seconds() as f32 / 3600.0;
+        let gained = charging_power_kw * hours * 1000.0 / capacity_wh as f32 * 100.0;
+        let prediction = DeparturePrediction {
+            departure,
+            soc,
+            predicted_soc: (soc + gained).min(100.0),
+            target_soc: self.target_soc,
+            charging_power_kw,
+        };
+
+        if let Some(target) = self.target_soc {
+            let mut warned = self.warned.lock().unwrap();
+            if prediction.predicted_soc < target && *warned != Some(departure) {
+                warn!(
+                    "Won't reach {:.0}% by {}: {:.0}% predicted at {:.1} kW.",
+                    target,
+                    departure.format("%H:%M"),
+                    prediction.predicted_soc,
+                    charging_power_kw
+                );
+                events::emit(Event::DepartureTargetMissed {
+                    departure: departure.to_rfc3339(),
+                    predicted_soc: prediction.predicted_soc,
+                    target_soc: target,
+                });
+                *warned = Some(departure);
+            } else if prediction.predicted_soc >= target && *warned == Some(departure) {
+                info!(
+                    "Now on track to reach {:.0}% by {}.",
+                    target,
+                    departure.format("%H:%M")
+                );
+                *warned = None;
+            }
+        }
+        *PREDICTION.lock().unwrap() = Some(prediction);
+    }
+
+    // Today's departure if still ahead, otherwise tomorrow's
+    fn next_departure(&self, now: DateTime<Local>) -> Option<DateTime<Local>> {
+        let today = now.date_naive();
+        [Some(today), today.checked_add_days(Days::new(1))]
+            .into_iter()
+            .flatten()
+            .filter_map(|day| day.and_time(self.time).and_local_timezone(Local).earliest())
+            .find(|departure| *departure > now)
+    }
+}
+
+pub fn latest() -> Option<DeparturePrediction> {
+    PREDICTION.lock().unwrap().clone()
+}
+
+// Parse a departure time written as HH:MM
+pub fn parse_time(value: &str) -> Result<NaiveTime> {
+    NaiveTime::parse_from_str(value.trim(), "%H:%M")
+        .map_err(|_| anyhow!("'{}' is not a time in HH:MM form", value))
+}
@@ -0,0 +1,238 @@
+use crate::rpa;
+use anyhow::{anyhow, Context, Result};
+use bluer::gatt::remote::Characteristic;
+use bluer::{Address, Device, Uuid};
+use chrono::{DateTime, Utc};
+use log::debug;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+
+const DEVICES_FILE: &str = "devices.json";
+
+// Store updated by the daemon, if enabled
+static STORE: OnceLock<DeviceStore> = OnceLock::new();
+
+// Address and record of the device currently in use, for the status file
+static CURRENT: Mutex<Option<KnownDevice>> = Mutex::new(None);
+
+// What is remembered about a device between runs
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct DeviceRecord {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub alias: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_seen: Option<DateTime<Utc>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub firmware_version: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dongle: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub vehicle_profile: Option<String>,
+    // Identity resolving key, as BlueZ stores it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub irk: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub characteristics: Option<CharacteristicHandles>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct KnownDevice {
+    pub address: String,
+    #[serde(flatten)]
+    pub record: DeviceRecord,
+}
+
+// BlueZ identifiers of the notify and write characteristics, which let them
+// be looked up directly instead of walking every service of the device. The
+// identifiers can change when the device is discovered again, so the UUIDs
+// are kept to check them.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CharacteristicHandles {
+    notify: Handle,
+    write: Handle,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Handle {
+    service: u16,
+    characteristic: u16,
+    uuid: Uuid,
+}
+
+impl Handle {
+    async fn new(characteristic: &Characteristic) -> Result<Self> {
+        Ok(Self {
+            service: characteristic.service_id(),
+            characteristic: characteristic.id(),
+            uuid: characteristic.uuid().await?,
+        })
+    }
+
+    async fn resolve(&self, device: &Device) -> Result<Characteristic> {
+        let characteristic = device
+            .service(self.service)
+            .await?
+            .characteristic(self.characteristic)
+            .await?;
+        if characteristic.uuid().await? != self.uuid {
+            return Err(anyhow!(
+                "Characteristic {} has changed",
+                self.characteristic
+            ));
+        }
+        Ok(characteristic)
+    }
+}
+
+impl CharacteristicHandles {
+    pub async fn new(notify: &Characteristic, write: &Characteristic) -> Result<Self> {
+        Ok(Self {
+            notify: Handle::new(notify).await?,
+            write: Handle::new(write).await?,
+        })
+    }
+
+    pub async fn resolve(&self, device: &Device) -> Result<(Characteristic, Characteristic)> {
+        Ok((
+            self.notify.resolve(device).await?,
+            self.write.resolve(device).await?,
+        ))
+    }
+}
+
+// Device records kept in the state directory as one JSON object keyed by
+// address
+pub struct DeviceStore {
+    path: PathBuf,
+}
+
+impl DeviceStore {
+    pub fn new(state_dir: &Path) -> Self {
+        Self {
+            path: state_dir.join(DEVICES_FILE),
+        }
+    }
+
+    pub fn load(&self) -> Result<BTreeMap<String, DeviceRecord>> {
+        match std::fs::read_to_string(&self.path) {
+            Ok(contents) => serde_json::from_str(&contents)
+                .with_context(|| format!("Failed to parse '{}'", self.path.display())),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(BTreeMap::new()),
+            Err(e) => Err(e).with_context(|| format!("Failed to read '{}'", self.path.display())),
+        }
+    }
+
+    // Write to a temporary file and rename it, so readers never see a partial file
+    // Only readable by us, as the identity keys are copied from the root-only
+    // BlueZ storage
+    fn save(&self, devices: &BTreeMap<String, DeviceRecord>) -> Result<()> {
+        let temporary = self.path.with_extension("json.tmp");
+        let contents = serde_json::to_vec_pretty(devices)?;
+        let _ = std::fs::remove_file(&temporary);
+        OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&temporary)
+            .and_then(|mut file| file.write_all(&contents))
+            .with_context(|| format!("Failed to write '{}'", temporary.display()))?;
+        std::fs::rename(&temporary, &self.path)
+            .with_context(|| format!("Failed to replace '{}'", self.path.display()))
+    }
+
+    // Change the record of a device, creating it if unknown
+    pub fn update(
+        &self,
+        address: Address,
+        change: impl FnOnce(&mut DeviceRecord),
+    ) -> Result<DeviceRecord> {
+        let mut devices = self.load()?;
+        let record = devices.entry(address.to_string()).or_default();
+        change(record);
+        let record = record.clone();
+        self.save(&devices)?;
+        Ok(record)
+    }
+
+    pub fn forget(&self, address: Address) -> Result<bool> {
+        let mut devices = self.load()?;
+        let removed = devices.remove(&address.to_string()).is_some();
+        if removed {
+            self.save(&devices)?;
+        }
+        Ok(removed)
+    }
+}
+
+pub fn set_store(store: DeviceStore) {
+    let _ = STORE.set(store);
+}
+
+// Record of a device, if the store is enabled and knows it
+pub fn get(address: Address) -> Option<DeviceRecord> {
+    let address = rpa::identity(address);
+    let devices = STORE.get()?.load().ok()?;
+    devices.get(&address.to_string()).cloned()
+}
+
+// Change the record of the device in use, if the store is enabled
+pub fn record(address: Address, change: impl FnOnce(&mut DeviceRecord)) {
+    let Some(store) = STORE.get() else {
+        return;
+    };
+    let address = rpa::identity(address);
+    match store.update(address, change) {
+        Ok(record) => {
+            *CURRENT.lock().unwrap() = Some(KnownDevice {
+                address: address.to_string(),
+                record,
+            })
+        }
+        Err(e) => debug!("Failed to update the device record: {:#}", e),
+    }
+}
+
+// The device in use for the status file and API, which anyone may read, so
+// without its identity key
+pub fn current() -> Option<KnownDevice> {
+    CURRENT.lock().unwrap().clone().map(|mut device| {
+        device.record.irk = None;
+        device
+    })
+}
+
+// Print the known devices
+pub fn list(store: &DeviceStore) -> Result<()> {
+    let devices = store.load()?;
+    if devices.is_empty() {
+        println!("No devices seen yet.");
+        return Ok(());
+    }
+
+    for (address, record) in devices {
+        let mut details = Vec::new();
+        if let Some(alias) = &record.alias {
+            details.push(format!("'{}'", alias));
+        }
+        if let Some(dongle) = &record.dongle {
+            details.push(dongle.clone());
+        }
+        if let Some(firmware_version) = &record.firmware_version {
+            details.push(format!("firmware {}", firmware_version));
+        }
+        if let Some(vehicle_profile) = &record.vehicle_profile {
+            details.push(format!("vehicle profile {}", vehicle_profile));
+        }
+        details.push(match record.last_seen {
+            Some(last_seen) => format!("last seen {}", last_seen.to_rfc3339()),
+            None => "never connected".to_string(),
+        });
+        println!("{}  {}", address, details.join("  "));
+    }
+    Ok(())
+}
@@ -0,0 +1,127 @@
+use anyhow::{anyhow, Context, Result};
+use bluer::{Adapter, AdapterEvent, Address, Device};
+use futures_util::StreamExt;
+use log::{debug, info};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::time;
+
+use crate::dongle::{WICAN_PRO_SERVICE_UUID, WICAN_SERVICE_UUID};
+
+const DISCOVERED_FILE: &str = "wican-address";
+
+// Names are often only known from the scan response, which arrives after the
+// device, so devices that didn't match yet are checked again this often
+const RECHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+pub struct DiscoveryOptions {
+    pub name_prefix: String,
+    pub timeout: Duration,
+}
+
+// Scan for a device advertising a WiCAN service or named like one, for when
+// no MAC address is configured. Devices BlueZ already knows are checked first,
+// preferring paired ones, so a WiCAN paired by hand is found without a scan.
+pub async fn discover(adapter: &Adapter, options: &DiscoveryOptions) -> Result<Address> {
+    let mut known = Vec::new();
+    for address in adapter.device_addresses().await? {
+        let device = adapter.device(address)?;
+        if is_wican(&device, &options.name_prefix).await {
+            known.push((device.is_paired().await.unwrap_or(false), address));
+        }
+    }
+    if let Some((_, address)) = known.iter().max_by_key(|(paired, _)| *paired) {
+        info!("Found known WiCAN {}.", address);
+        return Ok(*address);
+    }
+
+    info!(
+        "Scanning for a WiCAN advertising its service or named {}* for up to {:?}...",
+        options.name_prefix, options.timeout
+    );
+    // Discovery stops when the event stream is dropped
+    let mut device_events = adapter.discover_devices().await?;
+    let mut unmatched = HashSet::new();
+    let mut recheck = time::interval(RECHECK_INTERVAL);
+    let found = time::timeout(options.timeout, async {
+        loop {
+            tokio::select! {
+                event = device_events.next() => match event {
+                    Some(AdapterEvent::DeviceAdded(address)) => {
+                        if is_wican(&adapter.device(address)?, &options.name_prefix).await {
+                            return Ok(address);
+                        }
+                        unmatched.insert(address);
+                    }
+                    Some(AdapterEvent::DeviceRemoved(address)) => {
+                        unmatched.remove(&address);
+                    }
+                    Some(_) => {}
+                    None => return Err(anyhow!("Device discovery ended unexpectedly.")),
+                },
+                _ = recheck.tick() => {
+                    for address in &unmatched {
+                        if is_wican(&adapter.device(*address)?, &options.name_prefix).await {
+                            return Ok(*address);
+                        }
+                    }
+                }
+            }
+        }
+    })
+    .await;
+    match found {
+        Ok(Ok(address)) => {
+            info!("Found WiCAN {}.", address);
+            Ok(address)
+        }
+        Ok(Err(e)) => Err(e),
+        Err(_) => Err(anyhow!("Scan timed out without finding a WiCAN.")),
+    }
+}
+
+async fn is_wican(device: &Device, name_prefix: &str) -> bool {
+    let uuids = device.uuids().await.ok().flatten().unwrap_or_default();
+    if uuids.contains(&WICAN_SERVICE_UUID) || uuids.contains(&WICAN_PRO_SERVICE_UUID) {
+        debug!("{} advertises the WiCAN service.", device.address());
+        return true;
+    }
+    let name = device.name().await.ok().flatten().unwrap_or_default();
+    if !name_prefix.is_empty() && name.starts_with(name_prefix) {
+        debug!("{} is named '{}'.", device.address(), name);
+        return true;
+    }
+    false
+}
+
+// The address found by an earlier discovery, kept in the state directory so
+// later runs don't have to scan for it
+pub struct DiscoveredAddress {
+    path: PathBuf,
+}
+
+impl DiscoveredAddress {
+    pub fn new(state_dir: &Path) -> Self {
+        Self {
+            path: state_dir.join(DISCOVERED_FILE),
+        }
+    }
+
+    pub fn load(&self) -> Result<Option<Address>> {
+        match std::fs::read_to_string(&self.path) {
+            Ok(contents) => contents
+                .trim()
+                .parse()
+                .map(Some)
+                .with_context(|| format!("Invalid address in '{}'", self.path.display())),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e).with_context(|| format!("Failed to read '{}'", self.path.display())),
+        }
+    }
+
+    pub fn save(&self, address: Address) -> Result<()> {
+        std::fs::write(&self.path, format!("{}\n", address))
+            .with_context(|| format!("Failed to write '{}'", self.path.display()))
+    }
+}
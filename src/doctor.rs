@@ -0,0 +1,335 @@
+use crate::api::ApiClient;
+use crate::bluez;
+use anyhow::{anyhow, Result};
+use bluer::{Adapter, Session};
+use nix::unistd::{getgroups, Gid, Group, Uid, User};
+use std::fs::OpenOptions;
+use std::path::{Path, PathBuf};
+
+const BLUEZ_CONFIG: &str = "/etc/bluetooth/main.conf";
+
+pub struct DoctorOptions {
+    pub state_dir: PathBuf,
+    // Files written while running, with what they are
+    pub files: Vec<(&'static str, PathBuf)>,
+}
+
+enum Status {
+    Ok,
+    Warn,
+    Fail,
+}
+
+struct Finding {
+    check: String,
+    status: Status,
+    detail: String,
+    fix: Option<String>,
+}
+
+#[derive(Default)]
+struct Report {
+    findings: Vec<Finding>,
+}
+
+impl Report {
+    fn ok(&mut self, check: impl Into<String>, detail: impl Into<String>) {
+        self.push(check, Status::Ok, detail, None);
+    }
+
+    fn warn(&mut self, check: impl Into<String>, detail: impl Into<String>, fix: &str) {
+        self.push(check, Status::Warn, detail, Some(fix));
+    }
+
+    fn fail(&mut self, check: impl Into<String>, detail: impl Into<String>, fix: &str) {
+        self.push(check, Status::Fail, detail, Some(fix));
+    }
+
+    fn push(
+        &mut self,
+        check: impl Into<String>,
+        status: Status,
+        detail: impl Into<String>,
+        fix: Option<&str>,
+    ) {
+        self.findings.push(Finding {
+            check: check.into(),
+            status,
+            detail: detail.into(),
+            fix: fix.map(str::to_string),
+        });
+    }
+
+    fn failures(&self) -> usize {
+        self.findings
+            .iter()
+            .filter(|finding| matches!(finding.status, Status::Fail))
+            .count()
+    }
+
+    fn print(&self) {
+        println!("aa-proxy-wican doctor report");
+        for finding in &self.findings {
+            let status = match finding.status {
+                Status::Ok => "OK  ",
+                Status::Warn => "WARN",
+                Status::Fail => "FAIL",
+            };
+            println!("{}  {}: {}", status, finding.check, finding.detail);
+            if let Some(fix) = &finding.fix {
+                println!("      fix: {}", fix);
+            }
+        }
+    }
+}
+
+// Check the environment aa-proxy-wican runs in, from BlueZ to aa-proxy-rs,
+// and print what is wrong with a suggested fix for each problem
+pub async fn doctor(api: &ApiClient, options: DoctorOptions) -> Result<()> {
+    let mut report = Report::default();
+    check_bluez(&mut report).await;
+    check_rfkill(&mut report);
+    check_permissions(&mut report);
+    check_adapter(&mut report).await;
+    check_paths(&mut report, &options);
+    check_api(&mut report, api).await;
+    report.print();
+
+    match report.failures() {
+        0 => Ok(()),
+        failures => Err(anyhow!("Doctor found {} problem(s)", failures)),
+    }
+}
+
+async fn check_bluez(report: &mut Report) {
+    match bluez::version().await {
+        Some(version) => report.ok(
+            "BlueZ version",
+            format!(
+                "{}, using the {} quirk profile unless --bluez-quirks is given",
+                version,
+                bluez::profile_for(Some(version))
+            ),
+        ),
+        None => report.warn(
+            "BlueZ version",
+            "bluetoothd was not found",
+            "Install BlueZ, e.g. the bluez package",
+        ),
+    }
+
+    let Some(arguments) = bluetoothd_arguments() else {
+        report.fail(
+            "BlueZ daemon",
+            "bluetoothd is not running",
+            "Start it with 'systemctl enable --now bluetooth'",
+        );
+        return;
+    };
+    report.ok("BlueZ daemon", "bluetoothd is running");
+
+    let on_command_line = arguments
+        .iter()
+        .any(|argument| argument == "-E" || argument == "--experimental");
+    let in_config = std::fs::read_to_string(BLUEZ_CONFIG).is_ok_and(|config| {
+        config.lines().any(|line| {
+            let line = line.trim().replace(' ', "").to_ascii_lowercase();
+            line == "experimental=true"
+        })
+    });
+    if on_command_line || in_config {
+        report.ok("BlueZ experimental features", "enabled");
+    } else {
+        report.warn(
+            "BlueZ experimental features",
+            "disabled, so some adapter and device properties are not available",
+            &format!(
+                "Set 'Experimental = true' in the [General] section of {} and restart bluetooth",
+                BLUEZ_CONFIG
+            ),
+        );
+    }
+}
+
+// Command line of the running bluetoothd, None when it isn't running
+fn bluetoothd_arguments() -> Option<Vec<String>> {
+    std::fs::read_dir("/proc")
+        .ok()?
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_name().to_string_lossy().parse::<u32>().is_ok())
+        .find(|entry| {
+            std::fs::read_to_string(entry.path().join("comm"))
+                .is_ok_and(|comm| comm.trim() == "bluetoothd")
+        })
+        .map(|entry| {
+            std::fs::read(entry.path().join("cmdline"))
+                .unwrap_or_default()
+                .split(|byte| *byte == 0)
+                .filter(|argument| !argument.is_empty())
+                .map(|argument| String::from_utf8_lossy(argument).to_string())
+                .collect()
+        })
+}
+
+fn check_rfkill(report: &mut Report) {
+    let Ok(entries) = std::fs::read_dir("/sys/class/rfkill") else {
+        report.ok("rfkill", "no rfkill switches");
+        return;
+    };
+    let read = |path: &Path, name: &str| {
+        std::fs::read_to_string(path.join(name))
+            .map(|value| value.trim().to_string())
+            .unwrap_or_default()
+    };
+
+    let mut found = false;
+    for path in entries.filter_map(|entry| entry.ok().map(|entry| entry.path())) {
+        if read(&path, "type") != "bluetooth" {
+            continue;
+        }
+        found = true;
+        let name = read(&path, "name");
+        let check = format!("rfkill {}", name);
+        if read(&path, "hard") == "1" {
+            report.fail(
+                check,
+                "hard blocked",
+                "Turn Bluetooth on with the hardware switch or in the firmware settings",
+            );
+        } else if read(&path, "soft") == "1" {
+            report.fail(check, "soft blocked", "Run 'rfkill unblock bluetooth'");
+        } else {
+            report.ok(check, "not blocked");
+        }
+    }
+    if !found {
+        report.ok("rfkill", "no Bluetooth rfkill switches");
+    }
+}
+
+fn check_permissions(report: &mut Report) {
+    if Uid::effective().is_root() {
+        report.ok("Permissions", "running as root");
+        return;
+    }
+
+    let user = User::from_uid(Uid::effective())
+        .ok()
+        .flatten()
+        .map_or_else(|| Uid::effective().to_string(), |user| user.name);
+    let in_group = match Group::from_name("bluetooth") {
+        Ok(Some(group)) => {
+            Gid::effective() == group.gid
+                || getgroups().is_ok_and(|groups| groups.contains(&group.gid))
+        }
+        _ => false,
+    };
+    if in_group {
+        report.ok("Permissions", format!("{} is in the bluetooth group", user));
+    } else {
+        report.warn(
+            "Permissions",
+            format!(
+                "{} is not root and not in the bluetooth group, so D-Bus or polkit may refuse pairing and connecting",
+                user
+            ),
+            &format!(
+                "Run 'usermod -aG bluetooth {}' and log in again, or allow the user to use org.bluez with a D-Bus policy or polkit rule",
+                user
+            ),
+        );
+    }
+}
+
+async fn check_adapter(report: &mut Report) {
+    let session = match Session::new().await {
+        Ok(session) => session,
+        Err(e) => {
+            report.fail(
+                "D-Bus",
+                format!("could not connect to the system bus: {}", e),
+                "Make sure dbus is running and /run/dbus/system_bus_socket is accessible",
+            );
+            return;
+        }
+    };
+    report.ok("D-Bus", "connected to the system bus");
+
+    let adapter = match bluez::adapter(&session).await {
+        Ok(adapter) => adapter,
+        Err(e) => {
+            report.fail(
+                "Bluetooth adapter",
+                format!("no adapter found: {:#}", e),
+                "Plug in a Bluetooth adapter, or check 'bluetoothctl list' and dmesg for firmware errors",
+            );
+            return;
+        }
+    };
+    check_powered(report, &adapter).await;
+}
+
+async fn check_powered(report: &mut Report, adapter: &Adapter) {
+    let check = format!("Bluetooth adapter {}", adapter.name());
+    match adapter.is_powered().await {
+        Ok(true) => report.ok(check, "powered"),
+        Ok(false) => report.fail(check, "not powered", "Run 'bluetoothctl power on'"),
+        Err(e) if e.kind == bluer::ErrorKind::NotAuthorized => report.fail(
+            check,
+            format!("access denied: {}", e),
+            "Run as root, or allow the user to use org.bluez with a D-Bus policy or polkit rule",
+        ),
+        Err(e) => report.fail(
+            check,
+            format!("could not read the adapter state: {}", e),
+            "Restart bluetooth with 'systemctl restart bluetooth'",
+        ),
+    }
+}
+
+fn check_paths(report: &mut Report, options: &DoctorOptions) {
+    let state_probe = options.state_dir.join(".doctor");
+    let state_result = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(&state_probe)
+        .and_then(|_| std::fs::remove_file(&state_probe));
+    check_writable(report, "State directory", &options.state_dir, state_result);
+
+    for (check, path) in &options.files {
+        let result = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(path)
+            .map(|_| ());
+        check_writable(report, check, path, result);
+    }
+}
+
+fn check_writable(report: &mut Report, check: &str, path: &Path, result: std::io::Result<()>) {
+    match result {
+        Ok(()) => report.ok(check, format!("{} is writable", path.display())),
+        Err(e) => report.fail(
+            check,
+            format!("{} is not writable: {}", path.display(), e),
+            "Fix the ownership or permissions, or choose another path with --state-dir, --log-file, --events-file or --status-file",
+        ),
+    }
+}
+
+async fn check_api(report: &mut Report, api: &ApiClient) {
+    match api.reachable().await {
+        Ok((url, status)) if status.is_server_error() => report.warn(
+            "aa-proxy-rs",
+            format!("{} answered {}", url, status),
+            "Check the aa-proxy-rs log for errors",
+        ),
+        Ok((url, status)) => report.ok("aa-proxy-rs", format!("{} answered {}", url, status)),
+        Err(e) => report.fail(
+            "aa-proxy-rs",
+            format!("{:#}", e),
+            "Make sure aa-proxy-rs is running with EV mode enabled and --api-url points at it",
+        ),
+    }
+}
@@ -0,0 +1,369 @@
+use crate::devices::{self, CharacteristicHandles};
+use crate::{bluez, metadata, trace};
+use anyhow::{anyhow, Result};
+use bluer::gatt::remote::Characteristic;
+use bluer::{Device, Uuid};
+use clap::ValueEnum;
+use futures_util::stream::{self, Stream, StreamExt};
+use log::{debug, info, warn};
+use serde::de::IgnoredAny;
+use serde_json::Deserializer;
+use std::collections::HashSet;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+// WiCAN UART service, which sends each response in a single notification
+pub const WICAN_SERVICE_UUID: Uuid = Uuid::from_u128(0x0100dec0_01ef_bc9a_5678_1234deadf0be);
+pub const WICAN_NOTIFY_UUID: Uuid = Uuid::from_u128(0x0200dec0_01ef_bc9a_5678_1234deadf0be);
+pub const WICAN_WRITE_UUID: Uuid = Uuid::from_u128(0x0300dec0_01ef_bc9a_5678_1234deadf0be);
+
+// WiCAN PRO UART service, which splits newline terminated responses over
+// several notifications
+pub const WICAN_PRO_SERVICE_UUID: Uuid = Uuid::from_u128(0x1000dec0_01ef_bc9a_5678_1234deadf0be);
+const WICAN_PRO_NOTIFY_UUID: Uuid = Uuid::from_u128(0x2000dec0_01ef_bc9a_5678_1234deadf0be);
+const WICAN_PRO_WRITE_UUID: Uuid = Uuid::from_u128(0x3000dec0_01ef_bc9a_5678_1234deadf0be);
+
+// UART services used by generic ELM327 BLE adapters, either a single
+// FFE1 characteristic or FFF1 for notifications and FFF2 for writes
+const FFE0_SERVICE_UUID: Uuid = Uuid::from_u128(0x0000ffe0_0000_1000_8000_00805f9b34fb);
+const FFF0_SERVICE_UUID: Uuid = Uuid::from_u128(0x0000fff0_0000_1000_8000_00805f9b34fb);
+
+// OBDLink CX service with separate notify and write characteristics
+const OBDLINK_CX_SERVICE_UUID: Uuid = Uuid::from_u128(0x000018f0_0000_1000_8000_00805f9b34fb);
+const OBDLINK_CX_NOTIFY_UUID: Uuid = Uuid::from_u128(0x00002af0_0000_1000_8000_00805f9b34fb);
+const OBDLINK_CX_WRITE_UUID: Uuid = Uuid::from_u128(0x00002af1_0000_1000_8000_00805f9b34fb);
+
+// vLinker service with a single UART characteristic
+const VLINKER_SERVICE_UUID: Uuid = Uuid::from_u128(0xe7810a71_73ae_499d_8c15_faa9aef0c3f2);
+
+// Upper bound for a response being reassembled, so a lost terminator can't
+// grow the buffer forever
+const MAX_FRAME_LEN: usize = 16 * 1024;
+
+// Longest a response may take to arrive in full. The rest of a response whose
+// chunks were lost would otherwise be taken for the start of the next one.
+const MESSAGE_TIMEOUT: Duration = Duration::from_secs(2);
+
+// Hardware selected on the command line
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum DongleKind {
+    Auto,
+    Wican,
+    #[value(name = "wican-pro")]
+    WicanPro,
+    ObdlinkCx,
+    Vlinker,
+    Elm327,
+}
+
+// Hardware family of the connected dongle, deciding which characteristics are
+// used and how responses are framed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Dongle {
+    Wican,
+    WicanPro,
+    ObdlinkCx,
+    Vlinker,
+    Elm327,
+}
+
+impl fmt::Display for Dongle {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Dongle::Wican => write!(f, "WiCAN"),
+            Dongle::WicanPro => write!(f, "WiCAN PRO"),
+            Dongle::ObdlinkCx => write!(f, "OBDLink CX"),
+            Dongle::Vlinker => write!(f, "vLinker"),
+            Dongle::Elm327 => write!(f, "ELM327"),
+        }
+    }
+}
+
+impl Dongle {
+    // In order of preference, generic ELM327 clones last as the dedicated
+    // adapters may expose the same UART services
+    const ALL: [Dongle; 5] = [
+        Dongle::Wican,
+        Dongle::WicanPro,
+        Dongle::ObdlinkCx,
+        Dongle::Vlinker,
+        Dongle::Elm327,
+    ];
+
+    fn service_uuids(self) -> &'static [Uuid] {
+        match self {
+            Dongle::Wican => &[WICAN_SERVICE_UUID],
+            Dongle::WicanPro => &[WICAN_PRO_SERVICE_UUID],
+            Dongle::ObdlinkCx => &[OBDLINK_CX_SERVICE_UUID],
+            Dongle::Vlinker => &[VLINKER_SERVICE_UUID],
+            Dongle::Elm327 => &[FFE0_SERVICE_UUID, FFF0_SERVICE_UUID],
+        }
+    }
+
+    // Fixed notify and write characteristics, None for adapters whose UART
+    // characteristics are found by their flags
+    fn characteristic_uuids(self) -> Option<(Uuid, Uuid)> {
+        match self {
+            Dongle::Wican => Some((WICAN_NOTIFY_UUID, WICAN_WRITE_UUID)),
+            Dongle::WicanPro => Some((WICAN_PRO_NOTIFY_UUID, WICAN_PRO_WRITE_UUID)),
+            Dongle::ObdlinkCx => Some((OBDLINK_CX_NOTIFY_UUID, OBDLINK_CX_WRITE_UUID)),
+            Dongle::Vlinker | Dongle::Elm327 => None,
+        }
+    }
+
+    // Bytes ending a response, None when every notification is a response.
+    // The WiCAN PRO ends autopid responses with a newline and replies to raw
+    // ELM327 commands with the prompt.
+    fn terminators(self) -> Option<&'static [u8]> {
+        match self {
+            Dongle::Wican => None,
+            Dongle::WicanPro => Some(b"\n>"),
+            Dongle::ObdlinkCx | Dongle::Vlinker | Dongle::Elm327 => Some(b">"),
+        }
+    }
+
+    // Whether the dongle speaks ELM327 AT and OBD commands instead of autopid
+    pub fn is_elm327(self) -> bool {
+        matches!(self, Dongle::ObdlinkCx | Dongle::Vlinker | Dongle::Elm327)
+    }
+
+    // The hardware whose service is among the given service UUIDs
+    pub fn from_services(uuids: &HashSet<Uuid>) -> Option<Self> {
+        Self::ALL.into_iter().find(|dongle| {
+            dongle
+                .service_uuids()
+                .iter()
+                .any(|uuid| uuids.contains(uuid))
+        })
+    }
+
+    // Work out the hardware from the advertised services, falling back to the
+    // firmware revision and finally the standard WiCAN layout
+    // Dongle reached over TCP, where nothing can be detected. A WiCAN ends
+    // every reply with a newline there, framed like WiCAN PRO notifications.
+    pub fn over_tcp(kind: DongleKind) -> Self {
+        match kind {
+            DongleKind::Auto | DongleKind::Wican | DongleKind::WicanPro => Dongle::WicanPro,
+            DongleKind::ObdlinkCx => Dongle::ObdlinkCx,
+            DongleKind::Vlinker => Dongle::Vlinker,
+            DongleKind::Elm327 => Dongle::Elm327,
+        }
+    }
+
+    pub async fn detect(device: &Device, kind: DongleKind) -> Self {
+        match kind {
+            DongleKind::Wican => return Dongle::Wican,
+            DongleKind::WicanPro => return Dongle::WicanPro,
+            DongleKind::ObdlinkCx => return Dongle::ObdlinkCx,
+            DongleKind::Vlinker => return Dongle::Vlinker,
+            DongleKind::Elm327 => return Dongle::Elm327,
+            DongleKind::Auto => {}
+        }
+
+        match device.uuids().await {
+            Ok(uuids) => {
+                if let Some(dongle) = Self::from_services(&uuids.unwrap_or_default()) {
+                    info!("Detected {} hardware from its advertised services.", dongle);
+                    return dongle;
+                }
+            }
+            Err(e) => debug!("Failed to read advertised services: {}", e),
+        }
+
+        if let Some(firmware) = metadata::read_firmware_version(device).await {
+            if firmware.to_ascii_lowercase().contains("pro") {
+                info!(
+                    "Detected WiCAN PRO hardware from firmware version '{}'.",
+                    firmware
+                );
+                return Dongle::WicanPro;
+            }
+        }
+
+        info!("Could not identify the hardware, assuming the standard WiCAN layout.");
+        Dongle::Wican
+    }
+
+    // Find the notify and write characteristics of this hardware
+    pub async fn characteristics(
+        self,
+        device: &Device,
+    ) -> Result<(Characteristic, Characteristic)> {
+        if bluez::quirks().wait_for_services {
+            bluez::wait_for_services(device).await?;
+        }
+
+        if let Some(record) = devices::get(device.address()) {
+            if let (Some(handles), Some(dongle)) = (record.characteristics, record.dongle) {
+                if dongle == self.to_string() {
+                    match handles.resolve(device).await {
+                        Ok(found) => {
+                            debug!("Using the cached {} characteristics.", self);
+                            return Ok(found);
+                        }
+                        Err(e) => debug!("The cached characteristics are stale: {:#}", e),
+                    }
+                }
+            }
+        }
+
+        let mut notify_char_opt: Option<Characteristic> = None;
+        let mut write_char_opt: Option<Characteristic> = None;
+
+        for service in device.services().await? {
+            let uart = self.characteristic_uuids().is_none()
+                && self.service_uuids().contains(&service.uuid().await?);
+            for characteristic in service.characteristics().await? {
+                if uart {
+                    let flags = characteristic.flags().await?;
+                    if flags.notify && notify_char_opt.is_none() {
+                        notify_char_opt = Some(characteristic.clone());
+                    }
+                    if (flags.write || flags.write_without_response) && write_char_opt.is_none() {
+                        write_char_opt = Some(characteristic);
+                    }
+                    continue;
+                }
+
+                let Some((notify_uuid, write_uuid)) = self.characteristic_uuids() else {
+                    continue;
+                };
+                let uuid = characteristic.uuid().await?;
+                if uuid == notify_uuid {
+                    notify_char_opt = Some(characteristic);
+                } else if uuid == write_uuid {
+                    write_char_opt = Some(characteristic);
+                }
+            }
+        }
+
+        let notify_char = notify_char_opt
+            .ok_or_else(|| anyhow!("Could not find the {} notify characteristic.", self))?;
+        let write_char = write_char_opt
+            .ok_or_else(|| anyhow!("Could not find the {} write characteristic.", self))?;
+
+        match CharacteristicHandles::new(&notify_char, &write_char).await {
+            Ok(handles) => devices::record(device.address(), |record| {
+                record.dongle = Some(self.to_string());
+                record.characteristics = Some(handles);
+            }),
+            Err(e) => debug!("Could not cache the characteristics: {:#}", e),
+        }
+
+        Ok((notify_char, write_char))
+    }
+
+    // Subscribe to notifications, yielding one item per complete response
+    pub async fn notifications(
+        self,
+        characteristic: &Characteristic,
+    ) -> Result<impl Stream<Item = Vec<u8>>> {
+        Ok(self.frames(characteristic.notify().await?))
+    }
+
+    // Reassemble the chunks read from the dongle, over any transport, into
+    // complete responses
+    pub fn frames(self, chunks: impl Stream<Item = Vec<u8>>) -> impl Stream<Item = Vec<u8>> {
+        let mut framer = Framer::new(self);
+        chunks
+            .inspect(|frame| trace::frame(trace::Direction::Received, frame))
+            .flat_map(move |chunk| stream::iter(framer.push(chunk)))
+    }
+}
+
+// Turns raw notifications into complete responses
+struct Framer {
+    dongle: Dongle,
+    buffer: Vec<u8>,
+    // When the response being reassembled started to arrive
+    started: Option<Instant>,
+}
+
+impl Framer {
+    fn new(dongle: Dongle) -> Self {
+        Self {
+            dongle,
+            buffer: Vec::new(),
+            started: None,
+        }
+    }
+
+    fn push(&mut self, chunk: Vec<u8>) -> Vec<Vec<u8>> {
+        if self
+            .started
+            .is_some_and(|started| started.elapsed() > MESSAGE_TIMEOUT)
+        {
+            warn!(
+                "Discarding {} bytes of a response from the {} that never completed.",
+                self.buffer.len(),
+                self.dongle
+            );
+            self.buffer.clear();
+        }
+
+        self.buffer.extend(chunk);
+        let frames = match self.dongle.terminators() {
+            Some(terminators) => self.split(terminators),
+            None => self.split_json(),
+        };
+        if self.buffer.trim_ascii().is_empty() {
+            self.buffer.clear();
+        }
+
+        if self.buffer.len() > MAX_FRAME_LEN {
+            warn!(
+                "Discarding {} bytes from the {} without a response terminator.",
+                self.buffer.len(),
+                self.dongle
+            );
+            self.buffer.clear();
+        }
+        // A partial response left after complete ones started in this chunk
+        self.started = match (self.buffer.is_empty(), self.started) {
+            (true, _) => None,
+            (false, Some(started)) if frames.is_empty() => Some(started),
+            (false, _) => Some(Instant::now()),
+        };
+        frames
+    }
+
+    fn split(&mut self, terminators: &[u8]) -> Vec<Vec<u8>> {
+        let mut frames = Vec::new();
+        while let Some(end) = self.buffer.iter().position(|b| terminators.contains(b)) {
+            let frame: Vec<u8> = self.buffer.drain(..=end).collect();
+            if !frame.trim_ascii().is_empty() {
+                frames.push(frame);
+            }
+        }
+        frames
+    }
+
+    // The standard WiCAN ends its responses with nothing, and splits a reply
+    // longer than the MTU over several notifications. A response is complete
+    // once it parses as a JSON document, or as soon as it can't be JSON, like
+    // the reply to a wake command.
+    fn split_json(&mut self) -> Vec<Vec<u8>> {
+        let mut frames = Vec::new();
+        let mut end = 0;
+        let mut documents = Deserializer::from_slice(&self.buffer).into_iter::<IgnoredAny>();
+        loop {
+            match documents.next() {
+                Some(Ok(_)) => {
+                    let offset = documents.byte_offset();
+                    frames.push(self.buffer[end..offset].to_vec());
+                    end = offset;
+                }
+                // Cut short, the rest is still to come
+                Some(Err(e)) if e.is_eof() => break,
+                Some(Err(_)) => {
+                    frames.push(self.buffer[end..].to_vec());
+                    end = self.buffer.len();
+                    break;
+                }
+                None => break,
+            }
+        }
+        self.buffer.drain(..end);
+        frames
+    }
+}
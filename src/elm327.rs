@@ -0,0 +1,345 @@
+use crate::dongle::Dongle;
+use crate::pid::{ObdPid, PidGroup};
+use crate::transport::CommandWriter;
+use crate::vehicle::Vehicle;
+use crate::{BatteryData, WicanResponse, WriteType};
+use anyhow::{anyhow, Context, Result};
+use bluer::Device;
+use futures_util::stream::{Stream, StreamExt};
+use futures_util::FutureExt;
+use log::{debug, info, warn};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::time;
+
+// Reset the adapter and ask for compact responses without echo, line feeds,
+// spaces or headers, letting it pick the OBD protocol
+const ELM327_INIT: &[&str] = &["ATZ", "ATE0", "ATL0", "ATS0", "ATH0", "ATSP0"];
+
+// STN based OBDLink adapters come back from a warm start much quicker than
+// from a full reset and handle aggressive adaptive timing
+const OBDLINK_INIT: &[&str] = &["ATWS", "ATE0", "ATL0", "ATS0", "ATH0", "ATAT2", "ATSP0"];
+
+// vLinker adapters with adaptive timing enabled, so replies from slow ECUs are
+// not cut short
+const VLINKER_INIT: &[&str] = &["ATZ", "ATE0", "ATL0", "ATS0", "ATH0", "ATAT1", "ATSP0"];
+
+// Functional address used for requests without a header once another header was set
+pub const DEFAULT_HEADER: &str = "7DF";
+
+// Replies an ELM327 gives instead of data
+const ERRORS: &[&str] = &[
+    "?",
+    "ACT ALERT",
+    "BUFFER FULL",
+    "BUS BUSY",
+    "BUS ERROR",
+    "CAN ERROR",
+    "DATA ERROR",
+    "ERROR",
+    "FB ERROR",
+    "LV RESET",
+    "NO DATA",
+    "STOPPED",
+    "UNABLE TO CONNECT",
+];
+
+// Values of the PID groups from their last read, reused until a group with
+// an interval is due again
+static READINGS: Mutex<Readings> = Mutex::new(Readings::new());
+
+#[derive(Debug, Clone)]
+struct Readings {
+    outdoor_temperature: Option<f32>,
+    cell_temperature_min: Option<f32>,
+    cell_temperature_max: Option<f32>,
+    coolant_temperature: Option<f32>,
+    hvac_power_kw: Option<f32>,
+    speed_kmh: Option<f32>,
+    soh: Option<f32>,
+    read_at: Vec<(PidGroup, Instant)>,
+}
+
+impl Readings {
+    const fn new() -> Self {
+        Self {
+            outdoor_temperature: None,
+            cell_temperature_min: None,
+            cell_temperature_max: None,
+            coolant_temperature: None,
+            hvac_power_kw: None,
+            speed_kmh: None,
+            soh: None,
+            read_at: Vec::new(),
+        }
+    }
+
+    // Whether a group is read in this poll, always when it has no interval
+    fn due(&self, group: PidGroup, vehicle: &Vehicle) -> bool {
+        let Some(interval) = vehicle.group_interval(group) else {
+            return true;
+        };
+        match self.read_at.iter().find(|(read, _)| *read == group) {
+            Some((_, at)) if at.elapsed() < interval => {
+                debug!("Reusing the {:?} PIDs read {:?} ago", group, at.elapsed());
+                false
+            }
+            _ => true,
+        }
+    }
+
+    fn mark(&mut self, group: PidGroup) {
+        self.read_at.retain(|(read, _)| *read != group);
+        self.read_at.push((group, Instant::now()));
+    }
+}
+
+// Command/response session with an ELM327 compatible adapter
+struct Elm327<S> {
+    responses: S,
+    writer: CommandWriter,
+    timeout: Duration,
+    header: Option<String>,
+}
+
+impl<S: Stream<Item = Vec<u8>> + Unpin> Elm327<S> {
+    // Send a command and wait for the reply ending at the prompt
+    async fn command(&mut self, command: &str) -> Result<String> {
+        // Drop anything left over from an earlier command that timed out
+        while let Some(Some(_)) = self.responses.next().now_or_never() {}
+
+        self.writer
+            .write(format!("{}\r", command).as_bytes())
+            .await?;
+        let reply = time::timeout(self.timeout, self.responses.next())
+            .await
+            .map_err(|_| anyhow!("No reply to '{}' within {:?}", command, self.timeout))?
+            .ok_or_else(|| anyhow!("Notification stream ended"))?;
+
+        let reply = String::from_utf8_lossy(&reply)
+            .trim_end_matches('>')
+            .trim()
+            .to_string();
+        debug!("ELM327 replied to '{}': {:?}", command, reply);
+        Ok(reply)
+    }
+
+    async fn init(&mut self, commands: &[&str]) -> Result<()> {
+        for command in commands {
+            let reply = self.command(command).await?;
+            if reply.lines().any(|line| line.trim() == "?") {
+                return Err(anyhow!("The adapter does not understand '{}'", command));
+            }
+        }
+        Ok(())
+    }
+
+    // Request a PID and evaluate its formula
+    async fn query(&mut self, pid: &ObdPid) -> Result<f32> {
+        let header = pid
+            .header
+            .clone()
+            .or_else(|| self.header.as_ref().map(|_| DEFAULT_HEADER.to_string()));
+        if header.is_some() && header != self.header {
+            let header = header.unwrap_or_default();
+            self.command(&format!("ATSH{}", header)).await?;
+            self.header = Some(header);
+        }
+
+        let reply = self.command(&pid.request_hex()).await?;
+        let response = parse_reply(&reply, &pid.response_prefix())?;
+        pid.decode(&response)
+    }
+
+    // Request a PID that is not needed for a sample, logging failures
+    async fn query_optional(&mut self, pid: Option<&ObdPid>, name: &str) -> Option<f32> {
+        let pid = pid?;
+        match self.query(pid).await {
+            Ok(value) => Some(value),
+            Err(e) => {
+                warn!("Failed to read the {} PID {}: {:#}", name, pid, e);
+                None
+            }
+        }
+    }
+}
+
+fn init_commands(dongle: Dongle) -> &'static [&'static str] {
+    match dongle {
+        Dongle::ObdlinkCx => OBDLINK_INIT,
+        Dongle::Vlinker => VLINKER_INIT,
+        _ => ELM327_INIT,
+    }
+}
+
+// Pull the response to a request out of an ELM327 reply, joining the numbered
+// lines of a multi-frame CAN response
+pub fn parse_reply(reply: &str, prefix: &[u8]) -> Result<Vec<u8>> {
+    let lines: Vec<&str> = reply
+        .split(['\r', '\n'])
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .filter(|line| !line.starts_with("SEARCHING") && !line.starts_with("BUS INIT"))
+        .collect();
+
+    if let Some(error) = lines.iter().find(|line| ERRORS.contains(line)) {
+        return Err(anyhow!("The adapter replied '{}'", error));
+    }
+
+    if lines.iter().any(|line| line.contains(':')) {
+        let mut response = Vec::new();
+        let mut length = None;
+        for line in &lines {
+            match line.split_once(':') {
+                Some((_, data)) => response.extend(decode_hex(data)?),
+                None => length = usize::from_str_radix(line, 16).ok(),
+            }
+        }
+        if let Some(length) = length {
+            response.truncate(length);
+        }
+        if response.starts_with(prefix) {
+            return Ok(response);
+        }
+    } else if let Some(response) = lines
+        .iter()
+        .filter_map(|line| decode_hex(line).ok())
+        .find(|response| response.starts_with(prefix))
+    {
+        return Ok(response);
+    }
+
+    Err(anyhow!("Unexpected reply '{}'", lines.join(" ")))
+}
+
+fn decode_hex(line: &str) -> Result<Vec<u8>> {
+    let digits: String = line.chars().filter(|c| !c.is_whitespace()).collect();
+    hex::decode(&digits).with_context(|| format!("'{}' is not hexadecimal", line))
+}
+
+// Read the vehicle's PIDs through an ELM327 adapter and convert them like an
+// autopid response
+pub async fn fetch_data(
+    device: &Device,
+    dongle: Dongle,
+    vehicle: &Vehicle,
+    response_timeout: Duration,
+    write_type: WriteType,
+) -> Result<BatteryData> {
+    let (notify_char, write_char) = dongle
+        .characteristics(device)
+        .await
+        .with_context(|| format!("Failed to find {} characteristics", dongle))?;
+
+    read(
+        Box::pin(dongle.notifications(&notify_char).await?),
+        CommandWriter::Ble {
+            characteristic: write_char,
+            write_type,
+        },
+        dongle,
+        vehicle,
+        response_timeout,
+    )
+    .await
+}
+
+// Initialise the adapter and read the PIDs over whichever transport reaches it
+pub async fn read<S: Stream<Item = Vec<u8>> + Unpin>(
+    responses: S,
+    writer: CommandWriter,
+    dongle: Dongle,
+    vehicle: &Vehicle,
+    response_timeout: Duration,
+) -> Result<BatteryData> {
+    let mut elm = Elm327 {
+        responses,
+        writer,
+        timeout: response_timeout,
+        header: None,
+    };
+    elm.init(init_commands(dongle))
+        .await
+        .with_context(|| format!("Failed to initialise the {}", dongle))?;
+    for step in &vehicle.wake_sequence {
+        debug!("Sending wake command {}", step);
+        if let Err(e) = elm.command(&step.command).await {
+            debug!("No reply to wake command '{}': {:#}", step.command, e);
+        }
+        time::sleep(step.delay).await;
+    }
+    info!("Initialised the {}. Requesting PIDs...", dongle);
+
+    let soc = elm
+        .query(&vehicle.soc_pid)
+        .await
+        .with_context(|| format!("Failed to read the SOC PID {}", vehicle.soc_pid))?;
+    let mut readings = READINGS.lock().unwrap().clone();
+    if readings.due(PidGroup::Temperatures, vehicle) {
+        readings.outdoor_temperature = elm
+            .query_optional(vehicle.temperature_pid.as_ref(), "temperature")
+            .await;
+        readings.cell_temperature_min = elm
+            .query_optional(
+                vehicle.cell_temperature_min_pid.as_ref(),
+                "lowest cell temperature",
+            )
+            .await;
+        readings.cell_temperature_max = elm
+            .query_optional(
+                vehicle.cell_temperature_max_pid.as_ref(),
+                "highest cell temperature",
+            )
+            .await;
+        readings.coolant_temperature = elm
+            .query_optional(
+                vehicle.coolant_temperature_pid.as_ref(),
+                "coolant temperature",
+            )
+            .await;
+        readings.mark(PidGroup::Temperatures);
+    }
+    if readings.due(PidGroup::Climate, vehicle) {
+        readings.hvac_power_kw = elm
+            .query_optional(vehicle.hvac_power_pid.as_ref(), "climate power")
+            .await;
+        readings.mark(PidGroup::Climate);
+    }
+    if readings.due(PidGroup::Speed, vehicle) {
+        readings.speed_kmh = elm
+            .query_optional(vehicle.speed_pid.as_ref(), "speed")
+            .await;
+        readings.mark(PidGroup::Speed);
+    }
+    if readings.due(PidGroup::Health, vehicle) {
+        readings.soh = elm
+            .query_optional(vehicle.soh_pid.as_ref(), "state of health")
+            .await;
+        readings.mark(PidGroup::Health);
+    }
+    *READINGS.lock().unwrap() = readings.clone();
+
+    Ok(crate::battery_data(
+        WicanResponse {
+            soc,
+            soc_d: None,
+            outdoor_temperature: readings.outdoor_temperature,
+            battery_preconditioning: None,
+            plug_inserted: None,
+            charge_port_open: None,
+            charging_type: None,
+            battery_power_kw: None,
+            cell_temperature_min: readings.cell_temperature_min,
+            cell_temperature_max: readings.cell_temperature_max,
+            coolant_temperature: readings.coolant_temperature,
+            hvac_power_kw: readings.hvac_power_kw,
+            speed_kmh: readings.speed_kmh,
+            soh: readings.soh,
+            charging: None,
+            battery_temperature: None,
+            odometer_km: None,
+            aux_battery_voltage: None,
+        },
+        vehicle,
+    ))
+}
@@ -0,0 +1,383 @@
+use anyhow::{anyhow, Context, Result};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use chrono::{DateTime, Utc};
+use log::{debug, info, warn};
+use percent_encoding::percent_decode_str;
+use reqwest::Url;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufStream};
+use tokio::net::TcpStream;
+use tokio::time;
+use uuid::Uuid;
+
+use crate::events::{Event, EVENT_NAMES};
+use crate::secrets::{self, Secret};
+
+// Limit for a whole SMTP session
+const TIMEOUT: Duration = Duration::from_secs(60);
+
+// Shortest time between two mails for the same event, so a flapping
+// connection or a failing post doesn't flood the inbox
+const MIN_INTERVAL: Duration = Duration::from_secs(15 * 60);
+
+// Longest reply accepted from the server
+const MAX_REPLY_LEN: usize = 64 * 1024;
+
+static ALERTS: OnceLock<Arc<EmailAlerts>> = OnceLock::new();
+
+pub struct EmailOptions {
+    // smtp://[user[:password]@]host[:port] or smtps://...
+    pub url: String,
+    pub password: Option<Secret>,
+    pub from: String,
+    pub to: Vec<String>,
+    pub events: Vec<String>,
+}
+
+// Mails selected events to a list of recipients over SMTP, upgrading to TLS
+// with STARTTLS when the server offers it, or from the start for smtps://
+pub struct EmailAlerts {
+    host: String,
+    port: u16,
+    implicit_tls: bool,
+    username: Option<String>,
+    password: Option<String>,
+    from: String,
+    to: Vec<String>,
+    events: Vec<String>,
+    last_sent: Mutex<HashMap<String, DateTime<Utc>>>,
+}
+
+trait Connection: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Connection for T {}
+
+impl EmailAlerts {
+    pub fn new(options: EmailOptions) -> Result<Self> {
+        let url = Url::parse(&options.url)
+            .with_context(|| format!("Invalid SMTP url '{}'", secrets::redact_url(&options.url)))?;
+        let (implicit_tls, default_port) = match url.scheme() {
+            "smtp" => (false, 25),
+            "smtps" => (true, 465),
+            scheme => return Err(anyhow!("Unsupported SMTP url scheme '{}'", scheme)),
+        };
+        if options.to.is_empty() {
+            return Err(anyhow!("Email alerts need at least one --email-to address"));
+        }
+        for address in options.to.iter().chain([&options.from]) {
+            if !address.contains('@') || address.contains(['<', '>', '\r', '\n']) {
+                return Err(anyhow!("Invalid email address '{}'", address));
+            }
+        }
+        for event in &options.events {
+            if !EVENT_NAMES.contains(&event.as_str()) {
+                return Err(anyhow!(
+                    "Unknown event '{}', expected one of {}",
+                    event,
+                    EVENT_NAMES.join(", ")
+                ));
+            }
+        }
+        let password = match options.password {
+            Some(password) => Some(password.expose().to_string()),
+            None => url.password().map(decode),
+        };
+
+        Ok(Self {
+            host: url
+                .host_str()
+                .ok_or_else(|| anyhow!("The SMTP url has no host"))?
+                .to_string(),
+            port: url.port().unwrap_or(default_port),
+            implicit_tls,
+            username: Some(decode(url.username())).filter(|username| !username.is_empty()),
+            password,
+            from: options.from,
+            to: options.to,
+            events: options.events,
+            last_sent: Mutex::new(HashMap::new()),
+        })
+    }
+
+    // Send a test message, for checking the settings
+    pub async fn send_test(&self) -> Result<()> {
+        self.send(
+            "aa-proxy-wican test message",
+            "Email alerts from aa-proxy-wican are working.",
+        )
+        .await
+    }
+
+    // Whether an event is selected and wasn't mailed too recently
+    fn should_send(&self, event: &Event) -> bool {
+        let name = event.name();
+        if !self.events.contains(&name) {
+            return false;
+        }
+        let now = Utc::now();
+        let mut last_sent = self.last_sent.lock().unwrap();
+        if let Some(last) = last_sent.get(&name) {
+            if (now - *last).to_std().unwrap_or_default() < MIN_INTERVAL {
+                debug!("Not mailing {} again so soon", name);
+                return false;
+            }
+        }
+        last_sent.insert(name, now);
+        true
+    }
+
+    async fn send(&self, subject: &str, body: &str) -> Result<()> {
+        time::timeout(TIMEOUT, self.session(subject, body))
+            .await
+            .map_err(|_| anyhow!("The SMTP server did not finish within {:?}", TIMEOUT))?
+    }
+
+    async fn session(&self, subject: &str, body: &str) -> Result<()> {
+        let tcp = TcpStream::connect((self.host.as_str(), self.port))
+            .await
+            .with_context(|| format!("Failed to connect to {}:{}", self.host, self.port))?;
+        let mut connection: Box<dyn Connection> = Box::new(tcp);
+        let mut secure = false;
+        if self.implicit_tls {
+            connection = tls(connection, &self.host).await?;
+            secure = true;
+        }
+        let mut stream = BufStream::new(connection);
+        expect(&mut stream, 220).await?;
+        let mut extensions = ehlo(&mut stream).await?;
+
+        if !secure && extensions.iter().any(|line| line == "STARTTLS") {
+            command(&mut stream, "STARTTLS", 220).await?;
+            stream = BufStream::new(tls(stream.into_inner(), &self.host).await?);
+            secure = true;
+            extensions = ehlo(&mut stream).await?;
+        }
+
+        if let (Some(username), Some(password)) = (&self.username, &self.password) {
+            if !secure {
+                return Err(anyhow!(
+                    "The SMTP server does not offer STARTTLS, refusing to send the password in plain text"
+                ));
+            }
+            self.authenticate(&mut stream, &extensions, username, password)
+                .await?;
+        }
+
+        command(&mut stream, &format!("MAIL FROM:<{}>", self.from), 250).await?;
+        for to in &self.to {
+            command(&mut stream, &format!("RCPT TO:<{}>", to), 250).await?;
+        }
+        command(&mut stream, "DATA", 354).await?;
+        stream
+            .write_all(self.message(subject, body).as_bytes())
+            .await?;
+        command(&mut stream, ".", 250).await?;
+        let _ = command(&mut stream, "QUIT", 221).await;
+        Ok(())
+    }
+
+    async fn authenticate(
+        &self,
+        stream: &mut BufStream<Box<dyn Connection>>,
+        extensions: &[String],
+        username: &str,
+        password: &str,
+    ) -> Result<()> {
+        let mechanisms = extensions
+            .iter()
+            .find_map(|line| line.strip_prefix("AUTH "))
+            .unwrap_or_default()
+            .split_whitespace()
+            .collect::<Vec<_>>();
+        if mechanisms.contains(&"PLAIN") {
+            let credentials = BASE64.encode(format!("\0{}\0{}", username, password));
+            command(stream, &format!("AUTH PLAIN {}", credentials), 235).await
+        } else if mechanisms.contains(&"LOGIN") {
+            command(stream, "AUTH LOGIN", 334).await?;
+            // Sent without the command helper, which would name them in errors
+            write_line(stream, &BASE64.encode(username)).await?;
+            expect(stream, 334).await?;
+            write_line(stream, &BASE64.encode(password)).await?;
+            expect(stream, 235).await
+        } else {
+            Err(anyhow!(
+                "The SMTP server offers no supported login method: {}",
+                mechanisms.join(" ")
+            ))
+        }
+        .context("SMTP login failed")
+    }
+
+    // The message for DATA, with lines starting with a dot escaped and the
+    // terminating line left to the caller
+    fn message(&self, subject: &str, body: &str) -> String {
+        let domain = self.from.rsplit('@').next().unwrap_or("localhost");
+        let mut message = format!(
+            "From: {}\r\nTo: {}\r\nSubject: {}\r\nDate: {}\r\nMessage-ID: <{}@{}>\r\nMIME-Version: 1.0\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Transfer-Encoding: 8bit\r\n\r\n",
+            self.from,
+            self.to.join(", "),
+            subject.replace(['\r', '\n'], " "),
+            Utc::now().to_rfc2822(),
+            Uuid::new_v4(),
+            domain
+        );
+        for line in body.lines() {
+            if line.starts_with('.') {
+                message.push('.');
+            }
+            message.push_str(line);
+            message.push_str("\r\n");
+        }
+        message
+    }
+}
+
+// Percent-decode a url component
+fn decode(value: &str) -> String {
+    percent_decode_str(value).decode_utf8_lossy().to_string()
+}
+
+pub fn set_alerts(alerts: EmailAlerts) {
+    let _ = ALERTS.set(Arc::new(alerts));
+}
+
+// Mail an event in the background if alerts are enabled for it
+pub fn alert(event: &Event) {
+    let Some(alerts) = ALERTS.get() else {
+        return;
+    };
+    if !alerts.should_send(event) {
+        return;
+    }
+    let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+        return;
+    };
+
+    let alerts = alerts.clone();
+    let subject = format!("aa-proxy-wican: {}", event);
+    let body = format!(
+        "{}\n\nEvent: {}\nTime: {}\n",
+        event,
+        event.name(),
+        Utc::now().to_rfc3339()
+    );
+    runtime.spawn(async move {
+        match alerts.send(&subject, &body).await {
+            Ok(()) => info!("Mailed '{}' to {}", subject, alerts.to.join(", ")),
+            Err(e) => warn!("Failed to send email alert: {:#}", e),
+        }
+    });
+}
+
+async fn ehlo(stream: &mut BufStream<Box<dyn Connection>>) -> Result<Vec<String>> {
+    let hostname = std::fs::read_to_string("/proc/sys/kernel/hostname")
+        .map(|name| name.trim().to_string())
+        .unwrap_or_else(|_| "localhost".to_string());
+    write_line(stream, &format!("EHLO {}", hostname)).await?;
+    let (code, lines) = read_reply(stream).await?;
+    if code != 250 {
+        return Err(anyhow!("EHLO failed: {} {}", code, lines.join(" ")));
+    }
+    // The first line is the greeting, the rest the supported extensions
+    Ok(lines
+        .into_iter()
+        .skip(1)
+        .map(|line| line.to_ascii_uppercase())
+        .collect())
+}
+
+async fn command(
+    stream: &mut BufStream<Box<dyn Connection>>,
+    line: &str,
+    expected: u16,
+) -> Result<()> {
+    write_line(stream, line).await?;
+    let name = match line {
+        "." => "DATA",
+        line => line.split([' ', ':']).next().unwrap_or(line),
+    };
+    expect(stream, expected)
+        .await
+        .with_context(|| format!("{} failed", name))
+}
+
+async fn write_line(stream: &mut BufStream<Box<dyn Connection>>, line: &str) -> Result<()> {
+    stream.write_all(line.as_bytes()).await?;
+    stream.write_all(b"\r\n").await?;
+    stream.flush().await?;
+    Ok(())
+}
+
+async fn expect(stream: &mut BufStream<Box<dyn Connection>>, expected: u16) -> Result<()> {
+    let (code, lines) = read_reply(stream).await?;
+    if code != expected {
+        return Err(anyhow!("{} {}", code, lines.join(" ")));
+    }
+    Ok(())
+}
+
+// Read a reply, made of lines starting with the code and a dash except the last
+async fn read_reply(stream: &mut BufStream<Box<dyn Connection>>) -> Result<(u16, Vec<String>)> {
+    let mut lines = Vec::new();
+    let mut length = 0;
+    loop {
+        let mut line = String::new();
+        if stream.read_line(&mut line).await? == 0 {
+            return Err(anyhow!("The SMTP server closed the connection"));
+        }
+        length += line.len();
+        if length > MAX_REPLY_LEN {
+            return Err(anyhow!("SMTP reply too long"));
+        }
+        let line = line.trim_end();
+        let code = line
+            .get(..3)
+            .and_then(|code| code.parse().ok())
+            .ok_or_else(|| anyhow!("Invalid SMTP reply '{}'", line))?;
+        lines.push(line.get(4..).unwrap_or_default().to_string());
+        if line.as_bytes().get(3) != Some(&b'-') {
+            return Ok((code, lines));
+        }
+    }
+}
+
+#[cfg(feature = "rustls")]
+async fn tls(connection: Box<dyn Connection>, host: &str) -> Result<Box<dyn Connection>> {
+    use tokio_rustls::rustls::pki_types::ServerName;
+    use tokio_rustls::rustls::{crypto, ClientConfig, RootCertStore};
+    use tokio_rustls::TlsConnector;
+
+    let roots = RootCertStore {
+        roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+    };
+    let config = ClientConfig::builder_with_provider(Arc::new(crypto::ring::default_provider()))
+        .with_safe_default_protocol_versions()?
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    let name = ServerName::try_from(host.to_string())?;
+    let stream = TlsConnector::from(Arc::new(config))
+        .connect(name, connection)
+        .await
+        .context("TLS handshake failed")?;
+    Ok(Box::new(stream))
+}
+
+#[cfg(all(feature = "native-tls", not(feature = "rustls")))]
+async fn tls(connection: Box<dyn Connection>, host: &str) -> Result<Box<dyn Connection>> {
+    let connector = tokio_native_tls::TlsConnector::from(native_tls::TlsConnector::new()?);
+    let stream = connector
+        .connect(host, connection)
+        .await
+        .context("TLS handshake failed")?;
+    Ok(Box::new(stream))
+}
+
+#[cfg(not(any(feature = "rustls", feature = "native-tls")))]
+async fn tls(_connection: Box<dyn Connection>, _host: &str) -> Result<Box<dyn Connection>> {
+    Err(anyhow!(
+        "SMTP over TLS needs a build with the 'rustls' or 'native-tls' feature"
+    ))
+}
@@ -0,0 +1,219 @@
+use crate::email;
+use crate::BatteryData;
+use chrono::{DateTime, Utc};
+use log::debug;
+use serde::Serialize;
+use std::fmt;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::PathBuf;
+use std::sync::{Mutex, OnceLock};
+
+// Path of the events file or pipe, if the journal is enabled
+static EVENTS_FILE: OnceLock<PathBuf> = OnceLock::new();
+
+// Plug and charge port state of the last sample reporting them
+static PLUG_STATE: Mutex<PlugState> = Mutex::new(PlugState {
+    plug_inserted: None,
+    charge_port_open: None,
+});
+
+struct PlugState {
+    plug_inserted: Option<bool>,
+    charge_port_open: Option<bool>,
+}
+
+// Lifecycle events written to the journal
+#[derive(Serialize, Debug)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    Connected {
+        address: String,
+    },
+    Disconnected {
+        address: String,
+        reason: String,
+    },
+    PairingRemoved {
+        address: String,
+    },
+    PostFailed {
+        error: String,
+    },
+    PlugInserted,
+    PlugRemoved,
+    ChargePortOpened,
+    ChargePortClosed,
+    LowSoc {
+        soc: f32,
+        threshold: f32,
+    },
+    DepartureTargetMissed {
+        departure: String,
+        predicted_soc: f32,
+        target_soc: f32,
+    },
+    LowCarbonIntensity {
+        intensity: f64,
+        threshold: f64,
+    },
+    VehicleAway {
+        address: String,
+    },
+    VehicleInRange {
+        address: String,
+    },
+}
+
+#[derive(Serialize)]
+struct JournalEntry<'a> {
+    timestamp: DateTime<Utc>,
+    #[serde(flatten)]
+    event: &'a Event,
+}
+
+pub fn set_events_file(path: PathBuf) {
+    let _ = EVENTS_FILE.set(path);
+}
+
+impl Event {
+    // Name of the event in the journal, e.g. low_soc
+    pub fn name(&self) -> String {
+        serde_json::to_value(self)
+            .ok()
+            .and_then(|value| value["event"].as_str().map(str::to_string))
+            .unwrap_or_default()
+    }
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Event::Connected { address } => write!(f, "Connected to {}", address),
+            Event::Disconnected { address, reason } => {
+                write!(f, "Disconnected from {}: {}", address, reason)
+            }
+            Event::PairingRemoved { address } => write!(
+                f,
+                "Removed the pairing with {} after it rejected the bond",
+                address
+            ),
+            Event::PostFailed { error } => write!(f, "Failed to post to aa-proxy-rs: {}", error),
+            Event::PlugInserted => write!(f, "Charging cable plugged in"),
+            Event::PlugRemoved => write!(f, "Charging cable unplugged"),
+            Event::ChargePortOpened => write!(f, "Charge port opened"),
+            Event::ChargePortClosed => write!(f, "Charge port closed"),
+            Event::LowSoc { soc, threshold } => write!(
+                f,
+                "Battery level {:.1}% is below the {:.1}% threshold",
+                soc, threshold
+            ),
+            Event::DepartureTargetMissed {
+                departure,
+                predicted_soc,
+                target_soc,
+            } => write!(
+                f,
+                "Predicted to reach {:.1}% of the {:.1}% target by {}",
+                predicted_soc, target_soc, departure
+            ),
+            Event::LowCarbonIntensity {
+                intensity,
+                threshold,
+            } => write!(
+                f,
+                "Grid carbon intensity {:.0} gCO2/kWh is below the {:.0} gCO2/kWh threshold, a good time to charge",
+                intensity, threshold
+            ),
+            Event::VehicleAway { address } => {
+                write!(f, "{} stopped advertising, the vehicle is away", address)
+            }
+            Event::VehicleInRange { address } => {
+                write!(f, "{} is advertising again, the vehicle is back", address)
+            }
+        }
+    }
+}
+
+// Names of every event, for validating the events to mail
+pub const EVENT_NAMES: &[&str] = &[
+    "connected",
+    "disconnected",
+    "pairing_removed",
+    "post_failed",
+    "plug_inserted",
+    "plug_removed",
+    "charge_port_opened",
+    "charge_port_closed",
+    "low_soc",
+    "departure_target_missed",
+    "low_carbon_intensity",
+];
+
+// Append an event to the journal as a JSON line, and mail it if email alerts
+// are enabled for it
+pub fn emit(event: Event) {
+    email::alert(&event);
+    journal(event);
+}
+
+// The file is opened for every event so it can be rotated, and a pipe
+// without a reader never blocks us
+fn journal(event: Event) {
+    let Some(path) = EVENTS_FILE.get() else {
+        return;
+    };
+
+    let entry = JournalEntry {
+        timestamp: Utc::now(),
+        event: &event,
+    };
+    let mut line = match serde_json::to_vec(&entry) {
+        Ok(line) => line,
+        Err(e) => {
+            debug!("Failed to serialize event {:?}: {}", event, e);
+            return;
+        }
+    };
+    line.push(b'\n');
+
+    let result = OpenOptions::new()
+        .append(true)
+        .create(true)
+        .custom_flags(libc::O_NONBLOCK)
+        .open(path)
+        .and_then(|mut file| file.write_all(&line));
+    if let Err(e) = result {
+        debug!(
+            "Failed to write event to '{}': {}. Dropping {:?}",
+            path.display(),
+            e,
+            event
+        );
+    }
+}
+
+// Journal the plug being inserted or removed and the charge port opening or
+// closing, once a previous sample has shown the state it changed from
+pub fn record_sample(sample: &BatteryData) {
+    let mut state = PLUG_STATE.lock().unwrap();
+    if let Some(inserted) = sample.plug_inserted {
+        if state.plug_inserted.replace(inserted) == Some(!inserted) {
+            emit(if inserted {
+                Event::PlugInserted
+            } else {
+                Event::PlugRemoved
+            });
+        }
+    }
+    if let Some(open) = sample.charge_port_open {
+        if state.charge_port_open.replace(open) == Some(!open) {
+            emit(if open {
+                Event::ChargePortOpened
+            } else {
+                Event::ChargePortClosed
+            });
+        }
+    }
+}
@@ -0,0 +1,155 @@
+use anyhow::{anyhow, Context, Result};
+use clap::ValueEnum;
+use log::{debug, info, warn};
+use std::process::Stdio;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::process::{Child, Command};
+use tokio::time;
+
+use crate::queue::{self, RetryQueue};
+use crate::BatteryData;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+pub enum ExecMode {
+    PerSample,
+    Persistent,
+}
+
+// Sink piping each sample as a JSON line to an external command
+pub struct ExecSink {
+    command: String,
+    mode: ExecMode,
+    timeout: Duration,
+    child: tokio::sync::Mutex<Option<Child>>,
+    queue: Mutex<RetryQueue>,
+}
+
+impl ExecSink {
+    pub fn new(command: String, mode: ExecMode, timeout: Duration, queue_size: usize) -> Self {
+        Self {
+            command,
+            mode,
+            timeout,
+            child: tokio::sync::Mutex::new(None),
+            queue: Mutex::new(RetryQueue::new(queue_size)),
+        }
+    }
+
+    // Send a sample after any samples queued by earlier failures, queueing
+    // whatever could not be delivered
+    pub async fn send(&self, sample: &BatteryData) {
+        let sent = queue::send_in_order(&self.queue, sample, async |sample| {
+            self.send_one(sample).await
+        })
+        .await;
+        if let Err((e, queued)) = sent {
+            warn!("Exec sink failed: {:#}", e);
+            if queued > 0 {
+                info!(
+                    "{} sample(s) queued for the exec sink's next attempt.",
+                    queued
+                );
+            }
+        }
+    }
+
+    async fn send_one(&self, sample: &BatteryData) -> Result<()> {
+        let mut line = serde_json::to_vec(sample)?;
+        line.push(b'\n');
+        match self.mode {
+            ExecMode::PerSample => self.run_once(&line).await,
+            ExecMode::Persistent => self.write_persistent(&line).await,
+        }
+    }
+
+    fn spawn(&self) -> Result<Child> {
+        Command::new("sh")
+            .arg("-c")
+            .arg(&self.command)
+            .stdin(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .with_context(|| format!("Failed to start '{}'", self.command))
+    }
+
+    // Run the command for this sample alone and wait for it to succeed
+    async fn run_once(&self, line: &[u8]) -> Result<()> {
+        let mut child = self.spawn()?;
+        // A command failing before reading its input is reported by its exit
+        // status rather than the broken pipe
+        let written = match child.stdin.take() {
+            Some(mut stdin) => stdin.write_all(line).await,
+            None => Ok(()),
+        };
+
+        let status = time::timeout(self.timeout, child.wait())
+            .await
+            .map_err(|_| {
+                anyhow!(
+                    "'{}' did not finish within {:?}",
+                    self.command,
+                    self.timeout
+                )
+            })??;
+        if !status.success() {
+            return Err(anyhow!("'{}' failed: {}", self.command, status));
+        }
+        written.context("Failed to write the sample")
+    }
+
+    // Write to a long-running child, starting it again if it has exited
+    async fn write_persistent(&self, line: &[u8]) -> Result<()> {
+        let mut guard = self.child.lock().await;
+        if let Some(child) = guard.as_mut() {
+            if let Some(status) = child.try_wait()? {
+                warn!("Exec sink command exited: {}. Restarting it.", status);
+                *guard = None;
+            }
+        }
+        let child = match guard.as_mut() {
+            Some(child) => child,
+            None => {
+                debug!("Starting exec sink command '{}'", self.command);
+                guard.insert(self.spawn()?)
+            }
+        };
+
+        let stdin = child
+            .stdin
+            .as_mut()
+            .ok_or_else(|| anyhow!("Exec sink command has no stdin"))?;
+        let written = time::timeout(self.timeout, async {
+            stdin.write_all(line).await?;
+            stdin.flush().await
+        })
+        .await;
+        match written {
+            Ok(Ok(())) => Ok(()),
+            Ok(Err(e)) => {
+                // The pipe breaks when the command exits, which is then soon,
+                // but one that closed its input and kept running is killed
+                let status = time::timeout(self.timeout, child.wait())
+                    .await
+                    .ok()
+                    .and_then(Result::ok);
+                *guard = None;
+                Err(anyhow!(
+                    "Failed to write to '{}': {}{}",
+                    self.command,
+                    e,
+                    status.map_or(String::new(), |status| format!(", it exited: {}", status))
+                ))
+            }
+            Err(_) => {
+                *guard = None;
+                Err(anyhow!(
+                    "'{}' did not accept the sample within {:?} and was killed",
+                    self.command,
+                    self.timeout
+                ))
+            }
+        }
+    }
+}
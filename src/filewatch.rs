@@ -0,0 +1,56 @@
+use anyhow::{Context, Result};
+use nix::errno::Errno;
+use nix::sys::inotify::{AddWatchFlags, InitFlags, Inotify};
+use std::os::fd::{AsFd, AsRawFd, RawFd};
+use std::path::{Path, PathBuf};
+use tokio::io::unix::AsyncFd;
+
+use crate::paths;
+
+// Watches a file through its directory, as the file may come and go or be
+// replaced rather than written to
+pub struct FileWatcher {
+    path: PathBuf,
+    inotify: Inotify,
+}
+
+impl FileWatcher {
+    // Start watching for the events given by the flags
+    pub fn watch(path: &Path, flags: AddWatchFlags) -> Result<Self> {
+        let dir = paths::parent_dir(path);
+        let inotify = Inotify::init(InitFlags::IN_NONBLOCK | InitFlags::IN_CLOEXEC)?;
+        inotify
+            .add_watch(dir, flags)
+            .with_context(|| format!("Failed to watch {}", dir.display()))?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            inotify,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    // Call changed for every batch of events naming the file, until reading
+    // the events fails
+    pub async fn run(&self, mut changed: impl FnMut()) -> Result<()> {
+        let fd = AsyncFd::new(self.inotify.as_fd().as_raw_fd() as RawFd)?;
+        let name = self.path.file_name();
+        loop {
+            let mut guard = fd.readable().await?;
+            let events = match self.inotify.read_events() {
+                Ok(events) => events,
+                Err(Errno::EAGAIN) => {
+                    guard.clear_ready();
+                    continue;
+                }
+                Err(e) => return Err(e.into()),
+            };
+
+            if events.iter().any(|event| event.name.as_deref() == name) {
+                changed();
+            }
+        }
+    }
+}
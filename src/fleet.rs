@@ -0,0 +1,143 @@
+use anyhow::{anyhow, Context, Result};
+use bluer::Address;
+use clap::ValueEnum;
+use std::fmt;
+use std::str::FromStr;
+
+use crate::profile::{AutopidKey, AutopidKeys, VehicleProfile};
+use crate::secrets;
+use crate::secrets::Secret;
+use crate::vehicle::Vehicle;
+
+// One of several cars with their own WiCAN, read from whichever is in range,
+// written as MAC[,passkey=N][,capacity=WH][,profile=NAME][,api-url=URL][,tag=NAME]
+// with the settings not given taken from the other options
+#[derive(Clone, PartialEq)]
+pub struct DeviceEntry {
+    pub address: Address,
+    // May be encrypted, or left out and given as the wican-passkey-MAC secret
+    pub passkey: Option<Secret>,
+    pub battery_capacity_wh: Option<u32>,
+    pub vehicle_profile: Option<VehicleProfile>,
+    pub api_url: Option<String>,
+    // Sent with every sample of the car as vehicle_tag, so a receiver shared
+    // by the cars can tell them apart
+    pub tag: Option<String>,
+}
+
+impl DeviceEntry {
+    pub fn new(address: Address) -> Self {
+        Self {
+            address,
+            passkey: None,
+            battery_capacity_wh: None,
+            vehicle_profile: None,
+            api_url: None,
+            tag: None,
+        }
+    }
+
+    // Name of the secret, in the secrets file or as a systemd credential,
+    // holding this car's passkey, e.g. wican-passkey-aabbccddeeff
+    pub fn passkey_credential(&self) -> String {
+        format!(
+            "wican-passkey-{}",
+            self.address.to_string().replace(':', "").to_lowercase()
+        )
+    }
+
+    pub fn passkey_number(&self) -> Result<Option<u32>> {
+        self.passkey
+            .as_ref()
+            .map(|passkey| {
+                passkey
+                    .expose()
+                    .parse()
+                    .with_context(|| format!("The passkey of {} must be a number", self.address))
+            })
+            .transpose()
+    }
+
+    // The vehicle settings with this car's capacity, profile and tag
+    pub fn vehicle(&self, base: &Vehicle, autopid_keys: &[AutopidKey]) -> Vehicle {
+        let mut vehicle = base.clone();
+        if let Some(capacity) = self.battery_capacity_wh {
+            vehicle.battery_capacity_wh = capacity;
+        }
+        if let Some(profile) = self.vehicle_profile {
+            vehicle.autopid_keys = AutopidKeys::new(profile, autopid_keys);
+        }
+        if self.tag.is_some() {
+            vehicle.tag = self.tag.clone();
+        }
+        vehicle
+    }
+}
+
+impl FromStr for DeviceEntry {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut parts = s.split(',').map(str::trim);
+        let address = parts.next().unwrap_or_default();
+        let mut entry = DeviceEntry::new(
+            address
+                .parse()
+                .map_err(|_| anyhow!("Invalid MAC address '{}'", address))?,
+        );
+        for part in parts {
+            let (key, value) = part
+                .split_once('=')
+                .ok_or_else(|| anyhow!("'{}' is not in KEY=VALUE form", part))?;
+            let value = value.trim();
+            match key.trim() {
+                "passkey" => entry.passkey = Some(value.parse()?),
+                "capacity" => {
+                    entry.battery_capacity_wh = Some(
+                        value
+                            .parse()
+                            .with_context(|| format!("Invalid capacity '{}'", value))?,
+                    )
+                }
+                "profile" => {
+                    entry.vehicle_profile = Some(
+                        VehicleProfile::from_str(value, true)
+                            .map_err(|_| anyhow!("Unknown vehicle profile '{}'", value))?,
+                    )
+                }
+                "api-url" => entry.api_url = Some(value.to_string()),
+                "tag" => entry.tag = Some(value.to_string()),
+                key => {
+                    return Err(anyhow!(
+                        "Unknown setting '{}', expected passkey, capacity, profile, api-url or tag",
+                        key
+                    ))
+                }
+            }
+        }
+        Ok(entry)
+    }
+}
+
+// Left out of the parsed configuration when it is printed
+impl fmt::Debug for DeviceEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("DeviceEntry")
+            .field("address", &self.address)
+            .field("passkey", &self.passkey)
+            .field("battery_capacity_wh", &self.battery_capacity_wh)
+            .field("vehicle_profile", &self.vehicle_profile)
+            .field("api_url", &self.api_url.as_deref().map(secrets::redact_url))
+            .field("tag", &self.tag)
+            .finish()
+    }
+}
+
+impl fmt::Display for DeviceEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.tag {
+            Some(tag) => write!(f, "{} ({})", self.address, tag),
+            None => write!(f, "{}", self.address),
+        }
+    }
+}
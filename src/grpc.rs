@@ -0,0 +1,309 @@
+use crate::BatteryData;
+use anyhow::Result;
+
+// Streams samples to a server implementing the BatteryTelemetry service of
+// proto/aa_proxy_wican.proto, over one client-streaming call kept open and
+// reopened in the background when it fails
+pub struct GrpcSink {
+    #[cfg(feature = "grpc")]
+    sender: tokio::sync::mpsc::Sender<Vec<u8>>,
+}
+
+#[cfg(not(feature = "grpc"))]
+impl GrpcSink {
+    pub fn connect(_url: &str) -> Result<Self> {
+        Err(anyhow::anyhow!(
+            "The gRPC sink needs aa-proxy-wican built with the 'grpc' feature"
+        ))
+    }
+
+    pub fn send(&self, _data: &BatteryData) {}
+}
+
+#[cfg(feature = "grpc")]
+impl GrpcSink {
+    pub fn connect(url: &str) -> Result<Self> {
+        use anyhow::{anyhow, Context};
+
+        let url =
+            reqwest::Url::parse(url).with_context(|| format!("Invalid gRPC url '{}'", url))?;
+        if url.scheme() != "http" {
+            return Err(anyhow!(
+                "Only http:// gRPC urls are supported, not '{}'",
+                url
+            ));
+        }
+        if url.host_str().is_none() {
+            return Err(anyhow!("gRPC url '{}' has no host", url));
+        }
+
+        let (sender, receiver) = tokio::sync::mpsc::channel(client::CHANNEL_CAPACITY);
+        tokio::spawn(client::run(url, receiver));
+        Ok(Self { sender })
+    }
+
+    pub fn send(&self, data: &BatteryData) {
+        if self.sender.try_send(proto::encode(data)).is_err() {
+            log::warn!("The gRPC stream is not keeping up, dropping a sample.");
+        }
+    }
+}
+
+#[cfg(feature = "grpc")]
+mod client {
+    use anyhow::{anyhow, Context, Result};
+    use bytes::Bytes;
+    use h2::client::ResponseFuture;
+    use h2::SendStream;
+    use http::Request;
+    use log::{debug, info, warn};
+    use reqwest::Url;
+    use std::future::poll_fn;
+    use std::time::Duration;
+    use tokio::net::TcpStream;
+    use tokio::sync::mpsc;
+    use tokio::time;
+
+    pub const CHANNEL_CAPACITY: usize = 64;
+
+    const RECONNECT_DELAY: Duration = Duration::from_secs(10);
+    const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+    const PUBLISH_PATH: &str = "/aa_proxy_wican.v1.BatteryTelemetry/Publish";
+
+    pub async fn run(url: Url, mut receiver: mpsc::Receiver<Vec<u8>>) {
+        loop {
+            match publish(&url, &mut receiver).await {
+                Ok(()) => return,
+                Err(e) => warn!(
+                    "gRPC stream to {} failed: {:#}. Reconnecting in {:?}...",
+                    url, e, RECONNECT_DELAY
+                ),
+            }
+            time::sleep(RECONNECT_DELAY).await;
+        }
+    }
+
+    // Open the Publish call and forward samples over it until it fails, or
+    // until the sink is dropped
+    async fn publish(url: &Url, receiver: &mut mpsc::Receiver<Vec<u8>>) -> Result<()> {
+        let host = url.host_str().unwrap_or_default();
+        let port = url.port_or_known_default().unwrap_or(80);
+        let tcp = time::timeout(CONNECT_TIMEOUT, TcpStream::connect((host, port)))
+            .await
+            .map_err(|_| anyhow!("Timed out connecting"))??;
+        tcp.set_nodelay(true)?;
+        let (client, connection) = h2::client::handshake(tcp)
+            .await
+            .context("HTTP/2 handshake failed")?;
+        tokio::spawn(async move {
+            if let Err(e) = connection.await {
+                debug!("gRPC connection closed: {}", e);
+            }
+        });
+
+        let request = Request::post(format!(
+            "http://{}:{}{}{}",
+            host,
+            port,
+            url.path().trim_end_matches('/'),
+            PUBLISH_PATH
+        ))
+        .header("content-type", "application/grpc+proto")
+        .header("te", "trailers")
+        .header(
+            "user-agent",
+            concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION")),
+        )
+        .body(())?;
+        let mut client = client.ready().await?;
+        let (mut response, mut stream) = client.send_request(request, false)?;
+        info!("Streaming samples to {} over gRPC", url);
+
+        loop {
+            tokio::select! {
+                message = receiver.recv() => {
+                    let Some(message) = message else {
+                        stream.send_data(Bytes::new(), true)?;
+                        return Ok(());
+                    };
+                    send_message(&mut stream, message).await?;
+                }
+                response = &mut response => return Err(call_ended(response)),
+            }
+        }
+    }
+
+    // Write one length-prefixed message, waiting for flow control to allow it
+    async fn send_message(stream: &mut SendStream<Bytes>, message: Vec<u8>) -> Result<()> {
+        let mut frame = Vec::with_capacity(message.len() + 5);
+        frame.push(0);
+        frame.extend((message.len() as u32).to_be_bytes());
+        frame.extend(message);
+        let mut data = Bytes::from(frame);
+
+        while !data.is_empty() {
+            stream.reserve_capacity(data.len());
+            let capacity = poll_fn(|cx| stream.poll_capacity(cx))
+                .await
+                .ok_or_else(|| anyhow!("Stream closed by the server"))??;
+            if capacity > 0 {
+                stream.send_data(data.split_to(capacity.min(data.len())), false)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Why the server answered the call before it was finished, from the
+    // gRPC status of a trailers-only response
+    fn call_ended(response: <ResponseFuture as std::future::Future>::Output) -> anyhow::Error {
+        let response = match response {
+            Ok(response) => response,
+            Err(e) => return anyhow!(e).context("Call failed"),
+        };
+        let header = |name: &str| {
+            response
+                .headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_string)
+        };
+        match (header("grpc-status"), header("grpc-message")) {
+            (Some(status), Some(message)) => {
+                anyhow!("Server ended the call with status {}: {}", status, message)
+            }
+            (Some(status), None) => anyhow!("Server ended the call with status {}", status),
+            _ => anyhow!("Server answered the call with {}", response.status()),
+        }
+    }
+}
+
+// Protocol Buffers encoding of the messages in proto/aa_proxy_wican.proto
+#[cfg(feature = "grpc")]
+mod proto {
+    use crate::charging::ChargingType;
+    use crate::lowsoc::Priority;
+    use crate::BatteryData;
+
+    const VARINT: u64 = 0;
+    const LENGTH_DELIMITED: u64 = 2;
+    const FIXED32: u64 = 5;
+
+    #[derive(Default)]
+    struct Message(Vec<u8>);
+
+    impl Message {
+        fn varint(&mut self, mut value: u64) {
+            while value >= 0x80 {
+                self.0.push(value as u8 | 0x80);
+                value >>= 7;
+            }
+            self.0.push(value as u8);
+        }
+
+        fn key(&mut self, field: u64, wire_type: u64) {
+            self.varint(field << 3 | wire_type);
+        }
+
+        fn uint(&mut self, field: u64, value: Option<u64>) {
+            if let Some(value) = value {
+                self.key(field, VARINT);
+                self.varint(value);
+            }
+        }
+
+        // sint32/sint64, zigzag encoded
+        fn sint(&mut self, field: u64, value: Option<i64>) {
+            self.uint(
+                field,
+                value.map(|value| ((value << 1) ^ (value >> 63)) as u64),
+            );
+        }
+
+        fn bool(&mut self, field: u64, value: Option<bool>) {
+            self.uint(field, value.map(u64::from));
+        }
+
+        fn float(&mut self, field: u64, value: Option<f32>) {
+            if let Some(value) = value {
+                self.key(field, FIXED32);
+                self.0.extend(value.to_le_bytes());
+            }
+        }
+
+        fn bytes(&mut self, field: u64, value: Option<&[u8]>) {
+            if let Some(value) = value {
+                self.key(field, LENGTH_DELIMITED);
+                self.varint(value.len() as u64);
+                self.0.extend(value);
+            }
+        }
+
+        fn string(&mut self, field: u64, value: Option<&str>) {
+            self.bytes(field, value.map(str::as_bytes));
+        }
+    }
+
+    pub fn encode(data: &BatteryData) -> Vec<u8> {
+        let mut message = Message::default();
+        message.float(1, data.battery_level_percentage);
+        message.uint(2, data.battery_level_wh.map(u64::from));
+        message.float(3, data.reference_air_density);
+        message.float(4, data.external_temp_celsius);
+        message.bool(5, data.battery_preconditioning);
+        message.bool(6, data.plug_inserted);
+        message.bool(7, data.charge_port_open);
+        message.uint(
+            8,
+            data.charging_type.map(|charging_type| match charging_type {
+                ChargingType::Ac => 1,
+                ChargingType::Dc => 2,
+            }),
+        );
+        message.uint(
+            9,
+            data.priority.map(|priority| match priority {
+                Priority::Normal => 1,
+                Priority::High => 2,
+            }),
+        );
+        message.float(10, data.battery_power_kw);
+        message.float(11, data.cell_temp_min_celsius);
+        message.float(12, data.cell_temp_max_celsius);
+        message.float(13, data.coolant_temp_celsius);
+        message.float(14, data.hvac_power_kw);
+        message.float(15, data.speed_kmh);
+        message.float(16, data.auxiliary_load_kw);
+        message.uint(17, data.battery_capacity_wh.map(u64::from));
+        if let Some(timestamp) = data.timestamp {
+            // google.protobuf.Timestamp
+            let mut inner = Message::default();
+            inner.uint(1, Some(timestamp.timestamp() as u64));
+            inner.uint(2, Some(u64::from(timestamp.timestamp_subsec_nanos())));
+            message.bytes(18, Some(&inner.0));
+        }
+        message.uint(19, data.sequence);
+        message.string(
+            20,
+            data.idempotency_key.map(|key| key.to_string()).as_deref(),
+        );
+        if let Some(source) = &data.source {
+            let mut inner = Message::default();
+            inner.string(1, Some(&source.collector));
+            inner.string(2, Some(&source.collector_version));
+            inner.string(3, source.wican_mac_address.as_deref());
+            inner.string(4, source.firmware_version.as_deref());
+            inner.string(5, source.vehicle_profile.as_deref());
+            message.bytes(21, Some(&inner.0));
+        }
+        message.float(22, data.state_of_health_percentage);
+        message.sint(23, data.link.rssi_dbm.map(i64::from));
+        message.sint(24, data.link.tx_power_dbm.map(i64::from));
+        message.bool(25, data.charging);
+        message.float(26, data.battery_temp_celsius);
+        message.float(27, data.odometer_km);
+        message.float(28, data.aux_battery_voltage);
+        message.float(29, data.estimated_range_km);
+        message.0
+    }
+}
@@ -0,0 +1,189 @@
+use crate::BatteryData;
+use anyhow::{anyhow, Context, Result};
+use chrono::{DateTime, NaiveDateTime, TimeZone, Utc};
+use log::info;
+use std::collections::HashSet;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+const HISTORY_FILE: &str = "history.jsonl";
+
+// Samples kept in the state directory as one JSON object per line
+pub struct HistoryStore {
+    path: PathBuf,
+}
+
+impl HistoryStore {
+    pub fn new(state_dir: &Path) -> Self {
+        Self {
+            path: state_dir.join(HISTORY_FILE),
+        }
+    }
+
+    pub fn append(&self, sample: &BatteryData) -> Result<()> {
+        let mut line = serde_json::to_vec(sample)?;
+        line.push(b'\n');
+        OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(&self.path)
+            .and_then(|mut file| file.write_all(&line))
+            .with_context(|| format!("Failed to append to '{}'", self.path.display()))
+    }
+
+    pub fn read_all(&self) -> Result<Vec<BatteryData>> {
+        if !self.path.exists() {
+            return Ok(Vec::new());
+        }
+        read_samples(&self.path)
+    }
+
+    // Merge samples into the history ordered by time, skipping any whose
+    // timestamp is already present. Returns the number added.
+    pub fn import(&self, samples: Vec<BatteryData>) -> Result<usize> {
+        let mut history = self.read_all()?;
+        let existing = history.len();
+
+        let mut known: HashSet<_> = history
+            .iter()
+            .filter_map(|sample| sample.timestamp)
+            .collect();
+        for sample in samples {
+            if sample
+                .timestamp
+                .is_none_or(|timestamp| known.insert(timestamp))
+            {
+                history.push(sample);
+            }
+        }
+        let added = history.len() - existing;
+        history.sort_by_key(|sample| sample.timestamp);
+
+        let mut contents = Vec::new();
+        for sample in &history {
+            serde_json::to_writer(&mut contents, sample)?;
+            contents.push(b'\n');
+        }
+        let temporary = self.path.with_extension("jsonl.tmp");
+        std::fs::write(&temporary, contents)
+            .with_context(|| format!("Failed to write '{}'", temporary.display()))?;
+        std::fs::rename(&temporary, &self.path)
+            .with_context(|| format!("Failed to replace '{}'", self.path.display()))?;
+        Ok(added)
+    }
+}
+
+// Read samples stored as one JSON object per line
+pub fn read_samples(path: &Path) -> Result<Vec<BatteryData>> {
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read '{}'", path.display()))?;
+
+    contents
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(index, line)| {
+            serde_json::from_str(line)
+                .with_context(|| format!("Invalid sample on line {}", index + 1))
+        })
+        .collect()
+}
+
+// Columns of an external SOC history CSV
+pub struct CsvColumns {
+    pub timestamp: String,
+    pub timestamp_format: Option<String>,
+    pub soc: String,
+    pub temperature: Option<String>,
+}
+
+// Parse a timestamp as RFC 3339, unix seconds or with a chrono format string,
+// treating timestamps without a zone as local time
+fn parse_timestamp(value: &str, format: Option<&str>) -> Result<DateTime<Utc>> {
+    match format {
+        Some("unix") => value
+            .parse::<i64>()
+            .ok()
+            .and_then(|seconds| DateTime::from_timestamp(seconds, 0))
+            .ok_or_else(|| anyhow!("Invalid unix timestamp '{}'", value)),
+        Some(format) => {
+            let naive = NaiveDateTime::parse_from_str(value, format)
+                .with_context(|| format!("Timestamp '{}' does not match '{}'", value, format))?;
+            chrono::Local
+                .from_local_datetime(&naive)
+                .earliest()
+                .map(|time| time.with_timezone(&Utc))
+                .ok_or_else(|| anyhow!("Timestamp '{}' does not exist locally", value))
+        }
+        None => DateTime::parse_from_rfc3339(value)
+            .map(|time| time.with_timezone(&Utc))
+            .with_context(|| format!("Timestamp '{}' is not RFC 3339", value)),
+    }
+}
+
+// Read SOC history exported by another app, e.g. a phone OBD logger
+pub fn read_csv(path: &Path, columns: &CsvColumns) -> Result<Vec<BatteryData>> {
+    let mut reader = csv::ReaderBuilder::new()
+        .flexible(true)
+        .trim(csv::Trim::All)
+        .from_path(path)
+        .with_context(|| format!("Failed to open '{}'", path.display()))?;
+
+    let headers = reader.headers()?.clone();
+    let column = |name: &str| {
+        headers
+            .iter()
+            .position(|header| header.eq_ignore_ascii_case(name))
+            .ok_or_else(|| anyhow!("CSV has no '{}' column", name))
+    };
+    let timestamp_column = column(&columns.timestamp)?;
+    let soc_column = column(&columns.soc)?;
+    let temperature_column = columns.temperature.as_deref().map(column).transpose()?;
+
+    let mut samples = Vec::new();
+    for (index, record) in reader.records().enumerate() {
+        // Line 1 holds the headers
+        let line = index + 2;
+        let record = record.with_context(|| format!("Invalid CSV on line {}", line))?;
+        let field = |column: usize| record.get(column).filter(|value| !value.is_empty());
+
+        let (Some(timestamp), Some(soc)) = (field(timestamp_column), field(soc_column)) else {
+            continue;
+        };
+        let timestamp = parse_timestamp(timestamp, columns.timestamp_format.as_deref())
+            .with_context(|| format!("Line {}", line))?;
+        let soc = soc
+            .replace(',', ".")
+            .parse::<f32>()
+            .with_context(|| format!("Invalid SOC '{}' on line {}", soc, line))?;
+        let temperature = temperature_column
+            .and_then(field)
+            .map(|value| value.replace(',', ".").parse::<f32>())
+            .transpose()
+            .with_context(|| format!("Invalid temperature on line {}", line))?;
+
+        samples.push(BatteryData {
+            battery_level_percentage: Some(soc),
+            external_temp_celsius: temperature,
+            timestamp: Some(timestamp),
+            ..Default::default()
+        });
+    }
+    Ok(samples)
+}
+
+// Import a CSV into the history store
+pub fn import_csv(store: &HistoryStore, path: &Path, columns: &CsvColumns) -> Result<()> {
+    let samples = read_csv(path, columns)?;
+    let read = samples.len();
+    let added = store.import(samples)?;
+    info!(
+        "Imported {} of {} sample(s) from '{}' into the history, {} already present.",
+        added,
+        read,
+        path.display(),
+        read - added
+    );
+    Ok(())
+}
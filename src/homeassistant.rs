@@ -0,0 +1,368 @@
+use anyhow::{anyhow, Context, Result};
+use log::{debug, warn};
+use reqwest::{Client, Url};
+use serde_json::{json, Value};
+
+use crate::charging::ChargingType;
+use crate::secrets::{self, Secret};
+use crate::BatteryData;
+
+// Home Assistant MQTT discovery settings, announcing the same entities as the
+// REST sink on an MQTT broker
+#[derive(Debug, Clone)]
+pub struct MqttDiscoveryOptions {
+    pub discovery_prefix: String,
+    pub device_name: String,
+    pub entity_prefix: String,
+}
+
+pub struct HomeAssistantOptions {
+    pub url: String,
+    pub token: Secret,
+    pub entity_prefix: String,
+}
+
+// Sink setting sensor states through the Home Assistant REST API, which
+// creates the entities on first use
+pub struct HomeAssistantSink {
+    client: Client,
+    states_url: Url,
+    token: Secret,
+    entity_prefix: String,
+}
+
+// An entity set from a sample field, skipped while the field is unknown
+struct Entity {
+    domain: &'static str,
+    id: &'static str,
+    name: &'static str,
+    device_class: Option<&'static str>,
+    unit: Option<&'static str>,
+    state: fn(&BatteryData) -> Option<Value>,
+}
+
+const ENTITIES: &[Entity] = &[
+    Entity {
+        domain: "sensor",
+        id: "soc",
+        name: "Battery level",
+        device_class: Some("battery"),
+        unit: Some("%"),
+        state: |data| data.battery_level_percentage.map(|value| json!(value)),
+    },
+    Entity {
+        domain: "sensor",
+        id: "energy",
+        name: "Battery energy",
+        device_class: Some("energy_storage"),
+        unit: Some("Wh"),
+        state: |data| data.battery_level_wh.map(|value| json!(value)),
+    },
+    Entity {
+        domain: "sensor",
+        id: "state_of_health",
+        name: "Battery state of health",
+        device_class: None,
+        unit: Some("%"),
+        state: |data| data.state_of_health_percentage.map(|value| json!(value)),
+    },
+    Entity {
+        domain: "sensor",
+        id: "battery_power",
+        name: "Battery power",
+        device_class: Some("power"),
+        unit: Some("kW"),
+        state: |data| data.battery_power_kw.map(|value| json!(value)),
+    },
+    Entity {
+        domain: "sensor",
+        id: "external_temperature",
+        name: "External temperature",
+        device_class: Some("temperature"),
+        unit: Some("°C"),
+        state: |data| data.external_temp_celsius.map(|value| json!(value)),
+    },
+    Entity {
+        domain: "sensor",
+        id: "cell_temperature_min",
+        name: "Lowest cell temperature",
+        device_class: Some("temperature"),
+        unit: Some("°C"),
+        state: |data| data.cell_temp_min_celsius.map(|value| json!(value)),
+    },
+    Entity {
+        domain: "sensor",
+        id: "cell_temperature_max",
+        name: "Highest cell temperature",
+        device_class: Some("temperature"),
+        unit: Some("°C"),
+        state: |data| data.cell_temp_max_celsius.map(|value| json!(value)),
+    },
+    Entity {
+        domain: "sensor",
+        id: "coolant_temperature",
+        name: "Battery coolant temperature",
+        device_class: Some("temperature"),
+        unit: Some("°C"),
+        state: |data| data.coolant_temp_celsius.map(|value| json!(value)),
+    },
+    Entity {
+        domain: "sensor",
+        id: "battery_temperature",
+        name: "Battery temperature",
+        device_class: Some("temperature"),
+        unit: Some("°C"),
+        state: |data| data.battery_temp_celsius.map(|value| json!(value)),
+    },
+    Entity {
+        domain: "sensor",
+        id: "odometer",
+        name: "Odometer",
+        device_class: Some("distance"),
+        unit: Some("km"),
+        state: |data| data.odometer_km.map(|value| json!(value)),
+    },
+    Entity {
+        domain: "sensor",
+        id: "estimated_range",
+        name: "Estimated range",
+        device_class: Some("distance"),
+        unit: Some("km"),
+        state: |data| data.estimated_range_km.map(|value| json!(value)),
+    },
+    Entity {
+        domain: "sensor",
+        id: "aux_battery_voltage",
+        name: "12V battery voltage",
+        device_class: Some("voltage"),
+        unit: Some("V"),
+        state: |data| data.aux_battery_voltage.map(|value| json!(value)),
+    },
+    Entity {
+        domain: "sensor",
+        id: "charging_type",
+        name: "Charging type",
+        device_class: None,
+        unit: None,
+        state: |data| {
+            Some(json!(match data.charging_type {
+                Some(ChargingType::Ac) => "ac",
+                Some(ChargingType::Dc) => "dc",
+                None => "none",
+            }))
+        },
+    },
+    Entity {
+        domain: "sensor",
+        id: "wican_rssi",
+        name: "WiCAN signal strength",
+        device_class: Some("signal_strength"),
+        unit: Some("dBm"),
+        state: |data| data.link.rssi_dbm.map(|value| json!(value)),
+    },
+    Entity {
+        domain: "binary_sensor",
+        id: "charging",
+        name: "Charging",
+        device_class: Some("battery_charging"),
+        unit: None,
+        state: |data| {
+            let charging = data.charging.unwrap_or_else(|| {
+                data.charging_type.is_some()
+                    || data.battery_power_kw.is_some_and(|power| power < 0.0)
+            });
+            Some(json!(if charging { "on" } else { "off" }))
+        },
+    },
+    Entity {
+        domain: "binary_sensor",
+        id: "plug",
+        name: "Charging cable",
+        device_class: Some("plug"),
+        unit: None,
+        state: |data| {
+            data.plug_inserted
+                .map(|plugged| json!(if plugged { "on" } else { "off" }))
+        },
+    },
+    Entity {
+        domain: "binary_sensor",
+        id: "battery_preconditioning",
+        name: "Battery preconditioning",
+        device_class: Some("heat"),
+        unit: None,
+        state: |data| {
+            data.battery_preconditioning
+                .map(|heating| json!(if heating { "on" } else { "off" }))
+        },
+    },
+];
+
+impl HomeAssistantSink {
+    pub fn new(options: HomeAssistantOptions, client: Client) -> Result<Self> {
+        let url = Url::parse(&options.url).with_context(|| {
+            format!(
+                "Invalid Home Assistant url '{}'",
+                secrets::redact_url(&options.url)
+            )
+        })?;
+        let states_url = url
+            .join("api/states/")
+            .context("Invalid Home Assistant url")?;
+        check_entity_prefix(&options.entity_prefix)?;
+        Ok(Self {
+            client,
+            states_url,
+            token: options.token,
+            entity_prefix: options.entity_prefix,
+        })
+    }
+
+    pub async fn send(&self, sample: &BatteryData) {
+        for entity in ENTITIES {
+            let Some(state) = (entity.state)(sample) else {
+                continue;
+            };
+            if let Err(e) = self.set_state(entity, state).await {
+                warn!("Failed to update Home Assistant: {:#}", e);
+                // The others would most likely fail the same way
+                return;
+            }
+        }
+    }
+
+    async fn set_state(&self, entity: &Entity, state: Value) -> Result<()> {
+        let entity_id = format!("{}.{}_{}", entity.domain, self.entity_prefix, entity.id);
+        let mut attributes = json!({
+            "friendly_name": entity.name,
+        });
+        if let Some(device_class) = entity.device_class {
+            attributes["device_class"] = json!(device_class);
+        }
+        if let Some(unit) = entity.unit {
+            attributes["unit_of_measurement"] = json!(unit);
+            attributes["state_class"] = json!("measurement");
+        }
+
+        let response = self
+            .client
+            .post(self.states_url.join(&entity_id)?)
+            .bearer_auth(self.token.expose())
+            .json(&json!({ "state": state, "attributes": attributes }))
+            .send()
+            .await
+            .context("Could not reach Home Assistant")?;
+        let status = response.status();
+        if !status.is_success() {
+            let body = response.text().await.unwrap_or_default();
+            return Err(anyhow!(
+                "Setting {} returned {}: {}",
+                entity_id,
+                status,
+                body.trim()
+            ));
+        }
+        debug!("Set {} to {}", entity_id, state);
+        Ok(())
+    }
+}
+
+pub fn check_entity_prefix(prefix: &str) -> Result<()> {
+    if prefix.is_empty()
+        || !prefix
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+    {
+        return Err(anyhow!(
+            "The Home Assistant entity prefix may only contain lowercase letters, digits and underscores"
+        ));
+    }
+    Ok(())
+}
+
+// Entities announced through Home Assistant MQTT discovery, each with a state
+// topic under the MQTT topic prefix
+pub struct MqttDiscovery {
+    options: MqttDiscoveryOptions,
+    state_prefix: String,
+    availability_topic: Option<String>,
+}
+
+impl MqttDiscovery {
+    pub fn new(
+        options: MqttDiscoveryOptions,
+        state_prefix: &str,
+        availability_topic: Option<String>,
+    ) -> Result<Self> {
+        check_entity_prefix(&options.entity_prefix)?;
+        Ok(Self {
+            options,
+            state_prefix: format!("{}/homeassistant", state_prefix),
+            availability_topic,
+        })
+    }
+
+    fn state_topic(&self, entity: &Entity) -> String {
+        format!("{}/{}", self.state_prefix, entity.id)
+    }
+
+    // Retained config messages creating the entities, published on every
+    // connect so they survive the broker losing its retained messages
+    pub fn configs(&self) -> Vec<(String, String)> {
+        let prefix = &self.options.entity_prefix;
+        ENTITIES
+            .iter()
+            .map(|entity| {
+                let unique_id = format!("{}_{}", prefix, entity.id);
+                let mut config = json!({
+                    "name": entity.name,
+                    "unique_id": unique_id,
+                    "object_id": unique_id,
+                    "state_topic": self.state_topic(entity),
+                    "device": {
+                        "identifiers": [prefix],
+                        "name": self.options.device_name,
+                        "manufacturer": "aa-proxy-wican",
+                        "sw_version": env!("CARGO_PKG_VERSION"),
+                    },
+                });
+                if let Some(device_class) = entity.device_class {
+                    config["device_class"] = json!(device_class);
+                }
+                if let Some(unit) = entity.unit {
+                    config["unit_of_measurement"] = json!(unit);
+                    config["state_class"] = json!("measurement");
+                }
+                if entity.domain == "binary_sensor" {
+                    config["payload_on"] = json!("on");
+                    config["payload_off"] = json!("off");
+                }
+                if let Some(topic) = &self.availability_topic {
+                    config["availability_topic"] = json!(topic);
+                }
+                let topic = format!(
+                    "{}/{}/{}/{}/config",
+                    self.options.discovery_prefix.trim_end_matches('/'),
+                    entity.domain,
+                    prefix,
+                    entity.id
+                );
+                (topic, config.to_string())
+            })
+            .collect()
+    }
+
+    // State messages for the entities a sample has a value for
+    pub fn states(&self, sample: &BatteryData) -> Vec<(String, String)> {
+        ENTITIES
+            .iter()
+            .filter_map(|entity| {
+                let state = match (entity.state)(sample)? {
+                    Value::String(text) => text,
+                    other => other.to_string(),
+                };
+                Some((self.state_topic(entity), state))
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,200 @@
+use log::{debug, warn};
+use serde_json::json;
+use std::collections::HashMap;
+use std::process::Stdio;
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+use tokio::time;
+
+use crate::stats::STATS;
+use crate::BatteryData;
+
+// Configured hooks, if any
+static HOOKS: OnceLock<Hooks> = OnceLock::new();
+
+// Shell commands run at points of the polling cycle
+pub struct Hooks {
+    pre_poll: Option<String>,
+    post_sample: Option<String>,
+    charge_start: Option<String>,
+    error: Option<String>,
+    low_soc: Option<String>,
+    timeout: Duration,
+    // Keyed by the vehicle tag, so each car's sessions are told apart
+    charge: Mutex<HashMap<Option<String>, ChargeDetector>>,
+}
+
+impl Hooks {
+    pub fn new(
+        pre_poll: Option<String>,
+        post_sample: Option<String>,
+        charge_start: Option<String>,
+        error: Option<String>,
+        low_soc: Option<String>,
+        timeout: Duration,
+    ) -> Self {
+        Self {
+            pre_poll,
+            post_sample,
+            charge_start,
+            error,
+            low_soc,
+            timeout,
+            charge: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+// Tracks whether the car is charging to spot a session starting
+#[derive(Default)]
+struct ChargeDetector {
+    charging: bool,
+}
+
+impl ChargeDetector {
+    // Returns true when a charging session starts with this sample
+    fn update(&mut self, sample: &BatteryData) -> bool {
+        let charging = sample.is_charging();
+        let started = charging && !self.charging;
+        self.charging = charging;
+        started
+    }
+}
+
+pub fn set_hooks(hooks: Hooks) {
+    let _ = HOOKS.set(hooks);
+}
+
+// Run the pre-poll hook before connecting to the WiCAN
+pub async fn pre_poll() {
+    if let Some(hooks) = HOOKS.get() {
+        run(hooks, "pre-poll", hooks.pre_poll.as_deref(), None, &[]).await;
+    }
+}
+
+// Run the post-sample hook, and the charge-start hook when the sample shows a
+// charging session starting
+pub async fn post_sample(sample: &BatteryData) {
+    let Some(hooks) = HOOKS.get() else {
+        return;
+    };
+    let payload = serde_json::to_string(sample).unwrap_or_default();
+    let env = [("AA_PROXY_WICAN_SAMPLE", payload.as_str())];
+    run(
+        hooks,
+        "post-sample",
+        hooks.post_sample.as_deref(),
+        Some(&payload),
+        &env,
+    )
+    .await;
+
+    let charge_started = hooks
+        .charge
+        .lock()
+        .unwrap()
+        .entry(sample.vehicle_tag.clone())
+        .or_default()
+        .update(sample);
+    if charge_started {
+        run(
+            hooks,
+            "charge-start",
+            hooks.charge_start.as_deref(),
+            Some(&payload),
+            &env,
+        )
+        .await;
+    }
+}
+
+// Run the low SOC hook when the SOC has dropped below the threshold
+pub async fn low_soc(sample: &BatteryData) {
+    let Some(hooks) = HOOKS.get() else {
+        return;
+    };
+    let payload = serde_json::to_string(sample).unwrap_or_default();
+    run(
+        hooks,
+        "low-soc",
+        hooks.low_soc.as_deref(),
+        Some(&payload),
+        &[("AA_PROXY_WICAN_SAMPLE", payload.as_str())],
+    )
+    .await;
+}
+
+// Run the error hook in the background, so reporting never holds up polling.
+// The error is kept for the status API either way.
+pub fn error(message: String) {
+    STATS.record_error(&message);
+    let Some(hooks) = HOOKS.get() else {
+        return;
+    };
+    if hooks.error.is_none() {
+        return;
+    }
+    tokio::spawn(async move {
+        let payload = json!({ "error": message }).to_string();
+        run(
+            hooks,
+            "error",
+            hooks.error.as_deref(),
+            Some(&payload),
+            &[("AA_PROXY_WICAN_ERROR", message.as_str())],
+        )
+        .await;
+    });
+}
+
+// Run a hook command with sh, passing the JSON payload on stdin. Failures are
+// logged and otherwise ignored.
+async fn run(
+    hooks: &Hooks,
+    name: &str,
+    command: Option<&str>,
+    payload: Option<&str>,
+    env: &[(&str, &str)],
+) {
+    let Some(command) = command else {
+        return;
+    };
+    debug!("Running hook {}: {}", name, command);
+
+    let mut process = Command::new("sh");
+    process
+        .arg("-c")
+        .arg(command)
+        .env("AA_PROXY_WICAN_HOOK", name)
+        .stdin(if payload.is_some() {
+            Stdio::piped()
+        } else {
+            Stdio::null()
+        })
+        .envs(env.iter().copied())
+        .kill_on_drop(true);
+
+    let mut child = match process.spawn() {
+        Ok(child) => child,
+        Err(e) => {
+            warn!("Failed to start hook {}: {}", name, e);
+            return;
+        }
+    };
+    if let (Some(mut stdin), Some(payload)) = (child.stdin.take(), payload) {
+        // A hook that ignores stdin may exit before reading it
+        let _ = stdin.write_all(payload.as_bytes()).await;
+    }
+
+    match time::timeout(hooks.timeout, child.wait()).await {
+        Ok(Ok(status)) if status.success() => {}
+        Ok(Ok(status)) => warn!("Hook {} failed: {}", name, status),
+        Ok(Err(e)) => warn!("Failed to wait for hook {}: {}", name, e),
+        Err(_) => warn!(
+            "Hook {} did not finish within {:?} and was killed.",
+            name, hooks.timeout
+        ),
+    }
+}
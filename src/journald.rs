@@ -0,0 +1,86 @@
+use anyhow::{Context, Result};
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use std::os::unix::net::UnixDatagram;
+
+// Socket journald reads native protocol messages from
+const JOURNAL_SOCKET: &str = "/run/systemd/journal/socket";
+
+const IDENTIFIER: &str = "aa-proxy-wican";
+
+// Logs straight to the systemd journal with the level, source location and
+// target as fields, so `journalctl -p warning` and friends work
+pub struct JournaldLogger {
+    socket: UnixDatagram,
+    level: LevelFilter,
+}
+
+impl JournaldLogger {
+    pub fn connect(level: LevelFilter) -> Result<Self> {
+        let socket = UnixDatagram::unbound().context("Failed to create the journal socket")?;
+        socket
+            .connect(JOURNAL_SOCKET)
+            .with_context(|| format!("Failed to connect to the journal at {}", JOURNAL_SOCKET))?;
+        Ok(Self { socket, level })
+    }
+}
+
+// Syslog priority of a log level
+fn priority(level: Level) -> u8 {
+    match level {
+        Level::Error => 3,
+        Level::Warn => 4,
+        Level::Info => 6,
+        Level::Debug | Level::Trace => 7,
+    }
+}
+
+// Append a field, in the binary form when the value spans several lines
+fn field(message: &mut Vec<u8>, name: &str, value: &str) {
+    message.extend_from_slice(name.as_bytes());
+    if value.contains('\n') {
+        message.push(b'\n');
+        message.extend_from_slice(&(value.len() as u64).to_le_bytes());
+    } else {
+        message.push(b'=');
+    }
+    message.extend_from_slice(value.as_bytes());
+    message.push(b'\n');
+}
+
+impl Log for JournaldLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let text = record.args().to_string();
+        let mut message = Vec::new();
+        field(&mut message, "MESSAGE", &text);
+        field(
+            &mut message,
+            "PRIORITY",
+            &priority(record.level()).to_string(),
+        );
+        field(&mut message, "SYSLOG_IDENTIFIER", IDENTIFIER);
+        field(&mut message, "TARGET", record.target());
+        if let Some(file) = record.file() {
+            field(&mut message, "CODE_FILE", file);
+        }
+        if let Some(line) = record.line() {
+            field(&mut message, "CODE_LINE", &line.to_string());
+        }
+        if let Some(module) = record.module_path() {
+            field(&mut message, "CODE_MODULE", module);
+        }
+        // Messages too big for a datagram, such as long frame dumps, still
+        // reach the journal through stderr
+        if self.socket.send(&message).is_err() {
+            eprintln!("[{}] {}", record.level(), text);
+        }
+    }
+
+    fn flush(&self) {}
+}
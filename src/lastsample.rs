@@ -0,0 +1,59 @@
+use crate::BatteryData;
+use anyhow::{Context, Result};
+use chrono::Utc;
+use log::debug;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+const LAST_SAMPLE_FILE: &str = "last-sample.json";
+
+// The latest sample, kept in the state directory so it can be posted as soon
+// as aa-proxy-wican starts instead of after the first scan and connect
+pub struct LastSample {
+    path: PathBuf,
+}
+
+impl LastSample {
+    pub fn new(state_dir: &Path) -> Self {
+        Self {
+            path: state_dir.join(LAST_SAMPLE_FILE),
+        }
+    }
+
+    // Write to a temporary file and rename it, so a power cut never leaves a
+    // partial sample behind
+    pub fn save(&self, sample: &BatteryData) -> Result<()> {
+        let temporary = self.path.with_extension("json.tmp");
+        std::fs::write(&temporary, serde_json::to_vec(sample)?)
+            .with_context(|| format!("Failed to write '{}'", temporary.display()))?;
+        std::fs::rename(&temporary, &self.path)
+            .with_context(|| format!("Failed to replace '{}'", self.path.display()))
+    }
+
+    // The saved sample marked as cached, unless there is none or it was read
+    // longer than max_age ago
+    pub fn load(&self, max_age: Duration) -> Result<Option<BatteryData>> {
+        if !self.path.exists() {
+            return Ok(None);
+        }
+        let contents = std::fs::read(&self.path)
+            .with_context(|| format!("Failed to read '{}'", self.path.display()))?;
+        let sample: BatteryData = serde_json::from_slice(&contents)
+            .with_context(|| format!("Invalid sample in '{}'", self.path.display()))?;
+        let Some(timestamp) = sample.timestamp else {
+            return Ok(None);
+        };
+        let age = (Utc::now() - timestamp).to_std().unwrap_or_default();
+        if age > max_age {
+            debug!(
+                "Not posting the last sample from {}, it is older than {:?}",
+                timestamp, max_age
+            );
+            return Ok(None);
+        }
+        Ok(Some(BatteryData {
+            cached: Some(true),
+            ..sample
+        }))
+    }
+}
@@ -0,0 +1,23 @@
+// WiCAN access over Bluetooth LE, for embedding in other programs. The
+// aa-proxy-wican binary builds polling, the sinks and posting to aa-proxy-rs
+// on top of it.
+
+pub mod backoff;
+pub mod bluez;
+pub mod charging;
+pub mod client;
+pub mod de;
+pub mod devices;
+pub mod discovery;
+pub mod dongle;
+pub mod link;
+pub mod metadata;
+pub mod profile;
+pub mod rpa;
+pub mod telemetry;
+pub mod throttle;
+pub mod trace;
+pub mod units;
+
+pub use client::{ClientOptions, WicanClient, WriteType};
+pub use telemetry::WicanResponse;
@@ -0,0 +1,39 @@
+use bluer::Device;
+use log::debug;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+// Signal strength of the link to the WiCAN as last reported by BlueZ, which
+// only knows the RSSI from advertisements and inquiries, so either may be
+// missing while connected
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct LinkQuality {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rssi_dbm: Option<i16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tx_power_dbm: Option<i16>,
+}
+
+impl LinkQuality {
+    pub async fn read(device: &Device) -> Self {
+        let quality = Self {
+            rssi_dbm: device.rssi().await.ok().flatten(),
+            tx_power_dbm: device.tx_power().await.ok().flatten(),
+        };
+        debug!("WiCAN link quality: {}", quality);
+        quality
+    }
+}
+
+impl fmt::Display for LinkQuality {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let format_dbm =
+            |dbm: Option<i16>| dbm.map_or("unknown".to_string(), |dbm| format!("{} dBm", dbm));
+        write!(
+            f,
+            "RSSI {}, TX power {}",
+            format_dbm(self.rssi_dbm),
+            format_dbm(self.tx_power_dbm)
+        )
+    }
+}
@@ -0,0 +1,98 @@
+use anyhow::{Context, Result};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use crate::paths;
+
+pub struct RotationOptions {
+    // Size the log may grow to before it is rotated, 0 for no limit
+    pub max_bytes: u64,
+    // Age of the log at which it is rotated whatever its size
+    pub max_age: Option<Duration>,
+    // Rotated logs kept as PATH.1 to PATH.N
+    pub max_files: usize,
+}
+
+// The log file, appended to across restarts and rotated once it grows too big
+// or too old
+pub struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    options: RotationOptions,
+    written: u64,
+    opened: SystemTime,
+    // Records are written in several pieces, and a record is never split
+    // across two files
+    at_line_start: bool,
+    // Set after a failed rotation, e.g. when the log directory isn't writable
+    // once privileges are dropped, so it isn't retried on every line
+    rotation_failed: bool,
+}
+
+impl RotatingFile {
+    pub fn open(path: &Path, options: RotationOptions) -> Result<Self> {
+        let file = open(path)
+            .with_context(|| format!("Could not start logging to file '{}'", path.display()))?;
+        let metadata = file.metadata()?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            file,
+            options,
+            written: metadata.len(),
+            opened: metadata.created().unwrap_or_else(|_| SystemTime::now()),
+            at_line_start: true,
+            rotation_failed: false,
+        })
+    }
+
+    fn due(&self, len: usize) -> bool {
+        if !self.at_line_start || self.rotation_failed || self.written == 0 {
+            return false;
+        }
+        let too_big =
+            self.options.max_bytes > 0 && self.written + len as u64 > self.options.max_bytes;
+        let too_old = self.options.max_age.is_some_and(|max_age| {
+            self.opened
+                .elapsed()
+                .is_ok_and(|elapsed| elapsed >= max_age)
+        });
+        too_big || too_old
+    }
+
+    fn rotate(&mut self) -> Result<()> {
+        paths::rotate(&self.path, self.options.max_files)?;
+        self.file = open(&self.path)
+            .with_context(|| format!("Failed to reopen '{}'", self.path.display()))?;
+        self.written = 0;
+        self.opened = SystemTime::now();
+        Ok(())
+    }
+}
+
+fn open(path: &Path) -> io::Result<File> {
+    OpenOptions::new().append(true).create(true).open(path)
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.due(buf.len()) {
+            // Logging from within the logger would deadlock
+            if let Err(e) = self.rotate() {
+                eprintln!("Not rotating the log file any more: {:#}", e);
+                self.rotation_failed = true;
+            }
+        }
+        let written = self.file.write(buf)?;
+        self.written += written as u64;
+        if written > 0 {
+            self.at_line_start = buf[written - 1] == b'\n';
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
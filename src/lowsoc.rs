@@ -0,0 +1,67 @@
+use crate::BatteryData;
+use clap::ValueEnum;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+// How far the SOC has to climb back above the threshold before another alert,
+// so readings hovering around it don't alert on every sample
+const HYSTERESIS: f32 = 1.0;
+
+// Urgency of a sample for consumers that want to surface a warning
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Priority {
+    Normal,
+    High,
+}
+
+// Flags samples below the low SOC threshold as high priority and tells when
+// the SOC first drops below it
+pub struct LowSocAlert {
+    threshold: f32,
+    low: AtomicBool,
+}
+
+impl LowSocAlert {
+    pub fn new(threshold: f32) -> Self {
+        Self {
+            threshold,
+            low: AtomicBool::new(false),
+        }
+    }
+
+    pub fn threshold(&self) -> f32 {
+        self.threshold
+    }
+
+    // Set the sample's priority, returning true when the SOC has just dropped
+    // below the threshold
+    pub fn check(&self, sample: &mut BatteryData) -> bool {
+        let Some(soc) = sample.battery_level_percentage else {
+            return false;
+        };
+        let low = soc < self.threshold;
+        sample.priority = Some(if low {
+            Priority::High
+        } else {
+            Priority::Normal
+        });
+
+        if low {
+            let started = !self.low.swap(true, Ordering::Relaxed);
+            if started {
+                warn!(
+                    "Battery at {:.1}%, below the low SOC threshold of {:.0}%.",
+                    soc, self.threshold
+                );
+            }
+            started
+        } else {
+            if soc >= self.threshold + HYSTERESIS {
+                self.low.store(false, Ordering::Relaxed);
+            }
+            false
+        }
+    }
+}
@@ -1,22 +1,177 @@
-use anyhow::{anyhow, Context, Result};
-use bluer::gatt::remote::Characteristic;
-use bluer::{
-    agent::{Agent, AgentHandle},
-    Adapter, AdapterEvent, Address, Device, Session, Uuid,
+use aa_proxy_wican::client::{find_device, try_pair, write_command, WriteType};
+use aa_proxy_wican::telemetry::{self, WicanResponse};
+use aa_proxy_wican::{
+    backoff, bluez, charging, devices, discovery, dongle, link, metadata, profile, rpa, throttle,
+    trace, units,
 };
-use clap::{Parser, ValueEnum};
-use futures_util::stream::StreamExt;
+use anyhow::{anyhow, Context, Result};
+use bluer::{Adapter, Address, Device, Uuid};
+use chrono::{DateTime, NaiveTime, Utc};
+use clap::parser::ValueSource;
+use clap::{ArgGroup, ArgMatches, CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum};
+use futures_util::stream::{Stream, StreamExt};
+use futures_util::FutureExt;
 use log::{debug, error, info, warn, LevelFilter};
-use reqwest::Client;
 use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
 use simplelog::*;
-use std::fs::File;
+use std::collections::HashSet;
+use std::ffi::OsString;
+use std::io::Read;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
 use std::time::Duration;
+use tokio::signal::unix::{signal, SignalKind};
 use tokio::time;
 
-// WiCAN UUIDs
-const WICAN_NOTIFY_UUID: Uuid = Uuid::from_u128(0x0200dec0_01ef_bc9a_5678_1234deadf0be);
-const WICAN_WRITE_UUID: Uuid = Uuid::from_u128(0x0300dec0_01ef_bc9a_5678_1234deadf0be);
+mod abrp;
+mod adaptive;
+mod admin;
+mod api;
+mod auxload;
+mod bench;
+mod can;
+mod canlog;
+mod carbon;
+mod carwake;
+mod config;
+mod control;
+mod control_socket;
+mod cost;
+mod curve;
+mod dbc;
+mod dbus_control;
+mod dedup;
+mod departure;
+mod doctor;
+mod elm327;
+mod email;
+mod events;
+mod exec;
+mod filewatch;
+mod fleet;
+mod grpc;
+mod history;
+mod homeassistant;
+mod hooks;
+mod journald;
+mod lastsample;
+mod logfile;
+mod lowsoc;
+mod merge;
+mod mock;
+mod mqtt;
+mod ovms;
+mod passthrough;
+mod paths;
+mod persistent;
+mod pid;
+mod plugin;
+mod pollrule;
+mod postgres;
+mod presence;
+mod privileges;
+mod probe;
+mod queue;
+mod raw;
+mod recorder;
+mod redis;
+mod replay;
+mod sandbox;
+mod secrets;
+mod selftest;
+mod session;
+mod shutdown;
+mod simulate;
+mod sink;
+mod socfilter;
+mod socketcan;
+mod stats;
+mod status;
+mod systemd;
+mod transport;
+mod trigger;
+mod update;
+mod vehicle;
+mod wake;
+mod websocket;
+mod wicanconfig;
+
+use abrp::{AbrpOptions, AbrpSink};
+use adaptive::AdaptiveOptions;
+use api::{ApiClient, ApiOptions, HostOverride, RateLimited};
+use auxload::DriveModel;
+use backoff::{RetryPolicy, Stage};
+use bluez::{AdapterSelector, BluezConnection, BluezSession, QuirkProfile, ScanDutyCycle};
+use canlog::CanLog;
+use carbon::{CarbonIntensity, CarbonIntensityOptions, CarbonProvider};
+use carwake::{WakeDetector, WakeOptions};
+use charging::ChargingType;
+use config::ConfigWatcher;
+use control::{Listener, CONTROL};
+use control_socket::ControlRequest;
+use cost::{CostTracker, Tariff};
+use curve::{CurveFormat, CurveRecorder};
+use dbc::{Dbc, SignalMapping};
+use departure::DeparturePlan;
+use devices::DeviceStore;
+use discovery::{DiscoveredAddress, DiscoveryOptions};
+use dongle::{Dongle, DongleKind};
+use email::{EmailAlerts, EmailOptions};
+use events::Event;
+use exec::{ExecMode, ExecSink};
+use fleet::DeviceEntry;
+use grpc::GrpcSink;
+use history::HistoryStore;
+use homeassistant::{HomeAssistantOptions, HomeAssistantSink, MqttDiscoveryOptions};
+use journald::JournaldLogger;
+use lastsample::LastSample;
+use link::LinkQuality;
+use logfile::{RotatingFile, RotationOptions};
+use lowsoc::{LowSocAlert, Priority};
+use merge::{FieldPriority, MergeOptions, SampleMerger, SampleSource};
+use metadata::SourceMetadata;
+use mock::MockSource;
+use mqtt::{MqttConnectOptions, MqttLayout, MqttSink, MqttSinkOptions, MqttVersion, TlsFiles};
+use ovms::OvmsSource;
+use passthrough::ExtraPid;
+use persistent::PersistentConnection;
+use pid::{GroupInterval, ObdPid};
+use plugin::Plugins;
+use pollrule::SocPollRule;
+use postgres::{PostgresSink, PostgresSinkOptions};
+use profile::{AutopidKey, AutopidKeys, VehicleProfile};
+use raw::{RawDecoder, RawFrames};
+use recorder::{DataRecorder, RecordFormat, RecorderOptions};
+use redis::{RedisSink, RedisSinkOptions};
+use rpa::Irk;
+use secrets::{Secret, SecretsFile, SecretsKey};
+use session::SessionMonitor;
+use shutdown::ShutdownSignals;
+use sink::{AaProxySink, OutputKind, OutputTarget, Sink};
+use socfilter::{SocFilter, SocFilterOptions};
+use socketcan::CanSocket;
+use stats::STATS;
+use throttle::ThrottleOptions;
+use transport::{CommandWriter, MqttOptions, TcpAddress, TcpOptions, Transport};
+use trigger::TriggerFile;
+use units::TemperatureUnit;
+use vehicle::{CapacityBasis, SocCurve, Vehicle};
+use wake::WakeStep;
+use websocket::WebSocketSink;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum LogOutput {
+    // The terminal and the log file
+    File,
+    // The systemd journal, with structured fields
+    Journald,
+    // The terminal alone
+    Stdout,
+}
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
 pub enum LogLevel {
@@ -41,404 +196,3973 @@ impl From<LogLevel> for LevelFilter {
     }
 }
 
-#[derive(Debug, Deserialize)]
-struct WicanResponse {
-    #[serde(alias = "SOC")]
-    soc: f32,
-    #[serde(alias = "SOC_D")]
-    soc_d: Option<f32>,
-    #[serde(alias = "TMP_A")]
-    outdoor_temperature: Option<f32>,
-}
+// Speeds below this are taken as standing still, as some vehicles report a
+// little noise while parked
+const MOVING_KMH: f32 = 1.0;
 
-#[derive(Parser, Debug, Serialize, Deserialize, Default)]
+#[derive(Parser, Debug, Clone, Serialize, Deserialize, Default)]
 pub struct BatteryData {
+    /// Battery level in percent
+    #[arg(long)]
     #[serde(skip_serializing_if = "Option::is_none")]
     pub battery_level_percentage: Option<f32>,
+    /// Battery level in wh
+    #[arg(long)]
     #[serde(skip_serializing_if = "Option::is_none")]
     pub battery_level_wh: Option<u16>,
+    /// Reference air density
+    #[arg(long)]
     #[serde(skip_serializing_if = "Option::is_none")]
     pub reference_air_density: Option<f32>,
+    /// External temperature in celsius
+    #[arg(long)]
     #[serde(skip_serializing_if = "Option::is_none")]
     pub external_temp_celsius: Option<f32>,
+    /// Whether the battery heater is warming the pack, e.g. before a fast charge
+    #[arg(long)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub battery_preconditioning: Option<bool>,
+    /// Whether a charging cable is plugged in
+    #[arg(long)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub plug_inserted: Option<bool>,
+    /// Whether the charge port door is open
+    #[arg(long)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub charge_port_open: Option<bool>,
+    /// Whether the vehicle is charging from an AC or a DC charger
+    #[arg(long)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub charging_type: Option<ChargingType>,
+    /// Whether the vehicle is charging
+    #[arg(long)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub charging: Option<bool>,
+    /// High while the SOC is below the low SOC threshold
+    #[arg(long)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub priority: Option<Priority>,
+    /// Battery power in kW as reported by the vehicle
+    #[arg(long)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub battery_power_kw: Option<f32>,
+    /// Lowest battery cell temperature in celsius
+    #[arg(long)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cell_temp_min_celsius: Option<f32>,
+    /// Highest battery cell temperature in celsius
+    #[arg(long)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cell_temp_max_celsius: Option<f32>,
+    /// Battery coolant temperature in celsius
+    #[arg(long)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub coolant_temp_celsius: Option<f32>,
+    /// Battery pack temperature in celsius
+    #[arg(long)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub battery_temp_celsius: Option<f32>,
+    /// Power drawn by the climate system, heater and heat pump in kW
+    #[arg(long)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hvac_power_kw: Option<f32>,
+    /// Vehicle speed in km/h
+    #[arg(long)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub speed_kmh: Option<f32>,
+    /// Estimated power drawn by everything but the drivetrain in kW
+    #[arg(long)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub auxiliary_load_kw: Option<f32>,
+    /// Battery capacity in wh
+    #[arg(long)]
     pub battery_capacity_wh: Option<u32>,
+    /// Battery state of health in percent
+    #[arg(long)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub state_of_health_percentage: Option<f32>,
+    /// Odometer reading in km
+    #[arg(long)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub odometer_km: Option<f32>,
+    /// Voltage of the 12V auxiliary battery
+    #[arg(long)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub aux_battery_voltage: Option<f32>,
+    /// Estimated range in km from the energy left and the configured consumption
+    #[arg(long)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub estimated_range_km: Option<f32>,
+    /// Time the sample was taken
+    #[arg(skip)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<DateTime<Utc>>,
+    /// Monotonically increasing sample number
+    #[arg(skip)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sequence: Option<u64>,
+    /// Unique key identifying the sample across retries
+    #[arg(skip)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub idempotency_key: Option<Uuid>,
+    /// Where the sample came from
+    #[arg(skip)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source: Option<SourceMetadata>,
+    /// Signal strength of the WiCAN when the sample was read
+    #[arg(skip)]
+    #[serde(flatten)]
+    pub link: LinkQuality,
+    /// Set on the sample saved before a restart, posted again at startup
+    #[arg(skip)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cached: Option<bool>,
+    /// Tag of the car the sample was read from, with several set up with --wican-device
+    #[arg(skip)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub vehicle_tag: Option<String>,
+}
+
+// Sequence numbers start at the startup time in milliseconds so they keep
+// increasing across restarts
+static SEQUENCE: AtomicU64 = AtomicU64::new(0);
+
+impl BatteryData {
+    pub fn is_moving(&self) -> bool {
+        self.speed_kmh.is_some_and(|speed| speed >= MOVING_KMH)
+    }
+
+    // Whether the car is charging, as it reports it, else by the charging type
+    // or the battery power. Vehicles report the battery power negative while
+    // charging, and while regenerating, so it only counts when standing still.
+    pub fn is_charging(&self) -> bool {
+        self.charging.unwrap_or_else(|| {
+            self.charging_type.is_some()
+                || (!self.is_moving() && self.battery_power_kw.is_some_and(|power| power < 0.0))
+        })
+    }
+
+    // The battery power going into the battery, 0 while it is discharging
+    pub fn charging_power_kw(&self) -> Option<f32> {
+        self.battery_power_kw.map(|power| (-power).max(0.0))
+    }
+
+    // Mark a new sample with its time, sequence number and idempotency key
+    pub fn stamp(self) -> Self {
+        Self {
+            timestamp: Some(Utc::now()),
+            idempotency_key: Some(Uuid::new_v4()),
+            ..self.resequence()
+        }
+    }
+
+    // Give a sample sent again, such as a replayed one, the next sequence
+    // number while keeping the time it was read
+    pub fn resequence(self) -> Self {
+        let _ = SEQUENCE.compare_exchange(
+            0,
+            Utc::now().timestamp_millis() as u64,
+            Ordering::Relaxed,
+            Ordering::Relaxed,
+        );
+        Self {
+            sequence: Some(SEQUENCE.fetch_add(1, Ordering::Relaxed) + 1),
+            ..self
+        }
+    }
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Post synthetic battery data to aa-proxy-rs without using Bluetooth
+    TestPost(Box<BatteryData>),
+    /// Connect to the WiCAN and print its GATT services, characteristics and descriptors
+    Probe,
+    /// Connect to the WiCAN, send raw commands such as ATRV or 22B002 over its write characteristic and print the replies, e.g. to find manufacturer specific PIDs
+    SendCommand {
+        /// Commands sent in turn, without the line ending
+        #[arg(required = true)]
+        commands: Vec<String>,
+    },
+    /// Read or replace the WiCAN's AutoPID configuration over Bluetooth, or reboot it, without its web interface
+    WicanConfig {
+        #[command(subcommand)]
+        command: WicanConfigCommand,
+        /// Command the WiCAN answers with its AutoPID configuration
+        #[arg(long, default_value = wicanconfig::GET_COMMAND)]
+        get_command: String,
+        /// Command storing the AutoPID configuration that follows it
+        #[arg(long, default_value = wicanconfig::SET_COMMAND)]
+        set_command: String,
+        /// Command restarting the WiCAN
+        #[arg(long, default_value = wicanconfig::REBOOT_COMMAND)]
+        reboot_command: String,
+    },
+    /// Check BlueZ, the Bluetooth adapter, permissions, writable paths and aa-proxy-rs, printing fixes for any problems
+    Doctor,
+    /// Send a test email alert with the --smtp-url and --email-* settings
+    TestEmail,
+    /// Run every stage once, from the Bluetooth adapter to posting to aa-proxy-rs, and print a pass/fail report
+    SelfTest,
+    /// Re-post samples recorded as JSON lines, e.g. a history export, to aa-proxy-rs
+    Replay {
+        /// File with one battery data JSON object per line
+        file: PathBuf,
+        /// Playback speed relative to the recorded timing, 0 posts without waiting
+        #[arg(long, default_value_t = 1.0)]
+        speed: f64,
+    },
+    /// Import SOC history from a CSV file, e.g. exported from a phone OBD app, into the history store
+    ImportCsv {
+        /// CSV file with a header row
+        file: PathBuf,
+        /// Column holding the time of each reading
+        #[arg(long, default_value = "timestamp")]
+        timestamp_column: String,
+        /// Format of the timestamps, "unix" for seconds since the epoch or a chrono format such as "%Y-%m-%d %H:%M:%S" in local time [default: RFC 3339]
+        #[arg(long)]
+        timestamp_format: Option<String>,
+        /// Column holding the state of charge in percent
+        #[arg(long, default_value = "soc")]
+        soc_column: String,
+        /// Column holding the outdoor temperature in Celsius
+        #[arg(long)]
+        temperature_column: Option<String>,
+    },
+    /// List the recorded charging curves, or print the curve of one session
+    ChargingCurves {
+        /// Session to print, as listed
+        session: Option<String>,
+        /// Output format of the curve
+        #[arg(long, value_enum, default_value_t = CurveFormat::Csv)]
+        format: CurveFormat,
+    },
+    /// List the charging sessions recorded with --tariff or --carbon-intensity-region, with the energy added, its cost and carbon intensity
+    ChargingSessions,
+    /// Advertise a fake WiCAN from the local adapter and serve autopid responses, for developing without a car
+    Simulate {
+        /// Autopid JSON response to serve, may be repeated to cycle through several
+        #[arg(long)]
+        response: Vec<String>,
+        /// File with one autopid JSON response per line, served in turn after any --response
+        #[arg(long)]
+        script: Option<PathBuf>,
+        /// Seconds between unsolicited broadcasts to subscribed clients, 0 only answers autopid requests
+        #[arg(long, default_value_t = 0)]
+        broadcast_seconds: u16,
+        /// Name to advertise
+        #[arg(long, default_value = "WiCAN")]
+        name: String,
+    },
+    /// Measure scan time, connect time and autopid round-trip latency over several iterations
+    Bench {
+        /// Number of disconnect, scan, connect and fetch cycles
+        #[arg(long, default_value_t = 10)]
+        iterations: u16,
+    },
+    /// Encrypt a secret with the secrets key, printing a value usable in place of the plaintext
+    EncryptSecret {
+        /// Secret to encrypt, read from stdin when omitted
+        value: Option<String>,
+    },
+    /// Generate a new random secrets key
+    GenerateSecretsKey,
+    /// List the devices seen before, or change what is remembered about one
+    Devices {
+        #[command(subcommand)]
+        command: Option<DevicesCommand>,
+    },
+    /// Print the state of the running service, read over its control socket
+    Status,
+    /// Make the running service fetch and post battery data now
+    FetchNow,
+    /// Make the running service disconnect from the WiCAN and connect again
+    Reconnect,
+    /// Change how often the running service fetches battery data, until it restarts
+    SetInterval {
+        /// Minutes between updates
+        #[arg(value_parser = clap::value_parser!(u8).range(1..))]
+        minutes: u8,
+    },
+    /// Config file utilities
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommand,
+    },
+    /// Replace this binary with the latest release for the platform, after verifying its checksum and signature
+    SelfUpdate {
+        /// Only report whether a newer release is available
+        #[arg(long)]
+        check: bool,
+        /// GitHub API url of the release to update to
+        #[arg(long, default_value = update::RELEASES_URL)]
+        releases_url: String,
+        /// Base64 Ed25519 public key the release checksums must be signed with [default: the key pinned at build time]
+        #[arg(long, env = "AA_PROXY_WICAN_UPDATE_PUBLIC_KEY")]
+        update_public_key: Option<String>,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum DevicesCommand {
+    /// Set the alias or the vehicle profile sent in the source metadata of a device
+    Set {
+        address: Address,
+        /// Name to show for the device
+        #[arg(long)]
+        alias: Option<String>,
+        /// Vehicle profile the device reads
+        #[arg(long)]
+        vehicle_profile: Option<String>,
+    },
+    /// Forget everything remembered about a device
+    Forget { address: Address },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum WicanConfigCommand {
+    /// Print the AutoPID configuration, or save it to a file
+    Pull {
+        /// File to save the configuration to, printed when omitted
+        file: Option<PathBuf>,
+    },
+    /// Upload an AutoPID configuration from a JSON file
+    Push {
+        /// JSON file holding the configuration, e.g. one saved by pull and edited
+        file: PathBuf,
+        /// Reboot the WiCAN afterwards so the configuration takes effect
+        #[arg(long)]
+        reboot: bool,
+    },
+    /// Restart the WiCAN
+    Reboot,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum ConfigCommand {
+    /// Print a JSON Schema of the config file format, for editor completion and validation
+    Schema,
 }
 
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
+#[command(subcommand_negates_reqs = true)]
+#[command(group(ArgGroup::new("raw_frame_output").args(["dbc_file", "can_log_dir", "can_bridge_interface"]).multiple(true)))]
 pub struct Configuration {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
     /// Vehicle Battery Capacity in wh
-    #[arg(short, long)]
-    pub vehicle_battery_capacity: u32,
+    #[arg(short, long, required_unless_present = "wican_device")]
+    pub vehicle_battery_capacity: Option<u32>,
+
+    /// Capacity the energy left is worked out from, the battery capacity reduced by the measured state of health (SOH) while the vehicle reports one, or the nominal capacity as configured
+    #[arg(long, value_enum, default_value_t = CapacityBasis::Adjusted)]
+    pub vehicle_capacity_basis: CapacityBasis,
+
+    /// Share of the battery capacity in percent that can be used, for vehicles whose --vehicle-battery-capacity is the gross capacity including the buffers the BMS keeps
+    #[arg(long, default_value_t = 100, value_parser = clap::value_parser!(u8).range(1..=100))]
+    pub vehicle_usable_capacity_percentage: u8,
+
+    /// Average consumption in Wh/km, the energy left is divided by it to estimate the range
+    #[arg(long)]
+    pub vehicle_consumption_wh_per_km: Option<f32>,
+
+    /// Mapping from raw SOC to displayed SOC as raw:displayed points, used when SOC_D is unavailable, e.g. 0:0,5:0,97:100,100:100
+    #[arg(long)]
+    pub soc_display_curve: Option<SocCurve>,
+
+    /// Largest change in SOC percentage points from the previous reading that is taken as real, larger changes are dropped as glitches
+    #[arg(long)]
+    pub soc_max_delta: Option<f32>,
+
+    /// Readings in a row beyond --soc-max-delta that are dropped before the change is taken as real
+    #[arg(long, default_value_t = 2, requires = "soc_max_delta")]
+    pub soc_max_rejections: u32,
+
+    /// Smooth the SOC with an exponential moving average, giving each new reading this weight, above 0 and at most 1, e.g. 0.5
+    #[arg(long, value_parser = socfilter::parse_smoothing)]
+    pub soc_smoothing: Option<f32>,
+
+    /// Highest AC charging power of the vehicle's onboard charger in kW, faster charging is taken as DC when the vehicle doesn't report CHG_TYPE
+    #[arg(long, default_value_t = 11.0)]
+    pub vehicle_max_ac_charging_kw: f32,
+
+    /// Vehicle mass in kg including the driver, used to estimate the auxiliary load
+    #[arg(long, default_value_t = 2100.0)]
+    pub vehicle_mass_kg: f32,
+
+    /// Drag coefficient times frontal area in m², used to estimate the auxiliary load
+    #[arg(long, default_value_t = 0.75)]
+    pub vehicle_drag_area: f32,
+
+    /// Rolling resistance coefficient of the tyres, used to estimate the auxiliary load
+    #[arg(long, default_value_t = 0.011)]
+    pub vehicle_rolling_resistance: f32,
+
+    /// Share of the battery power reaching the wheels, used to estimate the auxiliary load
+    #[arg(long, default_value_t = 0.9)]
+    pub vehicle_drivetrain_efficiency: f32,
+
+    /// Built-in mapping of the keys the autopid profile of this vehicle reports its fields under
+    #[arg(long, value_enum, default_value_t = VehicleProfile::Generic)]
+    pub vehicle_profile: VehicleProfile,
+
+    /// Keys an autopid field is read from as FIELD=KEY[:KEY...], e.g. SOC=StateOfCharge, replacing the vehicle profile's keys for that field, may be repeated
+    #[arg(long)]
+    pub autopid_key: Vec<AutopidKey>,
+
+    /// Unit of the temperatures reported by the WiCAN autopid profile
+    #[arg(long, value_enum, default_value_t = TemperatureUnit::Celsius)]
+    pub wican_temperature_unit: TemperatureUnit,
+
+    /// Unit used for temperatures in logs and other local outputs
+    #[arg(long, global = true, value_enum, default_value_t = TemperatureUnit::Celsius)]
+    pub display_temperature_unit: TemperatureUnit,
 
     /// WiCAN MAC address
-    #[arg(short, long)]
-    pub wican_mac_address: Address,
+    #[arg(short, long, required_unless_present_any = ["ovms_url", "ovms_mqtt_url", "wican_host", "wican_mqtt_url", "wican_discover", "simulate", "wican_device"])]
+    pub wican_mac_address: Option<Address>,
+
+    /// WiCAN of one of several cars, read from whichever is in range by trying each in turn, as MAC[,passkey=N][,capacity=WH][,profile=NAME][,api-url=URL][,tag=NAME] with the settings left out taken from the other options, may be repeated instead of --wican-mac-address
+    #[arg(long, conflicts_with = "wican_mac_address")]
+    pub wican_device: Vec<DeviceEntry>,
+
+    /// Without --wican-mac-address, scan for a WiCAN advertising its service or named with --wican-name-prefix
+    #[arg(long, default_value_t = false)]
+    pub wican_discover: bool,
+
+    /// Start of the Bluetooth name of a WiCAN to discover, empty to only look for its service
+    #[arg(long, default_value = "WiC_")]
+    pub wican_name_prefix: String,
+
+    /// Save the address of a discovered WiCAN to the state directory and use it on later runs instead of scanning
+    #[arg(long, default_value_t = false, requires = "wican_discover")]
+    pub wican_remember_discovered: bool,
+
+    /// How the WiCAN is reached, over Bluetooth LE, the TCP port it serves over Wi-Fi or the MQTT broker it publishes to
+    #[arg(long, value_enum, default_value_t = Transport::Ble)]
+    pub transport: Transport,
+
+    /// Feed synthetic autopid responses through the outputs instead of reading a WiCAN, from a fixture file of JSON objects or a SOC curve written curve:FROM-TO[/STEP] [default: curve:80-20/1]
+    #[arg(long, num_args = 0..=1, default_missing_value = "curve", value_name = "FIXTURE|CURVE")]
+    pub simulate: Option<MockSource>,
+
+    /// Address of the WiCAN for --transport tcp, as HOST[:PORT], e.g. 192.168.80.1 [default port: 3333]
+    #[arg(long, required_if_eq("transport", "tcp"))]
+    pub wican_host: Option<TcpAddress>,
+
+    /// MQTT broker the WiCAN publishes its AutoPID data to, for --transport mqtt, e.g. mqtt://broker.local:1883
+    #[arg(long, required_if_eq("transport", "mqtt"))]
+    pub wican_mqtt_url: Option<String>,
+
+    /// Topic the WiCAN publishes AutoPID JSON on, + and # matching any level
+    #[arg(long, required_if_eq("transport", "mqtt"))]
+    pub wican_mqtt_topic: Option<String>,
+
+    /// Username for the broker the WiCAN publishes to
+    #[arg(long)]
+    pub wican_mqtt_username: Option<String>,
+
+    /// Password for the broker the WiCAN publishes to, may be encrypted
+    #[arg(
+        long,
+        env = "AA_PROXY_WICAN_WICAN_MQTT_PASSWORD",
+        hide_env_values = true
+    )]
+    pub wican_mqtt_password: Option<Secret>,
+
+    /// Identity resolving key of a WiCAN using resolvable private addresses, as 32 hex digits in the order BlueZ stores them [default: read from the BlueZ bond]
+    #[arg(long, value_parser = Irk::from_hex)]
+    pub wican_irk: Option<Irk>,
+
+    /// WiCAN passkey, may be encrypted
+    #[arg(
+        long,
+        default_value = "123456",
+        env = "AA_PROXY_WICAN_WICAN_PASSKEY",
+        hide_env_values = true
+    )]
+    pub wican_passkey: Secret,
+
+    /// File holding the WiCAN passkey, so it doesn't show up in the process list or shell history
+    #[arg(long, conflicts_with = "wican_passkey")]
+    pub wican_passkey_file: Option<PathBuf>,
+
+    /// Pair even if the device does not advertise the WiCAN service
+    #[arg(long, default_value_t = false)]
+    pub wican_skip_service_check: bool,
+
+    /// Workarounds for the installed BlueZ release, detected from the bluetoothd version by default
+    #[arg(long, value_enum, default_value_t = QuirkProfile::Auto)]
+    pub bluez_quirks: QuirkProfile,
+
+    /// Power off the Bluetooth adapter when stopped by SIGTERM or SIGINT, after disconnecting the WiCAN. It's powered on again at startup.
+    #[arg(long, default_value_t = false)]
+    pub power_off_adapter_on_shutdown: bool,
+
+    /// Bluetooth adapter to use, by name, e.g. hci1, or address [default: the first adapter]
+    #[arg(long, global = true)]
+    pub bluetooth_adapter: Option<AdapterSelector>,
+
+    /// Power-cycle the Bluetooth adapter after this many discovery or connection failures in a row that point at the adapter or bluetoothd rather than the WiCAN, 0 to never
+    #[arg(long, default_value_t = 3)]
+    pub bluetooth_adapter_recovery_failures: u32,
+
+    /// Dongle hardware, detected from its advertised services and firmware version by default
+    #[arg(long, value_enum, default_value_t = DongleKind::Auto)]
+    pub dongle: DongleKind,
+
+    /// PID read for the SOC from ELM327 dongles, as [HEADER:]REQUEST:FORMULA
+    #[arg(long, default_value = "015B:A*100/255")]
+    pub obd_soc_pid: ObdPid,
+
+    /// PID read for the outdoor temperature from ELM327 dongles, as [HEADER:]REQUEST:FORMULA, e.g. 0146:A-40
+    #[arg(long)]
+    pub obd_temperature_pid: Option<ObdPid>,
+
+    /// PID read for the lowest battery cell temperature from ELM327 dongles, as [HEADER:]REQUEST:FORMULA
+    #[arg(long)]
+    pub obd_cell_temperature_min_pid: Option<ObdPid>,
+
+    /// PID read for the highest battery cell temperature from ELM327 dongles, as [HEADER:]REQUEST:FORMULA
+    #[arg(long)]
+    pub obd_cell_temperature_max_pid: Option<ObdPid>,
+
+    /// PID read for the battery coolant temperature from ELM327 dongles, as [HEADER:]REQUEST:FORMULA
+    #[arg(long)]
+    pub obd_coolant_temperature_pid: Option<ObdPid>,
+
+    /// PID read for the climate system power draw in kW from ELM327 dongles, as [HEADER:]REQUEST:FORMULA
+    #[arg(long)]
+    pub obd_hvac_power_pid: Option<ObdPid>,
 
-    /// WiCAN passkey
-    #[arg(long, default_value_t = 123456)]
-    pub wican_passkey: u32,
+    /// PID read for the vehicle speed in km/h from ELM327 dongles, as [HEADER:]REQUEST:FORMULA, e.g. 010D:A
+    #[arg(long)]
+    pub obd_speed_pid: Option<ObdPid>,
 
-    /// WiCAN retries
-    #[arg(long, default_value_t = 5)]
-    pub wican_max_connect_retries: u8,
+    /// PID read for the battery state of health in percent from ELM327 dongles, as [HEADER:]REQUEST:FORMULA
+    #[arg(long)]
+    pub obd_soh_pid: Option<ObdPid>,
+
+    /// Seconds between reads of a group of PIDs from ELM327 dongles, reusing the last values in between so slow reads don't hold up the SOC, as GROUP=SECONDS with the groups temperatures, climate, speed and health, e.g. temperatures=300,health=3600
+    #[arg(long, value_delimiter = ',')]
+    pub obd_group_interval: Vec<GroupInterval>,
+
+    /// Web address of an OVMS module to read battery data from, instead of or alongside a WiCAN, e.g. http://192.168.4.1
+    #[arg(long, conflicts_with = "ovms_mqtt_url")]
+    pub ovms_url: Option<String>,
+
+    /// OVMS module password, may be encrypted
+    #[arg(long, env = "AA_PROXY_WICAN_OVMS_PASSWORD", hide_env_values = true)]
+    pub ovms_password: Option<Secret>,
+
+    /// MQTT broker an OVMS module publishes its metrics to, read instead of or alongside a WiCAN, e.g. mqtt://broker.local:1883
+    #[arg(long)]
+    pub ovms_mqtt_url: Option<String>,
+
+    /// Username for the OVMS MQTT broker
+    #[arg(long)]
+    pub ovms_mqtt_username: Option<String>,
+
+    /// Password for the OVMS MQTT broker, may be encrypted
+    #[arg(
+        long,
+        env = "AA_PROXY_WICAN_OVMS_MQTT_PASSWORD",
+        hide_env_values = true
+    )]
+    pub ovms_mqtt_password: Option<Secret>,
+
+    /// Topic prefix the OVMS module publishes below, + matching any username or vehicle id
+    #[arg(long, default_value = "ovms/+/+")]
+    pub ovms_mqtt_topic_prefix: String,
+
+    /// Sources in order of preference for every field, when reading from both a WiCAN and an OVMS module
+    #[arg(long, value_delimiter = ',', default_value = "wican,ovms")]
+    pub merge_priority: Vec<SampleSource>,
+
+    /// Sources in order of preference for one field, overriding --merge-priority, as FIELD=SOURCE[:SOURCE...], e.g. external_temp_celsius=ovms:wican, may be repeated
+    #[arg(long)]
+    pub merge_field_priority: Vec<FieldPriority>,
+
+    /// Seconds the values of one source are still preferred after its last sample, when reading from several sources
+    #[arg(long, default_value_t = 900)]
+    pub merge_max_age_seconds: u32,
+
+    /// Attempts at connecting to the WiCAN each poll
+    #[arg(long, alias = "wican-max-connect-retries", default_value_t = 5)]
+    pub wican_max_connect_attempts: u8,
+
+    /// Scans for the WiCAN each poll, each lasting up to --wican-timeout
+    #[arg(long, default_value_t = 1)]
+    pub wican_max_discovery_attempts: u8,
+
+    /// Attempts at pairing with the WiCAN each poll
+    #[arg(long, default_value_t = 3)]
+    pub wican_max_pairing_attempts: u8,
+
+    /// Attempts at reading a sample each poll once connected
+    #[arg(long, default_value_t = 3)]
+    pub wican_max_gatt_attempts: u8,
+
+    /// Seconds before the first retry, doubling with each further retry
+    #[arg(long, default_value_t = 2)]
+    pub wican_retry_initial_delay_seconds: u16,
+
+    /// Longest wait in seconds between retries
+    #[arg(long, default_value_t = 60)]
+    pub wican_retry_max_delay_seconds: u16,
 
     /// WiCAN timeout
     #[arg(long, default_value_t = 10)]
     pub wican_timeout: u8,
 
+    /// Seconds each discovery scan runs for before pausing, 0 scans continuously until the WiCAN timeout
+    #[arg(long, default_value_t = 3)]
+    pub wican_scan_window_seconds: u8,
+
+    /// Seconds to pause between discovery scans
+    #[arg(long, default_value_t = 2)]
+    pub wican_scan_gap_seconds: u8,
+
+    /// Listen for the WiCAN's advertisements before each connection and only connect while it is heard, reporting the vehicle as away otherwise
+    #[arg(long, default_value_t = false)]
+    pub wican_presence: bool,
+
+    /// Seconds to listen for the WiCAN's advertisements with --wican-presence
+    #[arg(long, default_value_t = 10)]
+    pub wican_presence_window_seconds: u8,
+
+    /// Seconds to wait for the WiCAN to respond to an autopid request [default: WiCAN timeout]
+    #[arg(long)]
+    pub wican_response_timeout: Option<u8>,
+
     /// WiCAN update frequency in minutes
     #[arg(long, default_value_t = 1)]
     pub wican_update_frequency_minutes: u8,
 
-    /// aa-proxy-rs url
-    #[arg(long, default_value = "http://localhost/battery")]
-    pub api_url: String,
+    /// Write type used when sending commands to the WiCAN, auto selects from the characteristic properties
+    #[arg(long, value_enum, default_value_t = WriteType::Auto)]
+    pub wican_write_type: WriteType,
 
-    /// Log file
-    #[arg(long, default_value = "/var/log/aa-proxy-wican.log")]
-    pub log_file: String,
+    /// Command sent before each request to wake vehicles whose ECUs ignore the first request after sleeping, as COMMAND[@MILLISECONDS] to wait after it, e.g. ATZ@1000, may be repeated
+    #[arg(long)]
+    pub wican_wake_command: Vec<WakeStep>,
 
-    /// Log level
-    #[arg(long, value_enum, default_value_t = LogLevel::Info)]
-    pub log_level: LogLevel,
-}
+    /// Manufacturer specific PID read after each autopid request, its value merged into the autopid response under NAME before the key mappings and unit conversion, as NAME=[HEADER:]REQUEST:FORMULA, e.g. SOH=7E4:220105:AF/2, may be repeated
+    #[arg(long)]
+    pub wican_extra_pid: Vec<ExtraPid>,
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    // Parse the command line
-    let configuration = Configuration::parse();
+    /// Stay subscribed and post every autopid frame the WiCAN broadcasts instead of requesting data
+    #[arg(long, default_value_t = false)]
+    pub wican_streaming: bool,
 
-    // Set log level from command line
-    let log_level = LevelFilter::from(configuration.log_level);
+    /// Keep the WiCAN connected and subscribed to notifications between polls, reconnecting only when BlueZ reports it disconnected
+    #[arg(long, default_value_t = false, conflicts_with_all = ["wican_streaming", "wican_raw_frames"])]
+    pub wican_persistent_connection: bool,
 
-    // Confirm we can write to the log file
-    let log_file_result = File::create(&configuration.log_file);
-    let log_file = match log_file_result {
-        Ok(file) => file,
-        Err(e) => {
-            return Err(anyhow!(
-                "Could not start logging to file '{}': {}",
-                configuration.log_file,
-                e
-            ));
-        }
-    };
+    /// Update straight away when the 12V battery, read with ATRV between polls, rises to this voltage, as it does when the car is switched on or starts charging, e.g. 13.2
+    #[arg(long, requires = "wican_persistent_connection")]
+    pub wican_wake_voltage: Option<f32>,
 
-    // Create a logger configuration
-    let log_config = ConfigBuilder::new()
-        .set_target_level(LevelFilter::Info)
-        .set_target_level(LevelFilter::Error)
-        .set_location_level(LevelFilter::Error)
-        .set_time_level(LevelFilter::Error)
-        .set_level_padding(LevelPadding::Right)
-        .set_time_offset_to_local()
-        .expect("Failed to set local time offset")
-        .build();
+    /// Seconds between reads of the 12V battery voltage with --wican-wake-voltage
+    #[arg(long, default_value_t = 15)]
+    pub wican_wake_voltage_interval_seconds: u16,
 
-    // Initialize the logger.
-    match CombinedLogger::init(vec![
-        TermLogger::new(
-            log_level,
-            log_config.clone(),
-            TerminalMode::Mixed,
-            ColorChoice::Auto,
-        ),
-        WriteLogger::new(log_level, log_config.clone(), log_file),
-    ]) {
-        Ok(_) => {}
-        Err(e) => {
-            return Err(anyhow!("Could not initialize combined logger: {}", e));
-        }
-    }
+    /// Update straight away when a notification from the WiCAN between polls contains TEXT, e.g. its wake-up message, may be repeated
+    #[arg(long, value_name = "TEXT", requires = "wican_persistent_connection")]
+    pub wican_wake_pattern: Vec<String>,
 
-    info!(
-        "WiCAN Client starting. Update frequency is {} minute(s).",
-        configuration.wican_update_frequency_minutes
-    );
+    /// The WiCAN is in SLCAN mode and sends raw CAN frames, used by --dbc-file, --can-log-dir and --can-bridge-interface
+    #[arg(long, default_value_t = false, requires = "raw_frame_output")]
+    pub wican_raw_frames: bool,
 
-    let mut first_run = true;
-    loop {
-        if !first_run {
-            info!(
-                "Sleeping for {} minute(s) before next update...",
-                configuration.wican_update_frequency_minutes
-            );
-            time::sleep(Duration::from_secs(
-                (configuration.wican_update_frequency_minutes as u64) * 60,
-            ))
-            .await;
-        }
-        first_run = false;
+    /// DBC file describing the signals in raw CAN frames
+    #[arg(long)]
+    pub dbc_file: Option<PathBuf>,
 
-        let wican_timeout = Duration::from_secs(configuration.wican_timeout as u64);
-        let session = Session::new().await?;
-        let adapter = session.default_adapter().await?;
+    /// Autopid field fed from a decoded DBC signal as FIELD=SIGNAL, e.g. SOC=BMS_SOC, may be repeated
+    #[arg(long)]
+    pub dbc_signal: Vec<SignalMapping>,
 
-        let device = match connect_to_device(
-            session,
-            adapter,
-            configuration.wican_mac_address,
-            configuration.wican_passkey,
-            wican_timeout,
-            configuration.wican_max_connect_retries,
-        )
-        .await
-        {
-            Ok(d) => d,
-            Err(e) => {
-                error!("Failed to connect to device: {}. Will retry...", e);
-                continue;
-            }
-        };
+    /// Directory raw CAN frames are logged to in candump format, e.g. for SavvyCAN
+    #[arg(long)]
+    pub can_log_dir: Option<PathBuf>,
 
-        if let Some(battery_data) = match fetch_data(
-            &device,
-            configuration.vehicle_battery_capacity,
-            wican_timeout,
-        )
-        .await
-        {
-            Ok(data) => data,
-            Err(e) => {
-                error!("Failed to fetch data from device: {}. Will retry...", e);
-                continue;
-            }
-        } {
-            if let Err(e) = post_battery_data(&configuration.api_url, &battery_data).await {
-                error!("Failed to post battery data: {}. Will retry...", e);
-            }
-        }
-    }
-}
+    /// Size in MiB at which a new CAN log file is started, 0 for no limit
+    #[arg(long, default_value_t = 10)]
+    pub can_log_max_size_mb: u64,
 
-// Finds the target Bluetooth device by its MAC address during a discovery scan.
-async fn find_device(
-    adapter: &Adapter,
-    wican_mac_address: Address,
-    wican_timeout: Duration,
-) -> Result<Device> {
-    if adapter
-        .device(wican_mac_address)?
-        .is_services_resolved()
-        .await
-        .is_ok()
-    {
-        info!("Device {} is known and available.", wican_mac_address);
-        return Ok(adapter.device(wican_mac_address)?);
-    }
+    /// Number of CAN log files kept, the oldest are removed, 0 to keep all
+    #[arg(long, default_value_t = 10)]
+    pub can_log_max_files: usize,
 
-    info!(
-        "Starting device discovery to find {} for a maximum of {:?}",
-        wican_mac_address, wican_timeout
-    );
-    let mut device_events = adapter.discover_devices().await?;
+    /// Linux CAN interface, e.g. vcan0, raw CAN frames are injected into for socketcan tools
+    #[arg(long)]
+    pub can_bridge_interface: Option<String>,
 
-    match tokio::time::timeout(wican_timeout, async {
-        loop {
-            if let Some(AdapterEvent::DeviceAdded(addr)) = device_events.next().await {
-                if addr == wican_mac_address {
-                    info!("Found device with address: {}", addr);
-                    break Ok(adapter.device(addr)?);
-                }
-            }
-        }
-    })
-    .await
-    {
-        Ok(result) => result,
-        Err(_) => Err(anyhow!("Scan timed out without finding device.")),
-    }
-}
+    /// Send frames written to --can-bridge-interface by other programs to the bus through the WiCAN
+    #[arg(long, default_value_t = false, requires = "can_bridge_interface")]
+    pub can_bridge_forward_writes: bool,
 
-// Attempts to pair with the device if it is not already paired.
-async fn try_pair(session: &Session, device: &Device, wican_passkey: u32) -> Result<()> {
-    if device.is_paired().await? {
-        info!("Device is already paired. Skipping pairing.");
-        return Ok(());
-    }
+    /// Interval in seconds between keep-alive writes to the WiCAN while waiting for the next update, on a persistent connection while the car is charging, 0 to disable
+    #[arg(long, default_value_t = 0)]
+    pub wican_keep_alive_seconds: u16,
 
-    let agent = Agent {
-        request_default: true,
-        request_passkey: Some(Box::new(move |_path| {
-            Box::pin(async move {
-                info!(
-                    "A device requested a passkey code. We're providing '{}'.",
-                    wican_passkey
-                );
-                Ok(wican_passkey)
-            })
-        })),
-        ..Default::default()
-    };
-    let _agent_handle: AgentHandle = session.register_agent(agent).await?;
+    /// Command written to the WiCAN as a keep-alive
+    #[arg(long, default_value = "")]
+    pub wican_keep_alive_command: String,
 
-    info!("Attempting to pair with device...");
-    device.pair().await.context("Failed to pair with device")?;
+    /// Least time in milliseconds between writes to the WiCAN, for firmware that drops commands sent in quick succession
+    #[arg(long, default_value_t = 0)]
+    pub wican_write_spacing_ms: u16,
 
-    info!("Pairing successful!");
-    Ok(())
-}
+    /// Most writes to the WiCAN per poll, counting wake commands, PID queries and the keep-alives until the next poll
+    #[arg(long, value_parser = clap::value_parser!(u16).range(1..))]
+    pub wican_write_budget: Option<u16>,
 
-// Connects to wican device
-async fn connect_to_device(
-    session: Session,
-    adapter: Adapter,
-    wican_mac_address: Address,
-    wican_passkey: u32,
-    wican_timeout: Duration,
-    max_retries: u8,
-) -> Result<Device> {
-    let device = find_device(&adapter, wican_mac_address, wican_timeout).await?;
+    /// WiCAN update frequency in minutes while no Android Auto session is active, 0 to pause updates
+    #[arg(long, default_value_t = 0)]
+    pub idle_update_frequency_minutes: u8,
 
-    try_pair(&session, &device, wican_passkey).await?;
+    /// aa-proxy-rs url, may be repeated to fall back to the next one when a url can't be reached, e.g. localhost first and then the head unit's LAN address
+    #[arg(long, global = true, default_value = "http://localhost/battery")]
+    pub api_url: Vec<String>,
 
-    if device.is_connected().await? {
-        info!("Device is already connected. Skipping connection.");
-        return Ok(device);
-    }
+    /// Include the sample timestamp in the payload sent to aa-proxy-rs
+    #[arg(long, global = true, default_value_t = false)]
+    pub api_send_timestamp: bool,
 
-    for i in 0..max_retries {
-        info!(
-            "Connecting to device... (Attempt {}/{})",
-            i + 1,
-            max_retries
-        );
-        match device.connect().await {
-            Ok(_) => {
-                info!("Connected successfully!");
-                break;
-            }
-            Err(e) => {
-                if i + 1 < max_retries {
-                    warn!("Connection failed: {}.  Retrying in 10 seconds...", e);
-                    time::sleep(Duration::from_secs(10)).await;
-                } else {
-                    warn!("Connection failed the maximum number of times: {}.  Will remove pairing and retry...", e);
-                    adapter
-                        .remove_device(device.address())
-                        .await
-                        .context("Failed to remove pairing")?;
-                    return Err(anyhow!(
-                        "Failed to connect to the device after {} attempts.",
-                        max_retries
-                    ));
-                }
-            }
-        }
-    }
+    /// Include a sequence number and idempotency key with each sample, also sent as an Idempotency-Key header
+    #[arg(long, global = true, default_value_t = false)]
+    pub api_send_idempotency: bool,
 
-    Ok(device)
-}
+    /// Include the RSSI and TX power of the WiCAN link, as rssi_dbm and tx_power_dbm, in the payload sent to aa-proxy-rs
+    #[arg(long, global = true, default_value_t = false)]
+    pub api_send_link_quality: bool,
 
-// Find the device characteristics using the provided UUID's
-async fn find_characteristics(device: &Device) -> Result<(Characteristic, Characteristic)> {
-    let services = device.services().await?;
-    let mut notify_char_opt: Option<Characteristic> = None;
-    let mut write_char_opt: Option<Characteristic> = None;
+    /// Field left out of the payload sent to aa-proxy-rs, e.g. external_temp_celsius when the vehicle reports a bogus value, may be repeated or comma separated
+    #[arg(long, global = true, value_delimiter = ',', value_parser = api::parse_payload_field)]
+    pub api_omit_field: Vec<String>,
 
-    for service in services {
-        let characteristics = service.characteristics().await?;
-        for characteristic in characteristics {
-            let uuid = characteristic.uuid().await?;
-            if uuid == WICAN_NOTIFY_UUID {
-                notify_char_opt = Some(characteristic);
-            } else if uuid == WICAN_WRITE_UUID {
-                write_char_opt = Some(characteristic);
-            }
-        }
-    }
+    /// Read the current battery state from aa-proxy-rs first and skip posting if it is newer than our sample
+    #[arg(long, global = true, default_value_t = false)]
+    pub api_conditional_update: bool,
 
-    let notify_char = notify_char_opt
-        .ok_or_else(|| anyhow!("Could not find the WiCAN notify characteristic."))?;
-    let write_char =
-        write_char_opt.ok_or_else(|| anyhow!("Could not find the WiCAN write characteristic."))?;
+    /// Seconds to wait for a connection to aa-proxy-rs
+    #[arg(long, global = true, default_value_t = 5)]
+    pub api_connect_timeout_seconds: u16,
 
-    Ok((notify_char, write_char))
-}
+    /// Seconds to wait for aa-proxy-rs to respond
+    #[arg(long, global = true, default_value_t = 10)]
+    pub api_read_timeout_seconds: u16,
 
-// Submit autopid request and parse as JSON
-async fn fetch_data(
-    device: &Device,
-    vehicle_battery_capacity: u32,
-    wican_timeout: Duration,
-) -> Result<Option<BatteryData>> {
-    let (notify_char, write_char) = find_characteristics(device)
-        .await
-        .context("Failed to find WiCAN characteristics")?;
+    /// User-Agent sent to aa-proxy-rs
+    #[arg(long, global = true, default_value = concat!("aa-proxy-wican/", env!("CARGO_PKG_VERSION")))]
+    pub api_user_agent: String,
 
-    let mut notif_stream = Box::pin(notify_char.notify().await?);
-    write_char.write(b"autopid -d\n").await?;
+    /// Resolve an aa-proxy-rs host name to a fixed address as host:ip, may be repeated
+    #[arg(long, global = true)]
+    pub api_resolve: Vec<HostOverride>,
 
-    info!(
-        "Successfully sent WiCAN autopid request. Waiting for a response for up to 10 seconds..."
-    );
+    /// Seconds an idle connection to aa-proxy-rs is kept open for reuse, 0 to keep it indefinitely
+    #[arg(long, global = true, default_value_t = 600)]
+    pub api_pool_idle_timeout_seconds: u16,
 
-    let timeout = time::sleep(wican_timeout);
-    tokio::select! {
-        _ = timeout => {
-            warn!("Timeout: No reply from WiCAN received.");
-            Ok(None)
-        }
-        notification = notif_stream.next() => {
-            if let Some(n) = notification {
-                let response_string = String::from_utf8(n)
-                    .context("Failed to decode WiCAN response as string")?
-                    .trim_end()
-                    .to_string();
+    /// Interval in seconds between TCP keep-alive probes on the aa-proxy-rs connection, 0 to disable
+    #[arg(long, global = true, default_value_t = 60)]
+    pub api_tcp_keepalive_seconds: u16,
 
-                debug!("Successfully decoded WiCAN response as string: {}", response_string);
+    /// Talk HTTP/2 to aa-proxy-rs without negotiating it first
+    #[arg(long, global = true, default_value_t = false)]
+    pub api_http2_prior_knowledge: bool,
 
-                let wican_response: WicanResponse = serde_json::from_str(&response_string)
-                    .context("Failed to parse WiCAN response JSON")?;
+    /// Interval in seconds between HTTP/2 keep-alive pings, 0 to disable
+    #[arg(long, global = true, default_value_t = 0)]
+    pub api_http2_keep_alive_seconds: u16,
 
-                debug!("Successfully decoded WiCAN response as JSON: {:?}", wican_response);
+    /// Include the WiCAN MAC address, firmware version and collector version in the payload
+    #[arg(long, global = true, default_value_t = false)]
+    pub api_send_metadata: bool,
 
-                let battery_data = BatteryData {
-                    battery_level_percentage: Some(
-                        wican_response
-                            .soc_d
-                            .unwrap_or(wican_response.soc)
-                    ),
-                    external_temp_celsius: wican_response.outdoor_temperature,
-                    battery_capacity_wh: Some(vehicle_battery_capacity),
-                    ..Default::default()
-                };
+    /// Number of samples kept for another attempt after failing to post, 0 to drop failed samples
+    #[arg(long, global = true, default_value_t = 0)]
+    pub api_retry_queue_size: usize,
 
-                Ok(Some(battery_data))
-            } else {
-                warn!("Notification stream ended unexpectedly.");
-                Ok(None)
-            }
-        }
-    }
-}
+    /// Minutes after which a queued sample is too old to post and dropped, 0 to post it however old
+    #[arg(long, global = true, default_value_t = 60)]
+    pub api_retry_max_age_minutes: u32,
 
-// Post battery data to aa-proxy-rs
-async fn post_battery_data(url: &str, data: &BatteryData) -> Result<()> {
-    info!("Sending {:?} to aa-proxy-rs at: {}", data, url);
+    /// Keep the retry queue in the state directory, so queued samples are posted after a restart
+    #[arg(long, global = true, default_value_t = false)]
+    pub api_retry_queue_persist: bool,
 
-    let client = Client::new();
+    /// Seconds between attempts at posting queued samples, 0 to only retry with the next sample
+    #[arg(long, global = true, default_value_t = 30)]
+    pub api_retry_interval_seconds: u16,
 
-    let res = client.post(url).json(data).send().await?;
+    /// Log the body that would be posted to aa-proxy-rs instead of sending it
+    #[arg(long, global = true, default_value_t = false)]
+    pub dry_run: bool,
 
-    if res.status().is_success() {
-        info!(
-            "Successfully posted to aa-proxy-rs at: {}. Status: {}",
-            url,
-            res.status()
-        );
-        Ok(())
-    } else {
-        let status = res.status();
-        warn!(
-            "Failed to post to aa-proxy-rs at: {}. Status: {}",
-            url, status
-        );
-        Err(anyhow!(
-            "Failed to post to aa-proxy-rs at: {}. Status: {}",
-            url,
-            status
-        ))
-    }
+    /// Post queued samples together with the new sample as a single JSON array
+    #[arg(long, global = true, default_value_t = false)]
+    pub api_batch: bool,
+
+    /// Shared secret used to sign request bodies with HMAC-SHA256, may be encrypted
+    #[arg(
+        long,
+        global = true,
+        env = "AA_PROXY_WICAN_API_HMAC_SECRET",
+        hide_env_values = true
+    )]
+    pub api_hmac_secret: Option<Secret>,
+
+    /// Header carrying the request body signature, formatted as sha256=<hex>
+    #[arg(long, global = true, default_value = "X-Signature")]
+    pub api_hmac_header: String,
+
+    /// Bearer token sent to aa-proxy-rs, e.g. for a reverse proxy in front of it, may be encrypted
+    #[arg(
+        long,
+        global = true,
+        env = "AA_PROXY_WICAN_API_TOKEN",
+        hide_env_values = true,
+        conflicts_with = "api_username"
+    )]
+    pub api_token: Option<Secret>,
+
+    /// User name for basic authentication with aa-proxy-rs
+    #[arg(long, global = true)]
+    pub api_username: Option<String>,
+
+    /// Password for basic authentication with aa-proxy-rs, may be encrypted
+    #[arg(
+        long,
+        global = true,
+        env = "AA_PROXY_WICAN_API_PASSWORD",
+        hide_env_values = true,
+        requires = "api_username"
+    )]
+    pub api_password: Option<Secret>,
+
+    /// PEM file with the CA certificate(s) aa-proxy-rs' certificate is checked against, besides the system ones
+    #[arg(long, global = true)]
+    pub api_ca_cert: Option<PathBuf>,
+
+    /// Accept any certificate from aa-proxy-rs, such as a self-signed one. Only use this on a trusted network.
+    #[arg(long, global = true, default_value_t = false)]
+    pub api_insecure_tls: bool,
+
+    /// Seconds a whole request to aa-proxy-rs may take, 0 for no limit besides the connect and read timeouts
+    #[arg(long, global = true, default_value_t = 30)]
+    pub api_timeout_seconds: u16,
+
+    /// aa-proxy-rs url reporting whether an Android Auto session is active, enables idle polling when set
+    #[arg(long)]
+    pub api_session_url: Option<String>,
+
+    /// aa-proxy-rs server-sent event stream announcing head-unit connects and disconnects, enables idle polling and polls as soon as a head unit connects
+    #[arg(long)]
+    pub api_events_url: Option<String>,
+
+    /// aa-proxy-rs WebSocket ingestion endpoint (ws://), samples are pushed over a persistent connection and posted over HTTP while it is down
+    #[arg(long)]
+    pub api_websocket_url: Option<String>,
+
+    /// Field of the session url response or event data holding the session state
+    #[arg(long, default_value = "connected")]
+    pub api_session_field: String,
+
+    /// Seconds between session state checks while no Android Auto session is active
+    #[arg(long, default_value_t = 10)]
+    pub api_session_check_seconds: u16,
+
+    /// API version aa-proxy-rs is expected to report, a warning is logged on mismatch
+    #[arg(long, global = true)]
+    pub api_expected_version: Option<String>,
+
+    /// Address to serve the admin API on, e.g. 127.0.0.1:8095, to change polling and inspect the retry queue at runtime
+    #[arg(long)]
+    pub admin_listen: Option<SocketAddr>,
+
+    /// Bearer token required by the admin API, may be encrypted
+    #[arg(long, env = "AA_PROXY_WICAN_ADMIN_TOKEN", hide_env_values = true)]
+    pub admin_token: Option<Secret>,
+
+    /// Address to serve the read-only /health and /status endpoints on, e.g. 127.0.0.1:8096
+    #[arg(long)]
+    pub status_listen: Option<SocketAddr>,
+
+    /// Minutes without a sample after which /health reports the client as stale
+    #[arg(long, default_value_t = 60)]
+    pub status_max_sample_age_minutes: u32,
+
+    /// File whose creation starts a poll immediately, e.g. /run/aa-proxy-wican/poll, removed again once seen
+    #[arg(long)]
+    pub poll_trigger_file: Option<PathBuf>,
+
+    /// MQTT broker to publish samples to, e.g. mqtt://broker.local:1883
+    #[arg(long)]
+    pub mqtt_url: Option<String>,
+
+    /// MQTT client id
+    #[arg(long, default_value = "aa-proxy-wican")]
+    pub mqtt_client_id: String,
+
+    /// MQTT protocol version
+    #[arg(long, value_enum, default_value_t = MqttVersion::V311)]
+    pub mqtt_version: MqttVersion,
+
+    /// MQTT user name
+    #[arg(long, env = "AA_PROXY_WICAN_MQTT_USERNAME")]
+    pub mqtt_username: Option<String>,
+
+    /// MQTT password, may be encrypted
+    #[arg(long, env = "AA_PROXY_WICAN_MQTT_PASSWORD", hide_env_values = true)]
+    pub mqtt_password: Option<Secret>,
+
+    /// PEM file with the CA certificate(s) to trust for mqtts:// urls [default: system certificates]
+    #[arg(long)]
+    pub mqtt_ca_file: Option<PathBuf>,
+
+    /// PEM client certificate for mqtts:// urls
+    #[arg(long)]
+    pub mqtt_client_cert_file: Option<PathBuf>,
+
+    /// PEM private key of the client certificate
+    #[arg(long)]
+    pub mqtt_client_key_file: Option<PathBuf>,
+
+    /// Seconds the broker keeps the session after a disconnect (MQTT 5)
+    #[arg(long)]
+    pub mqtt_session_expiry_seconds: Option<u32>,
+
+    /// Seconds after which the broker drops undelivered sample messages (MQTT 5)
+    #[arg(long)]
+    pub mqtt_message_expiry_seconds: Option<u32>,
+
+    /// Value of {prefix} in MQTT topic templates
+    #[arg(long, default_value = "aa-proxy-wican")]
+    pub mqtt_topic_prefix: String,
+
+    /// Publish each sample as a single JSON message, one message per field or both
+    #[arg(long, value_enum, default_value_t = MqttLayout::Json)]
+    pub mqtt_layout: MqttLayout,
+
+    /// Topic template for JSON samples
+    #[arg(long, default_value = "{prefix}/state")]
+    pub mqtt_json_topic: String,
+
+    /// Topic template for per-field messages, {field} is replaced by the field name
+    #[arg(long, default_value = "{prefix}/{field}")]
+    pub mqtt_field_topic: String,
+
+    /// QoS level of sample messages
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u8).range(0..=2))]
+    pub mqtt_qos: u8,
+
+    /// Publish sample messages with the retain flag
+    #[arg(long, default_value_t = false)]
+    pub mqtt_retain: bool,
+
+    /// Topic template receiving a retained "online" on connect and "offline" as the last will, e.g. {prefix}/availability
+    #[arg(long)]
+    pub mqtt_availability_topic: Option<String>,
+
+    /// Announce the sample fields as Home Assistant entities through MQTT discovery and publish their states
+    #[arg(long, default_value_t = false, requires = "mqtt_url")]
+    pub mqtt_homeassistant_discovery: bool,
+
+    /// Topic prefix Home Assistant watches for MQTT discovery
+    #[arg(long, default_value = "homeassistant")]
+    pub mqtt_homeassistant_discovery_prefix: String,
+
+    /// Name of the device the discovered entities belong to in Home Assistant
+    #[arg(long, default_value = "aa-proxy-wican")]
+    pub mqtt_homeassistant_device_name: String,
+
+    /// Shell command each sample is written to as a JSON line on stdin
+    #[arg(long)]
+    pub exec_sink: Option<String>,
+
+    /// Run the exec sink command once per sample and check its exit status, or keep one instance running
+    #[arg(long, value_enum, default_value_t = ExecMode::PerSample)]
+    pub exec_sink_mode: ExecMode,
+
+    /// Seconds the exec sink command may take to handle a sample
+    #[arg(long, default_value_t = 30)]
+    pub exec_sink_timeout_seconds: u16,
+
+    /// Number of samples kept for another attempt after the exec sink failed, 0 to drop failed samples
+    #[arg(long, default_value_t = 0)]
+    pub exec_sink_retry_queue_size: usize,
+
+    /// gRPC server (http://) implementing the BatteryTelemetry service, every sample is streamed to it as protobuf (needs the 'grpc' feature)
+    #[arg(long)]
+    pub grpc_url: Option<String>,
+
+    /// Redis or Valkey server to send every sample to as JSON, e.g. redis://localhost:6379/0
+    #[arg(long)]
+    pub redis_url: Option<String>,
+
+    /// Redis password, may be encrypted [default: the password in --redis-url]
+    #[arg(long, env = "AA_PROXY_WICAN_REDIS_PASSWORD", hide_env_values = true)]
+    pub redis_password: Option<Secret>,
+
+    /// Redis channel every sample is published to
+    #[arg(long)]
+    pub redis_channel: Option<String>,
+
+    /// Redis key set to the latest sample
+    #[arg(long)]
+    pub redis_key: Option<String>,
+
+    /// Seconds before the latest sample key expires, 0 to keep it
+    #[arg(long, default_value_t = 900)]
+    pub redis_key_ttl_seconds: u16,
+
+    /// PostgreSQL or TimescaleDB database to insert every sample into, e.g. postgres://car@localhost/telemetry
+    #[arg(long)]
+    pub postgres_url: Option<String>,
+
+    /// PostgreSQL password, may be encrypted [default: the password in --postgres-url]
+    #[arg(long, env = "AA_PROXY_WICAN_POSTGRES_PASSWORD", hide_env_values = true)]
+    pub postgres_password: Option<Secret>,
+
+    /// Table samples are inserted into, created if missing, optionally with a schema
+    #[arg(long, default_value = "battery_samples")]
+    pub postgres_table: String,
+
+    /// Number of samples inserted together
+    #[arg(long, default_value_t = 1)]
+    pub postgres_batch_size: usize,
+
+    /// Home Assistant url to set sensor states through its REST API, e.g. http://homeassistant.local:8123
+    #[arg(long)]
+    pub homeassistant_url: Option<String>,
+
+    /// Home Assistant long-lived access token, may be encrypted
+    #[arg(
+        long,
+        env = "AA_PROXY_WICAN_HOMEASSISTANT_TOKEN",
+        hide_env_values = true
+    )]
+    pub homeassistant_token: Option<Secret>,
+
+    /// Prefix of the Home Assistant entity ids, e.g. sensor.<prefix>_soc
+    #[arg(long, default_value = "aa_proxy_wican")]
+    pub homeassistant_entity_prefix: String,
+
+    /// ABRP (A Better Route Planner) user token to send telemetry with, shown when linking a generic OBD device to a car in ABRP, may be encrypted
+    #[arg(long, env = "AA_PROXY_WICAN_ABRP_TOKEN", hide_env_values = true)]
+    pub abrp_token: Option<Secret>,
+
+    /// ABRP API key identifying the application, may be encrypted
+    #[arg(long, env = "AA_PROXY_WICAN_ABRP_API_KEY", hide_env_values = true)]
+    pub abrp_api_key: Option<Secret>,
+
+    /// ABRP car model sent with the telemetry, e.g. hyundai:ioniq5:22:77:other
+    #[arg(long)]
+    pub abrp_car_model: Option<String>,
+
+    /// ABRP telemetry API url
+    #[arg(long, default_value = "https://api.iternio.com/1/tlm/send")]
+    pub abrp_url: String,
+
+    /// Additional output every sample is sent to, an http(s):// url posted the sample as JSON, file:PATH kept holding the latest sample or stdout for JSON lines, optionally followed by ;retry-queue=N and ;timeout=SECONDS for urls, can be repeated
+    #[arg(long)]
+    pub output: Vec<OutputTarget>,
+
+    /// SMTP server to send email alerts through, smtps:// for TLS from the start or smtp:// to use STARTTLS when offered, e.g. smtps://alerts%40example.com@smtp.example.com
+    #[arg(long)]
+    pub smtp_url: Option<String>,
+
+    /// SMTP password, may be encrypted [default: the password in --smtp-url]
+    #[arg(long, env = "AA_PROXY_WICAN_SMTP_PASSWORD", hide_env_values = true)]
+    pub smtp_password: Option<Secret>,
+
+    /// Sender address of email alerts
+    #[arg(long, default_value = "aa-proxy-wican@localhost")]
+    pub email_from: String,
+
+    /// Recipient of email alerts, may be repeated
+    #[arg(long)]
+    pub email_to: Vec<String>,
+
+    /// Events mailed as alerts, as named in the event journal
+    #[arg(
+        long,
+        value_delimiter = ',',
+        default_value = "low_soc,departure_target_missed,pairing_removed"
+    )]
+    pub email_events: Vec<String>,
+
+    /// Shell command run before each poll, e.g. to wake the WiCAN
+    #[arg(long)]
+    pub hook_pre_poll: Option<String>,
+
+    /// Shell command run after each sample is processed, with the sample JSON on stdin and in AA_PROXY_WICAN_SAMPLE
+    #[arg(long)]
+    pub hook_post_sample: Option<String>,
+
+    /// Shell command run when the car starts charging, with the sample JSON on stdin and in AA_PROXY_WICAN_SAMPLE
+    #[arg(long)]
+    pub hook_charge_start: Option<String>,
+
+    /// Shell command run when connecting, fetching or posting fails, with the message in AA_PROXY_WICAN_ERROR and as JSON on stdin
+    #[arg(long)]
+    pub hook_error: Option<String>,
+
+    /// Shell command run when the SOC drops below --low-soc-threshold, with the sample JSON on stdin and in AA_PROXY_WICAN_SAMPLE
+    #[arg(long)]
+    pub hook_low_soc: Option<String>,
+
+    /// Seconds a hook may run before it is killed
+    #[arg(long, default_value_t = 30)]
+    pub hook_timeout_seconds: u16,
+
+    /// Unix socket the status, fetch-now, reconnect and set-interval commands reach the running service on [default: /run/aa-proxy-wican/control.sock as root, otherwise $XDG_RUNTIME_DIR/aa-proxy-wican.sock]
+    #[arg(long, global = true)]
+    pub control_socket: Option<PathBuf>,
+
+    /// Don't listen on the control socket
+    #[arg(long, default_value_t = false)]
+    pub no_control_socket: bool,
+
+    /// Export PollNow, Pause, Resume and GetStatus on the system bus, needs a D-Bus policy allowing the name to be owned
+    #[arg(long, default_value_t = false)]
+    pub dbus_control: bool,
+
+    /// File holding the base64 key used to decrypt "enc:" secrets, defaults to the AA_PROXY_WICAN_SECRETS_KEY environment variable
+    #[arg(long, global = true)]
+    pub secrets_key_file: Option<PathBuf>,
+
+    /// File of NAME=VALUE lines holding secrets not given on the command line or in the environment, named like the systemd credentials, e.g. api-token=...
+    #[arg(long, global = true)]
+    pub secrets_file: Option<PathBuf>,
+
+    /// User to switch to after the log file is opened and secrets are read
+    #[arg(long, global = true)]
+    pub user: Option<String>,
+
+    /// Group to switch to after startup, defaults to the primary group of --user
+    #[arg(long, global = true)]
+    pub group: Option<String>,
+
+    /// Restrict the process with Landlock to reading system paths, writing its log file and making TCP connections only to the ports of the configured urls
+    #[arg(long, global = true, default_value_t = false)]
+    pub sandbox: bool,
+
+    /// Directory for state kept between runs [default: /var/lib/aa-proxy-wican as root, otherwise $XDG_STATE_HOME/aa-proxy-wican]
+    #[arg(long, global = true)]
+    pub state_dir: Option<PathBuf>,
+
+    /// Post the sample saved in the state directory at startup, before the first poll, when it was read less than this many hours ago, 0 to not save it
+    #[arg(long, global = true, default_value_t = 24)]
+    pub last_sample_max_age_hours: u16,
+
+    /// Keep every sample in history.jsonl in the state directory
+    #[arg(long, global = true, default_value_t = false)]
+    pub history: bool,
+
+    /// Append every sample with the result of posting it to this file, e.g. to chart the SOC over time
+    #[arg(long)]
+    pub record_file: Option<PathBuf>,
+
+    /// Format of --record-file, one JSON object per line or CSV with a header
+    #[arg(long, value_enum, default_value_t = RecordFormat::Jsonl, requires = "record_file")]
+    pub record_format: RecordFormat,
+
+    /// Size in MiB at which --record-file is rotated, 0 to never rotate
+    #[arg(long, default_value_t = 10, requires = "record_file")]
+    pub record_max_size_mb: u64,
+
+    /// Number of rotated record files kept as FILE.1 to FILE.N, 0 to discard the file when it's full
+    #[arg(long, default_value_t = 5, requires = "record_file")]
+    pub record_max_files: usize,
+
+    /// Record SOC against charging power during DC charging sessions in the state directory
+    #[arg(long, default_value_t = false)]
+    pub record_charging_curves: bool,
+
+    /// Seconds between samples while a charging curve is being recorded
+    #[arg(long, default_value_t = 30)]
+    pub charging_curve_interval_seconds: u16,
+
+    /// Electricity price per kWh to track charging costs with, flat or as HH:MM-HH:MM=PRICE windows in local time with a price for the rest of the day, e.g. 0.30,00:00-07:00=0.12
+    #[arg(long)]
+    pub tariff: Option<Tariff>,
+
+    /// Currency shown with charging costs, e.g. EUR
+    #[arg(long, requires = "tariff")]
+    pub tariff_currency: Option<String>,
+
+    /// Region to follow the grid carbon intensity of, an Electricity Maps zone such as DE or, with --carbon-intensity-provider uk, an outward postcode such as RG10
+    #[arg(long)]
+    pub carbon_intensity_region: Option<String>,
+
+    /// Service the grid carbon intensity is read from
+    #[arg(long, value_enum, default_value_t = CarbonProvider::ElectricityMaps)]
+    pub carbon_intensity_provider: CarbonProvider,
+
+    /// Electricity Maps API token, may be encrypted
+    #[arg(
+        long,
+        env = "AA_PROXY_WICAN_CARBON_INTENSITY_TOKEN",
+        hide_env_values = true
+    )]
+    pub carbon_intensity_token: Option<Secret>,
+
+    /// Grid carbon intensity in gCO2/kWh below which a low_carbon_intensity event signals a good time to charge
+    #[arg(long, requires = "carbon_intensity_region")]
+    pub carbon_intensity_threshold: Option<f64>,
+
+    /// SOC in percent below which samples are sent with a high priority and the low SOC alerts fire
+    #[arg(long)]
+    pub low_soc_threshold: Option<f32>,
+
+    /// Poll every SECONDS while the SOC is within MIN-MAX, optionally only while charging, as MIN-MAX:SECONDS[:charging], e.g. 95-100:30:charging, may be repeated
+    #[arg(long)]
+    pub soc_poll_rule: Vec<SocPollRule>,
+
+    /// Poll at an interval chosen by whether the vehicle is charging, driving or parked instead of the update frequency
+    #[arg(long, default_value_t = false)]
+    pub adaptive_polling: bool,
+
+    /// Seconds between polls while charging with --adaptive-polling
+    #[arg(long, default_value_t = 30, value_parser = clap::value_parser!(u32).range(1..))]
+    pub adaptive_charging_seconds: u32,
+
+    /// Seconds between polls while driving with --adaptive-polling
+    #[arg(long, default_value_t = 60, value_parser = clap::value_parser!(u32).range(1..))]
+    pub adaptive_driving_seconds: u32,
+
+    /// Seconds between polls while parked with --adaptive-polling
+    #[arg(long, default_value_t = 1800, value_parser = clap::value_parser!(u32).range(1..))]
+    pub adaptive_parked_seconds: u32,
+
+    /// Consecutive polls without a reply after which the vehicle is taken as parked with --adaptive-polling
+    #[arg(long, default_value_t = 3, value_parser = clap::value_parser!(u32).range(1..))]
+    pub adaptive_parked_after_failures: u32,
+
+    /// Daily departure time as HH:MM in local time, to predict the SOC at departure from the charging rate
+    #[arg(long, value_parser = departure::parse_time)]
+    pub departure_time: Option<NaiveTime>,
+
+    /// SOC in percent wanted by --departure-time, warning when charging won't reach it
+    #[arg(long, requires = "departure_time")]
+    pub departure_target_soc: Option<f32>,
+
+    /// WebAssembly module transforming samples or acting as a sink, may be repeated (needs the 'wasm' feature)
+    #[arg(long, global = true)]
+    pub wasm_plugin: Vec<PathBuf>,
+
+    /// File or named pipe to append lifecycle events to as JSON lines
+    #[arg(long, global = true)]
+    pub events_file: Option<PathBuf>,
+
+    /// JSON file kept up to date with the connection state, last sample and post results, e.g. /run/aa-proxy-wican/status.json
+    #[arg(long, global = true)]
+    pub status_file: Option<PathBuf>,
+
+    /// Where log messages go, the terminal and the log file, the systemd journal or the terminal alone
+    #[arg(long, global = true, value_enum, default_value_t = LogOutput::File)]
+    pub log_output: LogOutput,
+
+    /// Log file [default: /var/log/aa-proxy-wican.log as root, otherwise aa-proxy-wican.log in the state directory]
+    #[arg(long, global = true)]
+    pub log_file: Option<PathBuf>,
+
+    /// Size in MiB at which the log file is rotated, 0 for no limit
+    #[arg(long, global = true, default_value_t = 10)]
+    pub log_max_size_mb: u64,
+
+    /// Hours after which the log file is rotated whatever its size, 0 to only rotate by size
+    #[arg(long, global = true, default_value_t = 0)]
+    pub log_rotate_hours: u32,
+
+    /// Number of rotated log files kept as FILE.1 to FILE.N, 0 to discard the log when it's rotated
+    #[arg(long, global = true, default_value_t = 5)]
+    pub log_max_files: usize,
+
+    /// Minutes between summaries of repeated identical warnings and errors, which are otherwise only logged at debug level, 0 logs every copy
+    #[arg(long, global = true, default_value_t = 10)]
+    pub log_repeat_summary_minutes: u16,
+
+    /// Mark every frame sent to or received from the WiCAN in btmon captures, needs CAP_NET_ADMIN
+    #[arg(long, global = true, default_value_t = false)]
+    pub btmon_markers: bool,
+
+    /// Log level, trace also hex dumps every frame sent to or received from the WiCAN
+    #[arg(long, global = true, value_enum, default_value_t = LogLevel::Info)]
+    pub log_level: LogLevel,
+
+    /// TOML file setting options by their long name, e.g. api-url = ["http://localhost/battery"], overridden by the command line and environment and reloaded when it changes
+    #[arg(long, global = true, env = "AA_PROXY_WICAN_CONFIG")]
+    pub config: Option<PathBuf>,
+}
+
+impl Configuration {
+    // How long to wait for an autopid reply, defaulting to the WiCAN timeout
+    fn response_timeout(&self) -> Duration {
+        Duration::from_secs(self.wican_response_timeout.unwrap_or(self.wican_timeout) as u64)
+    }
+
+    fn drive_model(&self) -> DriveModel {
+        DriveModel {
+            mass_kg: self.vehicle_mass_kg,
+            drag_area_m2: self.vehicle_drag_area,
+            rolling_resistance: self.vehicle_rolling_resistance,
+            drivetrain_efficiency: self.vehicle_drivetrain_efficiency,
+        }
+    }
+
+    // The WiCANs to read from, empty when none is configured
+    fn devices(&self) -> Vec<DeviceEntry> {
+        match self.wican_mac_address {
+            Some(address) => vec![DeviceEntry::new(address)],
+            None => self.wican_device.clone(),
+        }
+    }
+
+    // One line description of the main settings for diagnostics
+    fn summary(&self) -> String {
+        format!(
+            "WiCAN {}, battery capacity {} Wh, {}, write type {:?}, API {}, session url {}",
+            match (self.devices().as_slice(), self.wican_discover) {
+                ([], true) => "discovered".to_string(),
+                ([], false) => "unset".to_string(),
+                (devices, _) => devices
+                    .iter()
+                    .map(|device| device.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", "),
+            },
+            self.vehicle_battery_capacity.unwrap_or_default(),
+            if self.wican_streaming {
+                "streaming".to_string()
+            } else {
+                format!(
+                    "polling every {} minute(s)",
+                    self.wican_update_frequency_minutes
+                )
+            },
+            self.wican_write_type,
+            redacted_urls(&self.api_url),
+            self.api_session_url
+                .as_deref()
+                .map_or("unset".to_string(), secrets::redact_url)
+        )
+    }
+
+    // Fill secrets that were not given on the command line or in the
+    // environment from their files, the secrets file or systemd credentials,
+    // in that order
+    fn load_credentials(&mut self, matches: &ArgMatches) -> Result<()> {
+        let unset = |id: &str| {
+            !matches!(
+                matches.value_source(id),
+                Some(ValueSource::CommandLine | ValueSource::EnvVariable)
+            )
+        };
+        let secrets_file = self
+            .secrets_file
+            .as_deref()
+            .map(SecretsFile::load)
+            .transpose()?;
+        let credential = |name: &str| match secrets_file.as_ref().and_then(|file| file.get(name)) {
+            Some(secret) => Ok(Some(secret)),
+            None => secrets::load_credential(name),
+        };
+
+        if let Some(path) = &self.wican_passkey_file {
+            self.wican_passkey = secrets::read_file(path)?;
+        } else if unset("wican_passkey") {
+            if let Some(secret) = credential("wican-passkey")? {
+                self.wican_passkey = secret;
+            }
+        }
+        for device in &mut self.wican_device {
+            if device.passkey.is_none() {
+                device.passkey = credential(&device.passkey_credential())?;
+            }
+        }
+        if unset("mqtt_username") {
+            if let Some(username) = credential("mqtt-username")? {
+                self.mqtt_username = Some(username.expose().to_string());
+            }
+        }
+        if unset("api_hmac_secret") {
+            if let Some(secret) = credential("api-hmac-secret")? {
+                self.api_hmac_secret = Some(secret);
+            }
+        }
+        if unset("api_token") {
+            if let Some(secret) = credential("api-token")? {
+                self.api_token = Some(secret);
+            }
+        }
+        if unset("api_password") {
+            if let Some(secret) = credential("api-password")? {
+                self.api_password = Some(secret);
+            }
+        }
+        if unset("mqtt_password") {
+            if let Some(secret) = credential("mqtt-password")? {
+                self.mqtt_password = Some(secret);
+            }
+        }
+        if unset("redis_password") {
+            if let Some(secret) = credential("redis-password")? {
+                self.redis_password = Some(secret);
+            }
+        }
+        if unset("postgres_password") {
+            if let Some(secret) = credential("postgres-password")? {
+                self.postgres_password = Some(secret);
+            }
+        }
+        if unset("homeassistant_token") {
+            if let Some(secret) = credential("homeassistant-token")? {
+                self.homeassistant_token = Some(secret);
+            }
+        }
+        if unset("abrp_token") {
+            if let Some(secret) = credential("abrp-token")? {
+                self.abrp_token = Some(secret);
+            }
+        }
+        if unset("abrp_api_key") {
+            if let Some(secret) = credential("abrp-api-key")? {
+                self.abrp_api_key = Some(secret);
+            }
+        }
+        if unset("carbon_intensity_token") {
+            if let Some(secret) = credential("carbon-intensity-token")? {
+                self.carbon_intensity_token = Some(secret);
+            }
+        }
+        if unset("smtp_password") {
+            if let Some(secret) = credential("smtp-password")? {
+                self.smtp_password = Some(secret);
+            }
+        }
+        if unset("admin_token") {
+            if let Some(secret) = credential("admin-token")? {
+                self.admin_token = Some(secret);
+            }
+        }
+        if unset("ovms_password") {
+            if let Some(secret) = credential("ovms-password")? {
+                self.ovms_password = Some(secret);
+            }
+        }
+        if unset("ovms_mqtt_password") {
+            if let Some(secret) = credential("ovms-mqtt-password")? {
+                self.ovms_mqtt_password = Some(secret);
+            }
+        }
+        if unset("wican_mqtt_password") {
+            if let Some(secret) = credential("wican-mqtt-password")? {
+                self.wican_mqtt_password = Some(secret);
+            }
+        }
+        Ok(())
+    }
+
+    // Decrypt any encrypted secret values in place
+    fn resolve_secrets(&mut self, key: Option<&SecretsKey>) -> Result<()> {
+        self.wican_passkey
+            .decrypt(key)
+            .context("Failed to decrypt --wican-passkey")?;
+        for device in &mut self.wican_device {
+            if let Some(secret) = device.passkey.as_mut() {
+                secret
+                    .decrypt(key)
+                    .with_context(|| format!("Failed to decrypt the passkey of {}", device))?;
+            }
+        }
+        if let Some(secret) = self.api_hmac_secret.as_mut() {
+            secret
+                .decrypt(key)
+                .context("Failed to decrypt --api-hmac-secret")?;
+        }
+        if let Some(secret) = self.api_token.as_mut() {
+            secret
+                .decrypt(key)
+                .context("Failed to decrypt --api-token")?;
+        }
+        if let Some(secret) = self.api_password.as_mut() {
+            secret
+                .decrypt(key)
+                .context("Failed to decrypt --api-password")?;
+        }
+        if let Some(secret) = self.mqtt_password.as_mut() {
+            secret
+                .decrypt(key)
+                .context("Failed to decrypt --mqtt-password")?;
+        }
+        if let Some(secret) = self.redis_password.as_mut() {
+            secret
+                .decrypt(key)
+                .context("Failed to decrypt --redis-password")?;
+        }
+        if let Some(secret) = self.postgres_password.as_mut() {
+            secret
+                .decrypt(key)
+                .context("Failed to decrypt --postgres-password")?;
+        }
+        if let Some(secret) = self.homeassistant_token.as_mut() {
+            secret
+                .decrypt(key)
+                .context("Failed to decrypt --homeassistant-token")?;
+        }
+        if let Some(secret) = self.abrp_token.as_mut() {
+            secret
+                .decrypt(key)
+                .context("Failed to decrypt --abrp-token")?;
+        }
+        if let Some(secret) = self.abrp_api_key.as_mut() {
+            secret
+                .decrypt(key)
+                .context("Failed to decrypt --abrp-api-key")?;
+        }
+        if let Some(secret) = self.carbon_intensity_token.as_mut() {
+            secret
+                .decrypt(key)
+                .context("Failed to decrypt --carbon-intensity-token")?;
+        }
+        if let Some(secret) = self.smtp_password.as_mut() {
+            secret
+                .decrypt(key)
+                .context("Failed to decrypt --smtp-password")?;
+        }
+        if let Some(secret) = self.admin_token.as_mut() {
+            secret
+                .decrypt(key)
+                .context("Failed to decrypt --admin-token")?;
+        }
+        if let Some(secret) = self.ovms_password.as_mut() {
+            secret
+                .decrypt(key)
+                .context("Failed to decrypt --ovms-password")?;
+        }
+        if let Some(secret) = self.ovms_mqtt_password.as_mut() {
+            secret
+                .decrypt(key)
+                .context("Failed to decrypt --ovms-mqtt-password")?;
+        }
+        if let Some(secret) = self.wican_mqtt_password.as_mut() {
+            secret
+                .decrypt(key)
+                .context("Failed to decrypt --wican-mqtt-password")?;
+        }
+        Ok(())
+    }
+}
+
+// A single thread is plenty for one device and keeps the sandbox, which only
+// applies to the calling thread and threads it creates, covering everything
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> Result<()> {
+    // Parse the command line and config file
+    let args: Vec<OsString> = std::env::args_os().collect();
+    let matches = match config::matches(Configuration::command(), &args) {
+        Ok(matches) => matches,
+        Err(e) => match e.downcast::<clap::Error>() {
+            Ok(e) => e.exit(),
+            Err(e) => return Err(e),
+        },
+    };
+    let mut configuration = Configuration::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+
+    // Set log level from command line
+    let log_level = LevelFilter::from(configuration.log_level);
+    // Log everything and filter with the global level alone, so a reloaded
+    // config file can raise it as well as lower it
+    let logger_level = match configuration.config {
+        Some(_) => LevelFilter::Trace,
+        None => log_level,
+    };
+
+    let state_dir = paths::state_dir(configuration.state_dir.as_deref())?;
+    let control_socket_path =
+        paths::control_socket(configuration.control_socket.as_deref(), &state_dir);
+
+    // Commands for the running service are answered by it, so they don't
+    // write to its log file or create its directories
+    if let Some(request) = configuration
+        .command
+        .as_ref()
+        .and_then(ControlRequest::from_command)
+    {
+        return control_socket::request(&control_socket_path, request).await;
+    }
+    paths::ensure_dir(&state_dir)?;
+    let log_file_path = (configuration.log_output == LogOutput::File)
+        .then(|| paths::log_file(configuration.log_file.as_deref(), &state_dir));
+    let log_file = match &log_file_path {
+        Some(path) => {
+            if let Some(parent) = path.parent() {
+                paths::ensure_dir(parent)?;
+            }
+            // Confirm we can write to the log file
+            Some(RotatingFile::open(
+                path,
+                RotationOptions {
+                    max_bytes: configuration.log_max_size_mb * 1024 * 1024,
+                    max_age: (configuration.log_rotate_hours > 0)
+                        .then(|| Duration::from_secs(configuration.log_rotate_hours as u64 * 3600)),
+                    max_files: configuration.log_max_files,
+                },
+            )?)
+        }
+        None => None,
+    };
+
+    // Create a logger configuration
+    let log_config = ConfigBuilder::new()
+        .set_target_level(LevelFilter::Info)
+        .set_target_level(LevelFilter::Error)
+        .set_location_level(LevelFilter::Error)
+        .set_time_level(LevelFilter::Error)
+        .set_level_padding(LevelPadding::Right)
+        .set_time_offset_to_local()
+        .expect("Failed to set local time offset")
+        .build();
+
+    // Initialize the logger.
+    let logger: Box<dyn log::Log> = match configuration.log_output {
+        LogOutput::Journald => Box::new(JournaldLogger::connect(logger_level)?),
+        LogOutput::File | LogOutput::Stdout => {
+            let mut loggers: Vec<Box<dyn SharedLogger>> = vec![TermLogger::new(
+                logger_level,
+                log_config.clone(),
+                // Keep stdout to the samples when they are printed there
+                if configuration
+                    .output
+                    .iter()
+                    .any(|output| output.kind == OutputKind::Stdout)
+                {
+                    TerminalMode::Stderr
+                } else {
+                    TerminalMode::Mixed
+                },
+                ColorChoice::Auto,
+            )];
+            if let Some(log_file) = log_file {
+                loggers.push(WriteLogger::new(logger_level, log_config.clone(), log_file));
+            }
+            CombinedLogger::new(loggers)
+        }
+    };
+    let logger = match configuration.log_repeat_summary_minutes {
+        0 => logger,
+        minutes => Box::new(dedup::DedupLogger::new(
+            logger,
+            Duration::from_secs(minutes as u64 * 60),
+        )),
+    };
+    log::set_max_level(log_level);
+    match log::set_boxed_logger(logger) {
+        Ok(_) => {}
+        Err(e) => {
+            return Err(anyhow!("Could not initialize combined logger: {}", e));
+        }
+    }
+
+    units::set_display_temperature_unit(configuration.display_temperature_unit);
+
+    // A WiCAN found by an earlier discovery is used like a configured one
+    if configuration.wican_mac_address.is_none() && configuration.wican_remember_discovered {
+        match DiscoveredAddress::new(&state_dir).load() {
+            Ok(Some(address)) => {
+                info!("Using WiCAN {} found by an earlier discovery.", address);
+                configuration.wican_mac_address = Some(address);
+            }
+            Ok(None) => {}
+            Err(e) => warn!("Discovering the WiCAN again: {:#}", e),
+        }
+    }
+
+    let secrets_key = SecretsKey::load(configuration.secrets_key_file.as_deref())?;
+    match &configuration.command {
+        Some(Command::Config {
+            command: ConfigCommand::Schema,
+        }) => {
+            let schema = config::schema(&Configuration::command());
+            println!("{}", serde_json::to_string_pretty(&schema)?);
+            return Ok(());
+        }
+        Some(Command::Devices { command }) => {
+            let store = DeviceStore::new(&state_dir);
+            match command {
+                None => devices::list(&store)?,
+                Some(DevicesCommand::Set {
+                    address,
+                    alias,
+                    vehicle_profile,
+                }) => {
+                    store.update(*address, |record| {
+                        if let Some(alias) = alias {
+                            record.alias = Some(alias.clone()).filter(|alias| !alias.is_empty());
+                        }
+                        if let Some(vehicle_profile) = vehicle_profile {
+                            record.vehicle_profile =
+                                Some(vehicle_profile.clone()).filter(|profile| !profile.is_empty());
+                        }
+                    })?;
+                }
+                Some(DevicesCommand::Forget { address }) => {
+                    if !store.forget(*address)? {
+                        return Err(anyhow!("Device {} is not known", address));
+                    }
+                }
+            }
+            return Ok(());
+        }
+        Some(Command::SelfUpdate {
+            check,
+            releases_url,
+            update_public_key,
+        }) => {
+            return update::self_update(update::UpdateOptions {
+                releases_url: releases_url.clone(),
+                public_key: update_public_key.clone(),
+                check: *check,
+            })
+            .await;
+        }
+        Some(Command::GenerateSecretsKey) => {
+            println!("{}", SecretsKey::generate().encode());
+            return Ok(());
+        }
+        Some(Command::EncryptSecret { value }) => {
+            let key = secrets_key.as_ref().ok_or_else(|| {
+                anyhow!(
+                    "A secrets key is required. Use --secrets-key-file or {}.",
+                    secrets::SECRETS_KEY_ENV
+                )
+            })?;
+            let value = match value {
+                Some(value) => value.clone(),
+                None => {
+                    let mut value = String::new();
+                    std::io::stdin().read_to_string(&mut value)?;
+                    value.trim_end_matches(['\r', '\n']).to_string()
+                }
+            };
+            println!("{}", key.encrypt(&value)?);
+            return Ok(());
+        }
+        _ => {}
+    }
+
+    configuration.load_credentials(&matches)?;
+    configuration.resolve_secrets(secrets_key.as_ref())?;
+    let wican_passkey: u32 = configuration
+        .wican_passkey
+        .expose()
+        .parse()
+        .context("--wican-passkey must be a number")?;
+
+    if let Some(path) = &configuration.events_file {
+        events::set_events_file(path.clone());
+    }
+
+    // The logging channel needs CAP_NET_ADMIN, so open it while still privileged
+    if configuration.btmon_markers {
+        if let Err(e) = trace::enable_btmon_markers() {
+            warn!("Could not enable btmon markers: {}", e);
+        }
+    }
+
+    let mut owned_paths = vec![state_dir.as_path()];
+    if let Some(path) = &configuration.status_file {
+        let dir = paths::parent_dir(path);
+        if paths::create_dir(dir)? {
+            owned_paths.push(dir);
+        } else {
+            // Only the file is handed over, and written in place
+            paths::touch(path)?;
+            owned_paths.push(path);
+        }
+        status::set_status_file(path.clone());
+    }
+
+    let poll_trigger = match (&configuration.command, &configuration.poll_trigger_file) {
+        (None, Some(path)) => {
+            let dir = paths::parent_dir(path);
+            if paths::create_dir(dir)? {
+                owned_paths.push(dir);
+                Some(TriggerFile::watch(path, false)?)
+            } else {
+                let trigger = TriggerFile::watch(path, true)?;
+                owned_paths.push(path);
+                Some(trigger)
+            }
+        }
+        _ => None,
+    };
+
+    let config_watcher = match (&configuration.command, &configuration.config) {
+        (None, Some(path)) => match ConfigWatcher::watch(path) {
+            Ok(watcher) => Some(watcher),
+            Err(e) => {
+                warn!("Changes to {} need a restart: {:#}", path.display(), e);
+                None
+            }
+        },
+        _ => None,
+    };
+
+    let control_listener = match (&configuration.command, configuration.no_control_socket) {
+        (None, false) => {
+            let listener = paths::ensure_dir(paths::parent_dir(&control_socket_path))
+                .and_then(|()| control_socket::bind(&control_socket_path));
+            match listener {
+                Ok(listener) => {
+                    owned_paths.push(&control_socket_path);
+                    Some(listener)
+                }
+                Err(e) => {
+                    warn!("Control socket unavailable: {:#}", e);
+                    None
+                }
+            }
+        }
+        _ => None,
+    };
+
+    let admin_listener = match (&configuration.command, configuration.admin_listen) {
+        (None, Some(address)) => {
+            if configuration.admin_token.is_none() {
+                return Err(anyhow!("--admin-listen requires --admin-token"));
+            }
+            Some(admin::bind(address).await?)
+        }
+        _ => None,
+    };
+
+    if configuration.command.is_none() {
+        systemd::init();
+    }
+    let status_listener = match (&configuration.command, configuration.status_listen) {
+        (None, Some(address)) => Some(status::bind(address).await?),
+        _ => None,
+    };
+
+    // Keys may only be readable by root, so read them while still privileged
+    let api_ca_cert = configuration
+        .api_ca_cert
+        .as_deref()
+        .map(|path| {
+            std::fs::read(path).with_context(|| format!("Failed to read '{}'", path.display()))
+        })
+        .transpose()?;
+    let mqtt_tls = match (&configuration.command, &configuration.mqtt_url) {
+        (None, Some(_)) => Some(TlsFiles::read(
+            configuration.mqtt_ca_file.as_deref(),
+            configuration.mqtt_client_cert_file.as_deref(),
+            configuration.mqtt_client_key_file.as_deref(),
+        )?),
+        _ => None,
+    };
+
+    let mut raw_frames = match &configuration.command {
+        None if configuration.wican_raw_frames => Some(RawFrames {
+            decoder: match &configuration.dbc_file {
+                Some(path) => Some(RawDecoder {
+                    dbc: Dbc::load(path)?,
+                    mappings: configuration.dbc_signal.clone(),
+                }),
+                None => None,
+            },
+            log: match &configuration.can_log_dir {
+                Some(dir) => {
+                    paths::ensure_dir(dir)?;
+                    owned_paths.push(dir);
+                    Some(CanLog::new(
+                        dir,
+                        configuration.can_log_max_size_mb * 1024 * 1024,
+                        configuration.can_log_max_files,
+                    ))
+                }
+                None => None,
+            },
+            bridge: match &configuration.can_bridge_interface {
+                Some(interface) => Some(CanSocket::open(interface)?),
+                None => None,
+            },
+            forward_writes: configuration.can_bridge_forward_writes,
+        }),
+        _ => None,
+    };
+
+    bluez::select_quirks(configuration.bluez_quirks).await;
+    backoff::configure(RetryPolicy {
+        initial_delay: Duration::from_secs(configuration.wican_retry_initial_delay_seconds as u64),
+        max_delay: Duration::from_secs(configuration.wican_retry_max_delay_seconds as u64),
+        discovery_attempts: configuration.wican_max_discovery_attempts,
+        pairing_attempts: configuration.wican_max_pairing_attempts,
+        connect_attempts: configuration.wican_max_connect_attempts,
+        gatt_attempts: configuration.wican_max_gatt_attempts,
+    });
+    if let Some(adapter) = &configuration.bluetooth_adapter {
+        bluez::set_adapter(adapter.clone());
+    }
+    if let Some(window) = seconds_or_none(configuration.wican_scan_window_seconds as u16) {
+        bluez::set_scan_duty_cycle(ScanDutyCycle {
+            window,
+            gap: Duration::from_secs(configuration.wican_scan_gap_seconds as u64),
+        });
+    }
+
+    // BlueZ keeps the keys of bonded devices readable by root only
+    let bluez_irks: Vec<(Address, Irk)> = configuration
+        .devices()
+        .iter()
+        .filter_map(|device| rpa::read_bluez_irk(device.address).map(|irk| (device.address, irk)))
+        .collect();
+
+    privileges::drop_privileges(
+        configuration.user.as_deref(),
+        configuration.group.as_deref(),
+        &owned_paths,
+    )?;
+
+    if configuration.sandbox {
+        let mut rules = sandbox::SandboxRules::default();
+        if let Some(path) = &log_file_path {
+            // Rotating renames the log within its directory
+            if configuration.log_max_size_mb > 0 || configuration.log_rotate_hours > 0 {
+                rules.allow_write(paths::parent_dir(path));
+            } else {
+                rules.allow_write(path);
+            }
+        }
+        if let Some(path) = &configuration.config {
+            rules.allow_read(paths::parent_dir(path));
+        }
+        if let Some(path) = &configuration.events_file {
+            rules.allow_write(path);
+        }
+        // Rotating renames the file within its directory
+        if let Some(path) = &configuration.record_file {
+            rules.allow_write(paths::parent_dir(path));
+        }
+        for path in &owned_paths {
+            rules.allow_write(path);
+        }
+        if let (Transport::Tcp, Some(address)) =
+            (configuration.transport, &configuration.wican_host)
+        {
+            rules.allow_tcp_port(address.port);
+        }
+        for url in &configuration.api_url {
+            rules.allow_url(url)?;
+        }
+        for url in configuration
+            .wican_device
+            .iter()
+            .filter_map(|device| device.api_url.as_ref())
+        {
+            rules.allow_url(url)?;
+        }
+        if let Some(url) = &configuration.api_session_url {
+            rules.allow_url(url)?;
+        }
+        if let Some(url) = &configuration.api_events_url {
+            rules.allow_url(url)?;
+        }
+        if let Some(url) = &configuration.api_websocket_url {
+            rules.allow_url(url)?;
+        }
+        if let Some(url) = &configuration.mqtt_url {
+            rules.allow_url(url)?;
+        }
+        if let Some(url) = &configuration.grpc_url {
+            rules.allow_url(url)?;
+        }
+        if let Some(url) = &configuration.redis_url {
+            rules.allow_url(url)?;
+        }
+        if let Some(url) = &configuration.postgres_url {
+            rules.allow_url(url)?;
+        }
+        if let Some(url) = &configuration.homeassistant_url {
+            rules.allow_url(url)?;
+        }
+        if configuration.abrp_token.is_some() {
+            rules.allow_url(&configuration.abrp_url)?;
+        }
+        for output in &configuration.output {
+            match &output.kind {
+                OutputKind::Http(url) => rules.allow_url(url.as_str())?,
+                // The file is replaced through a temporary file next to it
+                OutputKind::File(path) => rules.allow_write(paths::parent_dir(path)),
+                OutputKind::Stdout => {}
+            }
+        }
+        if let Some(url) = &configuration.smtp_url {
+            rules.allow_url(url)?;
+        }
+        if configuration.carbon_intensity_region.is_some() {
+            rules.allow_url(configuration.carbon_intensity_provider.url())?;
+        }
+        for url in [
+            &configuration.ovms_url,
+            &configuration.ovms_mqtt_url,
+            &configuration.wican_mqtt_url,
+        ]
+        .into_iter()
+        .flatten()
+        {
+            rules.allow_url(url)?;
+        }
+        sandbox::restrict(rules)?;
+    }
+
+    let api = Arc::new(ApiClient::new(ApiOptions {
+        urls: configuration.api_url.clone(),
+        expected_version: configuration.api_expected_version.clone(),
+        send_timestamp: configuration.api_send_timestamp,
+        send_idempotency: configuration.api_send_idempotency,
+        send_link_quality: configuration.api_send_link_quality,
+        omit_fields: configuration.api_omit_field.clone(),
+        conditional_update: configuration.api_conditional_update,
+        connect_timeout: Duration::from_secs(configuration.api_connect_timeout_seconds as u64),
+        read_timeout: Duration::from_secs(configuration.api_read_timeout_seconds as u64),
+        user_agent: configuration.api_user_agent.clone(),
+        resolve: configuration.api_resolve.clone(),
+        pool_idle_timeout: seconds_or_none(configuration.api_pool_idle_timeout_seconds),
+        tcp_keepalive: seconds_or_none(configuration.api_tcp_keepalive_seconds),
+        http2_prior_knowledge: configuration.api_http2_prior_knowledge,
+        http2_keep_alive_interval: seconds_or_none(configuration.api_http2_keep_alive_seconds),
+        timeout: seconds_or_none(configuration.api_timeout_seconds),
+        token: configuration
+            .api_token
+            .as_ref()
+            .map(|secret| secret.expose().to_string()),
+        username: configuration.api_username.clone(),
+        password: configuration
+            .api_password
+            .as_ref()
+            .map(|secret| secret.expose().to_string()),
+        ca_cert: api_ca_cert,
+        insecure_tls: configuration.api_insecure_tls,
+        retry_queue_size: configuration.api_retry_queue_size,
+        retry_max_age: (configuration.api_retry_max_age_minutes > 0)
+            .then(|| Duration::from_secs(configuration.api_retry_max_age_minutes as u64 * 60)),
+        retry_queue_file: configuration
+            .api_retry_queue_persist
+            .then(|| state_dir.join(queue::RETRY_QUEUE_FILE)),
+        batch: configuration.api_batch,
+        dry_run: configuration.dry_run,
+        hmac_secret: configuration
+            .api_hmac_secret
+            .as_ref()
+            .map(|secret| secret.expose().to_string()),
+        hmac_header: configuration.api_hmac_header.clone(),
+    })?);
+
+    let email_alerts = match &configuration.smtp_url {
+        Some(url) => Some(EmailAlerts::new(EmailOptions {
+            url: url.clone(),
+            password: configuration.smtp_password.clone(),
+            from: configuration.email_from.clone(),
+            to: configuration.email_to.clone(),
+            events: configuration.email_events.clone(),
+        })?),
+        None => None,
+    };
+
+    match &configuration.command {
+        Some(Command::TestPost(battery_data)) => {
+            return api::test_post(&api, &battery_data.as_ref().clone().stamp()).await;
+        }
+        Some(Command::TestEmail) => {
+            let alerts = email_alerts.context("--smtp-url is required to send a test email")?;
+            alerts.send_test().await?;
+            println!(
+                "Sent a test email to {}.",
+                configuration.email_to.join(", ")
+            );
+            return Ok(());
+        }
+        Some(Command::Probe) => {
+            let wican_mac_address = configuration
+                .wican_mac_address
+                .context("--wican-mac-address is required to probe a device")?;
+            let bluez = BluezSession::open(wican_passkey).await?;
+            let device = connect_to_device(
+                &bluez.adapter,
+                wican_mac_address,
+                Duration::from_secs(configuration.wican_timeout as u64),
+                // Probing is used to diagnose non-standard dongles, so never refuse them
+                false,
+            )
+            .await
+            .context("Failed to connect to device")?;
+            return probe::probe_device(&device).await;
+        }
+        Some(Command::SendCommand { commands }) => {
+            let mut session = CommandSession::open(&configuration, wican_passkey).await?;
+            for command in commands {
+                println!("> {}", command);
+                match passthrough::exchange(
+                    &mut session.writer,
+                    &mut session.responses,
+                    command,
+                    session.dongle,
+                    configuration.response_timeout(),
+                )
+                .await
+                {
+                    Ok(reply) => println!("{}", reply),
+                    Err(e) => println!("Failed: {:#}", e),
+                }
+            }
+            return Ok(());
+        }
+        Some(Command::WicanConfig {
+            command,
+            get_command,
+            set_command,
+            reboot_command,
+        }) => {
+            let mut session = CommandSession::open(&configuration, wican_passkey).await?;
+            if session.dongle.is_elm327() {
+                return Err(anyhow!(
+                    "The {} has no AutoPID configuration",
+                    session.dongle
+                ));
+            }
+            let commands = wicanconfig::ConfigCommands {
+                get: get_command.clone(),
+                set: set_command.clone(),
+                reboot: reboot_command.clone(),
+            };
+            return wicanconfig::run(
+                command,
+                &commands,
+                &mut session.writer,
+                &mut session.responses,
+                session.dongle,
+                configuration.response_timeout(),
+            )
+            .await;
+        }
+        Some(Command::Doctor) => {
+            let mut files = Vec::new();
+            if let Some(path) = &log_file_path {
+                files.push(("Log file", path.clone()));
+            }
+            if let Some(path) = &configuration.events_file {
+                files.push(("Events file", path.clone()));
+            }
+            if let Some(path) = &configuration.status_file {
+                files.push(("Status file", path.clone()));
+            }
+            let options = doctor::DoctorOptions {
+                state_dir: state_dir.clone(),
+                files,
+            };
+            return doctor::doctor(&api, options).await;
+        }
+        Some(Command::SelfTest) => {
+            let vehicle = Vehicle {
+                battery_capacity_wh: configuration
+                    .vehicle_battery_capacity
+                    .context("--vehicle-battery-capacity is required for the self-test")?,
+                soc_display_curve: configuration.soc_display_curve.clone(),
+                temperature_unit: configuration.wican_temperature_unit,
+                soc_pid: configuration.obd_soc_pid.clone(),
+                temperature_pid: configuration.obd_temperature_pid.clone(),
+                cell_temperature_min_pid: configuration.obd_cell_temperature_min_pid.clone(),
+                cell_temperature_max_pid: configuration.obd_cell_temperature_max_pid.clone(),
+                coolant_temperature_pid: configuration.obd_coolant_temperature_pid.clone(),
+                hvac_power_pid: configuration.obd_hvac_power_pid.clone(),
+                speed_pid: configuration.obd_speed_pid.clone(),
+                soh_pid: configuration.obd_soh_pid.clone(),
+                extra_pids: configuration.wican_extra_pid.clone(),
+                wake_sequence: configuration.wican_wake_command.clone(),
+                pid_group_intervals: configuration.obd_group_interval.clone(),
+                capacity_basis: configuration.vehicle_capacity_basis,
+                usable_capacity_percentage: configuration.vehicle_usable_capacity_percentage,
+                consumption_wh_per_km: configuration.vehicle_consumption_wh_per_km,
+                max_ac_charging_kw: configuration.vehicle_max_ac_charging_kw,
+                drive_model: configuration.drive_model(),
+                autopid_keys: AutopidKeys::new(
+                    configuration.vehicle_profile,
+                    &configuration.autopid_key,
+                ),
+                tag: None,
+            };
+            let wican_timeout = Duration::from_secs(configuration.wican_timeout as u64);
+            let options = selftest::SelfTestOptions {
+                wican_mac_address: configuration
+                    .wican_mac_address
+                    .context("--wican-mac-address is required for the self-test")?,
+                wican_passkey,
+                wican_timeout,
+                response_timeout: configuration.response_timeout(),
+                write_type: configuration.wican_write_type,
+                verify_service: !configuration.wican_skip_service_check,
+                dongle: configuration.dongle,
+            };
+            return selftest::self_test(&api, &vehicle, options).await;
+        }
+        Some(Command::Replay { file, speed }) => {
+            return replay::replay(&api, file, *speed).await;
+        }
+        Some(Command::ImportCsv {
+            file,
+            timestamp_column,
+            timestamp_format,
+            soc_column,
+            temperature_column,
+        }) => {
+            let columns = history::CsvColumns {
+                timestamp: timestamp_column.clone(),
+                timestamp_format: timestamp_format.clone(),
+                soc: soc_column.clone(),
+                temperature: temperature_column.clone(),
+            };
+            return history::import_csv(&HistoryStore::new(&state_dir), file, &columns);
+        }
+        Some(Command::ChargingCurves { session, format }) => {
+            return curve::export(&state_dir, session.as_deref(), *format);
+        }
+        Some(Command::ChargingSessions) => {
+            return cost::list(&state_dir);
+        }
+        Some(Command::Simulate {
+            response,
+            script,
+            broadcast_seconds,
+            name,
+        }) => {
+            return simulate::simulate(simulate::SimulateOptions {
+                responses: response.clone(),
+                script: script.clone(),
+                broadcast_interval: seconds_or_none(*broadcast_seconds),
+                name: name.clone(),
+            })
+            .await;
+        }
+        Some(Command::Bench { iterations }) => {
+            let wican_timeout = Duration::from_secs(configuration.wican_timeout as u64);
+            let options = bench::BenchOptions {
+                wican_mac_address: configuration
+                    .wican_mac_address
+                    .context("--wican-mac-address is required for the benchmark")?,
+                wican_passkey,
+                wican_timeout,
+                response_timeout: configuration.response_timeout(),
+                write_type: configuration.wican_write_type,
+                verify_service: !configuration.wican_skip_service_check,
+                dongle: configuration.dongle,
+                iterations: *iterations,
+            };
+            return bench::bench(options).await;
+        }
+        _ => {}
+    }
+
+    // Required arguments are enforced by clap when no subcommand is given
+    let devices = configuration.devices();
+    let vehicle = Vehicle {
+        battery_capacity_wh: configuration
+            .vehicle_battery_capacity
+            .or_else(|| {
+                devices
+                    .iter()
+                    .all(|device| device.battery_capacity_wh.is_some())
+                    .then(|| devices.first().and_then(|device| device.battery_capacity_wh))
+                    .flatten()
+            })
+            .context(
+                "Vehicle battery capacity is required, with --vehicle-battery-capacity or for every --wican-device",
+            )?,
+        soc_display_curve: configuration.soc_display_curve.clone(),
+        temperature_unit: configuration.wican_temperature_unit,
+        soc_pid: configuration.obd_soc_pid.clone(),
+        temperature_pid: configuration.obd_temperature_pid.clone(),
+        cell_temperature_min_pid: configuration.obd_cell_temperature_min_pid.clone(),
+        cell_temperature_max_pid: configuration.obd_cell_temperature_max_pid.clone(),
+        coolant_temperature_pid: configuration.obd_coolant_temperature_pid.clone(),
+        hvac_power_pid: configuration.obd_hvac_power_pid.clone(),
+        speed_pid: configuration.obd_speed_pid.clone(),
+        soh_pid: configuration.obd_soh_pid.clone(),
+        extra_pids: configuration.wican_extra_pid.clone(),
+        wake_sequence: configuration.wican_wake_command.clone(),
+        pid_group_intervals: configuration.obd_group_interval.clone(),
+        capacity_basis: configuration.vehicle_capacity_basis,
+        usable_capacity_percentage: configuration.vehicle_usable_capacity_percentage,
+        consumption_wh_per_km: configuration.vehicle_consumption_wh_per_km,
+        max_ac_charging_kw: configuration.vehicle_max_ac_charging_kw,
+        drive_model: configuration.drive_model(),
+        autopid_keys: AutopidKeys::new(configuration.vehicle_profile, &configuration.autopid_key),
+        tag: None,
+    };
+    bluez::set_passkeys(
+        devices
+            .iter()
+            .filter_map(|device| {
+                device
+                    .passkey_number()
+                    .transpose()
+                    .map(|passkey| passkey.map(|passkey| (device.address, passkey)))
+            })
+            .collect::<Result<_>>()?,
+    );
+    if configuration.wican_streaming {
+        info!("WiCAN Client starting in streaming mode.");
+    } else if raw_frames.is_some() {
+        info!(
+            "WiCAN Client starting in raw frame mode. Update frequency is {} minute(s).",
+            configuration.wican_update_frequency_minutes
+        );
+    } else {
+        info!(
+            "WiCAN Client starting. Update frequency is {} minute(s).",
+            configuration.wican_update_frequency_minutes
+        );
+    }
+
+    let keep_alive_interval = seconds_or_none(configuration.wican_keep_alive_seconds);
+    let keep_alive_command = format!("{}\n", configuration.wican_keep_alive_command);
+
+    let session_monitor = match (
+        &configuration.api_session_url,
+        &configuration.api_events_url,
+    ) {
+        (None, None) => None,
+        (url, events_url) => {
+            let monitor = SessionMonitor::new(
+                api.aa_proxy_client(),
+                url.as_deref(),
+                &configuration.api_session_field,
+                Duration::from_secs(configuration.api_session_check_seconds as u64),
+            );
+            Some(match events_url {
+                Some(events_url) => monitor.with_events(events_url),
+                None => monitor,
+            })
+        }
+    };
+
+    status::update(&STATS);
+
+    if let Some(alerts) = email_alerts {
+        email::set_alerts(alerts);
+    }
+    hooks::set_hooks(hooks::Hooks::new(
+        configuration.hook_pre_poll.clone(),
+        configuration.hook_post_sample.clone(),
+        configuration.hook_charge_start.clone(),
+        configuration.hook_error.clone(),
+        configuration.hook_low_soc.clone(),
+        Duration::from_secs(configuration.hook_timeout_seconds as u64),
+    ));
+    CONTROL.configure(configuration.wican_update_frequency_minutes);
+    if configuration.adaptive_polling {
+        adaptive::configure(AdaptiveOptions {
+            charging: Duration::from_secs(configuration.adaptive_charging_seconds as u64),
+            driving: Duration::from_secs(configuration.adaptive_driving_seconds as u64),
+            parked: Duration::from_secs(configuration.adaptive_parked_seconds as u64),
+            parked_after_failures: configuration.adaptive_parked_after_failures,
+        });
+    }
+    if configuration.wican_write_spacing_ms > 0 || configuration.wican_write_budget.is_some() {
+        throttle::configure(ThrottleOptions {
+            min_spacing: Duration::from_millis(configuration.wican_write_spacing_ms as u64),
+            budget: configuration.wican_write_budget,
+        });
+    }
+    if let Some(listener) = control_listener {
+        info!(
+            "Listening for commands on {}.",
+            control_socket_path.display()
+        );
+        tokio::spawn(control_socket::serve(listener));
+    }
+    if let (Some(listener), Some(token)) = (admin_listener, configuration.admin_token.clone()) {
+        tokio::spawn(admin::serve(listener, token, api.clone()));
+    }
+    if let Some(listener) = status_listener {
+        tokio::spawn(status::serve(
+            listener,
+            Duration::from_secs(configuration.status_max_sample_age_minutes as u64 * 60),
+        ));
+    }
+    if let Some(poll_trigger) = poll_trigger {
+        tokio::spawn(poll_trigger.run());
+    }
+    if let Some(interval) = seconds_or_none(configuration.api_retry_interval_seconds) {
+        if configuration.api_retry_queue_size > 0 {
+            tokio::spawn(api.clone().replay_queued(interval));
+        }
+    }
+    if let Some(config_watcher) = config_watcher {
+        let api = api.clone();
+        let mut settings = LiveSettings::from(&configuration);
+        tokio::spawn(config_watcher.run(move || settings.reload(&args, &api)));
+    }
+    if let Some(region) = &configuration.carbon_intensity_region {
+        let carbon_intensity = CarbonIntensity::new(
+            CarbonIntensityOptions {
+                provider: configuration.carbon_intensity_provider,
+                region: region.clone(),
+                token: configuration.carbon_intensity_token.clone(),
+                threshold: configuration.carbon_intensity_threshold,
+            },
+            api.http_client(),
+        )?;
+        tokio::spawn(carbon_intensity.run());
+    }
+    if configuration.dbus_control {
+        if let Err(e) = dbus_control::serve().await {
+            warn!("D-Bus control interface unavailable: {:#}", e);
+        }
+    }
+
+    let configuration_summary = configuration.summary();
+    tokio::spawn(async move {
+        let mut signals = match signal(SignalKind::user_defined1()) {
+            Ok(signals) => signals,
+            Err(e) => {
+                warn!(
+                    "Could not listen for SIGUSR1, statistics dumps are unavailable: {}",
+                    e
+                );
+                return;
+            }
+        };
+        while signals.recv().await.is_some() {
+            STATS.dump(&configuration_summary);
+        }
+    });
+    tokio::spawn(async move {
+        let mut signals = match signal(SignalKind::user_defined2()) {
+            Ok(signals) => signals,
+            Err(e) => {
+                warn!(
+                    "Could not listen for SIGUSR2, signal triggered polls are unavailable: {}",
+                    e
+                );
+                return;
+            }
+        };
+        while signals.recv().await.is_some() {
+            CONTROL.poll_now();
+        }
+    });
+
+    let shutdown = ShutdownSignals::listen();
+
+    let mut control = CONTROL.listen();
+    let mut first_run = true;
+    let mut last_device: Option<(Device, Dongle)> = None;
+    let mut detected_dongle: Option<Dongle> = None;
+    let mut source_metadata: Option<SourceMetadata> = None;
+    // Read once per run, None inside when the device doesn't report one
+    let mut firmware_version: Option<Option<String>> = None;
+    let mut connected = false;
+    let mut persistent: Option<PersistentConnection> = None;
+    // Everything a sample is delivered to, aa-proxy-rs first, in the order
+    // they are sent to
+    let mut sinks: Vec<Box<dyn Sink + '_>> = vec![Box::new(AaProxySink::new(
+        &api,
+        configuration
+            .api_websocket_url
+            .as_deref()
+            .map(WebSocketSink::new)
+            .transpose()?,
+        configuration.record_file.as_ref().map(|path| {
+            DataRecorder::new(RecorderOptions {
+                path: path.clone(),
+                format: configuration.record_format,
+                max_bytes: configuration.record_max_size_mb * 1024 * 1024,
+                max_files: configuration.record_max_files,
+            })
+        }),
+    ))];
+    if let Some(url) = &configuration.mqtt_url {
+        sinks.push(Box::new(MqttSink::connect(MqttSinkOptions {
+            connection: MqttConnectOptions {
+                url: url.clone(),
+                client_id: configuration.mqtt_client_id.clone(),
+                version: configuration.mqtt_version,
+                username: configuration.mqtt_username.clone(),
+                password: configuration.mqtt_password.clone(),
+                tls: mqtt_tls.unwrap_or_default(),
+                session_expiry: configuration.mqtt_session_expiry_seconds,
+                message_expiry: configuration.mqtt_message_expiry_seconds,
+            },
+            topic_prefix: configuration.mqtt_topic_prefix.clone(),
+            layout: configuration.mqtt_layout,
+            json_topic: configuration.mqtt_json_topic.clone(),
+            field_topic: configuration.mqtt_field_topic.clone(),
+            qos: configuration.mqtt_qos,
+            retain: configuration.mqtt_retain,
+            availability_topic: configuration.mqtt_availability_topic.clone(),
+            discovery: configuration
+                .mqtt_homeassistant_discovery
+                .then(|| MqttDiscoveryOptions {
+                    discovery_prefix: configuration.mqtt_homeassistant_discovery_prefix.clone(),
+                    device_name: configuration.mqtt_homeassistant_device_name.clone(),
+                    entity_prefix: configuration.homeassistant_entity_prefix.clone(),
+                }),
+        })?));
+    }
+    if let Some(command) = &configuration.exec_sink {
+        sinks.push(Box::new(ExecSink::new(
+            command.clone(),
+            configuration.exec_sink_mode,
+            Duration::from_secs(configuration.exec_sink_timeout_seconds as u64),
+            configuration.exec_sink_retry_queue_size,
+        )));
+    }
+    if let Some(url) = &configuration.grpc_url {
+        sinks.push(Box::new(GrpcSink::connect(url)?));
+    }
+    if let Some(url) = &configuration.redis_url {
+        sinks.push(Box::new(RedisSink::new(RedisSinkOptions {
+            url: url.clone(),
+            password: configuration.redis_password.clone(),
+            channel: configuration.redis_channel.clone(),
+            key: configuration.redis_key.clone(),
+            key_ttl: seconds_or_none(configuration.redis_key_ttl_seconds),
+        })?));
+    }
+    if let Some(url) = &configuration.postgres_url {
+        sinks.push(Box::new(PostgresSink::new(PostgresSinkOptions {
+            url: url.clone(),
+            password: configuration.postgres_password.clone(),
+            table: configuration.postgres_table.clone(),
+            batch_size: configuration.postgres_batch_size,
+        })?));
+    }
+    match (
+        &configuration.homeassistant_url,
+        &configuration.homeassistant_token,
+    ) {
+        (Some(url), Some(token)) => sinks.push(Box::new(HomeAssistantSink::new(
+            HomeAssistantOptions {
+                url: url.clone(),
+                token: token.clone(),
+                entity_prefix: configuration.homeassistant_entity_prefix.clone(),
+            },
+            api.http_client(),
+        )?)),
+        (Some(_), None) => return Err(anyhow!("--homeassistant-url needs --homeassistant-token")),
+        _ => {}
+    }
+    if let Some(token) = &configuration.abrp_token {
+        sinks.push(Box::new(AbrpSink::new(
+            AbrpOptions {
+                url: configuration.abrp_url.clone(),
+                token: token.clone(),
+                api_key: configuration.abrp_api_key.clone(),
+                car_model: configuration.abrp_car_model.clone(),
+            },
+            api.http_client(),
+        )?));
+    }
+    for output in &configuration.output {
+        sinks.push(output.sink(api.http_client()));
+    }
+    let outputs = Outputs {
+        merge: ((!devices.is_empty()
+            || configuration.wican_discover
+            || configuration.simulate.is_some()
+            || configuration.transport != Transport::Ble)
+            && (configuration.ovms_url.is_some() || configuration.ovms_mqtt_url.is_some()))
+        .then(|| {
+            SampleMerger::new(MergeOptions {
+                priority: configuration.merge_priority.clone(),
+                field_priorities: configuration.merge_field_priority.clone(),
+                max_age: Duration::from_secs(configuration.merge_max_age_seconds as u64),
+            })
+        }),
+        history: configuration.history.then(|| HistoryStore::new(&state_dir)),
+        last_sample: (configuration.last_sample_max_age_hours > 0)
+            .then(|| LastSample::new(&state_dir)),
+        plugins: Plugins::load(&configuration.wasm_plugin, api.http_client()).await?,
+        sinks,
+        curves: configuration.record_charging_curves.then(|| {
+            CurveRecorder::new(
+                &state_dir,
+                Duration::from_secs(configuration.charging_curve_interval_seconds.max(1) as u64),
+            )
+        }),
+        departure: configuration
+            .departure_time
+            .map(|time| DeparturePlan::new(time, configuration.departure_target_soc)),
+        costs: (configuration.tariff.is_some() || configuration.carbon_intensity_region.is_some())
+            .then(|| {
+                CostTracker::new(
+                    &state_dir,
+                    configuration.tariff.clone(),
+                    configuration.tariff_currency.clone(),
+                )
+            }),
+        soc_poll_rules: configuration.soc_poll_rule.clone(),
+        low_soc: configuration.low_soc_threshold.map(LowSocAlert::new),
+        soc_filter: (configuration.soc_max_delta.is_some()
+            || configuration.soc_smoothing.is_some())
+        .then(|| {
+            SocFilter::new(SocFilterOptions {
+                max_delta: configuration.soc_max_delta,
+                max_rejections: configuration.soc_max_rejections,
+                smoothing: configuration.soc_smoothing,
+            })
+        }),
+        max_ac_charging_kw: configuration.vehicle_max_ac_charging_kw,
+    };
+
+    if let Some(last_sample) = &outputs.last_sample {
+        let max_age = Duration::from_secs(configuration.last_sample_max_age_hours as u64 * 3600);
+        match last_sample.load(max_age) {
+            Ok(Some(sample)) => {
+                info!(
+                    "Posting the last sample, read at {}, until the first poll completes.",
+                    sample.timestamp.unwrap_or_default()
+                );
+                let api = api.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = api.submit(sample).await {
+                        log_post_error(&e);
+                    }
+                });
+            }
+            Ok(None) => {}
+            Err(e) => warn!("Failed to load the last sample: {:#}", e),
+        }
+    }
+    systemd::ready();
+
+    let ovms_source = match (&configuration.ovms_url, &configuration.ovms_mqtt_url) {
+        (Some(url), _) => Some(OvmsSource::Http {
+            url: url.clone(),
+            password: configuration.ovms_password.clone(),
+        }),
+        (None, Some(url)) => Some(OvmsSource::Mqtt {
+            connection: MqttConnectOptions {
+                url: url.clone(),
+                client_id: format!("{}-ovms", configuration.mqtt_client_id),
+                version: MqttVersion::V311,
+                username: configuration.ovms_mqtt_username.clone(),
+                password: configuration.ovms_mqtt_password.clone(),
+                tls: TlsFiles::default(),
+                session_expiry: None,
+                message_expiry: None,
+            },
+            topic_prefix: configuration.ovms_mqtt_topic_prefix.clone(),
+        }),
+        (None, None) => None,
+    };
+    let ovms = ovms_source.map(|source| {
+        let metadata = configuration
+            .api_send_metadata
+            .then(|| SourceMetadata::new(None));
+        source.run(&api, &vehicle, &outputs, metadata)
+    });
+    // None when simulating or for the transports other than Bluetooth, and
+    // Some(None) for a WiCAN that is yet to be discovered
+    let wican_mac_address = match (
+        configuration.transport,
+        devices.first().map(|device| device.address),
+    ) {
+        _ if configuration.simulate.is_some() => None,
+        (Transport::Tcp | Transport::Mqtt, _) => None,
+        (Transport::Ble, Some(address)) => Some(Some(address)),
+        (Transport::Ble, None) if configuration.wican_discover => Some(None),
+        (Transport::Ble, None) => {
+            return match ovms {
+                Some(ovms) => shutdown::run_until_signal(ovms, shutdown, false, false).await,
+                None => Err(anyhow!("WiCAN MAC address is required")),
+            };
+        }
+    };
+    let wican = async {
+        let Some(wican_mac_address) = wican_mac_address else {
+            let source = configuration
+                .api_send_metadata
+                .then(|| SourceMetadata::new(None));
+            if let Some(mock) = &configuration.simulate {
+                return mock::poll(mock, &api, &vehicle, &outputs, source).await;
+            }
+            if configuration.transport == Transport::Mqtt {
+                let url = configuration
+                    .wican_mqtt_url
+                    .clone()
+                    .context("--wican-mqtt-url is required with --transport mqtt")?;
+                let topic = configuration
+                    .wican_mqtt_topic
+                    .clone()
+                    .context("--wican-mqtt-topic is required with --transport mqtt")?;
+                return transport::subscribe_mqtt(
+                    MqttOptions {
+                        connection: MqttConnectOptions {
+                            url,
+                            client_id: format!("{}-wican", configuration.mqtt_client_id),
+                            version: MqttVersion::V311,
+                            username: configuration.wican_mqtt_username.clone(),
+                            password: configuration.wican_mqtt_password.clone(),
+                            tls: TlsFiles::default(),
+                            session_expiry: None,
+                            message_expiry: None,
+                        },
+                        topic,
+                    },
+                    &vehicle,
+                    &outputs,
+                    source,
+                )
+                .await;
+            }
+            let address = configuration
+                .wican_host
+                .clone()
+                .context("--wican-host is required with --transport tcp")?;
+            return transport::poll_tcp(
+                TcpOptions {
+                    address,
+                    dongle: Dongle::over_tcp(configuration.dongle),
+                    connect_timeout: Duration::from_secs(configuration.wican_timeout as u64),
+                    response_timeout: configuration.response_timeout(),
+                },
+                &api,
+                &vehicle,
+                &outputs,
+                source,
+            )
+            .await;
+        };
+        if let Err(e) = bluez::watch_restarts().await {
+            warn!("Could not watch for bluetoothd restarts: {:#}", e);
+        }
+        let mut bluez = BluezConnection::new(wican_passkey)
+            .with_recovery(configuration.bluetooth_adapter_recovery_failures);
+        let mut wican_mac_address = match wican_mac_address {
+            Some(address) => address,
+            None => {
+                let address = discover_wican(&mut bluez, &configuration).await;
+                if configuration.wican_remember_discovered {
+                    if let Err(e) = DiscoveredAddress::new(&state_dir).save(address) {
+                        warn!("Failed to remember the WiCAN address: {:#}", e);
+                    }
+                }
+                address
+            }
+        };
+        devices::set_store(DeviceStore::new(&state_dir));
+        let devices = match devices.is_empty() {
+            true => vec![DeviceEntry::new(wican_mac_address)],
+            false => devices.clone(),
+        };
+        // The filters and estimates kept per car tell the cars apart by tag
+        if devices.len() > 1 {
+            let mut tags = HashSet::new();
+            for device in &devices {
+                match &device.tag {
+                    Some(tag) if !tags.insert(tag) => {
+                        return Err(anyhow!("The tag '{}' is given to more than one car", tag))
+                    }
+                    Some(_) => {}
+                    None => {
+                        return Err(anyhow!(
+                            "With several cars each --wican-device needs a tag=, {} has none",
+                            device.address
+                        ))
+                    }
+                }
+            }
+        }
+        for device in &devices {
+            // The IRK given on the command line is the --wican-mac-address one
+            let configured_irk = configuration.wican_irk.filter(|_| devices.len() == 1);
+            let bluez_irk = bluez_irks
+                .iter()
+                .find(|(address, _)| *address == device.address)
+                .map(|(_, irk)| *irk);
+            remember_identity(device.address, configured_irk.or(bluez_irk));
+        }
+        let mut known_device = devices::get(wican_mac_address).unwrap_or_default();
+        if let Some(alias) = &known_device.alias {
+            info!("Using device {} '{}'.", wican_mac_address, alias);
+        }
+        // With several WiCANs, the next is tried straight away when one can't
+        // be reached until each has been tried once, and the one reached is
+        // kept for as long as it answers
+        let mut device_index = 0;
+        let mut untried_devices = devices.len() - 1;
+        let mut switched_device = false;
+        let mut device_vehicle = devices[0].vehicle(&vehicle, &configuration.autopid_key);
+        let mut wake_detector = (configuration.wican_wake_voltage.is_some()
+            || !configuration.wican_wake_pattern.is_empty())
+        .then(|| {
+            WakeDetector::new(WakeOptions {
+                voltage: configuration.wican_wake_voltage,
+                voltage_interval: Duration::from_secs(
+                    configuration.wican_wake_voltage_interval_seconds.max(1) as u64,
+                ),
+                patterns: configuration.wican_wake_pattern.clone(),
+            })
+        });
+        let presence_window = configuration.wican_presence.then(|| {
+            Duration::from_secs(configuration.wican_presence_window_seconds.max(1) as u64)
+        });
+        if let Some(url) = &devices[0].api_url {
+            api.set_urls(vec![url.clone()]);
+        }
+        loop {
+            let session_active = match &session_monitor {
+                Some(monitor) => monitor.is_active().await,
+                None => true,
+            };
+
+            // Keep-alives only hold the link open for a charging session
+            // followed on a persistent connection
+            let charging = STATS
+                .last_sample
+                .lock()
+                .unwrap()
+                .as_ref()
+                .is_some_and(BatteryData::is_charging);
+            let keep_alive_device = last_device
+                .as_ref()
+                .filter(|_| persistent.is_some() && charging)
+                .map(|(device, dongle)| (device, *dongle));
+            if let (Some(monitor), false) = (&session_monitor, session_active) {
+                match configuration.idle_update_frequency_minutes {
+                    0 => {
+                        info!("No Android Auto session. Pausing updates until one starts...");
+                        tokio::select! {
+                            _ = monitor.wait_until_active() => {}
+                            _ = control.poll_requested() => {}
+                            _ = systemd::feed_watchdog() => {}
+                        }
+                    }
+                    minutes => {
+                        info!(
+                            "No Android Auto session. Sleeping for {} minute(s) or until a session starts...",
+                            minutes
+                        );
+                        tokio::select! {
+                            _ = sleep_with_keep_alive(
+                                Duration::from_secs((minutes as u64) * 60),
+                                keep_alive_device,
+                                configuration.wican_write_type,
+                                keep_alive_interval,
+                                keep_alive_command.as_bytes(),
+                            ) => {}
+                            _ = monitor.wait_until_active() => {}
+                            _ = control.poll_requested() => {}
+                            _ = systemd::feed_watchdog() => {}
+                        }
+                    }
+                }
+            } else if !first_run && !switched_device {
+                let wait = wait_for_next_update(
+                    &mut control,
+                    api.retry_after(),
+                    keep_alive_device,
+                    configuration.wican_write_type,
+                    keep_alive_interval,
+                    keep_alive_command.as_bytes(),
+                );
+                match (persistent.as_mut(), wake_detector.as_mut()) {
+                    (Some(open), Some(detector)) if !CONTROL.is_paused() => {
+                        tokio::select! {
+                            _ = wait => {}
+                            reason = open.wait_for_wake(detector, configuration.wican_write_type) => {
+                                info!("{}. Updating now...", reason);
+                            }
+                        }
+                    }
+                    _ => wait.await,
+                }
+            }
+            first_run = false;
+            switched_device = false;
+            systemd::watchdog();
+            // The cycle starting now answers any poll requested while waiting
+            control.take_poll_request();
+            if CONTROL.take_reconnect_request() {
+                persistent = None;
+                if let Some((device, _)) = last_device.take() {
+                    info!("Disconnecting from {} to reconnect...", wican_mac_address);
+                    if let Err(e) = device.disconnect().await {
+                        warn!("Failed to disconnect from {}: {}", wican_mac_address, e);
+                    }
+                }
+                if connected {
+                    events::emit(Event::Disconnected {
+                        address: wican_mac_address.to_string(),
+                        reason: "Reconnect requested".to_string(),
+                    });
+                    connected = false;
+                    STATS.set_connected(false);
+                }
+            }
+            hooks::pre_poll().await;
+
+            let wican_timeout = Duration::from_secs(configuration.wican_timeout as u64);
+            let response_timeout = configuration.response_timeout();
+            if persistent.as_mut().is_some_and(|open| !open.is_connected()) {
+                info!("The WiCAN disconnected. Reconnecting...");
+                persistent = None;
+                events::emit(Event::Disconnected {
+                    address: wican_mac_address.to_string(),
+                    reason: "Disconnected by the device".to_string(),
+                });
+                connected = false;
+                STATS.set_connected(false);
+            }
+            let device = match match &persistent {
+                Some(open) => Ok(open.device().clone()),
+                None => match bluez.session().await {
+                    Ok(session) => {
+                        let adapter = session.adapter.clone();
+                        let present = match presence_window {
+                            Some(window) => {
+                                presence::check(&adapter, wican_mac_address, window).await
+                            }
+                            None => Ok(()),
+                        };
+                        match present {
+                            Ok(()) => {
+                                connect_to_device(
+                                    &adapter,
+                                    wican_mac_address,
+                                    wican_timeout,
+                                    !configuration.wican_skip_service_check,
+                                )
+                                .await
+                            }
+                            Err(e) => Err(e),
+                        }
+                    }
+                    Err(e) => Err(e),
+                },
+            } {
+                Ok(d) => {
+                    bluez.record_success();
+                    untried_devices = devices.len() - 1;
+                    d
+                }
+                Err(e) => {
+                    // Not hearing the WiCAN is expected while the car is away
+                    // and skips the attempt rather than failing it
+                    if !presence::is_away(&e) {
+                        error!("Failed to connect to device: {:#}. Will retry...", e);
+                        bluez.record_failure(&e).await;
+                        hooks::error(format!("Failed to connect to device: {:#}", e));
+                        STATS.record_connect_failure();
+                        adaptive::record_failure();
+                    }
+                    STATS.set_connected(false);
+                    if connected {
+                        events::emit(Event::Disconnected {
+                            address: wican_mac_address.to_string(),
+                            reason: e.to_string(),
+                        });
+                        connected = false;
+                    }
+                    last_device = None;
+                    if devices.len() > 1 {
+                        device_index = (device_index + 1) % devices.len();
+                        let device = &devices[device_index];
+                        info!("Trying the WiCAN of {}...", device);
+                        wican_mac_address = device.address;
+                        known_device = devices::get(wican_mac_address).unwrap_or_default();
+                        device_vehicle = device.vehicle(&vehicle, &configuration.autopid_key);
+                        api.set_urls(match &device.api_url {
+                            Some(url) => vec![url.clone()],
+                            None => configuration.api_url.clone(),
+                        });
+                        persistent = None;
+                        detected_dongle = None;
+                        firmware_version = None;
+                        source_metadata = None;
+                        if untried_devices > 0 {
+                            untried_devices -= 1;
+                            switched_device = true;
+                        } else {
+                            untried_devices = devices.len() - 1;
+                        }
+                    }
+                    continue;
+                }
+            };
+            let dongle = match detected_dongle {
+                Some(dongle) => dongle,
+                None => {
+                    *detected_dongle.insert(Dongle::detect(&device, configuration.dongle).await)
+                }
+            };
+            last_device = Some((device.clone(), dongle));
+            let link = LinkQuality::read(&device).await;
+            if !connected {
+                info!("Connected to {}, {}", wican_mac_address, link);
+                events::emit(Event::Connected {
+                    address: wican_mac_address.to_string(),
+                });
+                connected = true;
+            }
+            STATS.set_link_quality(link);
+            STATS.set_connected(true);
+
+            let firmware_version = match &firmware_version {
+                Some(firmware_version) => firmware_version.clone(),
+                None => firmware_version
+                    .insert(metadata::read_firmware_version(&device).await)
+                    .clone(),
+            };
+            devices::record(wican_mac_address, |record| {
+                record.last_seen = Some(Utc::now());
+                record.dongle = Some(dongle.to_string());
+                if firmware_version.is_some() {
+                    record.firmware_version = firmware_version.clone();
+                }
+            });
+            if configuration.api_send_metadata && source_metadata.is_none() {
+                source_metadata = Some(SourceMetadata {
+                    wican_mac_address: Some(wican_mac_address.to_string()),
+                    firmware_version,
+                    ..SourceMetadata::new(known_device.vehicle_profile.clone().or_else(|| {
+                        let profile = devices[device_index]
+                            .vehicle_profile
+                            .unwrap_or(configuration.vehicle_profile);
+                        (profile != VehicleProfile::Generic).then(|| profile.to_string())
+                    }))
+                });
+            }
+
+            if configuration.wican_streaming || raw_frames.is_some() {
+                let streamed = match &mut raw_frames {
+                    Some(raw_frames) => {
+                        raw::stream_frames(
+                            &device,
+                            dongle,
+                            &device_vehicle,
+                            &outputs,
+                            source_metadata.as_ref(),
+                            raw_frames,
+                            configuration.wican_write_type,
+                        )
+                        .await
+                    }
+                    None => {
+                        stream_data(
+                            &device,
+                            dongle,
+                            &device_vehicle,
+                            &outputs,
+                            source_metadata.as_ref(),
+                        )
+                        .await
+                    }
+                };
+                let reason = match streamed {
+                    Ok(()) => "Notification stream ended".to_string(),
+                    Err(e) => {
+                        error!("Failed to stream data from device: {}. Will retry...", e);
+                        hooks::error(format!("Failed to stream data from device: {}", e));
+                        e.to_string()
+                    }
+                };
+                events::emit(Event::Disconnected {
+                    address: wican_mac_address.to_string(),
+                    reason,
+                });
+                connected = false;
+                STATS.set_connected(false);
+                continue;
+            }
+
+            if configuration.wican_persistent_connection && persistent.is_none() {
+                match PersistentConnection::open(&device, dongle).await {
+                    Ok(open) => persistent = Some(open),
+                    Err(e) => warn!(
+                        "Could not keep the connection open: {:#}. Reconnecting next poll.",
+                        e
+                    ),
+                }
+            }
+            let gatt_attempts = backoff::policy().attempts(Stage::Gatt);
+            let mut gatt_attempt = 1;
+            let fetched = loop {
+                let fetched = match &mut persistent {
+                    Some(open) => {
+                        open.fetch_data(
+                            &device_vehicle,
+                            response_timeout,
+                            configuration.wican_write_type,
+                        )
+                        .await
+                    }
+                    None => {
+                        fetch_data(
+                            &device,
+                            dongle,
+                            &device_vehicle,
+                            response_timeout,
+                            configuration.wican_write_type,
+                        )
+                        .await
+                    }
+                };
+                match fetched {
+                    Err(e) if gatt_attempt < gatt_attempts => {
+                        let delay = backoff::policy().delay(gatt_attempt);
+                        warn!(
+                            "Failed to fetch data from device: {}. Retrying in {:.1?}...",
+                            e, delay
+                        );
+                        // Subscribe again in case the subscription is what
+                        // failed
+                        persistent = None;
+                        time::sleep(delay).await;
+                        gatt_attempt += 1;
+                    }
+                    fetched => break fetched,
+                }
+            };
+            if let Some(battery_data) = match fetched {
+                Ok(data) => data,
+                Err(e) => {
+                    error!("Failed to fetch data from device: {}. Will retry...", e);
+                    hooks::error(format!("Failed to fetch data from device: {}", e));
+                    adaptive::record_failure();
+                    // Subscribe again on the next poll in case the
+                    // subscription is what failed
+                    persistent = None;
+                    continue;
+                }
+            } {
+                let battery_data = BatteryData {
+                    source: source_metadata.clone(),
+                    link: LinkQuality::read(&device).await,
+                    ..battery_data
+                };
+                outputs.publish(SampleSource::Wican, battery_data).await;
+            } else {
+                adaptive::record_failure();
+            }
+        }
+    };
+    let run = async {
+        match ovms {
+            Some(ovms) => {
+                info!("Reading from both the WiCAN and the OVMS module, merging their samples.");
+                tokio::select! {
+                    result = ovms => result,
+                    result = wican => result,
+                }
+            }
+            None => wican.await,
+        }
+    };
+    shutdown::run_until_signal(
+        run,
+        shutdown,
+        wican_mac_address.is_some(),
+        configuration.power_off_adapter_on_shutdown,
+    )
+    .await
+}
+
+// Match the resolvable private addresses of a WiCAN with the IRK given or the
+// one remembered for it, remembering a new one
+fn remember_identity(address: Address, irk: Option<Irk>) {
+    let known_device = devices::get(address).unwrap_or_default();
+    let irk = irk.or_else(|| {
+        known_device
+            .irk
+            .as_deref()
+            .and_then(|irk| Irk::from_hex(irk).ok())
+    });
+    if let Some(irk) = irk {
+        rpa::set_identity(address, irk);
+        if known_device.irk.as_deref() != Some(irk.to_hex().as_str()) {
+            devices::record(address, |record| record.irk = Some(irk.to_hex()));
+        }
+    }
+}
+
+// Settings a changed config file takes effect for without a restart
+struct LiveSettings {
+    update_frequency_minutes: u8,
+    api_url: Vec<String>,
+    log_level: LogLevel,
+    // With --sandbox, the hosts and ports of the aa-proxy-rs urls given at
+    // startup, the only ones a reload may post to
+    sandboxed_targets: Option<Vec<(String, u16)>>,
+}
+
+impl From<&Configuration> for LiveSettings {
+    fn from(configuration: &Configuration) -> Self {
+        Self {
+            update_frequency_minutes: configuration.wican_update_frequency_minutes,
+            api_url: configuration.api_url.clone(),
+            log_level: configuration.log_level,
+            sandboxed_targets: configuration.sandbox.then(|| {
+                configuration
+                    .api_url
+                    .iter()
+                    .filter_map(|url| sandbox::target(url))
+                    .collect()
+            }),
+        }
+    }
+}
+
+impl LiveSettings {
+    // Parse the command line and config file again and apply what changed,
+    // keeping the running configuration if the file is now invalid
+    fn reload(&mut self, args: &[OsString], api: &ApiClient) {
+        let configuration = config::matches(Configuration::command(), args).and_then(|matches| {
+            Configuration::from_arg_matches(&matches).map_err(anyhow::Error::from)
+        });
+        let configuration = match configuration {
+            Ok(configuration) => configuration,
+            Err(e) => {
+                error!("Ignoring the changed config file: {:#}", e);
+                return;
+            }
+        };
+        let mut reloaded = LiveSettings::from(&configuration);
+        // The sandbox set up at startup stays as it was
+        reloaded.sandboxed_targets = self.sandboxed_targets.take();
+        if let Some(targets) = &reloaded.sandboxed_targets {
+            let outside = reloaded
+                .api_url
+                .iter()
+                .filter(|url| sandbox::target(url).is_none_or(|target| !targets.contains(&target)))
+                .collect::<Vec<_>>();
+            if !outside.is_empty() {
+                warn!(
+                    "Keeping the aa-proxy-rs url, the sandbox only allows the hosts and ports given at startup. Restart to post to {}.",
+                    outside
+                        .iter()
+                        .map(|url| secrets::redact_url(url))
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                );
+                reloaded.api_url = self.api_url.clone();
+            }
+        }
+        if reloaded.update_frequency_minutes != self.update_frequency_minutes {
+            if let Err(e) = CONTROL.set_update_frequency_minutes(reloaded.update_frequency_minutes)
+            {
+                warn!("Ignoring the changed update frequency: {:#}", e);
+            }
+        }
+        if reloaded.api_url != self.api_url {
+            info!(
+                "Posting to {} from now on.",
+                redacted_urls(&reloaded.api_url)
+            );
+            api.set_urls(reloaded.api_url.clone());
+        }
+        if reloaded.log_level != self.log_level {
+            info!("Log level changed to {:?}.", reloaded.log_level);
+            log::set_max_level(LevelFilter::from(reloaded.log_level));
+        }
+        *self = reloaded;
+    }
+}
+
+// Convert a number of seconds to a duration, treating 0 as disabled
+fn seconds_or_none(seconds: u16) -> Option<Duration> {
+    (seconds > 0).then(|| Duration::from_secs(seconds as u64))
+}
+
+// Scan until a WiCAN is found, for when no MAC address is configured
+async fn discover_wican(bluez: &mut BluezConnection, configuration: &Configuration) -> Address {
+    let options = DiscoveryOptions {
+        name_prefix: configuration.wican_name_prefix.clone(),
+        timeout: Duration::from_secs(configuration.wican_timeout as u64),
+    };
+    let mut attempt = 1;
+    loop {
+        let found = match bluez.session().await {
+            Ok(session) => discovery::discover(&session.adapter, &options).await,
+            Err(e) => Err(e),
+        };
+        match found {
+            Ok(address) => return address,
+            Err(e) => {
+                let delay = backoff::policy().delay(attempt);
+                warn!(
+                    "No WiCAN found: {:#}. Scanning again in {:.1?}...",
+                    e, delay
+                );
+                bluez.record_failure(&e).await;
+                hooks::error(format!("No WiCAN found: {:#}", e));
+                tokio::select! {
+                    _ = time::sleep(delay) => {}
+                    _ = systemd::feed_watchdog() => {}
+                }
+                attempt = attempt.saturating_add(1);
+            }
+        }
+    }
+}
+
+// Connects to wican device, retrying each stage with backoff within its own
+// budget
+async fn connect_to_device(
+    adapter: &Adapter,
+    wican_mac_address: Address,
+    wican_timeout: Duration,
+    verify_service: bool,
+) -> Result<Device> {
+    let retry = backoff::policy();
+    let mut device = retry
+        .retry(Stage::Discovery, || {
+            find_device(adapter, wican_mac_address, wican_timeout)
+        })
+        .await?;
+    bluez::set_wican(&device);
+
+    retry
+        .retry(Stage::Pairing, || try_pair(&device, verify_service))
+        .await?;
+
+    if device.is_connected().await? {
+        info!("Device is already connected. Skipping connection.");
+        return Ok(device);
+    }
+
+    // The pairing is only removed when the device rejects it, as other
+    // failures are usually the car being out of range or asleep
+    let attempts = retry.attempts(Stage::Connect);
+    let mut repaired = false;
+    let mut attempt = 1;
+    loop {
+        info!("Connecting to device... (Attempt {}/{})", attempt, attempts);
+        match device.connect().await {
+            Ok(_) => {
+                info!("Connected successfully!");
+                return Ok(device);
+            }
+            Err(e) if !repaired && bluez::is_bond_rejected(&e) => {
+                warn!(
+                    "Device {} rejected the pairing: {}. Removing it and pairing again...",
+                    device.address(),
+                    e
+                );
+                device = repair(adapter, &device, wican_timeout, verify_service).await?;
+                bluez::set_wican(&device);
+                repaired = true;
+            }
+            Err(e) if attempt < attempts => {
+                let delay = retry.delay(attempt);
+                warn!("Connection failed: {}. Retrying in {:.1?}...", e, delay);
+                time::sleep(delay).await;
+                attempt += 1;
+            }
+            Err(e) => {
+                return Err(anyhow::Error::from(e).context(format!(
+                    "Failed to connect to the device after {} attempts",
+                    attempts
+                )));
+            }
+        }
+    }
+}
+
+// Remove a pairing the device no longer accepts, e.g. after a firmware update
+// wiped its keys, and pair again
+async fn repair(
+    adapter: &Adapter,
+    device: &Device,
+    wican_timeout: Duration,
+    verify_service: bool,
+) -> Result<Device> {
+    let address = device.address();
+    adapter
+        .remove_device(address)
+        .await
+        .context("Failed to remove pairing")?;
+    events::emit(Event::PairingRemoved {
+        address: address.to_string(),
+    });
+    let device = find_device(adapter, rpa::identity(address), wican_timeout).await?;
+    try_pair(&device, verify_service).await?;
+    Ok(device)
+}
+
+// A connection to the WiCAN for the subcommands sending it commands
+struct CommandSession {
+    // Kept open for as long as the device is used
+    _bluez: BluezSession,
+    dongle: Dongle,
+    writer: CommandWriter,
+    responses: Pin<Box<dyn Stream<Item = Vec<u8>>>>,
+}
+
+impl CommandSession {
+    async fn open(configuration: &Configuration, wican_passkey: u32) -> Result<Self> {
+        let wican_mac_address = configuration
+            .wican_mac_address
+            .context("--wican-mac-address is required to send commands")?;
+        let bluez = BluezSession::open(wican_passkey).await?;
+        let device = connect_to_device(
+            &bluez.adapter,
+            wican_mac_address,
+            Duration::from_secs(configuration.wican_timeout as u64),
+            !configuration.wican_skip_service_check,
+        )
+        .await
+        .context("Failed to connect to device")?;
+        let dongle = Dongle::detect(&device, configuration.dongle).await;
+        let (notify_char, write_char) = dongle
+            .characteristics(&device)
+            .await
+            .with_context(|| format!("Failed to find {} characteristics", dongle))?;
+        let responses = Box::pin(dongle.notifications(&notify_char).await?);
+        Ok(Self {
+            _bluez: bluez,
+            dongle,
+            writer: CommandWriter::Ble {
+                characteristic: write_char,
+                write_type: configuration.wican_write_type,
+            },
+            responses,
+        })
+    }
+}
+
+// Submit autopid request and parse as JSON
+async fn fetch_data(
+    device: &Device,
+    dongle: Dongle,
+    vehicle: &Vehicle,
+    response_timeout: Duration,
+    write_type: WriteType,
+) -> Result<Option<BatteryData>> {
+    throttle::start_cycle();
+    if dongle.is_elm327() {
+        return elm327::fetch_data(device, dongle, vehicle, response_timeout, write_type)
+            .await
+            .map(Some);
+    }
+
+    let (notify_char, write_char) = dongle
+        .characteristics(device)
+        .await
+        .context("Failed to find WiCAN characteristics")?;
+
+    let mut notif_stream = Box::pin(dongle.notifications(&notify_char).await?);
+    let mut writer = CommandWriter::Ble {
+        characteristic: write_char,
+        write_type,
+    };
+    request_autopid(
+        &mut notif_stream,
+        &mut writer,
+        dongle,
+        vehicle,
+        response_timeout,
+    )
+    .await
+}
+
+// Send the wake commands and an autopid request, and parse the reply, over
+// whichever transport reaches the WiCAN
+async fn request_autopid<S: Stream<Item = Vec<u8>> + Unpin>(
+    responses: &mut S,
+    writer: &mut CommandWriter,
+    dongle: Dongle,
+    vehicle: &Vehicle,
+    response_timeout: Duration,
+) -> Result<Option<BatteryData>> {
+    wake::send(writer, responses, &vehicle.wake_sequence)
+        .await
+        .context("Failed to send the wake commands")?;
+    writer.write(b"autopid -d\n").await?;
+
+    info!(
+        "Successfully sent WiCAN autopid request. Waiting for a response for up to {:?}...",
+        response_timeout
+    );
+
+    let timeout = time::sleep(response_timeout);
+    tokio::select! {
+        _ = timeout => {
+            warn!("Timeout: No reply from WiCAN received.");
+            Ok(None)
+        }
+        notification = responses.next() => {
+            if let Some(n) = notification {
+                // Taken off the stream first so the replies to the extra PIDs
+                // aren't mixed up with them
+                let frames = queued_frames(responses, n);
+                let extra = passthrough::query(
+                    writer,
+                    responses,
+                    &vehicle.extra_pids,
+                    dongle,
+                    response_timeout,
+                )
+                .await;
+                Ok(Some(parse_latest(frames, vehicle, &extra)?))
+            } else {
+                warn!("Notification stream ended unexpectedly.");
+                Ok(None)
+            }
+        }
+    }
+}
+
+// Decode a WiCAN autopid response and convert it to battery data
+fn parse_response(notification: Vec<u8>, vehicle: &Vehicle) -> Result<BatteryData> {
+    parse_merged_response(notification, vehicle, &Map::new())
+}
+
+// Decode a WiCAN autopid response along with the values of the extra PIDs
+fn parse_merged_response(
+    notification: Vec<u8>,
+    vehicle: &Vehicle,
+    extra: &Map<String, Value>,
+) -> Result<BatteryData> {
+    let wican_response = telemetry::parse_merged(&notification, &vehicle.autopid_keys, extra)?;
+    Ok(battery_data(wican_response, vehicle))
+}
+
+// Convert the values read from the dongle to battery data
+fn battery_data(wican_response: WicanResponse, vehicle: &Vehicle) -> BatteryData {
+    let battery_level_percentage = vehicle.displayed_soc(wican_response.soc, wican_response.soc_d);
+    let celsius = |temperature: Option<f32>| {
+        temperature.map(|temperature| vehicle.temperature_unit.to_celsius(temperature))
+    };
+    let external_temp_celsius = celsius(wican_response.outdoor_temperature);
+
+    match external_temp_celsius {
+        Some(temperature) => info!(
+            "WiCAN reports battery at {:.1}%, outdoor temperature {}",
+            battery_level_percentage,
+            units::display_temperature(temperature)
+        ),
+        None => info!("WiCAN reports battery at {:.1}%", battery_level_percentage),
+    }
+    if wican_response.battery_preconditioning == Some(true) {
+        info!("The battery is being preconditioned.");
+    }
+    let battery_capacity_wh = vehicle.capacity_wh(wican_response.soh);
+    if let Some(soh) = wican_response.soh {
+        debug!(
+            "Battery state of health {:.1}%, usable capacity {} Wh",
+            soh, battery_capacity_wh
+        );
+    }
+    let energy_wh = battery_capacity_wh as f32 * battery_level_percentage / 100.0;
+
+    let mut battery_data = BatteryData {
+        battery_level_percentage: Some(battery_level_percentage),
+        external_temp_celsius,
+        battery_preconditioning: wican_response.battery_preconditioning,
+        plug_inserted: wican_response.plug_inserted,
+        charge_port_open: wican_response.charge_port_open,
+        charging_type: wican_response.charging_type,
+        charging: wican_response.charging,
+        battery_power_kw: wican_response.battery_power_kw,
+        cell_temp_min_celsius: celsius(wican_response.cell_temperature_min),
+        cell_temp_max_celsius: celsius(wican_response.cell_temperature_max),
+        coolant_temp_celsius: celsius(wican_response.coolant_temperature),
+        battery_temp_celsius: celsius(wican_response.battery_temperature),
+        hvac_power_kw: wican_response.hvac_power_kw,
+        speed_kmh: wican_response.speed_kmh,
+        auxiliary_load_kw: wican_response
+            .battery_power_kw
+            .zip(wican_response.speed_kmh)
+            .and_then(|(power, speed)| vehicle.drive_model.auxiliary_load_kw(power, speed)),
+        // Omitted when it doesn't fit the field, above 65.5 kWh
+        battery_level_wh: u16::try_from(energy_wh.round() as u32).ok(),
+        battery_capacity_wh: Some(battery_capacity_wh),
+        state_of_health_percentage: wican_response.soh,
+        odometer_km: wican_response.odometer_km,
+        aux_battery_voltage: wican_response.aux_battery_voltage,
+        estimated_range_km: vehicle.range_km(energy_wh),
+        vehicle_tag: vehicle.tag.clone(),
+        ..Default::default()
+    }
+    .stamp();
+    if let Some(charging_type) = battery_data.charging_type {
+        info!("The vehicle is {} charging.", charging_type);
+        battery_data.charging.get_or_insert(true);
+    }
+    battery_data
+}
+
+// Guess the charging type from how fast the SOC rises when the vehicle doesn't
+// report it. Done only for samples that are passed on, so one dropped by the
+// SOC filter doesn't skew the estimate.
+fn estimate_charging(battery_data: &mut BatteryData, max_ac_charging_kw: f32) {
+    // Not worth estimating when the vehicle says it isn't charging
+    if battery_data.charging_type.is_some() || battery_data.charging == Some(false) {
+        return;
+    }
+    let (Some(soc), Some(timestamp), Some(capacity_wh)) = (
+        battery_data.battery_level_percentage,
+        battery_data.timestamp,
+        battery_data.battery_capacity_wh,
+    ) else {
+        return;
+    };
+    battery_data.charging_type = charging::estimate(
+        battery_data.vehicle_tag.as_deref(),
+        soc,
+        timestamp,
+        capacity_wh,
+        max_ac_charging_kw,
+    );
+    if let Some(charging_type) = battery_data.charging_type {
+        info!("The vehicle is {} charging.", charging_type);
+        battery_data.charging.get_or_insert(true);
+    }
+}
+
+// The received frame followed by any further frames already waiting on the stream
+fn queued_frames<S: Stream<Item = Vec<u8>> + Unpin>(
+    notif_stream: &mut S,
+    notification: Vec<u8>,
+) -> Vec<Vec<u8>> {
+    let mut frames = vec![notification];
+    while let Some(Some(frame)) = notif_stream.next().now_or_never() {
+        frames.push(frame);
+    }
+    frames
+}
+
+// Parse the received frames, returning only the newest so a backlog of frames
+// is never forwarded late. Only the newest is turned into battery data, so the
+// frames discarded use up no sequence number.
+fn parse_latest(
+    frames: Vec<Vec<u8>>,
+    vehicle: &Vehicle,
+    extra: &Map<String, Value>,
+) -> Result<BatteryData> {
+    let frame_count = frames.len();
+    let mut latest = None;
+    let mut parsed = 0;
+    for frame in frames {
+        match telemetry::parse_merged(&frame, &vehicle.autopid_keys, extra) {
+            Ok(response) => {
+                parsed += 1;
+                latest = Some(Ok(response));
+            }
+            Err(e) => {
+                if frame_count > 1 {
+                    warn!("Failed to parse queued WiCAN frame: {}", e);
+                }
+                if latest.is_none() {
+                    latest = Some(Err(e));
+                }
+            }
+        }
+    }
+
+    if parsed > 1 {
+        let discarded = STATS.add_discarded_frames(parsed - 1);
+        info!(
+            "Received {} WiCAN frames, forwarding only the newest. {} frame(s) discarded in total.",
+            parsed, discarded
+        );
+    }
+
+    let response = latest.unwrap_or_else(|| Err(anyhow!("No WiCAN frames to parse")))?;
+    Ok(battery_data(response, vehicle))
+}
+
+// Stay subscribed to WiCAN notifications and post every pushed autopid frame,
+// returning once the notification stream ends
+async fn stream_data(
+    device: &Device,
+    dongle: Dongle,
+    vehicle: &Vehicle,
+    outputs: &Outputs<'_>,
+    source: Option<&SourceMetadata>,
+) -> Result<()> {
+    if dongle.is_elm327() {
+        return Err(anyhow!(
+            "Streaming needs autopid broadcasts, which the {} does not send",
+            dongle
+        ));
+    }
+
+    let (notify_char, _) = dongle
+        .characteristics(device)
+        .await
+        .context("Failed to find WiCAN characteristics")?;
+
+    let mut notif_stream = Box::pin(dongle.notifications(&notify_char).await?);
+    info!("Subscribed to WiCAN notifications. Waiting for autopid broadcasts...");
+
+    while let Some(notification) = notif_stream.next().await {
+        let frames = queued_frames(&mut notif_stream, notification);
+        let battery_data = match parse_latest(frames, vehicle, &Map::new()) {
+            Ok(data) => data,
+            Err(e) => {
+                warn!("Failed to parse WiCAN broadcast: {}. Skipping frame.", e);
+                continue;
+            }
+        };
+
+        let battery_data = BatteryData {
+            source: source.cloned(),
+            link: LinkQuality::read(device).await,
+            ..battery_data
+        };
+        outputs.publish(SampleSource::Wican, battery_data).await;
+    }
+
+    warn!("Notification stream ended.");
+    Ok(())
+}
+
+// Everything a sample is handed to once it has been read from the WiCAN
+struct Outputs<'a> {
+    merge: Option<SampleMerger>,
+    history: Option<HistoryStore>,
+    last_sample: Option<LastSample>,
+    plugins: Plugins,
+    sinks: Vec<Box<dyn Sink + 'a>>,
+    curves: Option<CurveRecorder>,
+    departure: Option<DeparturePlan>,
+    costs: Option<CostTracker>,
+    soc_poll_rules: Vec<SocPollRule>,
+    low_soc: Option<LowSocAlert>,
+    soc_filter: Option<SocFilter>,
+    // For estimating the charging type, which vehicles may not report
+    max_ac_charging_kw: f32,
+}
+
+impl Outputs<'_> {
+    // Run a sample through the plugin transforms, then record, sink and post it
+    async fn publish(&self, source: SampleSource, battery_data: BatteryData) {
+        // A sample getting through shows the loop is alive, also in streaming
+        // mode where there is no wait between polls
+        systemd::watchdog();
+        let mut battery_data = battery_data;
+        if let Some(filter) = &self.soc_filter {
+            if !filter.check(source, &mut battery_data) {
+                return;
+            }
+        }
+        estimate_charging(&mut battery_data, self.max_ac_charging_kw);
+        STATS.record_sample(&battery_data);
+        let mut battery_data = match &self.merge {
+            Some(merge) => merge.merge(source, battery_data),
+            None => battery_data,
+        };
+        let low_soc_started = self
+            .low_soc
+            .as_ref()
+            .is_some_and(|alert| alert.check(&mut battery_data));
+        let battery_data = self.plugins.transform(battery_data).await;
+
+        if let Some(history) = &self.history {
+            if let Err(e) = history.append(&battery_data) {
+                warn!("Failed to record sample in history: {:#}", e);
+            }
+        }
+        if let Some(last_sample) = &self.last_sample {
+            if let Err(e) = last_sample.save(&battery_data) {
+                warn!("Failed to save the last sample: {:#}", e);
+            }
+        }
+        self.plugins.sink(&battery_data).await;
+        for sink in &self.sinks {
+            sink.send(&battery_data).await;
+        }
+
+        events::record_sample(&battery_data);
+        if let Some(curves) = &self.curves {
+            if let Err(e) = curves.record(&battery_data) {
+                warn!("Failed to record the charging curve: {:#}", e);
+            }
+        }
+        if let Some(departure) = &self.departure {
+            departure.update(&battery_data);
+        }
+        if let Some(costs) = &self.costs {
+            if let Err(e) = costs.record(&battery_data) {
+                warn!("Failed to record the charging session: {:#}", e);
+            }
+        }
+        if !self.soc_poll_rules.is_empty() {
+            pollrule::apply(&self.soc_poll_rules, &battery_data);
+        }
+        adaptive::record_sample(&battery_data);
+
+        hooks::post_sample(&battery_data).await;
+
+        if let (true, Some(alert)) = (low_soc_started, &self.low_soc) {
+            events::emit(Event::LowSoc {
+                soc: battery_data.battery_level_percentage.unwrap_or_default(),
+                threshold: alert.threshold(),
+            });
+            hooks::low_soc(&battery_data).await;
+        }
+    }
+}
+
+// Log and journal a failed post, treating rate limiting by aa-proxy-rs as a soft failure
+fn log_post_error(e: &anyhow::Error) {
+    if let Some(rate_limited) = e.downcast_ref::<RateLimited>() {
+        warn!("Battery data not posted: {}.", rate_limited);
+    } else {
+        error!("Failed to post battery data: {}. Will retry...", e);
+        hooks::error(format!("Failed to post battery data: {}", e));
+    }
+    events::emit(Event::PostFailed {
+        error: e.to_string(),
+    });
+}
+
+fn redacted_urls(urls: &[String]) -> String {
+    urls.iter()
+        .map(|url| secrets::redact_url(url))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+// Sleep until the next update is due, waking early when the update frequency
+// changes or an immediate poll is requested and not at all while paused
+async fn wait_for_next_update(
+    control: &mut Listener,
+    retry_after: Option<Duration>,
+    device: Option<(&Device, Dongle)>,
+    write_type: WriteType,
+    keep_alive_interval: Option<Duration>,
+    keep_alive_command: &[u8],
+) {
+    let started = time::Instant::now();
+    let mut announced = None;
+    loop {
+        if control.take_poll_request() {
+            return;
+        }
+
+        if CONTROL.is_paused() {
+            if announced != Some(None) {
+                info!("Polling is paused. Waiting until resumed or a poll is requested...");
+                announced = Some(None);
+            }
+            tokio::select! {
+                _ = control.changed() => {}
+                _ = systemd::feed_watchdog() => {}
+            }
+            continue;
+        }
+
+        let minutes = CONTROL.update_frequency_minutes();
+        let fast_poll = CONTROL.fast_poll_interval();
+        let mut sleep_duration = CONTROL.poll_interval();
+        if announced != Some(Some(sleep_duration)) {
+            match retry_after.filter(|r| *r > sleep_duration) {
+                Some(retry_after) => info!(
+                    "aa-proxy-rs asked us to back off. Sleeping for {:?} before next update...",
+                    retry_after
+                ),
+                None if fast_poll.is_some() => info!(
+                    "Sleeping for {:?} before next update while sampling frequently...",
+                    sleep_duration
+                ),
+                None if CONTROL.state_interval().is_some() => {
+                    info!("Sleeping for {:?} before next update...", sleep_duration)
+                }
+                None => info!("Sleeping for {} minute(s) before next update...", minutes),
+            }
+            announced = Some(Some(sleep_duration));
+        }
+        if let Some(retry_after) = retry_after {
+            sleep_duration = sleep_duration.max(retry_after);
+        }
+
+        let remaining = sleep_duration.saturating_sub(started.elapsed());
+        tokio::select! {
+            _ = sleep_with_keep_alive(
+                remaining,
+                device,
+                write_type,
+                keep_alive_interval,
+                keep_alive_command,
+            ) => return,
+            _ = control.changed() => {}
+            _ = systemd::feed_watchdog() => {}
+        }
+    }
+}
+
+// Sleep until the next update, periodically writing a keep-alive command to the
+// connected device so the WiCAN doesn't drop the GATT link between polls
+async fn sleep_with_keep_alive(
+    duration: Duration,
+    device: Option<(&Device, Dongle)>,
+    write_type: WriteType,
+    keep_alive_interval: Option<Duration>,
+    keep_alive_command: &[u8],
+) {
+    let (Some((device, dongle)), Some(keep_alive_interval)) = (device, keep_alive_interval) else {
+        time::sleep(duration).await;
+        return;
+    };
+
+    let deadline = time::Instant::now() + duration;
+    let mut keep_alive_active = true;
+    loop {
+        let now = time::Instant::now();
+        if now >= deadline {
+            break;
+        }
+
+        if !keep_alive_active {
+            time::sleep_until(deadline).await;
+            break;
+        }
+
+        time::sleep((deadline - now).min(keep_alive_interval)).await;
+        if time::Instant::now() >= deadline {
+            break;
+        }
+
+        if let Err(e) = send_keep_alive(device, dongle, keep_alive_command, write_type).await {
+            warn!(
+                "Failed to send keep-alive to WiCAN: {}. Stopping keep-alives until the next update.",
+                e
+            );
+            keep_alive_active = false;
+        }
+    }
+}
+
+// Write the keep-alive command to the WiCAN
+async fn send_keep_alive(
+    device: &Device,
+    dongle: Dongle,
+    keep_alive_command: &[u8],
+    write_type: WriteType,
+) -> Result<()> {
+    if !device.is_connected().await? {
+        return Err(anyhow!("Device is no longer connected"));
+    }
+
+    let (_, write_char) = dongle
+        .characteristics(device)
+        .await
+        .context("Failed to find WiCAN characteristics")?;
+    write_command(&write_char, keep_alive_command, write_type).await?;
+
+    debug!("Sent keep-alive to WiCAN.");
+    Ok(())
 }
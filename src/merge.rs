@@ -0,0 +1,168 @@
+use anyhow::{anyhow, Result};
+use clap::{CommandFactory, ValueEnum};
+use log::{debug, warn};
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::api;
+use crate::BatteryData;
+
+// Where a sample was read from, when several sources are active at once
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, ValueEnum)]
+pub enum SampleSource {
+    // The WiCAN over Bluetooth, TCP or MQTT, whether autopid, ELM327 or raw
+    // frames
+    Wican,
+    // An OVMS module over its web API or MQTT
+    Ovms,
+}
+
+impl fmt::Display for SampleSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SampleSource::Wican => write!(f, "wican"),
+            SampleSource::Ovms => write!(f, "ovms"),
+        }
+    }
+}
+
+// Sources in order of preference for one field, written
+// FIELD=SOURCE[:SOURCE...], e.g. external_temp_celsius=ovms:wican
+#[derive(Debug, Clone, PartialEq)]
+pub struct FieldPriority {
+    pub field: String,
+    pub sources: Vec<SampleSource>,
+}
+
+impl FromStr for FieldPriority {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (field, sources) = s
+            .split_once('=')
+            .ok_or_else(|| anyhow!("Expected FIELD=SOURCE[:SOURCE...], got '{}'", s))?;
+        let field = api::parse_payload_field(field.trim())?;
+        let sources = sources
+            .split(':')
+            .map(|source| SampleSource::from_str(source.trim(), true).map_err(|e| anyhow!(e)))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self { field, sources })
+    }
+}
+
+pub struct MergeOptions {
+    pub priority: Vec<SampleSource>,
+    pub field_priorities: Vec<FieldPriority>,
+    // How long a source's values may be used after its last sample
+    pub max_age: Duration,
+}
+
+// Combines the samples of several sources field by field, taking each value
+// from the most preferred source that has reported it recently, so sources
+// polled at different rates don't overwrite each other's better readings
+pub struct SampleMerger {
+    options: MergeOptions,
+    fields: Vec<String>,
+    latest: Mutex<HashMap<SampleSource, Readings>>,
+}
+
+// Fields of the last sample from a source and when it arrived
+struct Readings {
+    values: Map<String, Value>,
+    read_at: Instant,
+}
+
+impl SampleMerger {
+    pub fn new(options: MergeOptions) -> Self {
+        // Metadata such as the timestamp and sequence always comes from the
+        // sample being published, only the readings are merged
+        let fields = BatteryData::command()
+            .get_arguments()
+            .map(|arg| arg.get_id().to_string())
+            .collect();
+        Self {
+            options,
+            fields,
+            latest: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn merge(&self, source: SampleSource, sample: BatteryData) -> BatteryData {
+        match self.try_merge(source, &sample) {
+            Ok(merged) => merged,
+            Err(e) => {
+                warn!("Failed to merge the {} sample: {:#}", source, e);
+                sample
+            }
+        }
+    }
+
+    fn try_merge(&self, source: SampleSource, sample: &BatteryData) -> Result<BatteryData> {
+        let Value::Object(values) = serde_json::to_value(sample)? else {
+            return Err(anyhow!("Sample is not an object"));
+        };
+        let mut latest = self.latest.lock().unwrap();
+        latest.insert(
+            source,
+            Readings {
+                values: values.clone(),
+                read_at: Instant::now(),
+            },
+        );
+
+        let mut merged = values;
+        for field in &self.fields {
+            let order = self.order(field);
+            let chosen = order.iter().find_map(|candidate| {
+                let readings = latest.get(candidate)?;
+                if *candidate != source && readings.read_at.elapsed() > self.options.max_age {
+                    return None;
+                }
+                readings
+                    .values
+                    .get(field)
+                    .filter(|value| !value.is_null())
+                    .map(|value| (*candidate, value.clone()))
+            });
+            match chosen {
+                Some((candidate, value)) => {
+                    if candidate != source {
+                        debug!(
+                            "Using {} from {} in the {} sample",
+                            field, candidate, source
+                        );
+                    }
+                    merged.insert(field.clone(), value);
+                }
+                None => {
+                    merged.remove(field);
+                }
+            }
+        }
+        Ok(serde_json::from_value(Value::Object(merged))?)
+    }
+
+    // Sources to take a field from, most preferred first, with any source
+    // missing from the configured order after the others
+    fn order(&self, field: &str) -> Vec<SampleSource> {
+        let mut order = self
+            .options
+            .field_priorities
+            .iter()
+            .find(|priority| priority.field == field)
+            .map_or_else(
+                || self.options.priority.clone(),
+                |priority| priority.sources.clone(),
+            );
+        for source in SampleSource::value_variants() {
+            if !order.contains(source) {
+                order.push(*source);
+            }
+        }
+        order
+    }
+}
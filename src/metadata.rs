@@ -0,0 +1,54 @@
+use bluer::{Device, Uuid};
+use log::debug;
+use serde::{Deserialize, Serialize};
+
+// Device Information service and its Firmware Revision String characteristic
+const DEVICE_INFORMATION_UUID: Uuid = Uuid::from_u128(0x0000180a_0000_1000_8000_00805f9b34fb);
+const FIRMWARE_REVISION_UUID: Uuid = Uuid::from_u128(0x00002a26_0000_1000_8000_00805f9b34fb);
+
+// Describes where a sample came from, for receivers with several data sources
+#[derive(Debug, Clone, Serialize, Deserialize, Default, PartialEq)]
+pub struct SourceMetadata {
+    pub collector: String,
+    pub collector_version: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub wican_mac_address: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub firmware_version: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub vehicle_profile: Option<String>,
+}
+
+impl SourceMetadata {
+    pub fn new(vehicle_profile: Option<String>) -> Self {
+        Self {
+            collector: env!("CARGO_PKG_NAME").to_string(),
+            collector_version: env!("CARGO_PKG_VERSION").to_string(),
+            vehicle_profile,
+            ..Default::default()
+        }
+    }
+}
+
+// Read the firmware revision from the Device Information service, if the device has one
+pub async fn read_firmware_version(device: &Device) -> Option<String> {
+    for service in device.services().await.ok()? {
+        if service.uuid().await.ok()? != DEVICE_INFORMATION_UUID {
+            continue;
+        }
+        for characteristic in service.characteristics().await.ok()? {
+            if characteristic.uuid().await.ok()? == FIRMWARE_REVISION_UUID {
+                let value = characteristic.read().await.ok()?;
+                let version = String::from_utf8_lossy(&value)
+                    .trim_end_matches('\0')
+                    .trim()
+                    .to_string();
+                debug!("WiCAN firmware version: {}", version);
+                return Some(version);
+            }
+        }
+    }
+
+    debug!("WiCAN does not report a firmware version.");
+    None
+}
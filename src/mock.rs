@@ -0,0 +1,226 @@
+use anyhow::{anyhow, Context, Result};
+use log::{info, warn};
+use serde_json::{json, Map, Value};
+use std::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use crate::api::ApiClient;
+use crate::control::CONTROL;
+use crate::merge::SampleSource;
+use crate::metadata::SourceMetadata;
+use crate::stats::STATS;
+use crate::vehicle::Vehicle;
+use crate::{hooks, trace, BatteryData, Outputs, WriteType};
+
+// Outdoor temperature reported along with a scripted SOC curve
+const CURVE_TEMPERATURE: f32 = 15.0;
+
+// Synthetic autopid responses used in place of a WiCAN with --simulate, for
+// testing the outputs and aa-proxy-rs on a desk without the car
+#[derive(Debug, Clone, PartialEq)]
+pub enum MockSource {
+    // SOC moving from one value towards another by a step each poll, then
+    // starting over, written curve:FROM-TO[/STEP]
+    Curve { from: f32, to: f32, step: f32 },
+    // Autopid JSON objects served in turn from a file, either as one array or
+    // one object per line
+    Fixture(PathBuf),
+}
+
+impl Default for MockSource {
+    fn default() -> Self {
+        MockSource::Curve {
+            from: 80.0,
+            to: 20.0,
+            step: 1.0,
+        }
+    }
+}
+
+impl FromStr for MockSource {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let s = s.trim();
+        if s == "curve" {
+            return Ok(MockSource::default());
+        }
+        let Some(curve) = s.strip_prefix("curve:") else {
+            if s.is_empty() {
+                return Err(anyhow!("Expected a fixture file or curve:FROM-TO[/STEP]"));
+            }
+            return Ok(MockSource::Fixture(PathBuf::from(s)));
+        };
+        let (range, step) = match curve.split_once('/') {
+            Some((range, step)) => (
+                range,
+                step.trim()
+                    .parse()
+                    .with_context(|| format!("Invalid step '{}'", step))?,
+            ),
+            None => (curve, 1.0),
+        };
+        let (from, to) = range
+            .split_once('-')
+            .ok_or_else(|| anyhow!("Curve '{}' is not in FROM-TO[/STEP] form", curve))?;
+        let soc = |value: &str| -> Result<f32> {
+            let soc: f32 = value
+                .trim()
+                .parse()
+                .with_context(|| format!("Invalid SOC '{}'", value))?;
+            if !(0.0..=100.0).contains(&soc) {
+                return Err(anyhow!("SOC {} is not between 0 and 100", soc));
+            }
+            Ok(soc)
+        };
+        let (from, to) = (soc(from)?, soc(to)?);
+        if step <= 0.0 {
+            return Err(anyhow!("The step must be above 0, got {}", step));
+        }
+        Ok(MockSource::Curve { from, to, step })
+    }
+}
+
+impl fmt::Display for MockSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MockSource::Curve { from, to, step } => write!(
+                f,
+                "a SOC curve from {}% to {}% in steps of {}",
+                from, to, step
+            ),
+            MockSource::Fixture(path) => write!(f, "responses from '{}'", path.display()),
+        }
+    }
+}
+
+// The responses of a source, produced one per poll
+enum Responses {
+    Curve {
+        from: f32,
+        to: f32,
+        step: f32,
+        soc: f32,
+    },
+    Fixture {
+        responses: Vec<Map<String, Value>>,
+        next: usize,
+    },
+}
+
+impl Responses {
+    fn new(source: &MockSource) -> Result<Self> {
+        match source {
+            MockSource::Curve { from, to, step } => Ok(Responses::Curve {
+                from: *from,
+                to: *to,
+                step: *step,
+                soc: *from,
+            }),
+            MockSource::Fixture(path) => {
+                let fixture = std::fs::read_to_string(path)
+                    .with_context(|| format!("Failed to read fixture '{}'", path.display()))?;
+                let responses = load_fixture(&fixture)
+                    .with_context(|| format!("Invalid fixture '{}'", path.display()))?;
+                if responses.is_empty() {
+                    return Err(anyhow!("Fixture '{}' has no responses", path.display()));
+                }
+                Ok(Responses::Fixture { responses, next: 0 })
+            }
+        }
+    }
+
+    fn next(&mut self) -> Map<String, Value> {
+        match self {
+            Responses::Curve {
+                from,
+                to,
+                step,
+                soc,
+            } => {
+                let current = *soc;
+                let towards = if to >= from { *step } else { -*step };
+                let next = current + towards;
+                *soc = if (towards > 0.0 && next > *to) || (towards < 0.0 && next < *to) {
+                    *from
+                } else {
+                    next
+                };
+                let mut response = Map::new();
+                response.insert("SOC".to_string(), json!((current * 10.0).round() / 10.0));
+                response.insert("TMP_A".to_string(), json!(CURVE_TEMPERATURE));
+                response
+            }
+            Responses::Fixture { responses, next } => {
+                let response = responses[*next % responses.len()].clone();
+                *next += 1;
+                response
+            }
+        }
+    }
+}
+
+fn load_fixture(fixture: &str) -> Result<Vec<Map<String, Value>>> {
+    if fixture.trim_start().starts_with('[') {
+        return serde_json::from_str(fixture).context("Expected an array of JSON objects");
+    }
+    fixture
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(index, line)| {
+            serde_json::from_str(line)
+                .with_context(|| format!("Line {} is not a JSON object", index + 1))
+        })
+        .collect()
+}
+
+// Serve synthetic responses at the update frequency, through the same parsing
+// and outputs as responses read from a WiCAN
+pub async fn poll(
+    source: &MockSource,
+    api: &ApiClient,
+    vehicle: &Vehicle,
+    outputs: &Outputs<'_>,
+    metadata: Option<SourceMetadata>,
+) -> Result<()> {
+    let mut responses = Responses::new(source)?;
+    info!(
+        "Simulating a WiCAN with {}. No Bluetooth device is used.",
+        source
+    );
+    STATS.set_connected(true);
+    let mut control = CONTROL.listen();
+    let mut first_run = true;
+    loop {
+        if !first_run {
+            crate::wait_for_next_update(
+                &mut control,
+                api.retry_after(),
+                None,
+                WriteType::Auto,
+                None,
+                b"",
+            )
+            .await;
+        }
+        first_run = false;
+        control.take_poll_request();
+        CONTROL.take_reconnect_request();
+        hooks::pre_poll().await;
+
+        let response = serde_json::to_vec(&responses.next())?;
+        trace::frame(trace::Direction::Received, &response);
+        match crate::parse_response(response, vehicle) {
+            Ok(battery_data) => {
+                let battery_data = BatteryData {
+                    source: metadata.clone(),
+                    ..battery_data
+                };
+                outputs.publish(SampleSource::Wican, battery_data).await;
+            }
+            Err(e) => warn!("Ignoring simulated response: {:#}", e),
+        }
+    }
+}
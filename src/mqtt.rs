@@ -0,0 +1,507 @@
+use anyhow::{anyhow, Context, Result};
+use clap::ValueEnum;
+use log::{debug, info, warn};
+use reqwest::Url;
+use rumqttc::v5::mqttbytes::v5::{LastWill as LastWillV5, PublishProperties};
+use rumqttc::v5::mqttbytes::QoS as QoSV5;
+use rumqttc::{v5, AsyncClient, Event, EventLoop, LastWill, MqttOptions, Packet, QoS, Transport};
+use serde_json::Value;
+use std::path::Path;
+use std::time::Duration;
+use tokio::time;
+
+use crate::homeassistant::{MqttDiscovery, MqttDiscoveryOptions};
+use crate::secrets::{self, Secret};
+use crate::BatteryData;
+
+// Delay before reconnecting after the broker connection failed
+pub const RECONNECT_DELAY: Duration = Duration::from_secs(10);
+
+// Requests buffered for the event loop while the broker is unreachable
+const CHANNEL_CAPACITY: usize = 64;
+
+const KEEP_ALIVE: Duration = Duration::from_secs(30);
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+pub enum MqttLayout {
+    Json,
+    Fields,
+    Both,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+pub enum MqttVersion {
+    #[value(name = "3.1.1")]
+    V311,
+    #[value(name = "5")]
+    V5,
+}
+
+// How to reach and log in to a broker
+pub struct MqttConnectOptions {
+    pub url: String,
+    pub client_id: String,
+    pub version: MqttVersion,
+    pub username: Option<String>,
+    pub password: Option<Secret>,
+    #[cfg_attr(not(any(feature = "rustls", feature = "native-tls")), allow(dead_code))]
+    pub tls: TlsFiles,
+    pub session_expiry: Option<u32>,
+    pub message_expiry: Option<u32>,
+}
+
+// Certificates and key for mqtts:// urls, read at startup as they may only be
+// readable before privileges are dropped
+#[derive(Default)]
+#[cfg_attr(not(any(feature = "rustls", feature = "native-tls")), allow(dead_code))]
+pub struct TlsFiles {
+    ca: Option<Vec<u8>>,
+    client_cert: Option<Vec<u8>>,
+    client_key: Option<Vec<u8>>,
+}
+
+impl TlsFiles {
+    pub fn read(
+        ca_file: Option<&Path>,
+        client_cert_file: Option<&Path>,
+        client_key_file: Option<&Path>,
+    ) -> Result<Self> {
+        if client_cert_file.is_some() != client_key_file.is_some() {
+            return Err(anyhow!(
+                "--mqtt-client-cert-file and --mqtt-client-key-file must be given together"
+            ));
+        }
+        Ok(Self {
+            ca: ca_file.map(read_file).transpose()?,
+            client_cert: client_cert_file.map(read_file).transpose()?,
+            client_key: client_key_file.map(read_file).transpose()?,
+        })
+    }
+}
+
+// Settings for the MQTT sink
+pub struct MqttSinkOptions {
+    pub connection: MqttConnectOptions,
+    pub topic_prefix: String,
+    pub layout: MqttLayout,
+    pub json_topic: String,
+    pub field_topic: String,
+    pub qos: u8,
+    pub retain: bool,
+    pub availability_topic: Option<String>,
+    pub discovery: Option<MqttDiscoveryOptions>,
+}
+
+// Client for either protocol version
+enum Client {
+    V311(AsyncClient),
+    V5(v5::AsyncClient),
+}
+
+impl Client {
+    fn publish(
+        &self,
+        topic: String,
+        qos: u8,
+        retain: bool,
+        payload: String,
+        message_expiry: Option<u32>,
+    ) -> Result<()> {
+        match self {
+            Client::V311(client) => client.try_publish(topic, qos_v311(qos), retain, payload)?,
+            Client::V5(client) => client.try_publish_with_properties(
+                topic,
+                qos_v5(qos),
+                retain,
+                payload,
+                PublishProperties {
+                    message_expiry_interval: message_expiry,
+                    ..Default::default()
+                },
+            )?,
+        }
+        Ok(())
+    }
+}
+
+// Publishes samples to an MQTT broker
+pub struct MqttSink {
+    client: Client,
+    layout: MqttLayout,
+    json_topic: String,
+    field_topic: String,
+    qos: u8,
+    retain: bool,
+    message_expiry: Option<u32>,
+    discovery: Option<MqttDiscovery>,
+}
+
+impl MqttSink {
+    // Connect to the broker in the background, announcing availability and
+    // leaving "offline" as the last will if an availability topic is set
+    pub fn connect(options: MqttSinkOptions) -> Result<Self> {
+        let connection = &options.connection;
+        let prefix = options.topic_prefix.trim_end_matches('/');
+        let availability_topic = options
+            .availability_topic
+            .as_deref()
+            .map(|topic| topic.replace("{prefix}", prefix));
+        let discovery = options
+            .discovery
+            .map(|discovery| MqttDiscovery::new(discovery, prefix, availability_topic.clone()))
+            .transpose()?;
+        let discovery_configs = discovery
+            .as_ref()
+            .map(MqttDiscovery::configs)
+            .unwrap_or_default();
+
+        let client = match connection.version {
+            MqttVersion::V311 => {
+                let mut mqtt_options = options_v311(connection)?;
+                if let Some(topic) = &availability_topic {
+                    mqtt_options.set_last_will(LastWill::new(
+                        topic,
+                        "offline",
+                        QoS::AtLeastOnce,
+                        true,
+                    ));
+                }
+                let (client, event_loop) = AsyncClient::new(mqtt_options, CHANNEL_CAPACITY);
+                tokio::spawn(run_event_loop(
+                    event_loop,
+                    client.clone(),
+                    availability_topic,
+                    discovery_configs,
+                    connection.url.clone(),
+                ));
+                Client::V311(client)
+            }
+            MqttVersion::V5 => {
+                let endpoint = endpoint(connection)?;
+                let mut mqtt_options =
+                    v5::MqttOptions::new(&connection.client_id, endpoint.host, endpoint.port);
+                mqtt_options
+                    .set_keep_alive(KEEP_ALIVE)
+                    .set_transport(endpoint.transport)
+                    .set_session_expiry_interval(connection.session_expiry);
+                if connection.session_expiry.is_some() {
+                    mqtt_options.set_clean_start(false);
+                }
+                if let Some((username, password)) = endpoint.credentials {
+                    mqtt_options.set_credentials(username, password);
+                }
+                if let Some(topic) = &availability_topic {
+                    mqtt_options.set_last_will(LastWillV5::new(
+                        topic,
+                        "offline",
+                        QoSV5::AtLeastOnce,
+                        true,
+                        None,
+                    ));
+                }
+                let (client, event_loop) = v5::AsyncClient::new(mqtt_options, CHANNEL_CAPACITY);
+                tokio::spawn(run_event_loop_v5(
+                    event_loop,
+                    client.clone(),
+                    availability_topic,
+                    discovery_configs,
+                    connection.url.clone(),
+                ));
+                Client::V5(client)
+            }
+        };
+
+        Ok(Self {
+            client,
+            layout: options.layout,
+            json_topic: options.json_topic.replace("{prefix}", prefix),
+            field_topic: options.field_topic.replace("{prefix}", prefix),
+            qos: options.qos,
+            retain: options.retain,
+            message_expiry: connection.message_expiry,
+            discovery,
+        })
+    }
+
+    // Queue a sample for publishing as one JSON message, one message per field
+    // or both. Never waits for the broker, so an unreachable broker can't hold
+    // up polling.
+    pub fn send(&self, sample: &BatteryData) {
+        if let Err(e) = self.publish(sample) {
+            warn!("Failed to publish sample to MQTT: {:#}", e);
+        }
+    }
+
+    fn publish(&self, sample: &BatteryData) -> Result<()> {
+        let value = serde_json::to_value(sample)?;
+        if matches!(self.layout, MqttLayout::Json | MqttLayout::Both) {
+            self.client.publish(
+                self.json_topic.clone(),
+                self.qos,
+                self.retain,
+                value.to_string(),
+                self.message_expiry,
+            )?;
+        }
+
+        if matches!(self.layout, MqttLayout::Fields | MqttLayout::Both) {
+            let Value::Object(fields) = value else {
+                return Ok(());
+            };
+            for (field, value) in fields {
+                let payload = match value {
+                    Value::Null => continue,
+                    Value::String(text) => text,
+                    other => other.to_string(),
+                };
+                self.client.publish(
+                    self.field_topic.replace("{field}", &field),
+                    self.qos,
+                    self.retain,
+                    payload,
+                    self.message_expiry,
+                )?;
+            }
+        }
+
+        // Retained, so Home Assistant shows the last state after a restart
+        if let Some(discovery) = &self.discovery {
+            for (topic, state) in discovery.states(sample) {
+                self.client
+                    .publish(topic, self.qos, true, state, self.message_expiry)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// Broker host, port, transport and credentials of a connection
+struct Endpoint {
+    host: String,
+    port: u16,
+    transport: Transport,
+    credentials: Option<(String, String)>,
+}
+
+fn endpoint(connection: &MqttConnectOptions) -> Result<Endpoint> {
+    let url = Url::parse(&connection.url).with_context(|| {
+        format!(
+            "Invalid MQTT url '{}'",
+            secrets::redact_url(&connection.url)
+        )
+    })?;
+    let host = url
+        .host_str()
+        .ok_or_else(|| {
+            anyhow!(
+                "MQTT url '{}' has no host",
+                secrets::redact_url(&connection.url)
+            )
+        })?
+        .to_string();
+    let (transport, default_port) = transport(&url, connection)?;
+    let port = url.port().unwrap_or(default_port);
+    let credentials = connection.username.as_ref().map(|username| {
+        (
+            username.clone(),
+            connection
+                .password
+                .as_ref()
+                .map_or(String::new(), |password| password.expose().to_string()),
+        )
+    });
+    Ok(Endpoint {
+        host,
+        port,
+        transport,
+        credentials,
+    })
+}
+
+// Whether a topic matches a subscription that may contain + and trailing #
+// wildcards
+pub fn topic_matches(filter: &str, topic: &str) -> bool {
+    let mut topic = topic.split('/');
+    for level in filter.split('/') {
+        match (level, topic.next()) {
+            ("#", _) => return true,
+            ("+", Some(_)) => {}
+            (level, Some(name)) if level == name => {}
+            _ => return false,
+        }
+    }
+    topic.next().is_none()
+}
+
+// MQTT 3.1.1 client options for a connection
+pub fn options_v311(connection: &MqttConnectOptions) -> Result<MqttOptions> {
+    let endpoint = endpoint(connection)?;
+    let mut mqtt_options = MqttOptions::new(&connection.client_id, endpoint.host, endpoint.port);
+    mqtt_options
+        .set_keep_alive(KEEP_ALIVE)
+        .set_transport(endpoint.transport);
+    if let Some((username, password)) = endpoint.credentials {
+        mqtt_options.set_credentials(username, password);
+    }
+    Ok(mqtt_options)
+}
+
+// Transport and default port for the url scheme, mqtts:// using TLS
+fn transport(url: &Url, options: &MqttConnectOptions) -> Result<(Transport, u16)> {
+    match url.scheme() {
+        "mqtt" | "tcp" => Ok((Transport::Tcp, 1883)),
+        "mqtts" | "ssl" => Ok((tls_transport(options)?, 8883)),
+        scheme => Err(anyhow!("Unsupported MQTT url scheme '{}'", scheme)),
+    }
+}
+
+fn read_file(path: &Path) -> Result<Vec<u8>> {
+    std::fs::read(path).with_context(|| format!("Failed to read {}", path.display()))
+}
+
+#[cfg(feature = "rustls")]
+fn tls_transport(options: &MqttConnectOptions) -> Result<Transport> {
+    use rumqttc::TlsConfiguration;
+
+    let tls = &options.tls;
+    let client_auth = tls.client_cert.clone().zip(tls.client_key.clone());
+    let configuration = match (&tls.ca, client_auth) {
+        (Some(ca), client_auth) => TlsConfiguration::Simple {
+            ca: ca.clone(),
+            alpn: None,
+            client_auth,
+        },
+        (None, None) => TlsConfiguration::default(),
+        (None, Some(_)) => {
+            return Err(anyhow!(
+                "MQTT client certificates need the broker CA in --mqtt-ca-file"
+            ))
+        }
+    };
+    Ok(Transport::tls_with_config(configuration))
+}
+
+#[cfg(all(feature = "native-tls", not(feature = "rustls")))]
+fn tls_transport(options: &MqttConnectOptions) -> Result<Transport> {
+    use rumqttc::TlsConfiguration;
+
+    if options.tls.client_cert.is_some() || options.tls.client_key.is_some() {
+        return Err(anyhow!(
+            "MQTT client certificates need a build with the 'rustls' feature"
+        ));
+    }
+    let configuration = match &options.tls.ca {
+        Some(ca) => TlsConfiguration::SimpleNative {
+            ca: ca.clone(),
+            client_auth: None,
+        },
+        None => TlsConfiguration::Native,
+    };
+    Ok(Transport::tls_with_config(configuration))
+}
+
+#[cfg(not(any(feature = "rustls", feature = "native-tls")))]
+fn tls_transport(_options: &MqttConnectOptions) -> Result<Transport> {
+    Err(anyhow!(
+        "mqtts:// urls need a build with the 'rustls' or 'native-tls' feature"
+    ))
+}
+
+fn qos_v311(level: u8) -> QoS {
+    match level {
+        0 => QoS::AtMostOnce,
+        1 => QoS::AtLeastOnce,
+        _ => QoS::ExactlyOnce,
+    }
+}
+
+fn qos_v5(level: u8) -> QoSV5 {
+    match level {
+        0 => QoSV5::AtMostOnce,
+        1 => QoSV5::AtLeastOnce,
+        _ => QoSV5::ExactlyOnce,
+    }
+}
+
+// Drive an MQTT 3.1.1 connection, reconnecting after failures
+async fn run_event_loop(
+    mut event_loop: EventLoop,
+    client: AsyncClient,
+    availability_topic: Option<String>,
+    discovery_configs: Vec<(String, String)>,
+    url: String,
+) {
+    loop {
+        match event_loop.poll().await {
+            Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                info!("Connected to MQTT broker {}", secrets::redact_url(&url));
+                if let Some(topic) = &availability_topic {
+                    if let Err(e) = client.try_publish(topic, QoS::AtLeastOnce, true, "online") {
+                        warn!("Failed to publish MQTT availability: {}", e);
+                    }
+                }
+                for (topic, config) in &discovery_configs {
+                    if let Err(e) =
+                        client.try_publish(topic, QoS::AtLeastOnce, true, config.clone())
+                    {
+                        warn!("Failed to publish Home Assistant discovery config: {}", e);
+                    }
+                }
+            }
+            Ok(event) => debug!("MQTT event: {:?}", event),
+            Err(e) => {
+                warn!(
+                    "MQTT connection to {} failed: {}. Retrying in {:?}...",
+                    secrets::redact_url(&url),
+                    e,
+                    RECONNECT_DELAY
+                );
+                time::sleep(RECONNECT_DELAY).await;
+            }
+        }
+    }
+}
+
+// Drive an MQTT 5 connection, reconnecting after failures
+async fn run_event_loop_v5(
+    mut event_loop: v5::EventLoop,
+    client: v5::AsyncClient,
+    availability_topic: Option<String>,
+    discovery_configs: Vec<(String, String)>,
+    url: String,
+) {
+    loop {
+        match event_loop.poll().await {
+            Ok(v5::Event::Incoming(v5::mqttbytes::v5::Packet::ConnAck(_))) => {
+                info!(
+                    "Connected to MQTT broker {} with MQTT 5",
+                    secrets::redact_url(&url)
+                );
+                if let Some(topic) = &availability_topic {
+                    if let Err(e) =
+                        client.try_publish(topic.clone(), QoSV5::AtLeastOnce, true, "online")
+                    {
+                        warn!("Failed to publish MQTT availability: {}", e);
+                    }
+                }
+                for (topic, config) in &discovery_configs {
+                    if let Err(e) =
+                        client.try_publish(topic.clone(), QoSV5::AtLeastOnce, true, config.clone())
+                    {
+                        warn!("Failed to publish Home Assistant discovery config: {}", e);
+                    }
+                }
+            }
+            Ok(event) => debug!("MQTT event: {:?}", event),
+            Err(e) => {
+                warn!(
+                    "MQTT connection to {} failed: {}. Retrying in {:?}...",
+                    secrets::redact_url(&url),
+                    e,
+                    RECONNECT_DELAY
+                );
+                time::sleep(RECONNECT_DELAY).await;
+            }
+        }
+    }
+}
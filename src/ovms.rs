@@ -0,0 +1,277 @@
+use crate::api::ApiClient;
+use crate::control::CONTROL;
+use crate::merge::SampleSource;
+use crate::metadata::SourceMetadata;
+use crate::mqtt::{self, MqttConnectOptions};
+use crate::secrets::{self, Secret};
+use crate::units::TemperatureUnit;
+use crate::vehicle::Vehicle;
+use crate::{hooks, BatteryData, Outputs, WicanResponse, WriteType};
+use anyhow::{anyhow, Context, Result};
+use log::{debug, error, info, warn};
+use reqwest::Client;
+use rumqttc::{AsyncClient, Event, Packet, QoS};
+use tokio::time;
+
+// OVMS metrics read for a sample
+const SOC_METRIC: &str = "v.b.soc";
+const TEMPERATURE_METRIC: &str = "v.e.temp";
+
+// Requests buffered for the subscriber's event loop
+const CHANNEL_CAPACITY: usize = 10;
+
+// Where the OVMS module is read from
+pub enum OvmsSource {
+    // The module's web server, polled every update
+    Http {
+        url: String,
+        password: Option<Secret>,
+    },
+    // Metrics the module publishes to an MQTT broker below the topic prefix
+    Mqtt {
+        connection: MqttConnectOptions,
+        topic_prefix: String,
+    },
+}
+
+impl OvmsSource {
+    // Read samples from the module and hand them to the outputs until stopped
+    pub async fn run(
+        self,
+        api: &ApiClient,
+        vehicle: &Vehicle,
+        outputs: &Outputs<'_>,
+        source: Option<SourceMetadata>,
+    ) -> Result<()> {
+        // OVMS reports metrics in metric units whatever the vehicle profile
+        let vehicle = Vehicle {
+            temperature_unit: TemperatureUnit::Celsius,
+            ..vehicle.clone()
+        };
+        match self {
+            OvmsSource::Http { url, password } => {
+                poll(api, &url, password.as_ref(), &vehicle, outputs, source).await
+            }
+            OvmsSource::Mqtt {
+                connection,
+                topic_prefix,
+            } => subscribe(connection, &topic_prefix, &vehicle, outputs, source).await,
+        }
+    }
+}
+
+// Poll the module's web API at the update frequency
+async fn poll(
+    api: &ApiClient,
+    url: &str,
+    password: Option<&Secret>,
+    vehicle: &Vehicle,
+    outputs: &Outputs<'_>,
+    source: Option<SourceMetadata>,
+) -> Result<()> {
+    let client = api.http_client();
+    info!(
+        "Reading battery data from the OVMS module at {}.",
+        secrets::redact_url(url)
+    );
+
+    let mut control = CONTROL.listen();
+    let mut first_run = true;
+    loop {
+        if !first_run {
+            crate::wait_for_next_update(
+                &mut control,
+                api.retry_after(),
+                None,
+                WriteType::Auto,
+                None,
+                b"",
+            )
+            .await;
+        }
+        first_run = false;
+        control.take_poll_request();
+        hooks::pre_poll().await;
+
+        let soc = match read_metric(&client, url, password, SOC_METRIC).await {
+            Ok(Some(soc)) => soc,
+            Ok(None) => {
+                warn!("The OVMS module does not report {} yet.", SOC_METRIC);
+                continue;
+            }
+            Err(e) => {
+                error!("Failed to read OVMS metrics: {:#}. Will retry...", e);
+                hooks::error(format!("Failed to read OVMS metrics: {:#}", e));
+                continue;
+            }
+        };
+        let temperature = read_metric(&client, url, password, TEMPERATURE_METRIC)
+            .await
+            .unwrap_or_else(|e| {
+                warn!("Failed to read {} from OVMS: {:#}", TEMPERATURE_METRIC, e);
+                None
+            });
+
+        outputs
+            .publish(
+                SampleSource::Ovms,
+                sample(soc, temperature, vehicle, source.as_ref()),
+            )
+            .await;
+    }
+}
+
+// Run a shell command on the module to list a metric, e.g. "v.b.soc 85%"
+async fn read_metric(
+    client: &Client,
+    url: &str,
+    password: Option<&Secret>,
+    metric: &str,
+) -> Result<Option<f32>> {
+    let command = format!("metrics list {}", metric);
+    let mut query = vec![("command", command.as_str())];
+    if let Some(password) = password {
+        query.push(("apikey", password.expose()));
+    }
+
+    let res = client
+        .get(format!("{}/api/execute", url.trim_end_matches('/')))
+        .query(&query)
+        .send()
+        .await
+        // The url holds the API key, and errors end up in the log and status
+        .map_err(reqwest::Error::without_url)
+        .context("Failed to reach the OVMS module")?;
+    let status = res.status();
+    let body = res.text().await.map_err(reqwest::Error::without_url)?;
+    if !status.is_success() {
+        return Err(anyhow!(
+            "The OVMS module responded {}: {}",
+            status,
+            body.trim()
+        ));
+    }
+    debug!("OVMS replied to '{}': {:?}", command, body);
+
+    Ok(body.lines().find_map(|line| {
+        let (name, value) = line.trim().split_once(char::is_whitespace)?;
+        (name == metric).then(|| parse_value(value)).flatten()
+    }))
+}
+
+// Follow the metrics the module publishes to a broker, sending a sample
+// whenever the SOC is published
+async fn subscribe(
+    connection: MqttConnectOptions,
+    topic_prefix: &str,
+    vehicle: &Vehicle,
+    outputs: &Outputs<'_>,
+    source: Option<SourceMetadata>,
+) -> Result<()> {
+    let prefix = topic_prefix.trim_end_matches('/');
+    let soc_topic = metric_topic(prefix, SOC_METRIC);
+    let temperature_topic = metric_topic(prefix, TEMPERATURE_METRIC);
+
+    let (client, mut event_loop) =
+        AsyncClient::new(mqtt::options_v311(&connection)?, CHANNEL_CAPACITY);
+    let mut temperature = None;
+    loop {
+        match event_loop.poll().await {
+            Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                info!(
+                    "Connected to MQTT broker {}, following OVMS metrics on {}.",
+                    secrets::redact_url(&connection.url),
+                    soc_topic
+                );
+                for topic in [&temperature_topic, &soc_topic] {
+                    client
+                        .subscribe(topic, QoS::AtLeastOnce)
+                        .await
+                        .context("Failed to subscribe to OVMS metrics")?;
+                }
+            }
+            Ok(Event::Incoming(Packet::Publish(publish))) => {
+                let payload = String::from_utf8_lossy(&publish.payload);
+                let value = parse_value(&payload);
+                debug!("OVMS published {} on {}", payload, publish.topic);
+
+                if mqtt::topic_matches(&temperature_topic, &publish.topic) {
+                    temperature = value;
+                } else if mqtt::topic_matches(&soc_topic, &publish.topic) {
+                    let Some(soc) = value else {
+                        warn!("Ignoring OVMS SOC '{}'", payload);
+                        continue;
+                    };
+                    if CONTROL.is_paused() {
+                        debug!("Polling is paused, ignoring the OVMS SOC.");
+                        continue;
+                    }
+                    outputs
+                        .publish(
+                            SampleSource::Ovms,
+                            sample(soc, temperature, vehicle, source.as_ref()),
+                        )
+                        .await;
+                }
+            }
+            Ok(event) => debug!("MQTT event: {:?}", event),
+            Err(e) => {
+                warn!(
+                    "MQTT connection to {} failed: {}. Retrying in {:?}...",
+                    secrets::redact_url(&connection.url),
+                    e,
+                    mqtt::RECONNECT_DELAY
+                );
+                time::sleep(mqtt::RECONNECT_DELAY).await;
+            }
+        }
+    }
+}
+
+// Topic OVMS publishes a metric on, e.g. "<prefix>/metric/v/b/soc"
+fn metric_topic(prefix: &str, metric: &str) -> String {
+    format!("{}/metric/{}", prefix, metric.replace('.', "/"))
+}
+
+// Leading number of a metric value, dropping units such as "%" or "°C"
+fn parse_value(value: &str) -> Option<f32> {
+    let value = value.trim();
+    let end = value
+        .find(|c: char| !(c.is_ascii_digit() || c == '.' || c == '-'))
+        .unwrap_or(value.len());
+    value[..end].parse().ok()
+}
+
+fn sample(
+    soc: f32,
+    temperature: Option<f32>,
+    vehicle: &Vehicle,
+    source: Option<&SourceMetadata>,
+) -> BatteryData {
+    BatteryData {
+        source: source.cloned(),
+        ..crate::battery_data(
+            WicanResponse {
+                soc,
+                soc_d: None,
+                outdoor_temperature: temperature,
+                battery_preconditioning: None,
+                plug_inserted: None,
+                charge_port_open: None,
+                charging_type: None,
+                battery_power_kw: None,
+                cell_temperature_min: None,
+                cell_temperature_max: None,
+                coolant_temperature: None,
+                hvac_power_kw: None,
+                speed_kmh: None,
+                soh: None,
+                charging: None,
+                battery_temperature: None,
+                odometer_km: None,
+                aux_battery_voltage: None,
+            },
+            vehicle,
+        )
+    }
+}
@@ -0,0 +1,174 @@
+use anyhow::{anyhow, Context, Result};
+use futures_util::stream::{Stream, StreamExt};
+use futures_util::FutureExt;
+use log::{debug, warn};
+use serde_json::{json, Map, Value};
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+use tokio::time;
+
+use crate::dongle::Dongle;
+use crate::elm327;
+use crate::pid::ObdPid;
+use crate::transport::CommandWriter;
+
+// How long to wait for the rest of a reply split across several frames when
+// the dongle never sends a prompt
+const REPLY_QUIET_TIME: Duration = Duration::from_millis(300);
+
+// A manufacturer specific PID read after each autopid request, its value
+// merged into the autopid response under NAME so the AutoPID names, key
+// mappings and units apply to it, written as NAME=[HEADER:]REQUEST:FORMULA,
+// e.g. "SOH=7E4:220105:AF/2"
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExtraPid {
+    pub name: String,
+    pub pid: ObdPid,
+}
+
+impl FromStr for ExtraPid {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (name, pid) = s
+            .split_once('=')
+            .ok_or_else(|| anyhow!("'{}' is not in NAME=[HEADER:]REQUEST:FORMULA form", s))?;
+        let name = name.trim();
+        if name.is_empty() {
+            return Err(anyhow!("'{}' has no name", s));
+        }
+        Ok(Self {
+            name: name.to_string(),
+            pid: pid.parse()?,
+        })
+    }
+}
+
+impl fmt::Display for ExtraPid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}={}", self.name, self.pid)
+    }
+}
+
+// Line ending of commands, matching what the wake commands and the ELM327
+// session send to each kind of dongle
+pub fn line_ending(dongle: Dongle) -> &'static str {
+    if dongle.is_elm327() {
+        "\r"
+    } else {
+        "\n"
+    }
+}
+
+// Send a raw command, e.g. an AT command or an OBD request, and return the
+// reply
+pub async fn exchange<S: Stream<Item = Vec<u8>> + Unpin>(
+    writer: &mut CommandWriter,
+    responses: &mut S,
+    command: &str,
+    dongle: Dongle,
+    timeout: Duration,
+) -> Result<String> {
+    drop_stale(responses);
+    writer
+        .write(format!("{}{}", command, line_ending(dongle)).as_bytes())
+        .await?;
+    let reply = collect_reply(responses, command, timeout).await?;
+    debug!("{} replied to '{}': {:?}", dongle, command, reply);
+    Ok(reply)
+}
+
+// Drop anything left over from an earlier command that timed out
+pub fn drop_stale<S: Stream<Item = Vec<u8>> + Unpin>(responses: &mut S) {
+    while let Some(Some(_)) = responses.next().now_or_never() {}
+}
+
+// Wait for the reply to a command just sent. WiCAN firmware splits long
+// replies across notifications, so frames are joined until the prompt or
+// until the dongle goes quiet.
+pub async fn collect_reply<S: Stream<Item = Vec<u8>> + Unpin>(
+    responses: &mut S,
+    command: &str,
+    timeout: Duration,
+) -> Result<String> {
+    let mut reply = time::timeout(timeout, responses.next())
+        .await
+        .map_err(|_| anyhow!("No reply to '{}' within {:?}", command, timeout))?
+        .ok_or_else(|| anyhow!("Notification stream ended"))?;
+    while !reply.contains(&b'>') {
+        match time::timeout(REPLY_QUIET_TIME, responses.next()).await {
+            Ok(Some(frame)) => reply.extend(frame),
+            _ => break,
+        }
+    }
+
+    Ok(String::from_utf8_lossy(&reply)
+        .trim_end()
+        .trim_end_matches('>')
+        .trim()
+        .to_string())
+}
+
+// Read each extra PID, returning the values read by name. A PID that can't be
+// read is logged and left out rather than failing the sample.
+pub async fn query<S: Stream<Item = Vec<u8>> + Unpin>(
+    writer: &mut CommandWriter,
+    responses: &mut S,
+    pids: &[ExtraPid],
+    dongle: Dongle,
+    timeout: Duration,
+) -> Map<String, Value> {
+    let mut values = Map::new();
+    let mut header: Option<String> = None;
+    for extra in pids {
+        match read(writer, responses, &extra.pid, &mut header, dongle, timeout).await {
+            Ok(value) => {
+                debug!("Read extra PID {}: {}", extra, value);
+                values.insert(extra.name.clone(), json!(value));
+            }
+            Err(e) => warn!("Failed to read the extra PID {}: {:#}", extra, e),
+        }
+    }
+    // Leave the dongle addressing the functional header for its own requests
+    if header.is_some_and(|header| header != elm327::DEFAULT_HEADER) {
+        let command = format!("ATSH{}", elm327::DEFAULT_HEADER);
+        if let Err(e) = exchange(writer, responses, &command, dongle, timeout).await {
+            warn!("Failed to restore the default header: {:#}", e);
+        }
+    }
+    values
+}
+
+async fn read<S: Stream<Item = Vec<u8>> + Unpin>(
+    writer: &mut CommandWriter,
+    responses: &mut S,
+    pid: &ObdPid,
+    header: &mut Option<String>,
+    dongle: Dongle,
+    timeout: Duration,
+) -> Result<f32> {
+    let wanted = pid
+        .header
+        .clone()
+        .or_else(|| header.as_ref().map(|_| elm327::DEFAULT_HEADER.to_string()));
+    if let Some(wanted) = wanted.filter(|wanted| Some(wanted) != header.as_ref()) {
+        let reply = exchange(
+            writer,
+            responses,
+            &format!("ATSH{}", wanted),
+            dongle,
+            timeout,
+        )
+        .await
+        .context("Failed to set the header")?;
+        if reply.lines().any(|line| line.trim() == "?") {
+            return Err(anyhow!("The {} does not understand ATSH{}", dongle, wanted));
+        }
+        *header = Some(wanted);
+    }
+
+    let reply = exchange(writer, responses, &pid.request_hex(), dongle, timeout).await?;
+    let response = elm327::parse_reply(&reply, &pid.response_prefix())?;
+    pid.decode(&response)
+}
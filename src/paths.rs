@@ -0,0 +1,113 @@
+use anyhow::{anyhow, Context, Result};
+use nix::unistd::Uid;
+use std::path::{Path, PathBuf};
+
+const APP_NAME: &str = "aa-proxy-wican";
+
+// Directory for state kept between runs. Root uses /var/lib, everyone else
+// the XDG state directory.
+pub fn state_dir(configured: Option<&Path>) -> Result<PathBuf> {
+    if let Some(path) = configured {
+        return Ok(path.to_path_buf());
+    }
+
+    if Uid::effective().is_root() {
+        return Ok(Path::new("/var/lib").join(APP_NAME));
+    }
+
+    let base = match std::env::var_os("XDG_STATE_HOME").filter(|dir| !dir.is_empty()) {
+        Some(dir) => PathBuf::from(dir),
+        None => std::env::var_os("HOME")
+            .map(|home| Path::new(&home).join(".local/state"))
+            .ok_or_else(|| anyhow!("Neither XDG_STATE_HOME nor HOME is set, use --state-dir"))?,
+    };
+    Ok(base.join(APP_NAME))
+}
+
+// Log file location, keeping the traditional /var/log path when running as root
+pub fn log_file(configured: Option<&Path>, state_dir: &Path) -> PathBuf {
+    if let Some(path) = configured {
+        return path.to_path_buf();
+    }
+
+    if Uid::effective().is_root() {
+        Path::new("/var/log").join(format!("{}.log", APP_NAME))
+    } else {
+        state_dir.join(format!("{}.log", APP_NAME))
+    }
+}
+
+// Control socket location, under /run when running as root and otherwise the
+// XDG runtime directory, falling back to the state directory
+pub fn control_socket(configured: Option<&Path>, state_dir: &Path) -> PathBuf {
+    if let Some(path) = configured {
+        return path.to_path_buf();
+    }
+
+    if Uid::effective().is_root() {
+        return Path::new("/run").join(APP_NAME).join("control.sock");
+    }
+
+    match std::env::var_os("XDG_RUNTIME_DIR").filter(|dir| !dir.is_empty()) {
+        Some(dir) => Path::new(&dir).join(format!("{}.sock", APP_NAME)),
+        None => state_dir.join("control.sock"),
+    }
+}
+
+// Create a directory and its parents if missing
+pub fn ensure_dir(path: &Path) -> Result<()> {
+    std::fs::create_dir_all(path)
+        .with_context(|| format!("Could not create directory '{}'", path.display()))
+}
+
+// Create a directory and its parents if missing, returning whether it had to
+// be created. Only directories made for us are handed to the service user, as
+// an existing one such as /run or /tmp is shared with others.
+pub fn create_dir(path: &Path) -> Result<bool> {
+    if path.is_dir() {
+        return Ok(false);
+    }
+    ensure_dir(path)?;
+    Ok(true)
+}
+
+// Create a file if missing, leaving any contents alone
+pub fn touch(path: &Path) -> Result<()> {
+    std::fs::OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(path)
+        .map(|_| ())
+        .with_context(|| format!("Could not create '{}'", path.display()))
+}
+
+// Directory holding a file, "." for a bare file name
+pub fn parent_dir(path: &Path) -> &Path {
+    path.parent()
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .unwrap_or(Path::new("."))
+}
+
+// Move a file to PATH.1, shifting older copies up a number and dropping those
+// beyond the number kept, or remove it when none are kept
+pub fn rotate(path: &Path, keep: usize) -> Result<()> {
+    if keep == 0 {
+        return std::fs::remove_file(path)
+            .with_context(|| format!("Failed to remove '{}'", path.display()));
+    }
+    for index in (1..keep).rev() {
+        let from = rotated(path, index);
+        if from.exists() {
+            std::fs::rename(&from, rotated(path, index + 1))
+                .with_context(|| format!("Failed to rename '{}'", from.display()))?;
+        }
+    }
+    std::fs::rename(path, rotated(path, 1))
+        .with_context(|| format!("Failed to rotate '{}'", path.display()))
+}
+
+fn rotated(path: &Path, index: usize) -> PathBuf {
+    let mut rotated = path.as_os_str().to_owned();
+    rotated.push(format!(".{}", index));
+    PathBuf::from(rotated)
+}
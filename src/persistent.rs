@@ -0,0 +1,163 @@
+use crate::carwake::WakeDetector;
+use crate::dongle::Dongle;
+use crate::transport::CommandWriter;
+use crate::vehicle::Vehicle;
+use crate::{elm327, passthrough, throttle, BatteryData, WriteType};
+use anyhow::{anyhow, Context, Result};
+use bluer::gatt::remote::Characteristic;
+use bluer::{Device, DeviceEvent, DeviceProperty};
+use futures_util::{FutureExt, Stream, StreamExt};
+use log::{debug, info, warn};
+use std::pin::Pin;
+use std::time::Duration;
+use tokio::time;
+
+type Notifications = Pin<Box<dyn Stream<Item = Vec<u8>> + Send>>;
+type DeviceEvents = Pin<Box<dyn Stream<Item = DeviceEvent> + Send>>;
+
+// A connection to the WiCAN kept open between polls, with the characteristics
+// resolved and notifications subscribed once, so a poll is only the request
+// written to the existing characteristic
+pub struct PersistentConnection {
+    device: Device,
+    dongle: Dongle,
+    write_char: Characteristic,
+    notifications: Notifications,
+    events: DeviceEvents,
+    connected: bool,
+}
+
+impl PersistentConnection {
+    pub async fn open(device: &Device, dongle: Dongle) -> Result<Self> {
+        let (notify_char, write_char) = dongle
+            .characteristics(device)
+            .await
+            .context("Failed to find WiCAN characteristics")?;
+        // Subscribe to the device's events first, so a disconnect while
+        // subscribing to notifications is not missed
+        let events = Box::pin(
+            device
+                .events()
+                .await
+                .context("Failed to watch the WiCAN connection")?,
+        );
+        let notifications = Box::pin(dongle.notifications(&notify_char).await?);
+        info!("Keeping the connection to the WiCAN open between polls.");
+        Ok(Self {
+            device: device.clone(),
+            dongle,
+            write_char,
+            notifications,
+            events,
+            connected: true,
+        })
+    }
+
+    pub fn device(&self) -> &Device {
+        &self.device
+    }
+
+    // Whether BlueZ still reports the device as connected, going by the
+    // events it sent since the last check
+    pub fn is_connected(&mut self) -> bool {
+        while self.connected {
+            match self.events.next().now_or_never() {
+                Some(Some(DeviceEvent::PropertyChanged(DeviceProperty::Connected(false)))) => {
+                    debug!("BlueZ reported the WiCAN disconnected.");
+                    self.connected = false;
+                }
+                Some(Some(_)) => {}
+                Some(None) => {
+                    debug!("The WiCAN's device events ended.");
+                    self.connected = false;
+                }
+                None => break,
+            }
+        }
+        self.connected
+    }
+
+    // Request a sample over the open connection, like fetch_data does over a
+    // fresh one
+    pub async fn fetch_data(
+        &mut self,
+        vehicle: &Vehicle,
+        response_timeout: Duration,
+        write_type: WriteType,
+    ) -> Result<Option<BatteryData>> {
+        // Frames broadcast since the last poll would be taken for the reply
+        let mut stale = 0;
+        while let Some(frame) = self.notifications.next().now_or_never() {
+            if frame.is_none() {
+                self.connected = false;
+                return Err(anyhow!("Notification stream ended"));
+            }
+            stale += 1;
+        }
+        if stale > 0 {
+            debug!("Discarded {} WiCAN frame(s) received between polls.", stale);
+        }
+
+        throttle::start_cycle();
+        let mut writer = CommandWriter::Ble {
+            characteristic: self.write_char.clone(),
+            write_type,
+        };
+        if self.dongle.is_elm327() {
+            return elm327::read(
+                &mut self.notifications,
+                writer,
+                self.dongle,
+                vehicle,
+                response_timeout,
+            )
+            .await
+            .map(Some);
+        }
+        crate::request_autopid(
+            &mut self.notifications,
+            &mut writer,
+            self.dongle,
+            vehicle,
+            response_timeout,
+        )
+        .await
+    }
+
+    // Watch the notifications received between polls until the detector sees
+    // the car wake up, reading the 12V voltage meanwhile if it watches that.
+    // Never returns once the connection is lost, leaving the next update to
+    // the timer.
+    pub async fn wait_for_wake(
+        &mut self,
+        detector: &mut WakeDetector,
+        write_type: WriteType,
+    ) -> String {
+        let mut voltage_reads = detector.voltage_interval().map(time::interval);
+        while self.connected {
+            tokio::select! {
+                frame = self.notifications.next() => match frame {
+                    Some(frame) => {
+                        if let Some(reason) = detector.check(&frame) {
+                            return reason;
+                        }
+                    }
+                    None => self.connected = false,
+                },
+                _ = async { voltage_reads.as_mut().unwrap().tick().await },
+                    if voltage_reads.is_some() =>
+                {
+                    let mut writer = CommandWriter::Ble {
+                        characteristic: self.write_char.clone(),
+                        write_type,
+                    };
+                    let command = format!("ATRV{}", passthrough::line_ending(self.dongle));
+                    if let Err(e) = writer.write(command.as_bytes()).await {
+                        warn!("Failed to read the 12V battery voltage: {:#}", e);
+                    }
+                }
+            }
+        }
+        std::future::pending().await
+    }
+}
@@ -0,0 +1,279 @@
+use anyhow::{anyhow, Context, Result};
+use clap::ValueEnum;
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+// PIDs read together, each group on its own schedule so slow reads needn't
+// hold up every SOC update
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, ValueEnum)]
+pub enum PidGroup {
+    // Outdoor, cell and coolant temperatures
+    Temperatures,
+    Climate,
+    Speed,
+    // State of health
+    Health,
+}
+
+// How often a group of PIDs is read, written as GROUP=SECONDS, e.g.
+// "temperatures=300"
+#[derive(Debug, Clone, PartialEq)]
+pub struct GroupInterval {
+    pub group: PidGroup,
+    pub interval: Duration,
+}
+
+impl FromStr for GroupInterval {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (group, seconds) = s
+            .split_once('=')
+            .ok_or_else(|| anyhow!("'{}' is not in GROUP=SECONDS form", s))?;
+        let group = PidGroup::from_str(group.trim(), true).map_err(|_| {
+            anyhow!(
+                "Unknown PID group '{}', expected temperatures, climate, speed or health",
+                group
+            )
+        })?;
+        let seconds: u64 = seconds
+            .trim()
+            .parse()
+            .with_context(|| format!("Invalid interval '{}'", seconds))?;
+        Ok(Self {
+            group,
+            interval: Duration::from_secs(seconds),
+        })
+    }
+}
+
+// An OBD request and the formula turning its response into a value, written as
+// [HEADER:]REQUEST:FORMULA, e.g. "015B:A*100/255" or "7E4:220105:AF/2".
+// The formula uses Torque style byte names, A being the first data byte after
+// the echoed service and PID, Z the 26th and AA the 27th.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ObdPid {
+    pub header: Option<String>,
+    pub request: Vec<u8>,
+    pub formula: Formula,
+    source: String,
+}
+
+impl ObdPid {
+    // Request as sent to an ELM327 style adapter
+    pub fn request_hex(&self) -> String {
+        hex::encode_upper(&self.request)
+    }
+
+    // Leading bytes of a positive response: the service plus 0x40 and the echoed PID
+    pub fn response_prefix(&self) -> Vec<u8> {
+        let mut prefix = self.request.clone();
+        prefix[0] += 0x40;
+        prefix
+    }
+
+    // Evaluate the formula against a full response, checking it answers this request
+    pub fn decode(&self, response: &[u8]) -> Result<f32> {
+        let prefix = self.response_prefix();
+        let data = response.strip_prefix(prefix.as_slice()).ok_or_else(|| {
+            anyhow!(
+                "Response {} does not answer request {}",
+                hex::encode_upper(response),
+                self.request_hex()
+            )
+        })?;
+        self.formula.eval(data)
+    }
+}
+
+impl fmt::Display for ObdPid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.source)
+    }
+}
+
+impl FromStr for ObdPid {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let parts: Vec<&str> = s.splitn(3, ':').map(str::trim).collect();
+        let (header, request, formula) = match parts[..] {
+            [request, formula] => (None, request, formula),
+            [header, request, formula] => (Some(header), request, formula),
+            _ => {
+                return Err(anyhow!(
+                    "PID '{}' is not in [HEADER:]REQUEST:FORMULA form",
+                    s
+                ))
+            }
+        };
+
+        if let Some(header) = header {
+            if !matches!(header.len(), 3 | 8) || !header.chars().all(|c| c.is_ascii_hexdigit()) {
+                return Err(anyhow!(
+                    "Header '{}' must be a 3 or 8 digit hexadecimal CAN id",
+                    header
+                ));
+            }
+        }
+        let request = hex::decode(request)
+            .with_context(|| format!("Request '{}' is not hexadecimal", request))?;
+        if request.len() < 2 {
+            return Err(anyhow!("A request needs at least a service and a PID"));
+        }
+        if request[0] >= 0x40 {
+            return Err(anyhow!("Service {:02X} is not a request", request[0]));
+        }
+
+        Ok(Self {
+            header: header.map(str::to_ascii_uppercase),
+            request,
+            formula: formula.parse()?,
+            source: s.to_string(),
+        })
+    }
+}
+
+// Arithmetic over response bytes with + - * / and parentheses
+#[derive(Debug, Clone, PartialEq)]
+pub enum Formula {
+    Number(f32),
+    Byte(usize),
+    Negate(Box<Formula>),
+    Binary(char, Box<Formula>, Box<Formula>),
+}
+
+impl Formula {
+    pub fn eval(&self, data: &[u8]) -> Result<f32> {
+        Ok(match self {
+            Formula::Number(value) => *value,
+            Formula::Byte(index) => *data.get(*index).ok_or_else(|| {
+                anyhow!(
+                    "Formula needs byte {} but the response has only {} data byte(s)",
+                    byte_name(*index),
+                    data.len()
+                )
+            })? as f32,
+            Formula::Negate(inner) => -inner.eval(data)?,
+            Formula::Binary(op, left, right) => {
+                let (left, right) = (left.eval(data)?, right.eval(data)?);
+                match op {
+                    '+' => left + right,
+                    '-' => left - right,
+                    '*' => left * right,
+                    _ => left / right,
+                }
+            }
+        })
+    }
+}
+
+impl FromStr for Formula {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let tokens: Vec<char> = s.chars().filter(|c| !c.is_whitespace()).collect();
+        let mut parser = Parser {
+            tokens,
+            position: 0,
+        };
+        let formula = parser
+            .sum()
+            .map_err(|e| anyhow!("Invalid formula '{}': {}", s, e))?;
+        if parser.position < parser.tokens.len() {
+            return Err(anyhow!(
+                "Invalid formula '{}': unexpected '{}'",
+                s,
+                parser.tokens[parser.position]
+            ));
+        }
+        Ok(formula)
+    }
+}
+
+// Recursive descent parser for formulas
+struct Parser {
+    tokens: Vec<char>,
+    position: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<char> {
+        self.tokens.get(self.position).copied()
+    }
+
+    fn sum(&mut self) -> Result<Formula> {
+        let mut formula = self.product()?;
+        while let Some(op @ ('+' | '-')) = self.peek() {
+            self.position += 1;
+            formula = Formula::Binary(op, Box::new(formula), Box::new(self.product()?));
+        }
+        Ok(formula)
+    }
+
+    fn product(&mut self) -> Result<Formula> {
+        let mut formula = self.unary()?;
+        while let Some(op @ ('*' | '/')) = self.peek() {
+            self.position += 1;
+            formula = Formula::Binary(op, Box::new(formula), Box::new(self.unary()?));
+        }
+        Ok(formula)
+    }
+
+    fn unary(&mut self) -> Result<Formula> {
+        if self.peek() == Some('-') {
+            self.position += 1;
+            return Ok(Formula::Negate(Box::new(self.unary()?)));
+        }
+        self.atom()
+    }
+
+    fn atom(&mut self) -> Result<Formula> {
+        match self.peek() {
+            Some('(') => {
+                self.position += 1;
+                let formula = self.sum()?;
+                if self.peek() != Some(')') {
+                    return Err(anyhow!("missing ')'"));
+                }
+                self.position += 1;
+                Ok(formula)
+            }
+            Some(c) if c.is_ascii_uppercase() => {
+                let mut index = 0;
+                while let Some(c) = self.peek().filter(char::is_ascii_uppercase) {
+                    index = index * 26 + (c as usize - 'A' as usize + 1);
+                    self.position += 1;
+                }
+                Ok(Formula::Byte(index - 1))
+            }
+            Some(c) if c.is_ascii_digit() || c == '.' => {
+                let start = self.position;
+                while self.peek().is_some_and(|c| c.is_ascii_digit() || c == '.') {
+                    self.position += 1;
+                }
+                let number: String = self.tokens[start..self.position].iter().collect();
+                number
+                    .parse()
+                    .map(Formula::Number)
+                    .map_err(|_| anyhow!("'{}' is not a number", number))
+            }
+            Some(c) => Err(anyhow!("unexpected '{}'", c)),
+            None => Err(anyhow!("unexpected end")),
+        }
+    }
+}
+
+// Torque style name of a data byte, 0 being A
+fn byte_name(mut index: usize) -> String {
+    let mut name = Vec::new();
+    loop {
+        name.push((b'A' + (index % 26) as u8) as char);
+        if index < 26 {
+            break;
+        }
+        index = index / 26 - 1;
+    }
+    name.iter().rev().collect()
+}
@@ -0,0 +1,327 @@
+use crate::BatteryData;
+use anyhow::Result;
+use std::path::PathBuf;
+
+// User supplied WebAssembly modules that can rewrite samples and receive them
+// as custom sinks.
+//
+// A plugin exports its memory, `alloc(len: i32) -> i32` and at least one of:
+//  - `transform(ptr: i32, len: i32) -> i64` receiving the sample as JSON and
+//    returning `(ptr << 32) | len` of the replacement JSON, or 0 to keep it
+//  - `sink(ptr: i32, len: i32) -> i32` receiving the sample as JSON and
+//    returning 0 on success
+//
+// The host provides, in the "aa_proxy_wican" module:
+//  - `log(level: i32, ptr: i32, len: i32)` with levels 1 (error) to 5 (trace)
+//  - `http_request(method_ptr, method_len, url_ptr, url_len, body_ptr,
+//    body_len: i32) -> i32` returning the HTTP status, or -1 on failure
+pub struct Plugins {
+    #[cfg(feature = "wasm")]
+    plugins: Vec<wasm::Plugin>,
+}
+
+#[cfg(not(feature = "wasm"))]
+impl Plugins {
+    pub async fn load(paths: &[PathBuf], _client: reqwest::Client) -> Result<Self> {
+        match paths.is_empty() {
+            true => Ok(Self {}),
+            false => Err(anyhow::anyhow!(
+                "WebAssembly plugins need aa-proxy-wican built with the 'wasm' feature"
+            )),
+        }
+    }
+
+    pub async fn transform(&self, data: BatteryData) -> BatteryData {
+        data
+    }
+
+    pub async fn sink(&self, _data: &BatteryData) {}
+}
+
+#[cfg(feature = "wasm")]
+impl Plugins {
+    pub async fn load(paths: &[PathBuf], client: reqwest::Client) -> Result<Self> {
+        let engine = wasm::engine()?;
+        let mut plugins = Vec::new();
+        for path in paths {
+            plugins.push(wasm::Plugin::load(&engine, path, client.clone()).await?);
+        }
+        Ok(Self { plugins })
+    }
+
+    // Pass the sample through every plugin's transform in order. A failing
+    // plugin is skipped so one bad module cannot stop updates.
+    pub async fn transform(&self, mut data: BatteryData) -> BatteryData {
+        for plugin in &self.plugins {
+            match plugin.transform(&data).await {
+                Ok(Some(transformed)) => data = transformed,
+                Ok(None) => {}
+                Err(e) => log::warn!("Plugin {} failed to transform sample: {:#}", plugin.name, e),
+            }
+        }
+        data
+    }
+
+    pub async fn sink(&self, data: &BatteryData) {
+        for plugin in &self.plugins {
+            if let Err(e) = plugin.sink(data).await {
+                log::warn!("Plugin {} failed to handle sample: {:#}", plugin.name, e);
+            }
+        }
+    }
+}
+
+#[cfg(feature = "wasm")]
+mod wasm {
+    use crate::BatteryData;
+    use anyhow::{anyhow, Context, Result};
+    use log::{debug, info, Level};
+    use std::path::Path;
+    use tokio::sync::Mutex;
+    use wasmtime::{Caller, Config, Engine, Extern, Instance, Linker, Memory, Module, Store};
+
+    const HOST_MODULE: &str = "aa_proxy_wican";
+
+    // Instructions a plugin may execute per call, so a runaway module can't
+    // stall the update loop
+    const FUEL_PER_CALL: u64 = 1_000_000_000;
+
+    pub struct HostState {
+        client: reqwest::Client,
+        name: String,
+    }
+
+    pub struct Plugin {
+        pub name: String,
+        store: Mutex<Store<HostState>>,
+        instance: Instance,
+    }
+
+    pub fn engine() -> Result<Engine> {
+        let mut config = Config::new();
+        config.consume_fuel(true);
+        Engine::new(&config).map_err(|e| anyhow!("Failed to create WebAssembly engine: {}", e))
+    }
+
+    // Copy a guest string out of the plugin's memory
+    fn read_string(
+        memory: &Memory,
+        caller: &Caller<'_, HostState>,
+        ptr: i32,
+        len: i32,
+    ) -> Option<String> {
+        let data = memory.data(caller);
+        let start = usize::try_from(ptr).ok()?;
+        let end = start.checked_add(usize::try_from(len).ok()?)?;
+        data.get(start..end)
+            .map(|bytes| String::from_utf8_lossy(bytes).into_owned())
+    }
+
+    fn caller_memory(caller: &mut Caller<'_, HostState>) -> Option<Memory> {
+        match caller.get_export("memory") {
+            Some(Extern::Memory(memory)) => Some(memory),
+            _ => None,
+        }
+    }
+
+    fn linker(engine: &Engine) -> Result<Linker<HostState>> {
+        let mut linker = Linker::new(engine);
+
+        linker
+            .func_wrap(
+                HOST_MODULE,
+                "log",
+                |mut caller: Caller<'_, HostState>, level: i32, ptr: i32, len: i32| {
+                    let Some(memory) = caller_memory(&mut caller) else {
+                        return;
+                    };
+                    let Some(message) = read_string(&memory, &caller, ptr, len) else {
+                        return;
+                    };
+                    let level = match level {
+                        1 => Level::Error,
+                        2 => Level::Warn,
+                        3 => Level::Info,
+                        4 => Level::Debug,
+                        _ => Level::Trace,
+                    };
+                    log::log!(level, "Plugin {}: {}", caller.data().name, message);
+                },
+            )
+            .map_err(|e| anyhow!("{}", e))?;
+
+        linker
+            .func_wrap_async(
+                HOST_MODULE,
+                "http_request",
+                |mut caller: Caller<'_, HostState>,
+                 (method_ptr, method_len, url_ptr, url_len, body_ptr, body_len): (
+                    i32,
+                    i32,
+                    i32,
+                    i32,
+                    i32,
+                    i32,
+                )| {
+                    Box::new(async move {
+                        let Some(memory) = caller_memory(&mut caller) else {
+                            return -1;
+                        };
+                        let (Some(method), Some(url), Some(body)) = (
+                            read_string(&memory, &caller, method_ptr, method_len),
+                            read_string(&memory, &caller, url_ptr, url_len),
+                            read_string(&memory, &caller, body_ptr, body_len),
+                        ) else {
+                            return -1;
+                        };
+                        let Ok(method) = reqwest::Method::from_bytes(method.as_bytes()) else {
+                            return -1;
+                        };
+
+                        let state = caller.data();
+                        debug!("Plugin {} requested {} {}", state.name, method, url);
+                        match state.client.request(method, &url).body(body).send().await {
+                            Ok(response) => response.status().as_u16() as i32,
+                            Err(e) => {
+                                debug!("Plugin {} request to {} failed: {}", state.name, url, e);
+                                -1
+                            }
+                        }
+                    })
+                },
+            )
+            .map_err(|e| anyhow!("{}", e))?;
+
+        Ok(linker)
+    }
+
+    impl Plugin {
+        pub async fn load(engine: &Engine, path: &Path, client: reqwest::Client) -> Result<Self> {
+            let name = path.file_stem().map_or("plugin".to_string(), |stem| {
+                stem.to_string_lossy().into_owned()
+            });
+            let bytes = std::fs::read(path)
+                .with_context(|| format!("Failed to read plugin '{}'", path.display()))?;
+            let module = Module::from_binary(engine, &bytes)
+                .map_err(|e| anyhow!("Failed to compile plugin '{}': {}", path.display(), e))?;
+
+            let mut store = Store::new(
+                engine,
+                HostState {
+                    client,
+                    name: name.clone(),
+                },
+            );
+            store
+                .set_fuel(FUEL_PER_CALL)
+                .map_err(|e| anyhow!("{}", e))?;
+            let instance = linker(engine)?
+                .instantiate_async(&mut store, &module)
+                .await
+                .map_err(|e| anyhow!("Failed to instantiate plugin '{}': {}", path.display(), e))?;
+
+            let has_transform = instance.get_func(&mut store, "transform").is_some();
+            let has_sink = instance.get_func(&mut store, "sink").is_some();
+            if !has_transform && !has_sink {
+                return Err(anyhow!(
+                    "Plugin '{}' exports neither 'transform' nor 'sink'",
+                    path.display()
+                ));
+            }
+            info!(
+                "Loaded plugin {} (transform: {}, sink: {})",
+                name, has_transform, has_sink
+            );
+
+            Ok(Self {
+                name,
+                store: Mutex::new(store),
+                instance,
+            })
+        }
+
+        // Copy the sample into guest memory, returning its location
+        async fn write_sample(
+            &self,
+            store: &mut Store<HostState>,
+            data: &BatteryData,
+        ) -> Result<(i32, i32)> {
+            let json = serde_json::to_vec(data)?;
+            let len = i32::try_from(json.len())?;
+            let alloc = self
+                .instance
+                .get_typed_func::<i32, i32>(&mut *store, "alloc")
+                .map_err(|e| anyhow!("Missing 'alloc' export: {}", e))?;
+            let ptr = alloc
+                .call_async(&mut *store, len)
+                .await
+                .map_err(|e| anyhow!("'alloc' failed: {}", e))?;
+            self.memory(store)?
+                .write(&mut *store, usize::try_from(ptr)?, &json)
+                .map_err(|e| anyhow!("Failed to write sample to plugin memory: {}", e))?;
+            Ok((ptr, len))
+        }
+
+        fn memory(&self, store: &mut Store<HostState>) -> Result<Memory> {
+            self.instance
+                .get_memory(&mut *store, "memory")
+                .ok_or_else(|| anyhow!("Missing 'memory' export"))
+        }
+
+        pub async fn transform(&self, data: &BatteryData) -> Result<Option<BatteryData>> {
+            let mut store = self.store.lock().await;
+            let Ok(transform) = self
+                .instance
+                .get_typed_func::<(i32, i32), i64>(&mut *store, "transform")
+            else {
+                return Ok(None);
+            };
+
+            store
+                .set_fuel(FUEL_PER_CALL)
+                .map_err(|e| anyhow!("{}", e))?;
+            let input = self.write_sample(&mut store, data).await?;
+            let output = transform
+                .call_async(&mut *store, input)
+                .await
+                .map_err(|e| anyhow!("'transform' failed: {}", e))?;
+            if output == 0 {
+                return Ok(None);
+            }
+
+            let ptr = usize::try_from(output >> 32)?;
+            let len = usize::try_from(output & 0xffff_ffff)?;
+            let memory = self.memory(&mut store)?;
+            let json = memory
+                .data(&*store)
+                .get(ptr..ptr + len)
+                .ok_or_else(|| anyhow!("'transform' returned memory out of bounds"))?;
+            let transformed = serde_json::from_slice(json)
+                .context("'transform' returned invalid battery data")?;
+            Ok(Some(transformed))
+        }
+
+        pub async fn sink(&self, data: &BatteryData) -> Result<()> {
+            let mut store = self.store.lock().await;
+            let Ok(sink) = self
+                .instance
+                .get_typed_func::<(i32, i32), i32>(&mut *store, "sink")
+            else {
+                return Ok(());
+            };
+
+            store
+                .set_fuel(FUEL_PER_CALL)
+                .map_err(|e| anyhow!("{}", e))?;
+            let input = self.write_sample(&mut store, data).await?;
+            match sink
+                .call_async(&mut *store, input)
+                .await
+                .map_err(|e| anyhow!("'sink' failed: {}", e))?
+            {
+                0 => Ok(()),
+                status => Err(anyhow!("'sink' returned {}", status)),
+            }
+        }
+    }
+}
@@ -0,0 +1,103 @@
+use crate::control::{FastPoll, CONTROL};
+use crate::BatteryData;
+use anyhow::{anyhow, Context, Result};
+use log::info;
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+// Poll at a different interval while the SOC is within a range, written as
+// MIN-MAX:SECONDS with an optional ":charging" to only apply while charging,
+// e.g. "0-15:30" or "95-100:30:charging"
+#[derive(Debug, Clone, PartialEq)]
+pub struct SocPollRule {
+    min: f32,
+    max: f32,
+    interval: Duration,
+    charging_only: bool,
+}
+
+impl SocPollRule {
+    fn matches(&self, soc: f32, charging: bool) -> bool {
+        (self.min..=self.max).contains(&soc) && (charging || !self.charging_only)
+    }
+}
+
+impl FromStr for SocPollRule {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut parts = s.split(':').map(str::trim);
+        let (Some(range), Some(seconds)) = (parts.next(), parts.next()) else {
+            return Err(anyhow!("Rule '{}' is not in MIN-MAX:SECONDS form", s));
+        };
+        let charging_only = match parts.next() {
+            None => false,
+            Some("charging") => true,
+            Some(other) => {
+                return Err(anyhow!(
+                    "Unknown condition '{}' in rule '{}', expected 'charging'",
+                    other,
+                    s
+                ))
+            }
+        };
+
+        let (min, max) = range
+            .split_once('-')
+            .ok_or_else(|| anyhow!("Range '{}' is not in MIN-MAX form", range))?;
+        let min: f32 = min
+            .trim()
+            .parse()
+            .with_context(|| format!("Invalid minimum SOC in rule '{}'", s))?;
+        let max: f32 = max
+            .trim()
+            .parse()
+            .with_context(|| format!("Invalid maximum SOC in rule '{}'", s))?;
+        if min > max {
+            return Err(anyhow!("The range of rule '{}' is empty", s));
+        }
+        let seconds: u64 = seconds
+            .parse()
+            .with_context(|| format!("Invalid interval in rule '{}'", s))?;
+        if seconds == 0 {
+            return Err(anyhow!("The interval of rule '{}' must be at least 1", s));
+        }
+
+        Ok(Self {
+            min,
+            max,
+            interval: Duration::from_secs(seconds),
+            charging_only,
+        })
+    }
+}
+
+impl fmt::Display for SocPollRule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}-{}:{}", self.min, self.max, self.interval.as_secs())?;
+        if self.charging_only {
+            write!(f, ":charging")?;
+        }
+        Ok(())
+    }
+}
+
+// Switch to the interval of the first rule matching a sample, or back to the
+// update frequency when none does
+pub fn apply(rules: &[SocPollRule], sample: &BatteryData) {
+    let Some(soc) = sample.battery_level_percentage else {
+        return;
+    };
+    let rule = rules
+        .iter()
+        .find(|rule| rule.matches(soc, sample.is_charging()));
+    let interval = rule.map(|rule| rule.interval);
+    if interval != CONTROL.fast_poll_interval_for(FastPoll::SocRule) {
+        match rule {
+            Some(rule) => info!("SOC poll rule {} applies at {:.1}%.", rule, soc),
+            None => info!("No SOC poll rule applies at {:.1}%.", soc),
+        }
+    }
+    CONTROL.set_fast_poll_interval(FastPoll::SocRule, interval);
+}
@@ -0,0 +1,157 @@
+use anyhow::{anyhow, Result};
+use bluer::{Adapter, AdapterEvent, Address};
+use chrono::{DateTime, Utc};
+use futures_util::stream::StreamExt;
+use log::{debug, info, warn};
+use serde::Serialize;
+use std::fmt;
+use std::time::Duration;
+use tokio::time;
+
+use crate::events::{self, Event};
+use crate::rpa;
+use crate::stats::STATS;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PresenceState {
+    InRange,
+    Away,
+}
+
+// Whether a WiCAN was heard advertising when last listened for, reported
+// as its vehicle's state by the status API
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Presence {
+    pub state: PresenceState,
+    pub last_seen: Option<DateTime<Utc>>,
+    pub rssi_dbm: Option<i16>,
+}
+
+impl Presence {
+    pub fn is_away(&self) -> bool {
+        self.state == PresenceState::Away
+    }
+}
+
+// Returned instead of connecting while the WiCAN isn't advertising, so the
+// attempt is skipped without counting as a failure
+#[derive(Debug)]
+pub struct VehicleAway;
+
+impl fmt::Display for VehicleAway {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "The vehicle is away, its WiCAN is not advertising")
+    }
+}
+
+impl std::error::Error for VehicleAway {}
+
+pub fn is_away(error: &anyhow::Error) -> bool {
+    error.is::<VehicleAway>()
+}
+
+// Listen for the WiCAN's advertisements for up to the window before
+// connecting, failing with VehicleAway if it isn't heard. A WiCAN already
+// connected doesn't advertise and counts as in range. When listening itself
+// fails, the connection is attempted as it would be without presence
+// detection.
+pub async fn check(adapter: &Adapter, address: Address, window: Duration) -> Result<()> {
+    let rssi = match listen(adapter, address, window).await {
+        Ok(Some(rssi)) => rssi,
+        Ok(None) => {
+            record_away(address);
+            return Err(VehicleAway.into());
+        }
+        Err(e) => {
+            warn!(
+                "Failed to listen for the advertisements of {}: {:#}. Connecting anyway.",
+                address, e
+            );
+            return Ok(());
+        }
+    };
+    let previous = STATS.presence(&address.to_string());
+    if previous.is_some_and(|presence| presence.is_away()) {
+        info!(
+            "{} is advertising again{}. Connecting...",
+            address,
+            rssi.map_or(String::new(), |rssi| format!(" at {} dBm", rssi))
+        );
+        events::emit(Event::VehicleInRange {
+            address: address.to_string(),
+        });
+    }
+    STATS.set_presence(
+        &address.to_string(),
+        Presence {
+            state: PresenceState::InRange,
+            last_seen: Some(Utc::now()),
+            rssi_dbm: rssi,
+        },
+    );
+    Ok(())
+}
+
+fn record_away(address: Address) {
+    let previous = STATS.presence(&address.to_string());
+    if !previous.is_some_and(|presence| presence.is_away()) {
+        info!(
+            "{} is not advertising, the vehicle is away or asleep. Not connecting until it is heard again.",
+            address
+        );
+        events::emit(Event::VehicleAway {
+            address: address.to_string(),
+        });
+    }
+    STATS.set_presence(
+        &address.to_string(),
+        Presence {
+            state: PresenceState::Away,
+            last_seen: previous.and_then(|presence| presence.last_seen),
+            rssi_dbm: None,
+        },
+    );
+}
+
+// The RSSI of the first advertisement heard, Some(None) when the WiCAN is
+// connected, None when it isn't heard within the window
+async fn listen(
+    adapter: &Adapter,
+    address: Address,
+    window: Duration,
+) -> Result<Option<Option<i16>>> {
+    for known in adapter.device_addresses().await? {
+        if rpa::matches(known, address) && adapter.device(known)?.is_connected().await? {
+            return Ok(Some(None));
+        }
+    }
+
+    debug!(
+        "Listening for the advertisements of {} for {:?}",
+        address, window
+    );
+    // BlueZ reports devices it already knows as added too, but only sets
+    // their RSSI once an advertisement is received during this discovery
+    let mut events = adapter.discover_devices_with_changes().await?;
+    let heard = time::timeout(window, async {
+        while let Some(event) = events.next().await {
+            if let AdapterEvent::DeviceAdded(addr) = event {
+                if !rpa::matches(addr, address) {
+                    continue;
+                }
+                if let Ok(Some(rssi)) = adapter.device(addr)?.rssi().await {
+                    return Ok(Some(rssi));
+                }
+            }
+        }
+        Ok::<_, anyhow::Error>(None)
+    })
+    .await;
+    match heard {
+        Ok(Ok(Some(rssi))) => Ok(Some(Some(rssi))),
+        Ok(Ok(None)) => Err(anyhow!("Device discovery ended unexpectedly")),
+        Ok(Err(e)) => Err(e),
+        Err(_) => Ok(None),
+    }
+}
@@ -0,0 +1,51 @@
+use anyhow::{anyhow, Context, Result};
+use log::info;
+use nix::unistd::{setgid, setgroups, setuid, Gid, Group, Uid, User};
+use std::path::Path;
+
+// Switch to an unprivileged user and group once startup no longer needs root,
+// handing them the directories and files we write to. The group defaults to the primary
+// group of the user.
+pub fn drop_privileges(
+    user: Option<&str>,
+    group: Option<&str>,
+    owned_paths: &[&Path],
+) -> Result<()> {
+    if user.is_none() && group.is_none() {
+        return Ok(());
+    }
+
+    let user = user
+        .map(|name| {
+            User::from_name(name)
+                .with_context(|| format!("Failed to look up user '{}'", name))?
+                .ok_or_else(|| anyhow!("User '{}' does not exist", name))
+        })
+        .transpose()?;
+
+    let gid = match group {
+        Some(name) => {
+            Group::from_name(name)
+                .with_context(|| format!("Failed to look up group '{}'", name))?
+                .ok_or_else(|| anyhow!("Group '{}' does not exist", name))?
+                .gid
+        }
+        None => user.as_ref().map(|user| user.gid).unwrap_or(Gid::current()),
+    };
+
+    let uid = user.as_ref().map(|user| user.uid).unwrap_or(Uid::current());
+    for path in owned_paths {
+        std::os::unix::fs::chown(path, Some(uid.as_raw()), Some(gid.as_raw()))
+            .with_context(|| format!("Failed to change owner of '{}'", path.display()))?;
+    }
+
+    // Supplementary groups, the group and then the user must be changed in this
+    // order, as each step needs the privileges given up by the next
+    setgroups(&[gid]).context("Failed to set supplementary groups")?;
+    setgid(gid).with_context(|| format!("Failed to switch to group {}", gid))?;
+
+    setuid(uid).with_context(|| format!("Failed to switch to user {}", uid))?;
+
+    info!("Dropped privileges to uid {} gid {}.", uid, gid);
+    Ok(())
+}
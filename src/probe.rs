@@ -0,0 +1,78 @@
+use anyhow::{Context, Result};
+use bluer::gatt::remote::Characteristic;
+use bluer::Device;
+use log::info;
+
+// Dump the GATT tree of a connected device to stdout
+pub async fn probe_device(device: &Device) -> Result<()> {
+    info!("Probing GATT services of {}...", device.address());
+
+    println!("Device: {}", device.address());
+    if let Some(name) = device.name().await? {
+        println!("Name: {}", name);
+    }
+    if let Some(rssi) = device.rssi().await? {
+        println!("RSSI: {} dBm", rssi);
+    }
+
+    let mut services = device
+        .services()
+        .await
+        .context("Failed to list GATT services")?;
+    services.sort_by_key(|s| s.id());
+
+    for service in services {
+        let kind = if service.primary().await? {
+            "primary"
+        } else {
+            "secondary"
+        };
+        println!(
+            "Service {} ({}, id {})",
+            service.uuid().await?,
+            kind,
+            service.id()
+        );
+
+        let mut characteristics = service.characteristics().await?;
+        characteristics.sort_by_key(|c| c.id());
+
+        for characteristic in characteristics {
+            println!(
+                "  Characteristic {} (id {}) [{}]",
+                characteristic.uuid().await?,
+                characteristic.id(),
+                characteristic_properties(&characteristic).await?.join(", ")
+            );
+
+            let mut descriptors = characteristic.descriptors().await?;
+            descriptors.sort_by_key(|d| d.id());
+
+            for descriptor in descriptors {
+                println!(
+                    "    Descriptor {} (id {})",
+                    descriptor.uuid().await?,
+                    descriptor.id()
+                );
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// Names of the properties set on a characteristic, e.g. "read", "notify"
+async fn characteristic_properties(characteristic: &Characteristic) -> Result<Vec<String>> {
+    let flags = serde_json::to_value(characteristic.flags().await?)?;
+
+    Ok(flags
+        .as_object()
+        .map(|flags| {
+            flags
+                .iter()
+                .filter(|(_, set)| set.as_bool().unwrap_or(false))
+                .map(|(name, _)| name.replace('_', "-"))
+                .collect()
+        })
+        .unwrap_or_default())
+}
@@ -0,0 +1,159 @@
+use anyhow::{anyhow, Result};
+use clap::ValueEnum;
+use log::debug;
+use serde_json::{Map, Value};
+use std::fmt;
+use std::str::FromStr;
+
+// AutoPID names aa-proxy-wican reads, as listed in the README
+pub const AUTOPID_FIELDS: &[&str] = &[
+    "SOC",
+    "SOC_D",
+    "TMP_A",
+    "PRECOND",
+    "PLUG",
+    "CHG_PORT",
+    "CHG_TYPE",
+    "PWR",
+    "CELL_TMIN",
+    "CELL_TMAX",
+    "COOL_TMP",
+    "HVAC_PWR",
+    "SPEED",
+    "SOH",
+    "CHARGING",
+    "BATT_TMP",
+    "ODO",
+    "AUX_V",
+];
+
+// Built-in mappings for autopid profiles that report fields under other keys
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum VehicleProfile {
+    // The AutoPID names as listed in the README
+    Generic,
+    // Hyundai Ioniq 5 and 6, Kia EV6 and other E-GMP vehicles
+    Ioniq5,
+    // Hyundai Kona Electric and Kia e-Niro
+    Kona,
+    // Volkswagen ID.3, ID.4 and other MEB vehicles
+    Id3,
+}
+
+impl fmt::Display for VehicleProfile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VehicleProfile::Generic => write!(f, "generic"),
+            VehicleProfile::Ioniq5 => write!(f, "ioniq5"),
+            VehicleProfile::Kona => write!(f, "kona"),
+            VehicleProfile::Id3 => write!(f, "id3"),
+        }
+    }
+}
+
+impl VehicleProfile {
+    // Keys each field is read from, in order of preference
+    fn keys(self) -> &'static [(&'static str, &'static [&'static str])] {
+        match self {
+            VehicleProfile::Generic => &[],
+            VehicleProfile::Ioniq5 => &[
+                ("SOC", &["SOC_BMS"]),
+                ("SOC_D", &["SOC_DISP"]),
+                ("TMP_A", &["EXT_T", "AMB_T"]),
+                ("CELL_TMIN", &["HV_T_MIN", "BATT_TMIN"]),
+                ("CELL_TMAX", &["HV_T_MAX", "BATT_TMAX"]),
+                ("ODO", &["ODOMETER"]),
+            ],
+            VehicleProfile::Kona => &[
+                ("SOC", &["SOC_BMS"]),
+                ("SOC_D", &["SOC_DISP"]),
+                ("TMP_A", &["EXT_T"]),
+                ("BATT_TMP", &["HV_T"]),
+                ("ODO", &["ODOMETER"]),
+            ],
+            VehicleProfile::Id3 => &[
+                ("SOC", &["HV_SOC"]),
+                ("SOC_D", &["SOC_USER"]),
+                ("TMP_A", &["AMB_T"]),
+                ("BATT_TMP", &["HV_T"]),
+                ("ODO", &["ODOMETER"]),
+            ],
+        }
+    }
+}
+
+// The keys one field is read from, written FIELD=KEY[:KEY...], e.g.
+// SOC=StateOfCharge
+#[derive(Debug, Clone, PartialEq)]
+pub struct AutopidKey {
+    pub field: String,
+    pub keys: Vec<String>,
+}
+
+impl FromStr for AutopidKey {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (field, keys) = s
+            .split_once('=')
+            .ok_or_else(|| anyhow!("Expected FIELD=KEY[:KEY...], got '{}'", s))?;
+        let field = field.trim().to_uppercase();
+        if !AUTOPID_FIELDS.contains(&field.as_str()) {
+            return Err(anyhow!(
+                "Unknown autopid field '{}', expected one of {}",
+                field,
+                AUTOPID_FIELDS.join(", ")
+            ));
+        }
+        let keys: Vec<String> = keys
+            .split(':')
+            .map(|key| key.trim().to_string())
+            .filter(|key| !key.is_empty())
+            .collect();
+        if keys.is_empty() {
+            return Err(anyhow!("No keys given for {}", field));
+        }
+        Ok(Self { field, keys })
+    }
+}
+
+// Where each field is found in the autopid JSON, from the vehicle profile
+// with any --autopid-key replacing the profile's keys for that field
+#[derive(Debug, Clone, Default)]
+pub struct AutopidKeys {
+    keys: Vec<AutopidKey>,
+}
+
+impl AutopidKeys {
+    pub fn new(profile: VehicleProfile, overrides: &[AutopidKey]) -> Self {
+        let mut keys: Vec<AutopidKey> = profile
+            .keys()
+            .iter()
+            .filter(|(field, _)| !overrides.iter().any(|key| key.field == *field))
+            .map(|(field, keys)| AutopidKey {
+                field: field.to_string(),
+                keys: keys.iter().map(|key| key.to_string()).collect(),
+            })
+            .collect();
+        keys.extend(overrides.iter().cloned());
+        Self { keys }
+    }
+
+    // Copy each mapped value to its AutoPID name, replacing any value already
+    // reported under that name
+    pub fn apply(&self, response: &mut Map<String, Value>) {
+        for mapping in &self.keys {
+            let Some((key, value)) = mapping
+                .keys
+                .iter()
+                .find_map(|key| Some((key, response.get(key)?.clone())))
+            else {
+                continue;
+            };
+            if *key != mapping.field {
+                debug!("Reading {} from {}", mapping.field, key);
+                response.insert(mapping.field.clone(), value);
+            }
+        }
+    }
+}
@@ -0,0 +1,164 @@
+use anyhow::{Context, Result};
+use chrono::Utc;
+use log::{info, warn};
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::BatteryData;
+
+pub const RETRY_QUEUE_FILE: &str = "retry-queue.json";
+
+// Bounded queue of samples that failed to post, oldest first
+pub struct RetryQueue {
+    samples: VecDeque<BatteryData>,
+    capacity: usize,
+    // Samples read longer ago are dropped rather than posted late
+    max_age: Option<Duration>,
+    // File the queue is kept in, so it survives restarts
+    path: Option<PathBuf>,
+}
+
+impl RetryQueue {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            samples: VecDeque::with_capacity(capacity),
+            capacity,
+            max_age: None,
+            path: None,
+        }
+    }
+
+    pub fn with_max_age(self, max_age: Option<Duration>) -> Self {
+        Self { max_age, ..self }
+    }
+
+    // Keep the queue in a file, starting with the samples the last run left
+    // there
+    pub fn persisted(mut self, path: PathBuf) -> Self {
+        match load(&path) {
+            Ok(samples) if !samples.is_empty() => {
+                info!(
+                    "Loaded {} queued sample(s) from '{}'.",
+                    samples.len(),
+                    path.display()
+                );
+                self.path = Some(path);
+                self.extend(samples);
+                self.drop_expired();
+            }
+            Ok(_) => self.path = Some(path),
+            Err(e) => {
+                warn!("Starting with an empty retry queue: {:#}", e);
+                self.path = Some(path);
+            }
+        }
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.samples.len()
+    }
+
+    // Queue samples for a later retry, dropping the oldest when full
+    pub fn extend(&mut self, samples: impl IntoIterator<Item = BatteryData>) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        for sample in samples {
+            if self.samples.len() == self.capacity {
+                self.samples.pop_front();
+                warn!("Retry queue is full. Dropping the oldest queued sample.");
+            }
+            self.samples.push_back(sample);
+        }
+        self.save();
+    }
+
+    pub fn samples(&self) -> Vec<BatteryData> {
+        self.samples.iter().cloned().collect()
+    }
+
+    // Remove and return every queued sample that isn't too old to post
+    pub fn take_all(&mut self) -> Vec<BatteryData> {
+        self.drop_expired();
+        let samples = self.samples.drain(..).collect();
+        self.save();
+        samples
+    }
+
+    fn drop_expired(&mut self) {
+        let Some(max_age) = self.max_age else {
+            return;
+        };
+        let now = Utc::now();
+        let before = self.samples.len();
+        self.samples.retain(|sample| {
+            sample
+                .timestamp
+                .is_none_or(|timestamp| (now - timestamp).to_std().unwrap_or_default() <= max_age)
+        });
+        let expired = before - self.samples.len();
+        if expired > 0 {
+            info!(
+                "Dropped {} queued sample(s) older than {:?}.",
+                expired, max_age
+            );
+            self.save();
+        }
+    }
+
+    // Write to a temporary file and rename it, so a power cut never leaves a
+    // partial queue behind
+    fn save(&self) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        let samples: Vec<&BatteryData> = self.samples.iter().collect();
+        let temporary = path.with_extension("json.tmp");
+        let saved = serde_json::to_vec(&samples)
+            .map_err(anyhow::Error::from)
+            .and_then(|contents| {
+                std::fs::write(&temporary, contents)
+                    .with_context(|| format!("Failed to write '{}'", temporary.display()))?;
+                std::fs::rename(&temporary, path)
+                    .with_context(|| format!("Failed to replace '{}'", path.display()))
+            });
+        if let Err(e) = saved {
+            warn!("Failed to save the retry queue: {:#}", e);
+        }
+    }
+}
+
+// Send a sample after any samples queued by earlier failures, oldest first.
+// Once one fails, it and the rest are queued for the next attempt and the
+// error is returned with the number of samples now queued.
+pub async fn send_in_order(
+    queue: &Mutex<RetryQueue>,
+    sample: &BatteryData,
+    mut send: impl AsyncFnMut(&BatteryData) -> Result<()>,
+) -> Result<(), (anyhow::Error, usize)> {
+    let mut pending = queue.lock().unwrap().take_all();
+    pending.push(sample.clone());
+
+    let mut pending = pending.into_iter();
+    while let Some(sample) = pending.next() {
+        if let Err(e) = send(&sample).await {
+            let mut queue = queue.lock().unwrap();
+            queue.extend(std::iter::once(sample).chain(pending));
+            return Err((e, queue.len()));
+        }
+    }
+    Ok(())
+}
+
+fn load(path: &Path) -> Result<Vec<BatteryData>> {
+    match std::fs::read(path) {
+        Ok(contents) => serde_json::from_slice(&contents)
+            .with_context(|| format!("Failed to parse '{}'", path.display())),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e).with_context(|| format!("Failed to read '{}'", path.display())),
+    }
+}
@@ -0,0 +1,185 @@
+use crate::can::{CanFrame, SlcanReader};
+use crate::canlog::CanLog;
+use crate::control::CONTROL;
+use crate::dbc::{self, Dbc, SignalMapping};
+use crate::dongle::Dongle;
+use crate::link::LinkQuality;
+use crate::merge::SampleSource;
+use crate::metadata::SourceMetadata;
+use crate::socketcan::CanSocket;
+use crate::vehicle::Vehicle;
+use crate::{trace, BatteryData, Outputs, WriteType};
+use anyhow::{anyhow, Context, Result};
+use bluer::gatt::remote::Characteristic;
+use bluer::Device;
+use chrono::Utc;
+use futures_util::StreamExt;
+use log::{debug, info, warn};
+use std::collections::HashMap;
+use tokio::time::Instant;
+
+// SLCAN command opening the CAN channel, ignored if it is already open
+const OPEN_CHANNEL: &[u8] = b"O\r";
+
+// How raw frames are turned into samples
+pub struct RawDecoder {
+    pub dbc: Dbc,
+    pub mappings: Vec<SignalMapping>,
+}
+
+// What happens to raw frames: decoded into samples, logged, bridged to a
+// socketcan interface, or any combination
+pub struct RawFrames {
+    pub decoder: Option<RawDecoder>,
+    pub log: Option<CanLog>,
+    pub bridge: Option<CanSocket>,
+    // Send frames other programs put on the bridge interface to the bus
+    pub forward_writes: bool,
+}
+
+// Stay subscribed to a WiCAN in SLCAN mode, logging and bridging every frame
+// and posting a sample from the mapped signals at the update frequency,
+// returning once the notification stream ends
+pub async fn stream_frames(
+    device: &Device,
+    dongle: Dongle,
+    vehicle: &Vehicle,
+    outputs: &Outputs<'_>,
+    source: Option<&SourceMetadata>,
+    raw: &mut RawFrames,
+    write_type: WriteType,
+) -> Result<()> {
+    if dongle.is_elm327() {
+        return Err(anyhow!("Raw frames need a WiCAN, not an {}", dongle));
+    }
+
+    let (notify_char, write_char) = dongle
+        .characteristics(device)
+        .await
+        .context("Failed to find WiCAN characteristics")?;
+    let mut notifications = Box::pin(
+        notify_char
+            .notify()
+            .await?
+            .inspect(|frame| trace::frame(trace::Direction::Received, frame)),
+    );
+    crate::write_command(&write_char, OPEN_CHANNEL, write_type).await?;
+    match &raw.decoder {
+        Some(decoder) => info!(
+            "Subscribed to raw CAN frames, decoding them with {} DBC signal(s)...",
+            decoder.dbc.signal_count()
+        ),
+        None => info!("Subscribed to raw CAN frames..."),
+    }
+    if let Some(bridge) = &raw.bridge {
+        info!(
+            "Bridging CAN frames to {}{}.",
+            bridge.interface(),
+            if raw.forward_writes { " and back" } else { "" }
+        );
+    }
+
+    let mut reader = SlcanReader::default();
+    let mut signals: HashMap<String, f64> = HashMap::new();
+    let mut last_sample: Option<Instant> = None;
+    let mut control = CONTROL.listen();
+    // Only warn when logging or bridging starts failing, not for every frame
+    // after that
+    let mut log_failing = false;
+    let mut bridge_failing = false;
+    loop {
+        let writes = raw.bridge.as_ref().filter(|_| raw.forward_writes);
+        let notification = tokio::select! {
+            notification = notifications.next() => notification,
+            frame = next_write(writes) => {
+                forward_write(&write_char, frame, write_type).await;
+                continue;
+            }
+        };
+        let Some(notification) = notification else {
+            break;
+        };
+
+        let received = Utc::now();
+        for frame in reader.push(&notification) {
+            if let Some(log) = raw.log.as_mut() {
+                report_failure(&mut log_failing, log.write(&frame, received));
+            }
+            if let Some(bridge) = &raw.bridge {
+                report_failure(&mut bridge_failing, bridge.send(&frame));
+            }
+            if let Some(decoder) = &raw.decoder {
+                for (name, value) in decoder.dbc.decode(&frame) {
+                    signals.insert(name.to_string(), value);
+                }
+            }
+        }
+
+        let Some(decoder) = &raw.decoder else {
+            continue;
+        };
+
+        let interval = CONTROL.poll_interval();
+        let due = last_sample.is_none_or(|last| last.elapsed() >= interval)
+            || control.take_poll_request();
+        if !due || CONTROL.is_paused() {
+            continue;
+        }
+
+        let response = match dbc::map_signals(&decoder.mappings, &signals) {
+            Ok(Some(response)) => response,
+            Ok(None) => continue,
+            Err(e) => {
+                warn!("Failed to map decoded signals: {:#}", e);
+                continue;
+            }
+        };
+        debug!("Decoded signals: {:?}", signals);
+        last_sample = Some(Instant::now());
+        let battery_data = BatteryData {
+            source: source.cloned(),
+            link: LinkQuality::read(device).await,
+            ..crate::battery_data(response, vehicle)
+        };
+        outputs.publish(SampleSource::Wican, battery_data).await;
+    }
+
+    warn!("Notification stream ended.");
+    Ok(())
+}
+
+// Next frame to forward from the bridge interface, never ready without one
+async fn next_write(bridge: Option<&CanSocket>) -> Result<CanFrame> {
+    match bridge {
+        Some(bridge) => bridge.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+async fn forward_write(
+    write_char: &Characteristic,
+    frame: Result<CanFrame>,
+    write_type: WriteType,
+) {
+    let result = match frame {
+        Ok(frame) => {
+            debug!("Forwarding {} to the WiCAN", frame);
+            crate::write_command(write_char, frame.to_slcan().as_bytes(), write_type).await
+        }
+        Err(e) => Err(e),
+    };
+    if let Err(e) = result {
+        warn!("Failed to forward a CAN frame: {:#}", e);
+    }
+}
+
+fn report_failure(failing: &mut bool, result: Result<()>) {
+    match result {
+        Ok(()) => *failing = false,
+        Err(e) if !*failing => {
+            warn!("{:#}", e);
+            *failing = true;
+        }
+        Err(_) => {}
+    }
+}
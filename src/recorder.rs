@@ -0,0 +1,168 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use clap::ValueEnum;
+use log::info;
+use serde::Serialize;
+use std::fs::OpenOptions;
+use std::io::{ErrorKind, Write};
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use crate::charging::ChargingType;
+use crate::paths;
+use crate::BatteryData;
+
+const CSV_HEADER: &str = "timestamp,soc,battery_level_wh,external_temp_celsius,charging,charging_type,plug_inserted,estimated_range_km,post_result,error";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum RecordFormat {
+    Jsonl,
+    Csv,
+}
+
+// What became of a sample sent to aa-proxy-rs
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PostResult {
+    Posted,
+    // Sent over the WebSocket instead of posted
+    Pushed,
+    Failed,
+}
+
+impl PostResult {
+    fn as_str(self) -> &'static str {
+        match self {
+            PostResult::Posted => "posted",
+            PostResult::Pushed => "pushed",
+            PostResult::Failed => "failed",
+        }
+    }
+}
+
+// One line of the recording
+#[derive(Debug, Serialize)]
+struct Record<'a> {
+    timestamp: Option<DateTime<Utc>>,
+    soc: Option<f32>,
+    battery_level_wh: Option<u16>,
+    external_temp_celsius: Option<f32>,
+    charging: Option<bool>,
+    charging_type: Option<ChargingType>,
+    plug_inserted: Option<bool>,
+    estimated_range_km: Option<f32>,
+    post_result: PostResult,
+    error: Option<&'a str>,
+}
+
+impl Record<'_> {
+    fn csv(&self) -> String {
+        fn field<T: ToString>(value: Option<T>) -> String {
+            value.map(|value| value.to_string()).unwrap_or_default()
+        }
+        [
+            field(self.timestamp.map(|timestamp| timestamp.to_rfc3339())),
+            field(self.soc),
+            field(self.battery_level_wh),
+            field(self.external_temp_celsius),
+            field(self.charging),
+            field(
+                self.charging_type
+                    .map(|charging_type| charging_type.to_string()),
+            ),
+            field(self.plug_inserted),
+            field(self.estimated_range_km),
+            self.post_result.as_str().to_string(),
+            self.error.map(quote).unwrap_or_default(),
+        ]
+        .join(",")
+    }
+}
+
+// Quote a CSV field, error messages can hold commas and quotes
+fn quote(value: &str) -> String {
+    format!("\"{}\"", value.replace('\n', " ").replace('"', "\"\""))
+}
+
+pub struct RecorderOptions {
+    pub path: PathBuf,
+    pub format: RecordFormat,
+    // Size the file may grow to before it is rotated, 0 to never rotate
+    pub max_bytes: u64,
+    // Rotated files kept as PATH.1 to PATH.N, the oldest dropped after that
+    pub max_files: usize,
+}
+
+// Appends every sample along with what became of its post to a JSON lines or
+// CSV file, for charting the SOC and comparing what the car reported with
+// what reached aa-proxy-rs
+pub struct DataRecorder {
+    options: RecorderOptions,
+    // Held while writing so rotation never races another sample
+    lock: Mutex<()>,
+}
+
+impl DataRecorder {
+    pub fn new(options: RecorderOptions) -> Self {
+        info!("Recording samples to {}.", options.path.display());
+        Self {
+            options,
+            lock: Mutex::new(()),
+        }
+    }
+
+    pub fn record(
+        &self,
+        sample: &BatteryData,
+        result: PostResult,
+        error: Option<&anyhow::Error>,
+    ) -> Result<()> {
+        let error = error.map(|e| format!("{:#}", e));
+        let record = Record {
+            timestamp: sample.timestamp,
+            soc: sample.battery_level_percentage,
+            battery_level_wh: sample.battery_level_wh,
+            external_temp_celsius: sample.external_temp_celsius,
+            charging: sample.charging,
+            charging_type: sample.charging_type,
+            plug_inserted: sample.plug_inserted,
+            estimated_range_km: sample.estimated_range_km,
+            post_result: result,
+            error: error.as_deref(),
+        };
+        let mut line = match self.options.format {
+            RecordFormat::Jsonl => serde_json::to_string(&record)?,
+            RecordFormat::Csv => record.csv(),
+        };
+        line.push('\n');
+
+        let _lock = self.lock.lock().unwrap();
+        let path = &self.options.path;
+        let size = match std::fs::metadata(path) {
+            Ok(metadata) => metadata.len(),
+            Err(e) if e.kind() == ErrorKind::NotFound => 0,
+            Err(e) => {
+                return Err(e).with_context(|| format!("Failed to read '{}'", path.display()))
+            }
+        };
+        let size = if self.options.max_bytes > 0
+            && size > 0
+            && size + line.len() as u64 > self.options.max_bytes
+        {
+            paths::rotate(path, self.options.max_files)?;
+            info!("Rotated {}.", path.display());
+            0
+        } else {
+            size
+        };
+        if size == 0 && self.options.format == RecordFormat::Csv {
+            line.insert_str(0, &format!("{}\n", CSV_HEADER));
+        }
+        OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(path)
+            .and_then(|mut file| file.write_all(line.as_bytes()))
+            .with_context(|| format!("Failed to append to '{}'", path.display()))
+    }
+}
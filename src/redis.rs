@@ -0,0 +1,209 @@
+use anyhow::{anyhow, Context, Result};
+use log::{debug, info, warn};
+use reqwest::Url;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufStream};
+use tokio::net::TcpStream;
+use tokio::sync::Mutex;
+use tokio::time;
+
+use crate::secrets::{self, Secret};
+use crate::BatteryData;
+
+pub const DEFAULT_PORT: u16 = 6379;
+
+// Limit for each connection attempt and command
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+pub struct RedisSinkOptions {
+    // redis://[username[:password]@]host[:port][/database]
+    pub url: String,
+    pub password: Option<Secret>,
+    pub channel: Option<String>,
+    pub key: Option<String>,
+    pub key_ttl: Option<Duration>,
+}
+
+// Sink publishing each sample as JSON to a Redis or Valkey channel and/or
+// storing it under a key holding the latest sample, spoken over RESP directly
+pub struct RedisSink {
+    host: String,
+    port: u16,
+    username: Option<String>,
+    password: Option<String>,
+    database: Option<u32>,
+    channel: Option<String>,
+    key: Option<String>,
+    key_ttl: Option<Duration>,
+    connection: Mutex<Option<BufStream<TcpStream>>>,
+}
+
+// Reply to a command, errors being returned as such
+enum Reply {
+    Simple,
+    Integer(i64),
+    Bulk,
+}
+
+impl RedisSink {
+    pub fn new(options: RedisSinkOptions) -> Result<Self> {
+        let url = Url::parse(&options.url).with_context(|| {
+            format!("Invalid Redis url '{}'", secrets::redact_url(&options.url))
+        })?;
+        if url.scheme() != "redis" {
+            return Err(anyhow!(
+                "Only redis:// urls are supported, not '{}'",
+                url.scheme()
+            ));
+        }
+        if options.channel.is_none() && options.key.is_none() {
+            return Err(anyhow!(
+                "--redis-url needs --redis-channel, --redis-key or both"
+            ));
+        }
+        let database = match url.path().trim_matches('/') {
+            "" => None,
+            database => Some(
+                database
+                    .parse()
+                    .with_context(|| format!("Invalid Redis database '{}'", database))?,
+            ),
+        };
+        let password = match options.password {
+            Some(password) => Some(password.expose().to_string()),
+            None => url.password().map(str::to_string),
+        };
+
+        Ok(Self {
+            host: url
+                .host_str()
+                .ok_or_else(|| anyhow!("Redis url has no host"))?
+                .to_string(),
+            port: url.port().unwrap_or(DEFAULT_PORT),
+            username: Some(url.username().to_string()).filter(|username| !username.is_empty()),
+            password,
+            database,
+            channel: options.channel,
+            key: options.key,
+            key_ttl: options.key_ttl,
+            connection: Mutex::new(None),
+        })
+    }
+
+    pub async fn send(&self, sample: &BatteryData) {
+        if let Err(e) = self.publish(sample).await {
+            warn!("Failed to send sample to Redis: {:#}", e);
+        }
+    }
+
+    // Run the commands for a sample, reconnecting once if the connection
+    // kept from the previous sample has gone stale
+    async fn publish(&self, sample: &BatteryData) -> Result<()> {
+        let payload = serde_json::to_string(sample)?;
+        let mut connection = self.connection.lock().await;
+        let reused = connection.is_some();
+        let result = self.run_commands(&mut connection, &payload).await;
+        match result {
+            Err(e) if reused => {
+                debug!("Redis connection failed, reconnecting: {:#}", e);
+                self.run_commands(&mut connection, &payload).await
+            }
+            result => result,
+        }
+    }
+
+    async fn run_commands(
+        &self,
+        connection: &mut Option<BufStream<TcpStream>>,
+        payload: &str,
+    ) -> Result<()> {
+        if connection.is_none() {
+            *connection = Some(self.connect().await?);
+        }
+        let stream = connection.as_mut().unwrap();
+        let result = async {
+            if let Some(channel) = &self.channel {
+                let reply = command(stream, &["PUBLISH", channel, payload]).await?;
+                if let Reply::Integer(0) = reply {
+                    debug!("No Redis subscribers on {}", channel);
+                }
+            }
+            if let Some(key) = &self.key {
+                match self.key_ttl {
+                    Some(ttl) => {
+                        let seconds = ttl.as_secs().to_string();
+                        command(stream, &["SET", key, payload, "EX", &seconds]).await?
+                    }
+                    None => command(stream, &["SET", key, payload]).await?,
+                };
+            }
+            Ok(())
+        }
+        .await;
+        if result.is_err() {
+            *connection = None;
+        }
+        result
+    }
+
+    async fn connect(&self) -> Result<BufStream<TcpStream>> {
+        let tcp = time::timeout(TIMEOUT, TcpStream::connect((self.host.as_str(), self.port)))
+            .await
+            .map_err(|_| anyhow!("Timed out connecting to {}:{}", self.host, self.port))?
+            .with_context(|| format!("Failed to connect to {}:{}", self.host, self.port))?;
+        let mut stream = BufStream::new(tcp);
+        if let Some(password) = &self.password {
+            match &self.username {
+                Some(username) => command(&mut stream, &["AUTH", username, password]).await,
+                None => command(&mut stream, &["AUTH", password]).await,
+            }
+            .context("Redis authentication failed")?;
+        }
+        if let Some(database) = self.database {
+            command(&mut stream, &["SELECT", &database.to_string()]).await?;
+        }
+        info!("Connected to Redis at {}:{}", self.host, self.port);
+        Ok(stream)
+    }
+}
+
+// Send a command as an array of bulk strings and read its reply
+async fn command(stream: &mut BufStream<TcpStream>, arguments: &[&str]) -> Result<Reply> {
+    let mut request = format!("*{}\r\n", arguments.len()).into_bytes();
+    for argument in arguments {
+        request.extend(format!("${}\r\n", argument.len()).as_bytes());
+        request.extend(argument.as_bytes());
+        request.extend(b"\r\n");
+    }
+    time::timeout(TIMEOUT, async {
+        stream.write_all(&request).await?;
+        stream.flush().await?;
+        read_reply(stream).await
+    })
+    .await
+    .map_err(|_| anyhow!("Redis did not answer {} within {:?}", arguments[0], TIMEOUT))?
+    .with_context(|| format!("{} failed", arguments[0]))
+}
+
+async fn read_reply(stream: &mut BufStream<TcpStream>) -> Result<Reply> {
+    let mut line = String::new();
+    if stream.read_line(&mut line).await? == 0 {
+        return Err(anyhow!("Connection closed"));
+    }
+    let line = line.trim_end();
+    let (kind, value) = line.split_at(line.len().min(1));
+    match kind {
+        "+" => Ok(Reply::Simple),
+        "-" => Err(anyhow!("{}", value)),
+        ":" => Ok(Reply::Integer(value.parse()?)),
+        "$" => {
+            // A value of -1 is a null reply with nothing following it
+            if let Ok(length) = value.parse::<usize>() {
+                let mut data = vec![0; length + 2];
+                stream.read_exact(&mut data).await?;
+            }
+            Ok(Reply::Bulk)
+        }
+        _ => Err(anyhow!("Unexpected reply '{}'", line)),
+    }
+}
@@ -0,0 +1,64 @@
+use crate::api::ApiClient;
+use crate::history::read_samples;
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use log::{info, warn};
+use std::path::Path;
+use std::time::Duration;
+use tokio::time;
+
+// Re-post recorded samples, waiting between them for the recorded interval
+// divided by the speed factor, or not at all for a speed of 0
+pub async fn replay(api: &ApiClient, path: &Path, speed: f64) -> Result<()> {
+    if speed < 0.0 {
+        return Err(anyhow!("--speed must not be negative"));
+    }
+
+    let samples = read_samples(path)?;
+    info!(
+        "Replaying {} sample(s) from '{}'...",
+        samples.len(),
+        path.display()
+    );
+
+    let mut previous: Option<DateTime<Utc>> = None;
+    let mut failures = 0;
+    for (index, sample) in samples.iter().enumerate() {
+        if let (Some(previous), Some(timestamp), true) = (previous, sample.timestamp, speed > 0.0) {
+            let recorded = (timestamp - previous).to_std().unwrap_or_default();
+            let delay = recorded.div_f64(speed);
+            if delay > Duration::ZERO {
+                info!("Waiting {:?} before the next sample...", delay);
+                time::sleep(delay).await;
+            }
+        }
+        previous = sample.timestamp.or(previous);
+
+        info!(
+            "Replaying sample {}/{} recorded at {}",
+            index + 1,
+            samples.len(),
+            sample
+                .timestamp
+                .map_or("unknown time".to_string(), |time| time.to_rfc3339())
+        );
+        // A fresh sequence number keeps aa-proxy-rs from taking it as out of
+        // order, while the recorded timestamp says when it was read
+        if let Err(e) = api.post_battery_data(&sample.clone().resequence()).await {
+            warn!("Failed to replay sample {}: {}", index + 1, e);
+            failures += 1;
+        }
+    }
+
+    match failures {
+        0 => {
+            info!("Replay finished.");
+            Ok(())
+        }
+        failures => Err(anyhow!(
+            "{} of {} sample(s) failed to post",
+            failures,
+            samples.len()
+        )),
+    }
+}
@@ -0,0 +1,250 @@
+use anyhow::{anyhow, Context, Result};
+use bluer::Address;
+use log::{debug, info};
+use std::fmt;
+use std::path::Path;
+use std::sync::Mutex;
+
+// Where BlueZ keeps the keys of bonded devices, one directory per adapter
+const BLUEZ_STORAGE: &str = "/var/lib/bluetooth";
+
+// Identity addresses and IRKs resolvable addresses are matched against, one
+// for each WiCAN in use
+static IDENTITIES: Mutex<Vec<(Address, Irk)>> = Mutex::new(Vec::new());
+
+// Identity resolving key exchanged when bonding, which lets the random
+// addresses a device rotates through be traced back to it
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Irk([u8; 16]);
+
+// Kept out of the parsed configuration when it is printed
+impl fmt::Debug for Irk {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Irk(***)")
+    }
+}
+
+impl Irk {
+    // Parse a key written as 32 hex digits, least significant byte first as
+    // BlueZ stores it
+    pub fn from_hex(value: &str) -> Result<Self> {
+        let bytes = hex::decode(value.trim()).context("The IRK is not hex")?;
+        let mut key: [u8; 16] = bytes
+            .try_into()
+            .map_err(|_| anyhow!("The IRK must be 16 bytes"))?;
+        key.reverse();
+        Ok(Self(key))
+    }
+
+    pub fn to_hex(self) -> String {
+        let mut key = self.0;
+        key.reverse();
+        hex::encode_upper(key)
+    }
+
+    // Whether an address is a resolvable private address generated with this
+    // key: its upper half is a random value whose hash is the lower half
+    pub fn resolves(&self, address: Address) -> bool {
+        let address = address.0;
+        if address[0] >> 6 != 0b01 {
+            return false;
+        }
+        let mut block = [0u8; 16];
+        block[13..].copy_from_slice(&address[..3]);
+        let hash = aes128_encrypt(&self.0, block);
+        hash[13..] == address[3..]
+    }
+}
+
+// Read the IRK BlueZ stored when bonding with a device, needing root
+pub fn read_bluez_irk(address: Address) -> Option<Irk> {
+    let adapters = std::fs::read_dir(BLUEZ_STORAGE).ok()?;
+    for adapter in adapters.filter_map(|entry| entry.ok()) {
+        let info = adapter.path().join(address.to_string()).join("info");
+        if let Some(irk) = read_info_irk(&info) {
+            debug!("Read the IRK of {} from {}.", address, info.display());
+            return Some(irk);
+        }
+    }
+    None
+}
+
+fn read_info_irk(path: &Path) -> Option<Irk> {
+    let contents = std::fs::read_to_string(path).ok()?;
+    let mut in_section = false;
+    for line in contents.lines().map(str::trim) {
+        if line.starts_with('[') {
+            in_section = line == "[IdentityResolvingKey]";
+        } else if let (true, Some(key)) = (in_section, line.strip_prefix("Key=")) {
+            return Irk::from_hex(key).ok();
+        }
+    }
+    None
+}
+
+// Match resolvable addresses against a WiCAN's IRK from now on
+pub fn set_identity(address: Address, irk: Irk) {
+    let mut identities = IDENTITIES.lock().unwrap();
+    if !identities.iter().any(|(known, _)| *known == address) {
+        identities.push((address, irk));
+        info!("Matching resolvable private addresses of {}.", address);
+    }
+}
+
+// Whether an address belongs to the device with an identity address, either
+// directly or as a resolvable private address
+pub fn matches(address: Address, identity: Address) -> bool {
+    address == identity
+        || IDENTITIES
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|(known, irk)| *known == identity && irk.resolves(address))
+}
+
+// Identity address of a resolvable private address, or the address itself
+pub fn identity(address: Address) -> Address {
+    IDENTITIES
+        .lock()
+        .unwrap()
+        .iter()
+        .find(|(_, irk)| irk.resolves(address))
+        .map_or(address, |(identity, _)| *identity)
+}
+
+#[rustfmt::skip]
+const SBOX: [u8; 256] = [
+    0x63, 0x7c, 0x77, 0x7b, 0xf2, 0x6b, 0x6f, 0xc5, 0x30, 0x01, 0x67, 0x2b, 0xfe, 0xd7, 0xab, 0x76,
+    0xca, 0x82, 0xc9, 0x7d, 0xfa, 0x59, 0x47, 0xf0, 0xad, 0xd4, 0xa2, 0xaf, 0x9c, 0xa4, 0x72, 0xc0,
+    0xb7, 0xfd, 0x93, 0x26, 0x36, 0x3f, 0xf7, 0xcc, 0x34, 0xa5, 0xe5, 0xf1, 0x71, 0xd8, 0x31, 0x15,
+    0x04, 0xc7, 0x23, 0xc3, 0x18, 0x96, 0x05, 0x9a, 0x07, 0x12, 0x80, 0xe2, 0xeb, 0x27, 0xb2, 0x75,
+    0x09, 0x83, 0x2c, 0x1a, 0x1b, 0x6e, 0x5a, 0xa0, 0x52, 0x3b, 0xd6, 0xb3, 0x29, 0xe3, 0x2f, 0x84,
+    0x53, 0xd1, 0x00, 0xed, 0x20, 0xfc, 0xb1, 0x5b, 0x6a, 0xcb, 0xbe, 0x39, 0x4a, 0x4c, 0x58, 0xcf,
+    0xd0, 0xef, 0xaa, 0xfb, 0x43, 0x4d, 0x33, 0x85, 0x45, 0xf9, 0x02, 0x7f, 0x50, 0x3c, 0x9f, 0xa8,
+    0x51, 0xa3, 0x40, 0x8f, 0x92, 0x9d, 0x38, 0xf5, 0xbc, 0xb6, 0xda, 0x21, 0x10, 0xff, 0xf3, 0xd2,
+    0xcd, 0x0c, 0x13, 0xec, 0x5f, 0x97, 0x44, 0x17, 0xc4, 0xa7, 0x7e, 0x3d, 0x64, 0x5d, 0x19, 0x73,
+    0x60, 0x81, 0x4f, 0xdc, 0x22, 0x2a, 0x90, 0x88, 0x46, 0xee, 0xb8, 0x14, 0xde, 0x5e, 0x0b, 0xdb,
+    0xe0, 0x32, 0x3a, 0x0a, 0x49, 0x06, 0x24, 0x5c, 0xc2, 0xd3, 0xac, 0x62, 0x91, 0x95, 0xe4, 0x79,
+    0xe7, 0xc8, 0x37, 0x6d, 0x8d, 0xd5, 0x4e, 0xa9, 0x6c, 0x56, 0xf4, 0xea, 0x65, 0x7a, 0xae, 0x08,
+    0xba, 0x78, 0x25, 0x2e, 0x1c, 0xa6, 0xb4, 0xc6, 0xe8, 0xdd, 0x74, 0x1f, 0x4b, 0xbd, 0x8b, 0x8a,
+    0x70, 0x3e, 0xb5, 0x66, 0x48, 0x03, 0xf6, 0x0e, 0x61, 0x35, 0x57, 0xb9, 0x86, 0xc1, 0x1d, 0x9e,
+    0xe1, 0xf8, 0x98, 0x11, 0x69, 0xd9, 0x8e, 0x94, 0x9b, 0x1e, 0x87, 0xe9, 0xce, 0x55, 0x28, 0xdf,
+    0x8c, 0xa1, 0x89, 0x0d, 0xbf, 0xe6, 0x42, 0x68, 0x41, 0x99, 0x2d, 0x0f, 0xb0, 0x54, 0xbb, 0x16,
+];
+
+// Multiply by x in GF(2^8)
+fn xtime(value: u8) -> u8 {
+    (value << 1) ^ if value & 0x80 != 0 { 0x1b } else { 0 }
+}
+
+// Encrypt one block with AES-128, the e() function the Bluetooth address
+// hash is built on
+fn aes128_encrypt(key: &[u8; 16], block: [u8; 16]) -> [u8; 16] {
+    let mut round_keys = [[0u8; 16]; 11];
+    round_keys[0] = *key;
+    let mut rcon = 1u8;
+    for round in 1..11 {
+        let previous = round_keys[round - 1];
+        let mut word = [previous[13], previous[14], previous[15], previous[12]];
+        for byte in &mut word {
+            *byte = SBOX[*byte as usize];
+        }
+        word[0] ^= rcon;
+        rcon = xtime(rcon);
+        for i in 0..16 {
+            let value = previous[i]
+                ^ if i < 4 {
+                    word[i]
+                } else {
+                    round_keys[round][i - 4]
+                };
+            round_keys[round][i] = value;
+        }
+    }
+
+    let mut state = block;
+    let add_round_key = |state: &mut [u8; 16], round: usize| {
+        for (byte, key) in state.iter_mut().zip(round_keys[round]) {
+            *byte ^= key;
+        }
+    };
+    add_round_key(&mut state, 0);
+    for round in 1..11 {
+        // SubBytes and ShiftRows, the state being stored column by column
+        let mut shifted = [0u8; 16];
+        for column in 0..4 {
+            for row in 0..4 {
+                shifted[column * 4 + row] = SBOX[state[((column + row) % 4) * 4 + row] as usize];
+            }
+        }
+        state = shifted;
+        if round < 10 {
+            for column in state.chunks_exact_mut(4) {
+                let [a, b, c, d] = [column[0], column[1], column[2], column[3]];
+                let all = a ^ b ^ c ^ d;
+                column[0] ^= all ^ xtime(a ^ b);
+                column[1] ^= all ^ xtime(b ^ c);
+                column[2] ^= all ^ xtime(c ^ d);
+                column[3] ^= all ^ xtime(d ^ a);
+            }
+        }
+        add_round_key(&mut state, round);
+    }
+    state
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Sample data for the ah() random address hash function, Bluetooth Core
+    // Specification Vol 3, Part H, D.7
+    const SPEC_IRK: [u8; 16] = [
+        0xec, 0x02, 0x34, 0xa3, 0x57, 0xc8, 0xad, 0x05, 0x34, 0x10, 0x10, 0xa6, 0x0a, 0x39, 0x7d,
+        0x9b,
+    ];
+
+    #[test]
+    fn aes128_matches_fips_197() {
+        let key: [u8; 16] = std::array::from_fn(|i| i as u8);
+        let block: [u8; 16] = std::array::from_fn(|i| (i as u8) * 0x11);
+        assert_eq!(
+            hex::encode(aes128_encrypt(&key, block)),
+            "69c4e0d86a7b0430d8cdb78070b4c55a"
+        );
+    }
+
+    #[test]
+    fn ah_matches_the_specification() {
+        let mut block = [0u8; 16];
+        block[13..].copy_from_slice(&[0x70, 0x81, 0x94]);
+        let hash = aes128_encrypt(&SPEC_IRK, block);
+        assert_eq!(hash[13..], [0x0d, 0xfb, 0xaa]);
+    }
+
+    #[test]
+    fn hex_is_least_significant_byte_first() {
+        let bluez = "9B7D390AA6101034 05ADC857A33402EC".replace(' ', "");
+        let irk = Irk::from_hex(&bluez).unwrap();
+        assert_eq!(irk, Irk(SPEC_IRK));
+        assert_eq!(irk.to_hex(), bluez);
+        assert!(Irk::from_hex("ec0234").is_err());
+        assert!(Irk::from_hex("not hex").is_err());
+    }
+
+    #[test]
+    fn resolves_only_its_own_addresses() {
+        let irk = Irk(SPEC_IRK);
+        assert!(irk.resolves(Address([0x70, 0x81, 0x94, 0x0d, 0xfb, 0xaa])));
+        assert!(!irk.resolves(Address([0x70, 0x81, 0x94, 0x0d, 0xfb, 0xab])));
+        // Same bits but a static random rather than resolvable address
+        assert!(!irk.resolves(Address([0xf0, 0x81, 0x94, 0x0d, 0xfb, 0xaa])));
+        let other = Irk([0x11; 16]);
+        assert!(!other.resolves(Address([0x70, 0x81, 0x94, 0x0d, 0xfb, 0xaa])));
+    }
+
+    #[test]
+    fn debug_hides_the_key() {
+        assert_eq!(format!("{:?}", Irk(SPEC_IRK)), "Irk(***)");
+    }
+}
@@ -0,0 +1,113 @@
+use anyhow::{anyhow, Context, Result};
+use landlock::{
+    path_beneath_rules, Access, AccessFs, AccessNet, NetPort, Ruleset, RulesetAttr,
+    RulesetCreatedAttr, RulesetStatus, ABI,
+};
+use log::{info, warn};
+use reqwest::Url;
+use std::path::{Path, PathBuf};
+
+use crate::secrets;
+
+// System paths the process still needs to read after sandboxing, e.g. for
+// name resolution, TLS roots and shared libraries
+const READ_ONLY_PATHS: &[&str] = &["/etc", "/usr", "/lib", "/lib64", "/proc", "/sys", "/dev"];
+
+// Paths and network targets the process may still use once sandboxed
+#[derive(Default)]
+pub struct SandboxRules {
+    readable_paths: Vec<PathBuf>,
+    writable_paths: Vec<PathBuf>,
+    tcp_ports: Vec<u16>,
+}
+
+impl SandboxRules {
+    pub fn allow_read(&mut self, path: impl AsRef<Path>) {
+        self.readable_paths.push(path.as_ref().to_path_buf());
+    }
+
+    pub fn allow_write(&mut self, path: impl AsRef<Path>) {
+        self.writable_paths.push(path.as_ref().to_path_buf());
+    }
+
+    pub fn allow_tcp_port(&mut self, port: u16) {
+        self.tcp_ports.push(port);
+    }
+
+    // Allow TCP connections to the port of a url
+    pub fn allow_url(&mut self, url: &str) -> Result<()> {
+        let url = Url::parse(url)
+            .with_context(|| format!("Invalid url '{}'", secrets::redact_url(url)))?;
+        let port = port(&url).ok_or_else(|| {
+            anyhow!(
+                "Unable to determine the port of '{}'",
+                secrets::redact_url(url.as_str())
+            )
+        })?;
+        self.tcp_ports.push(port);
+        Ok(())
+    }
+}
+
+// The host and port a url connects to, None when it can't be parsed
+pub fn target(url: &str) -> Option<(String, u16)> {
+    let url = Url::parse(url).ok()?;
+    Some((url.host_str()?.to_string(), port(&url)?))
+}
+
+fn port(url: &Url) -> Option<u16> {
+    url.port_or_known_default().or_else(|| match url.scheme() {
+        "mqtt" | "tcp" => Some(1883),
+        "mqtts" | "ssl" => Some(8883),
+        "redis" => Some(crate::redis::DEFAULT_PORT),
+        "postgres" | "postgresql" => Some(crate::postgres::DEFAULT_PORT),
+        "smtp" => Some(25),
+        "smtps" => Some(465),
+        _ => None,
+    })
+}
+
+// Restrict filesystem access and outgoing TCP connections with Landlock.
+// D-Bus is reached through a Unix socket, which these rules leave alone.
+pub fn restrict(rules: SandboxRules) -> Result<()> {
+    let abi = ABI::V4;
+
+    let read_only: Vec<&Path> = READ_ONLY_PATHS
+        .iter()
+        .map(Path::new)
+        .filter(|path| path.exists())
+        .collect();
+
+    let mut ruleset = Ruleset::default()
+        .handle_access(AccessFs::from_all(abi))?
+        .handle_access(AccessNet::from_all(abi))?
+        .create()?
+        .add_rules(path_beneath_rules(read_only, AccessFs::from_read(abi)))?
+        .add_rules(path_beneath_rules(
+            &rules.readable_paths,
+            AccessFs::from_read(abi),
+        ))?
+        .add_rules(path_beneath_rules(
+            &rules.writable_paths,
+            AccessFs::from_all(abi),
+        ))?;
+
+    for port in &rules.tcp_ports {
+        ruleset = ruleset.add_rule(NetPort::new(*port, AccessNet::ConnectTcp))?;
+    }
+
+    let status = ruleset
+        .restrict_self()
+        .context("Failed to apply the sandbox")?;
+
+    match status.ruleset {
+        RulesetStatus::FullyEnforced => info!("Sandbox enabled."),
+        RulesetStatus::PartiallyEnforced => {
+            warn!("Sandbox only partially enforced, the kernel does not support all Landlock features.")
+        }
+        RulesetStatus::NotEnforced => {
+            warn!("Sandbox not enforced, the kernel does not support Landlock.")
+        }
+    }
+    Ok(())
+}
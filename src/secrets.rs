@@ -0,0 +1,247 @@
+use anyhow::{anyhow, Context, Result};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use log::warn;
+use reqwest::Url;
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::fmt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::str::FromStr;
+
+// Prefix marking a secret value as encrypted
+const ENCRYPTED_PREFIX: &str = "enc:";
+const NONCE_LEN: usize = 12;
+
+// Environment variable that can hold the secrets key instead of a key file
+pub const SECRETS_KEY_ENV: &str = "AA_PROXY_WICAN_SECRETS_KEY";
+
+// Set by systemd to the directory holding LoadCredential= files
+const CREDENTIALS_DIRECTORY_ENV: &str = "CREDENTIALS_DIRECTORY";
+
+// Credential name the secrets key is loaded from
+const SECRETS_KEY_CREDENTIAL: &str = "secrets-key";
+
+// A configuration value that must not be leaked, optionally stored encrypted
+// as "enc:<base64>" and decrypted with the secrets key at startup
+#[derive(Clone, PartialEq, Eq)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+
+    // Replace an encrypted value with its plaintext
+    pub fn decrypt(&mut self, key: Option<&SecretsKey>) -> Result<()> {
+        let Some(encoded) = self.0.strip_prefix(ENCRYPTED_PREFIX) else {
+            return Ok(());
+        };
+        let key = key.ok_or_else(|| {
+            anyhow!(
+                "An encrypted secret was configured but no secrets key was provided. Use --secrets-key-file or {}.",
+                SECRETS_KEY_ENV
+            )
+        })?;
+        self.0 = key.decrypt(encoded)?;
+        Ok(())
+    }
+}
+
+impl FromStr for Secret {
+    type Err = Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Infallible> {
+        Ok(Self(s.to_string()))
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Secret(***)")
+    }
+}
+
+impl fmt::Display for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "***")
+    }
+}
+
+// Read a secret passed with systemd LoadCredential=, if running under systemd
+// and the credential exists
+pub fn load_credential(name: &str) -> Result<Option<Secret>> {
+    let Some(directory) = std::env::var_os(CREDENTIALS_DIRECTORY_ENV) else {
+        return Ok(None);
+    };
+
+    let path = Path::new(&directory).join(name);
+    if !path.exists() {
+        return Ok(None);
+    }
+
+    let value = std::fs::read_to_string(&path)
+        .with_context(|| format!("Failed to read credential {}", path.display()))?;
+    Ok(Some(Secret(
+        value.trim_end_matches(['\r', '\n']).to_string(),
+    )))
+}
+
+// Read a secret kept in a file of its own, such as --wican-passkey-file
+pub fn read_file(path: &Path) -> Result<Secret> {
+    let value = read_private(path)?;
+    Ok(Secret(value.trim_end_matches(['\r', '\n']).to_string()))
+}
+
+// Read a file of secrets, warning when others than its owner can read it
+fn read_private(path: &Path) -> Result<String> {
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read secrets from {}", path.display()))?;
+    if let Ok(metadata) = std::fs::metadata(path) {
+        if metadata.permissions().mode() & 0o077 != 0 {
+            warn!(
+                "{} can be read by other users, restrict it with chmod 600.",
+                path.display()
+            );
+        }
+    }
+    Ok(contents)
+}
+
+// Secrets given with --secrets-file, one NAME=VALUE per line using the
+// systemd credential names, with blank lines and # comments ignored
+pub struct SecretsFile(HashMap<String, Secret>);
+
+impl SecretsFile {
+    pub fn load(path: &Path) -> Result<Self> {
+        let contents = read_private(path)?;
+        let mut secrets = HashMap::new();
+        for (number, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            // The value itself is not shown, in case the line holds a secret
+            // without its name
+            let (name, value) = line.split_once('=').ok_or_else(|| {
+                anyhow!(
+                    "Line {} of {} is not in NAME=VALUE form",
+                    number + 1,
+                    path.display()
+                )
+            })?;
+            secrets.insert(name.trim().to_string(), Secret(value.trim().to_string()));
+        }
+        Ok(Self(secrets))
+    }
+
+    pub fn get(&self, name: &str) -> Option<Secret> {
+        self.0.get(name).cloned()
+    }
+}
+
+// A url with the password of any user:password@ part masked, for logging.
+// Urls that don't parse, as in the error about them, are masked by hand.
+pub fn redact_url(url: &str) -> String {
+    match Url::parse(url) {
+        Ok(mut parsed) if parsed.password().is_some() => {
+            let _ = parsed.set_password(Some("***"));
+            parsed.to_string()
+        }
+        Ok(_) => url.to_string(),
+        Err(_) => {
+            let Some((scheme, rest)) = url.split_once("://") else {
+                return url.to_string();
+            };
+            let authority = rest.split(['/', '?', '#']).next().unwrap_or_default();
+            match authority
+                .rsplit_once('@')
+                .and_then(|(userinfo, _)| userinfo.split_once(':'))
+            {
+                Some((user, password)) => url.replacen(
+                    &format!("{}://{}:{}@", scheme, user, password),
+                    &format!("{}://{}:***@", scheme, user),
+                    1,
+                ),
+                None => url.to_string(),
+            }
+        }
+    }
+}
+
+// Key used to encrypt and decrypt secrets
+pub struct SecretsKey(Key);
+
+impl SecretsKey {
+    pub fn generate() -> Self {
+        Self(ChaCha20Poly1305::generate_key(&mut OsRng))
+    }
+
+    // Parse a base64 encoded key
+    pub fn parse(encoded: &str) -> Result<Self> {
+        let bytes = BASE64
+            .decode(encoded.trim())
+            .context("The secrets key is not valid base64")?;
+        if bytes.len() != 32 {
+            return Err(anyhow!(
+                "The secrets key must be 32 bytes, got {}",
+                bytes.len()
+            ));
+        }
+        Ok(Self(*Key::from_slice(&bytes)))
+    }
+
+    // Load the key from a file, falling back to the environment and then the
+    // systemd credential
+    pub fn load(key_file: Option<&Path>) -> Result<Option<Self>> {
+        if let Some(path) = key_file {
+            let encoded = std::fs::read_to_string(path)
+                .with_context(|| format!("Failed to read secrets key file {}", path.display()))?;
+            return Self::parse(&encoded).map(Some);
+        }
+
+        if let Ok(encoded) = std::env::var(SECRETS_KEY_ENV) {
+            return Self::parse(&encoded).map(Some);
+        }
+
+        match load_credential(SECRETS_KEY_CREDENTIAL)? {
+            Some(encoded) => Self::parse(encoded.expose()).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    pub fn encode(&self) -> String {
+        BASE64.encode(self.0)
+    }
+
+    // Encrypt a value, returning it in the "enc:<base64>" form
+    pub fn encrypt(&self, plaintext: &str) -> Result<String> {
+        let cipher = ChaCha20Poly1305::new(&self.0);
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = cipher
+            .encrypt(&nonce, plaintext.as_bytes())
+            .map_err(|_| anyhow!("Failed to encrypt secret"))?;
+
+        let mut sealed = nonce.to_vec();
+        sealed.extend(ciphertext);
+        Ok(format!("{}{}", ENCRYPTED_PREFIX, BASE64.encode(sealed)))
+    }
+
+    fn decrypt(&self, encoded: &str) -> Result<String> {
+        let sealed = BASE64
+            .decode(encoded.trim())
+            .context("Encrypted secret is not valid base64")?;
+        if sealed.len() <= NONCE_LEN {
+            return Err(anyhow!("Encrypted secret is too short"));
+        }
+
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+        let plaintext = ChaCha20Poly1305::new(&self.0)
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| anyhow!("Failed to decrypt secret, is the secrets key correct?"))?;
+        String::from_utf8(plaintext).context("Decrypted secret is not valid UTF-8")
+    }
+}
@@ -0,0 +1,185 @@
+use crate::api::ApiClient;
+use crate::bluez::BluezSession;
+use crate::dongle::{Dongle, DongleKind};
+use crate::vehicle::Vehicle;
+use crate::WriteType;
+use anyhow::{anyhow, Context, Result};
+use bluer::Address;
+use futures_util::StreamExt;
+use std::time::{Duration, Instant};
+use tokio::time;
+
+// Stages in the order they run
+const STAGES: &[&str] = &[
+    "Bluetooth adapter",
+    "Discovery",
+    "Pairing",
+    "Connection",
+    "Characteristics",
+    "Autopid fetch",
+    "JSON parse",
+    "API post",
+];
+
+pub struct SelfTestOptions {
+    pub wican_mac_address: Address,
+    pub wican_passkey: u32,
+    pub wican_timeout: Duration,
+    pub response_timeout: Duration,
+    pub write_type: WriteType,
+    pub verify_service: bool,
+    pub dongle: DongleKind,
+}
+
+// Outcome of each stage that ran
+#[derive(Default)]
+struct Report {
+    results: Vec<(&'static str, Result<String, String>)>,
+}
+
+impl Report {
+    // Record a stage, passing its value on so the next stage can use it
+    fn check<T>(
+        &mut self,
+        stage: &'static str,
+        result: Result<T>,
+        detail: impl FnOnce(&T) -> String,
+    ) -> Result<T> {
+        match result {
+            Ok(value) => {
+                self.results.push((stage, Ok(detail(&value))));
+                Ok(value)
+            }
+            Err(e) => {
+                self.results.push((stage, Err(format!("{:#}", e))));
+                Err(e)
+            }
+        }
+    }
+
+    fn print(&self) {
+        println!("aa-proxy-wican self-test report");
+        for stage in STAGES {
+            match self.results.iter().find(|(name, _)| name == stage) {
+                Some((_, Ok(detail))) => println!("PASS  {}: {}", stage, detail),
+                Some((_, Err(error))) => println!("FAIL  {}: {}", stage, error),
+                None => println!("SKIP  {}", stage),
+            }
+        }
+    }
+}
+
+// Run every stage from the adapter to the API post once and print a pass/fail
+// report, stopping at the first failure
+pub async fn self_test(api: &ApiClient, vehicle: &Vehicle, options: SelfTestOptions) -> Result<()> {
+    let mut report = Report::default();
+    let result = run(&mut report, api, vehicle, &options).await;
+    report.print();
+    result.map_err(|_| anyhow!("Self-test failed"))
+}
+
+async fn run(
+    report: &mut Report,
+    api: &ApiClient,
+    vehicle: &Vehicle,
+    options: &SelfTestOptions,
+) -> Result<()> {
+    let bluez = report.check(
+        "Bluetooth adapter",
+        async {
+            let bluez = BluezSession::open(options.wican_passkey).await?;
+            if !bluez.adapter.is_powered().await? {
+                return Err(anyhow!("Adapter {} is not powered", bluez.adapter.name()));
+            }
+            Ok(bluez)
+        }
+        .await,
+        |bluez| format!("{} is powered", bluez.adapter.name()),
+    )?;
+    let adapter = &bluez.adapter;
+
+    let started = Instant::now();
+    let device = report.check(
+        "Discovery",
+        crate::find_device(adapter, options.wican_mac_address, options.wican_timeout).await,
+        |device| format!("found {} after {:?}", device.address(), started.elapsed()),
+    )?;
+
+    let was_paired = device.is_paired().await.unwrap_or(false);
+    report.check(
+        "Pairing",
+        crate::try_pair(&device, options.verify_service).await,
+        |_| {
+            if was_paired {
+                "already paired".to_string()
+            } else {
+                "paired successfully".to_string()
+            }
+        },
+    )?;
+
+    let started = Instant::now();
+    report.check(
+        "Connection",
+        async {
+            if !device.is_connected().await? {
+                device.connect().await?;
+            }
+            Ok(())
+        }
+        .await,
+        |_| format!("connected after {:?}", started.elapsed()),
+    )?;
+
+    let dongle = Dongle::detect(&device, options.dongle).await;
+    let characteristics = match dongle.is_elm327() {
+        true => Err(anyhow!(
+            "The self-test needs an autopid dongle, not an {}",
+            dongle
+        )),
+        false => dongle.characteristics(&device).await,
+    };
+    let (notify_char, write_char) = report.check("Characteristics", characteristics, |_| {
+        format!("found the {} notify and write characteristics", dongle)
+    })?;
+
+    let started = Instant::now();
+    let frame = report.check(
+        "Autopid fetch",
+        async {
+            let mut notifications = Box::pin(dongle.notifications(&notify_char).await?);
+            crate::write_command(&write_char, b"autopid -d\n", options.write_type).await?;
+            time::timeout(options.response_timeout, notifications.next())
+                .await
+                .map_err(|_| anyhow!("No reply within {:?}", options.response_timeout))?
+                .context("Notification stream ended")
+        }
+        .await,
+        |frame| {
+            format!(
+                "{} bytes received after {:?}",
+                frame.len(),
+                started.elapsed()
+            )
+        },
+    )?;
+
+    let battery_data = report.check(
+        "JSON parse",
+        crate::parse_response(frame, vehicle),
+        |data| {
+            format!(
+                "battery at {:.1}%",
+                data.battery_level_percentage.unwrap_or_default()
+            )
+        },
+    )?;
+
+    report.check(
+        "API post",
+        api.post_battery_data(&battery_data).await,
+        |body| format!("aa-proxy-rs responded '{}'", body),
+    )?;
+
+    Ok(())
+}
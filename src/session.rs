@@ -0,0 +1,264 @@
+use anyhow::{anyhow, Result};
+use log::{debug, info, warn};
+use reqwest::header::ACCEPT;
+use reqwest::Client;
+use serde_json::Value;
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::time;
+
+use crate::control::CONTROL;
+use crate::secrets;
+
+// Delay before reconnecting to the event stream after it failed
+const EVENTS_RECONNECT_DELAY: Duration = Duration::from_secs(10);
+
+// Watches aa-proxy-rs for an active Android Auto head-unit session, by polling
+// a status url, following an event stream or both
+pub struct SessionMonitor {
+    client: Client,
+    url: Option<String>,
+    field: String,
+    check_interval: Duration,
+    events: Option<watch::Receiver<Option<bool>>>,
+}
+
+impl SessionMonitor {
+    pub fn new(client: Client, url: Option<&str>, field: &str, check_interval: Duration) -> Self {
+        Self {
+            client,
+            url: url.map(str::to_string),
+            field: field.to_string(),
+            check_interval,
+            events: None,
+        }
+    }
+
+    // Follow a server-sent event stream announcing head-unit connects and
+    // disconnects, requesting a poll as soon as a session starts
+    pub fn with_events(mut self, url: &str) -> Self {
+        let (sender, receiver) = watch::channel(None);
+        tokio::spawn(follow_events(
+            self.client.clone(),
+            url.to_string(),
+            self.field.clone(),
+            sender,
+        ));
+        self.events = Some(receiver);
+        self
+    }
+
+    // Whether a head unit is connected. The event stream is trusted first; if
+    // aa-proxy-rs can't be asked we assume a session is active, so a broken
+    // status endpoint never stops updates.
+    pub async fn is_active(&self) -> bool {
+        if let Some(active) = self.event_state() {
+            debug!("Android Auto session active: {} (from events)", active);
+            return active;
+        }
+
+        let Some(url) = &self.url else {
+            return true;
+        };
+        match self.query(url).await {
+            Ok(active) => {
+                debug!("Android Auto session active: {}", active);
+                active
+            }
+            Err(e) => {
+                warn!(
+                    "Failed to query Android Auto session state from {}: {}. Assuming a session is active.",
+                    url, e
+                );
+                true
+            }
+        }
+    }
+
+    // Wait until a head-unit session starts
+    pub async fn wait_until_active(&self) {
+        let mut events = self.events.clone();
+        loop {
+            tokio::select! {
+                _ = time::sleep(self.check_interval), if self.url.is_some() => {
+                    if self.event_state().is_none() && self.is_active().await {
+                        info!("Android Auto session started.");
+                        return;
+                    }
+                }
+                _ = event_changed(&mut events) => {
+                    match self.event_state() {
+                        Some(true) => {
+                            info!("Android Auto session started.");
+                            return;
+                        }
+                        // Without a status url to fall back on, assume a session
+                        None if self.url.is_none() => return,
+                        _ => {}
+                    }
+                }
+            }
+        }
+    }
+
+    // Session state last announced on the event stream, if it is connected
+    fn event_state(&self) -> Option<bool> {
+        self.events.as_ref().and_then(|events| *events.borrow())
+    }
+
+    async fn query(&self, url: &str) -> Result<bool> {
+        let res = self.client.get(url).send().await?;
+        if !res.status().is_success() {
+            return Err(anyhow!("Status: {}", res.status()));
+        }
+
+        let body = res.text().await?;
+        parse_session_state(&body, &self.field)
+    }
+}
+
+// Wait for the event stream state to change, forever if there is no stream
+async fn event_changed(events: &mut Option<watch::Receiver<Option<bool>>>) {
+    match events {
+        Some(receiver) => {
+            if receiver.changed().await.is_err() {
+                *events = None;
+            }
+        }
+        None => std::future::pending().await,
+    }
+}
+
+// Interpret a session state given as a JSON boolean, a JSON object with a
+// boolean field or plain text
+fn parse_session_state(body: &str, field: &str) -> Result<bool> {
+    match serde_json::from_str::<Value>(body) {
+        Ok(Value::Bool(active)) => Ok(active),
+        Ok(Value::Object(object)) => object
+            .get(field)
+            .and_then(|value| match value {
+                Value::Bool(active) => Some(*active),
+                Value::Number(n) => Some(n.as_f64() != Some(0.0)),
+                _ => None,
+            })
+            .ok_or_else(|| anyhow!("Response has no boolean '{}' field", field)),
+        _ => match body.trim().to_lowercase().as_str() {
+            "true" | "1" | "connected" => Ok(true),
+            "false" | "0" | "disconnected" => Ok(false),
+            other => Err(anyhow!("Unrecognised session state '{}'", other)),
+        },
+    }
+}
+
+// Keep following the event stream, reconnecting when it drops
+async fn follow_events(
+    client: Client,
+    url: String,
+    field: String,
+    sender: watch::Sender<Option<bool>>,
+) {
+    loop {
+        match read_events(&client, &url, &field, &sender).await {
+            Ok(()) => debug!("aa-proxy-rs event stream ended. Reconnecting..."),
+            // The HTTP client's read timeout also ends quiet streams
+            Err(e)
+                if e.downcast_ref::<reqwest::Error>()
+                    .is_some_and(|e| e.is_timeout()) =>
+            {
+                debug!("aa-proxy-rs event stream idle. Reconnecting...");
+            }
+            Err(e) => {
+                warn!(
+                    "Failed to follow aa-proxy-rs events from {}: {}. Retrying in {:?}...",
+                    url, e, EVENTS_RECONNECT_DELAY
+                );
+                sender.send_replace(None);
+                time::sleep(EVENTS_RECONNECT_DELAY).await;
+            }
+        }
+        if sender.is_closed() {
+            return;
+        }
+    }
+}
+
+// Read server-sent events until the stream ends, publishing session changes
+async fn read_events(
+    client: &Client,
+    url: &str,
+    field: &str,
+    sender: &watch::Sender<Option<bool>>,
+) -> Result<()> {
+    let mut res = client
+        .get(url)
+        .header(ACCEPT, "text/event-stream")
+        .send()
+        .await?;
+    if !res.status().is_success() {
+        return Err(anyhow!("Status: {}", res.status()));
+    }
+    info!(
+        "Following aa-proxy-rs events from {}",
+        secrets::redact_url(url)
+    );
+
+    let mut buffer = Vec::new();
+    let mut event = String::new();
+    let mut data = Vec::new();
+    while let Some(chunk) = res.chunk().await? {
+        buffer.extend_from_slice(&chunk);
+        while let Some(end) = buffer.iter().position(|b| *b == b'\n') {
+            let line: Vec<u8> = buffer.drain(..=end).collect();
+            let line = String::from_utf8_lossy(&line);
+            let line = line.trim_end_matches(['\r', '\n']);
+
+            if line.is_empty() {
+                // Blank lines after comments, e.g. keep-alives, carry no event
+                if event.is_empty() && data.is_empty() {
+                    continue;
+                }
+                let message = data.join("\n");
+                match session_event(&event, &message, field) {
+                    Some(active) => publish(sender, active),
+                    None => debug!("Ignoring aa-proxy-rs event '{}': {}", event, message),
+                }
+                event.clear();
+                data.clear();
+                continue;
+            }
+
+            let (name, value) = line.split_once(':').unwrap_or((line, ""));
+            let value = value.strip_prefix(' ').unwrap_or(value);
+            match name {
+                "event" => event = value.to_string(),
+                "data" => data.push(value.to_string()),
+                _ => {}
+            }
+        }
+    }
+    Ok(())
+}
+
+// Session state carried by an event, from its name or its data
+fn session_event(event: &str, data: &str, field: &str) -> Option<bool> {
+    match event.to_lowercase().as_str() {
+        "connected" | "session_started" | "head_unit_connected" => Some(true),
+        "disconnected" | "session_ended" | "head_unit_disconnected" => Some(false),
+        _ => parse_session_state(data, field).ok(),
+    }
+}
+
+fn publish(sender: &watch::Sender<Option<bool>>, active: bool) {
+    let previous = sender.send_replace(Some(active));
+    if previous == Some(active) {
+        return;
+    }
+
+    info!(
+        "aa-proxy-rs reports the head unit {}.",
+        if active { "connected" } else { "disconnected" }
+    );
+    if active && previous.is_some() {
+        CONTROL.poll_now();
+    }
+}
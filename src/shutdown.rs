@@ -0,0 +1,113 @@
+use anyhow::Result;
+use log::{info, warn};
+use std::fmt;
+use std::future::Future;
+use tokio::signal::unix::{signal, Signal, SignalKind};
+
+use crate::bluez;
+use crate::events::{self, Event};
+use crate::systemd;
+
+// Signals that stop aa-proxy-wican cleanly
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShutdownSignal {
+    Terminate,
+    Interrupt,
+}
+
+impl fmt::Display for ShutdownSignal {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ShutdownSignal::Terminate => write!(f, "SIGTERM"),
+            ShutdownSignal::Interrupt => write!(f, "SIGINT"),
+        }
+    }
+}
+
+impl ShutdownSignal {
+    // SIGTERM is how service managers stop us, so it's a clean exit, while
+    // an interrupt exits like a shell reports one
+    fn exit_code(self) -> i32 {
+        match self {
+            ShutdownSignal::Terminate => 0,
+            ShutdownSignal::Interrupt => 130,
+        }
+    }
+}
+
+pub struct ShutdownSignals {
+    terminate: Option<Signal>,
+    interrupt: Option<Signal>,
+}
+
+impl ShutdownSignals {
+    // Start catching the signals, which otherwise end the process at once
+    pub fn listen() -> Self {
+        let listen = |kind: SignalKind, name: &str| match signal(kind) {
+            Ok(signals) => Some(signals),
+            Err(e) => {
+                warn!(
+                    "Could not listen for {}, it will stop aa-proxy-wican without disconnecting: {}",
+                    name, e
+                );
+                None
+            }
+        };
+        Self {
+            terminate: listen(SignalKind::terminate(), "SIGTERM"),
+            interrupt: listen(SignalKind::interrupt(), "SIGINT"),
+        }
+    }
+
+    async fn recv(&mut self) -> ShutdownSignal {
+        tokio::select! {
+            _ = recv(&mut self.terminate) => ShutdownSignal::Terminate,
+            _ = recv(&mut self.interrupt) => ShutdownSignal::Interrupt,
+        }
+    }
+}
+
+async fn recv(signals: &mut Option<Signal>) {
+    if let Some(signals) = signals {
+        if signals.recv().await.is_some() {
+            return;
+        }
+    }
+    std::future::pending().await
+}
+
+// Run until finished or told to stop. Stopping drops the run, which ends the
+// notification subscription and any discovery, then the WiCAN is
+// disconnected, the logs flushed and the process exits with the code for the
+// signal.
+pub async fn run_until_signal(
+    run: impl Future<Output = Result<()>>,
+    mut signals: ShutdownSignals,
+    bluetooth: bool,
+    power_off_adapter: bool,
+) -> Result<()> {
+    let signal = tokio::select! {
+        result = run => return result,
+        signal = signals.recv() => signal,
+    };
+    info!("Received {}, shutting down...", signal);
+    systemd::stopping();
+
+    if bluetooth {
+        match bluez::disconnect_on_shutdown(power_off_adapter).await {
+            Ok(Some(address)) => events::emit(Event::Disconnected {
+                address: address.to_string(),
+                reason: "Shutting down".to_string(),
+            }),
+            Ok(None) => {}
+            Err(e) => warn!("Failed to disconnect from the WiCAN cleanly: {:#}", e),
+        }
+    }
+
+    info!("Stopped.");
+    log::logger().flush();
+    match signal.exit_code() {
+        0 => Ok(()),
+        code => std::process::exit(code),
+    }
+}
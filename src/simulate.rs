@@ -0,0 +1,176 @@
+use crate::bluez;
+use crate::dongle::{WICAN_NOTIFY_UUID, WICAN_SERVICE_UUID, WICAN_WRITE_UUID};
+use anyhow::{anyhow, Context, Result};
+use bluer::adv::Advertisement;
+use bluer::gatt::local::{
+    Application, Characteristic, CharacteristicNotify, CharacteristicNotifyMethod,
+    CharacteristicWrite, CharacteristicWriteMethod, Service,
+};
+use bluer::Session;
+use futures_util::FutureExt;
+use log::{debug, info};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast;
+use tokio::time;
+
+// Response served when none are configured
+const DEFAULT_RESPONSE: &str = r#"{"SOC": 80.0, "SOC_D": 81.5, "TMP_A": 15.0}"#;
+
+pub struct SimulateOptions {
+    pub responses: Vec<String>,
+    pub script: Option<PathBuf>,
+    pub broadcast_interval: Option<Duration>,
+    pub name: String,
+}
+
+// Autopid responses served in turn, starting over after the last
+struct Responses {
+    responses: Vec<String>,
+    next: AtomicUsize,
+}
+
+impl Responses {
+    fn next(&self) -> Vec<u8> {
+        let index = self.next.fetch_add(1, Ordering::Relaxed) % self.responses.len();
+        self.responses[index].as_bytes().to_vec()
+    }
+}
+
+fn load_script(path: &Path) -> Result<Vec<String>> {
+    let script = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read script '{}'", path.display()))?;
+    Ok(script
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(str::to_string)
+        .collect())
+}
+
+// Advertise a fake WiCAN from the local adapter and serve autopid responses
+// until interrupted
+pub async fn simulate(options: SimulateOptions) -> Result<()> {
+    let mut responses = options.responses;
+    if let Some(script) = &options.script {
+        responses.extend(load_script(script)?);
+    }
+    if responses.is_empty() {
+        responses.push(DEFAULT_RESPONSE.to_string());
+    }
+    let responses = Arc::new(Responses {
+        responses,
+        next: AtomicUsize::new(0),
+    });
+
+    let session = Session::new().await?;
+    let adapter = bluez::adapter(&session).await?;
+    adapter.set_powered(true).await?;
+
+    // Autopid requests written by clients, fanned out to every subscriber
+    let (requests, _) = broadcast::channel::<()>(16);
+
+    let write_requests = requests.clone();
+    let write = CharacteristicWrite {
+        write: true,
+        write_without_response: true,
+        method: CharacteristicWriteMethod::Fun(Box::new(move |value, _request| {
+            let command = String::from_utf8_lossy(&value);
+            info!("Received command {:?}", command);
+            if command.trim() == "autopid -d" {
+                let _ = write_requests.send(());
+            }
+            async { Ok(()) }.boxed()
+        })),
+        ..Default::default()
+    };
+
+    let broadcast_interval = options.broadcast_interval;
+    let notify = CharacteristicNotify {
+        notify: true,
+        method: CharacteristicNotifyMethod::Fun(Box::new(move |mut notifier| {
+            let mut requests = requests.subscribe();
+            let responses = responses.clone();
+            async move {
+                info!("Client subscribed to notifications.");
+                loop {
+                    tokio::select! {
+                        request = requests.recv() => {
+                            if let Err(broadcast::error::RecvError::Closed) = request {
+                                break;
+                            }
+                        }
+                        _ = async {
+                            match broadcast_interval {
+                                Some(interval) => time::sleep(interval).await,
+                                None => std::future::pending().await,
+                            }
+                        } => {}
+                    }
+
+                    if notifier.is_stopped() {
+                        break;
+                    }
+                    let response = responses.next();
+                    debug!("Notifying {}", String::from_utf8_lossy(&response));
+                    if let Err(e) = notifier.notify(response).await {
+                        info!("Failed to notify client: {}", e);
+                        break;
+                    }
+                }
+                info!("Client unsubscribed from notifications.");
+            }
+            .boxed()
+        })),
+        ..Default::default()
+    };
+
+    let application = Application {
+        services: vec![Service {
+            uuid: WICAN_SERVICE_UUID,
+            primary: true,
+            characteristics: vec![
+                Characteristic {
+                    uuid: WICAN_NOTIFY_UUID,
+                    notify: Some(notify),
+                    ..Default::default()
+                },
+                Characteristic {
+                    uuid: WICAN_WRITE_UUID,
+                    write: Some(write),
+                    ..Default::default()
+                },
+            ],
+            ..Default::default()
+        }],
+        ..Default::default()
+    };
+    let _application = adapter
+        .serve_gatt_application(application)
+        .await
+        .context("Failed to register the simulated GATT service")?;
+
+    let advertisement = Advertisement {
+        service_uuids: [WICAN_SERVICE_UUID].into_iter().collect(),
+        discoverable: Some(true),
+        local_name: Some(options.name.clone()),
+        ..Default::default()
+    };
+    let _advertisement = adapter
+        .advertise(advertisement)
+        .await
+        .context("Failed to advertise the simulated WiCAN")?;
+
+    info!(
+        "Simulating WiCAN '{}' at {}. Press Ctrl+C to stop.",
+        options.name,
+        adapter.address().await?
+    );
+    tokio::signal::ctrl_c()
+        .await
+        .map_err(|e| anyhow!("Failed to wait for Ctrl+C: {}", e))?;
+    info!("Stopping simulation.");
+    Ok(())
+}
@@ -0,0 +1,345 @@
+use anyhow::{anyhow, Context, Result};
+use futures_util::future::LocalBoxFuture;
+use futures_util::FutureExt;
+use log::{debug, info, warn};
+use reqwest::{Client, Url};
+use std::io::Write;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::abrp::AbrpSink;
+use crate::api::ApiClient;
+use crate::exec::ExecSink;
+use crate::grpc::GrpcSink;
+use crate::homeassistant::HomeAssistantSink;
+use crate::mqtt::MqttSink;
+use crate::postgres::PostgresSink;
+use crate::queue::{self, RetryQueue};
+use crate::recorder::{DataRecorder, PostResult};
+use crate::redis::RedisSink;
+use crate::secrets;
+use crate::websocket::WebSocketSink;
+use crate::BatteryData;
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Somewhere samples are delivered to, aa-proxy-rs included. Sinks handle
+// their own failures, so one target being down never affects the others.
+pub trait Sink {
+    fn send<'a>(&'a self, sample: &'a BatteryData) -> LocalBoxFuture<'a, ()>;
+}
+
+// aa-proxy-rs, reached over its WebSocket while connected and otherwise
+// posted to with the retry queue, recording how each sample went
+pub struct AaProxySink<'a> {
+    api: &'a ApiClient,
+    websocket: Option<WebSocketSink>,
+    recorder: Option<DataRecorder>,
+}
+
+impl<'a> AaProxySink<'a> {
+    pub fn new(
+        api: &'a ApiClient,
+        websocket: Option<WebSocketSink>,
+        recorder: Option<DataRecorder>,
+    ) -> Self {
+        Self {
+            api,
+            websocket,
+            recorder,
+        }
+    }
+
+    async fn deliver(&self, sample: &BatteryData) {
+        let (result, error) = if self.push_websocket(sample) {
+            (PostResult::Pushed, None)
+        } else {
+            match self.api.submit(sample.clone()).await {
+                Ok(_) => (PostResult::Posted, None),
+                Err(e) => {
+                    crate::log_post_error(&e);
+                    (PostResult::Failed, Some(e))
+                }
+            }
+        };
+        if let Some(recorder) = &self.recorder {
+            if let Err(e) = recorder.record(sample, result, error.as_ref()) {
+                warn!("Failed to record the sample: {:#}", e);
+            }
+        }
+    }
+
+    // Push a sample over the WebSocket, returning false when it should be
+    // posted over HTTP instead. Samples queued for retry go over HTTP first so
+    // they arrive in order.
+    fn push_websocket(&self, sample: &BatteryData) -> bool {
+        let Some(websocket) = &self.websocket else {
+            return false;
+        };
+        if self.api.queue_depth() > 0 || self.api.retry_after().is_some() {
+            return false;
+        }
+        match self.api.payload(sample) {
+            Ok(payload) => websocket.send(payload.to_string()),
+            Err(e) => {
+                warn!("Failed to encode the sample for the WebSocket: {:#}", e);
+                false
+            }
+        }
+    }
+}
+
+impl Sink for AaProxySink<'_> {
+    fn send<'a>(&'a self, sample: &'a BatteryData) -> LocalBoxFuture<'a, ()> {
+        self.deliver(sample).boxed_local()
+    }
+}
+
+impl Sink for MqttSink {
+    fn send<'a>(&'a self, sample: &'a BatteryData) -> LocalBoxFuture<'a, ()> {
+        MqttSink::send(self, sample);
+        async {}.boxed_local()
+    }
+}
+
+impl Sink for ExecSink {
+    fn send<'a>(&'a self, sample: &'a BatteryData) -> LocalBoxFuture<'a, ()> {
+        ExecSink::send(self, sample).boxed_local()
+    }
+}
+
+impl Sink for GrpcSink {
+    fn send<'a>(&'a self, sample: &'a BatteryData) -> LocalBoxFuture<'a, ()> {
+        GrpcSink::send(self, sample);
+        async {}.boxed_local()
+    }
+}
+
+impl Sink for RedisSink {
+    fn send<'a>(&'a self, sample: &'a BatteryData) -> LocalBoxFuture<'a, ()> {
+        RedisSink::send(self, sample).boxed_local()
+    }
+}
+
+impl Sink for PostgresSink {
+    fn send<'a>(&'a self, sample: &'a BatteryData) -> LocalBoxFuture<'a, ()> {
+        PostgresSink::send(self, sample).boxed_local()
+    }
+}
+
+impl Sink for HomeAssistantSink {
+    fn send<'a>(&'a self, sample: &'a BatteryData) -> LocalBoxFuture<'a, ()> {
+        HomeAssistantSink::send(self, sample).boxed_local()
+    }
+}
+
+impl Sink for AbrpSink {
+    fn send<'a>(&'a self, sample: &'a BatteryData) -> LocalBoxFuture<'a, ()> {
+        AbrpSink::send(self, sample);
+        async {}.boxed_local()
+    }
+}
+
+// Where an --output sends samples
+#[derive(Debug, Clone, PartialEq)]
+pub enum OutputKind {
+    Http(Url),
+    File(PathBuf),
+    Stdout,
+}
+
+// An additional output, written TARGET[;OPTION=VALUE...] where the target is
+// an http(s):// url, file:PATH or stdout, e.g.
+// http://dashboard.local/battery;retry-queue=20;timeout=5
+#[derive(Debug, Clone, PartialEq)]
+pub struct OutputTarget {
+    pub kind: OutputKind,
+    // Samples kept for another attempt after a failed post
+    pub retry_queue_size: usize,
+    pub timeout: Duration,
+}
+
+impl FromStr for OutputTarget {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut parts = s.split(';').map(str::trim);
+        let target = parts.next().unwrap_or_default();
+        let kind =
+            if target == "stdout" || target == "-" {
+                OutputKind::Stdout
+            } else if let Some(path) = target.strip_prefix("file:") {
+                if path.is_empty() {
+                    return Err(anyhow!("Output '{}' has no path", s));
+                }
+                OutputKind::File(PathBuf::from(path))
+            } else if target.starts_with("http://") || target.starts_with("https://") {
+                OutputKind::Http(Url::parse(target).with_context(|| {
+                    format!("Invalid output url '{}'", secrets::redact_url(target))
+                })?)
+            } else {
+                return Err(anyhow!(
+                    "Unknown output '{}', expected an http(s):// url, file:PATH or stdout",
+                    secrets::redact_url(target)
+                ));
+            };
+
+        let mut output = OutputTarget {
+            kind,
+            retry_queue_size: 0,
+            timeout: DEFAULT_TIMEOUT,
+        };
+        for option in parts.filter(|option| !option.is_empty()) {
+            let (name, value) = option
+                .split_once('=')
+                .ok_or_else(|| anyhow!("Output option '{}' is not in NAME=VALUE form", option))?;
+            if !matches!(output.kind, OutputKind::Http(_)) {
+                return Err(anyhow!("Option '{}' only applies to http outputs", name));
+            }
+            match name.trim() {
+                "retry-queue" => {
+                    output.retry_queue_size = value
+                        .trim()
+                        .parse()
+                        .with_context(|| format!("Invalid retry queue size '{}'", value))?
+                }
+                "timeout" => {
+                    let seconds: u64 = value
+                        .trim()
+                        .parse()
+                        .with_context(|| format!("Invalid timeout '{}'", value))?;
+                    output.timeout = Duration::from_secs(seconds.max(1));
+                }
+                other => {
+                    return Err(anyhow!(
+                        "Unknown output option '{}', expected retry-queue or timeout",
+                        other
+                    ))
+                }
+            }
+        }
+        Ok(output)
+    }
+}
+
+impl OutputTarget {
+    pub fn sink(&self, client: Client) -> Box<dyn Sink> {
+        match &self.kind {
+            OutputKind::Http(url) => Box::new(HttpOutput {
+                client,
+                url: url.clone(),
+                timeout: self.timeout,
+                queue: Mutex::new(RetryQueue::new(self.retry_queue_size)),
+            }),
+            OutputKind::File(path) => Box::new(FileOutput { path: path.clone() }),
+            OutputKind::Stdout => Box::new(StdoutOutput),
+        }
+    }
+}
+
+// Posts each sample as JSON to another HTTP endpoint, such as a dashboard
+struct HttpOutput {
+    client: Client,
+    url: Url,
+    timeout: Duration,
+    queue: Mutex<RetryQueue>,
+}
+
+impl HttpOutput {
+    // Post a sample after any samples queued by earlier failures, queueing
+    // whatever could not be delivered
+    async fn post_all(&self, sample: &BatteryData) {
+        let posted =
+            queue::send_in_order(&self.queue, sample, async |sample| self.post(sample).await).await;
+        if let Err((e, queued)) = posted {
+            warn!(
+                "Failed to post to {}: {:#}",
+                secrets::redact_url(self.url.as_str()),
+                e
+            );
+            if queued > 0 {
+                info!(
+                    "{} sample(s) queued for the next post to {}.",
+                    queued,
+                    secrets::redact_url(self.url.as_str())
+                );
+            }
+        }
+    }
+
+    async fn post(&self, sample: &BatteryData) -> Result<()> {
+        let response = self
+            .client
+            .post(self.url.clone())
+            .timeout(self.timeout)
+            .json(sample)
+            .send()
+            .await
+            .context("Could not reach the output")?;
+        let status = response.status();
+        if !status.is_success() {
+            let body = response.text().await.unwrap_or_default();
+            return Err(anyhow!("The server returned {}: {}", status, body.trim()));
+        }
+        debug!(
+            "Posted sample to {}",
+            secrets::redact_url(self.url.as_str())
+        );
+        Ok(())
+    }
+}
+
+impl Sink for HttpOutput {
+    fn send<'a>(&'a self, sample: &'a BatteryData) -> LocalBoxFuture<'a, ()> {
+        self.post_all(sample).boxed_local()
+    }
+}
+
+// Keeps the latest sample as JSON in a file
+struct FileOutput {
+    path: PathBuf,
+}
+
+impl FileOutput {
+    // Write to a temporary file and rename it, so readers never see a partial
+    // sample
+    fn write(&self, sample: &BatteryData) -> Result<()> {
+        let mut temporary = self.path.clone().into_os_string();
+        temporary.push(".tmp");
+        std::fs::write(&temporary, serde_json::to_vec_pretty(sample)?)
+            .with_context(|| format!("Failed to write '{}'", self.path.display()))?;
+        std::fs::rename(&temporary, &self.path)
+            .with_context(|| format!("Failed to replace '{}'", self.path.display()))
+    }
+}
+
+impl Sink for FileOutput {
+    fn send<'a>(&'a self, sample: &'a BatteryData) -> LocalBoxFuture<'a, ()> {
+        if let Err(e) = self.write(sample) {
+            warn!("Failed to write the sample: {:#}", e);
+        }
+        async {}.boxed_local()
+    }
+}
+
+// Prints each sample as a JSON line, for piping into other tools
+struct StdoutOutput;
+
+impl Sink for StdoutOutput {
+    fn send<'a>(&'a self, sample: &'a BatteryData) -> LocalBoxFuture<'a, ()> {
+        let written = serde_json::to_string(sample)
+            .map_err(anyhow::Error::from)
+            .and_then(|line| {
+                let mut stdout = std::io::stdout().lock();
+                writeln!(stdout, "{}", line)?;
+                stdout.flush()?;
+                Ok(())
+            });
+        if let Err(e) = written {
+            warn!("Failed to print the sample: {:#}", e);
+        }
+        async {}.boxed_local()
+    }
+}
@@ -0,0 +1,144 @@
+use anyhow::{anyhow, Context, Result};
+use log::{debug, info, warn};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::merge::SampleSource;
+use crate::BatteryData;
+
+// A previous reading older than this says little about the next one, e.g.
+// after charging while the dongle was out of reach, so it isn't compared with
+const MAX_REFERENCE_AGE: Duration = Duration::from_secs(15 * 60);
+
+pub struct SocFilterOptions {
+    // Largest change in percentage points from the previous reading that is
+    // taken as real
+    pub max_delta: Option<f32>,
+    // Readings in a row rejected before a change is taken as real after all
+    pub max_rejections: u32,
+    // Weight of each new reading in the moving average, 1 to not smooth
+    pub smoothing: Option<f32>,
+}
+
+// Drops implausible SOC readings, such as a single 0% or 100% from a glitching
+// ECU, and optionally smooths the rest with an exponential moving average so
+// range estimates don't jump around. Each source of each car is filtered on
+// its own.
+pub struct SocFilter {
+    options: SocFilterOptions,
+    // Keyed by the source and the vehicle tag
+    state: Mutex<HashMap<(SampleSource, Option<String>), Reference>>,
+}
+
+struct Reference {
+    // Last accepted reading as reported, which new readings are compared with
+    soc: f32,
+    // Moving average passed on in its place when smoothing
+    smoothed: f32,
+    read_at: Instant,
+    rejected: u32,
+}
+
+impl SocFilter {
+    pub fn new(options: SocFilterOptions) -> Self {
+        Self {
+            options,
+            state: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Whether to pass the sample on, smoothing its SOC and the values worked
+    // out from it when it is
+    pub fn check(&self, source: SampleSource, sample: &mut BatteryData) -> bool {
+        let Some(soc) = sample.battery_level_percentage else {
+            return true;
+        };
+        if !(0.0..=100.0).contains(&soc) {
+            warn!("Ignoring an implausible SOC of {}%.", soc);
+            return false;
+        }
+
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        let key = (source, sample.vehicle_tag.clone());
+        let reference = match state.get_mut(&key) {
+            Some(reference) if now.duration_since(reference.read_at) < MAX_REFERENCE_AGE => {
+                reference
+            }
+            _ => {
+                state.insert(
+                    key,
+                    Reference {
+                        soc,
+                        smoothed: soc,
+                        read_at: now,
+                        rejected: 0,
+                    },
+                );
+                return true;
+            }
+        };
+
+        let delta = (soc - reference.soc).abs();
+        if let Some(max_delta) = self.options.max_delta.filter(|max| delta > *max) {
+            if reference.rejected < self.options.max_rejections {
+                reference.rejected += 1;
+                warn!(
+                    "Ignoring a SOC of {:.1}% from {}, {:.1} points from the previous {:.1}% (more than {}).",
+                    soc, source, delta, reference.soc, max_delta
+                );
+                return false;
+            }
+            info!(
+                "Accepting a SOC of {:.1}% from {} after {} implausible reading(s) in a row.",
+                soc, source, reference.rejected
+            );
+            // Averaging across a real jump would only delay it
+            reference.smoothed = soc;
+        }
+        reference.soc = soc;
+        reference.read_at = now;
+        reference.rejected = 0;
+
+        if let Some(weight) = self.options.smoothing {
+            reference.smoothed += weight * (soc - reference.smoothed);
+            let smoothed = (reference.smoothed * 10.0).round() / 10.0;
+            if smoothed != soc {
+                debug!("Smoothed SOC {:.1}% to {:.1}%", soc, smoothed);
+                scale(sample, soc, smoothed);
+            }
+        }
+        true
+    }
+}
+
+// Replace the SOC, scaling the energy left and the range along with it
+fn scale(sample: &mut BatteryData, soc: f32, smoothed: f32) {
+    sample.battery_level_percentage = Some(smoothed);
+    if soc > 0.0 {
+        let ratio = smoothed / soc;
+        sample.battery_level_wh = sample
+            .battery_level_wh
+            .and_then(|wh| u16::try_from((wh as f32 * ratio).round() as u32).ok());
+        sample.estimated_range_km = sample
+            .estimated_range_km
+            .map(|range| (range * ratio * 10.0).round() / 10.0);
+    }
+}
+
+// Weight of a new reading in the moving average, above 0 and at most 1
+pub fn parse_smoothing(s: &str) -> Result<f32> {
+    let weight: f32 = s
+        .trim()
+        .parse()
+        .with_context(|| format!("Invalid smoothing factor '{}'", s))?;
+    if weight > 0.0 && weight <= 1.0 {
+        Ok(weight)
+    } else {
+        Err(anyhow!(
+            "The smoothing factor must be above 0 and at most 1, got {}",
+            weight
+        ))
+    }
+}
@@ -0,0 +1,144 @@
+use crate::can::CanFrame;
+use anyhow::{anyhow, Context, Result};
+use std::ffi::CString;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use tokio::io::unix::AsyncFd;
+
+// Raw socket on a Linux CAN interface such as vcan0
+pub struct CanSocket {
+    interface: String,
+    fd: AsyncFd<OwnedFd>,
+}
+
+impl CanSocket {
+    pub fn open(interface: &str) -> Result<Self> {
+        let name = CString::new(interface)
+            .with_context(|| format!("Invalid CAN interface name '{}'", interface))?;
+        let index = unsafe { libc::if_nametoindex(name.as_ptr()) };
+        if index == 0 {
+            return Err(anyhow!(
+                "CAN interface '{}' not found: {}",
+                interface,
+                std::io::Error::last_os_error()
+            ));
+        }
+
+        let fd = unsafe {
+            libc::socket(
+                libc::AF_CAN,
+                libc::SOCK_RAW | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+                libc::CAN_RAW,
+            )
+        };
+        if fd < 0 {
+            return Err(anyhow!(
+                "Failed to open CAN socket: {}",
+                std::io::Error::last_os_error()
+            ));
+        }
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+        let mut address: libc::sockaddr_can = unsafe { std::mem::zeroed() };
+        address.can_family = libc::AF_CAN as libc::sa_family_t;
+        address.can_ifindex = index as libc::c_int;
+        let result = unsafe {
+            libc::bind(
+                fd.as_raw_fd(),
+                &address as *const libc::sockaddr_can as *const libc::sockaddr,
+                std::mem::size_of::<libc::sockaddr_can>() as libc::socklen_t,
+            )
+        };
+        if result < 0 {
+            return Err(anyhow!(
+                "Failed to bind to CAN interface '{}': {}",
+                interface,
+                std::io::Error::last_os_error()
+            ));
+        }
+
+        Ok(Self {
+            interface: interface.to_string(),
+            fd: AsyncFd::new(fd)?,
+        })
+    }
+
+    pub fn interface(&self) -> &str {
+        &self.interface
+    }
+
+    // Put a frame on the interface. Other sockets see it, this one doesn't.
+    pub fn send(&self, frame: &CanFrame) -> Result<()> {
+        let mut raw: libc::can_frame = unsafe { std::mem::zeroed() };
+        raw.can_id = if frame.extended {
+            frame.id | libc::CAN_EFF_FLAG
+        } else {
+            frame.id
+        };
+        raw.can_dlc = frame.data.len() as u8;
+        raw.data[..frame.data.len()].copy_from_slice(&frame.data);
+
+        let written = unsafe {
+            libc::write(
+                self.fd.as_raw_fd(),
+                &raw as *const libc::can_frame as *const libc::c_void,
+                std::mem::size_of::<libc::can_frame>(),
+            )
+        };
+        if written < 0 {
+            return Err(anyhow!(
+                "Failed to send {} on {}: {}",
+                frame,
+                self.interface,
+                std::io::Error::last_os_error()
+            ));
+        }
+        Ok(())
+    }
+
+    // Wait for a data frame sent to the interface by another program,
+    // skipping remote and error frames
+    pub async fn recv(&self) -> Result<CanFrame> {
+        loop {
+            let mut guard = self.fd.readable().await?;
+            let mut raw: libc::can_frame = unsafe { std::mem::zeroed() };
+            let read = guard.try_io(|fd| {
+                let read = unsafe {
+                    libc::read(
+                        fd.as_raw_fd(),
+                        &mut raw as *mut libc::can_frame as *mut libc::c_void,
+                        std::mem::size_of::<libc::can_frame>(),
+                    )
+                };
+                if read < 0 {
+                    Err(std::io::Error::last_os_error())
+                } else {
+                    Ok(read as usize)
+                }
+            });
+            let read = match read {
+                Ok(read) => {
+                    read.with_context(|| format!("Failed to read from {}", self.interface))?
+                }
+                Err(_would_block) => continue,
+            };
+
+            if read < std::mem::size_of::<libc::can_frame>()
+                || raw.can_id & (libc::CAN_RTR_FLAG | libc::CAN_ERR_FLAG) != 0
+            {
+                continue;
+            }
+            let extended = raw.can_id & libc::CAN_EFF_FLAG != 0;
+            let length = (raw.can_dlc as usize).min(raw.data.len());
+            return Ok(CanFrame {
+                id: raw.can_id
+                    & if extended {
+                        libc::CAN_EFF_MASK
+                    } else {
+                        libc::CAN_SFF_MASK
+                    },
+                extended,
+                data: raw.data[..length].to_vec(),
+            });
+        }
+    }
+}
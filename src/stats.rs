@@ -0,0 +1,224 @@
+use crate::link::LinkQuality;
+use crate::presence::Presence;
+use crate::secrets;
+use crate::status;
+use crate::BatteryData;
+use chrono::{DateTime, Utc};
+use log::info;
+use serde::Serialize;
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+// Errors kept for the status file and API, oldest dropped first
+const RECENT_ERRORS: usize = 20;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RecentError {
+    pub time: DateTime<Utc>,
+    pub message: String,
+}
+
+// Counters describing what the client has done since it started
+#[derive(Debug, Default)]
+pub struct Statistics {
+    pub discarded_frames: AtomicU64,
+    pub samples_received: AtomicU64,
+    pub connect_failures: AtomicU64,
+    pub posts_succeeded: AtomicU64,
+    pub posts_failed: AtomicU64,
+    pub queue_depth: AtomicUsize,
+    pub connected: AtomicBool,
+    pub last_sample: Mutex<Option<BatteryData>>,
+    // When the last sample was read, which for a replayed cached sample is
+    // later than its timestamp
+    pub last_fetch: Mutex<Option<DateTime<Utc>>>,
+    pub last_post_success: Mutex<Option<DateTime<Utc>>>,
+    pub last_post_failure: Mutex<Option<DateTime<Utc>>>,
+    // aa-proxy-rs url the last successful post went to
+    pub api_url: Mutex<Option<String>>,
+    // Signal strength of the WiCAN while connected
+    pub link_quality: Mutex<Option<LinkQuality>>,
+    // Whether each WiCAN was last heard advertising, by address, with
+    // presence detection
+    pub presence: Mutex<BTreeMap<String, Presence>>,
+    pub recent_errors: Mutex<VecDeque<RecentError>>,
+}
+
+impl Statistics {
+    pub const fn new() -> Self {
+        Self {
+            discarded_frames: AtomicU64::new(0),
+            samples_received: AtomicU64::new(0),
+            connect_failures: AtomicU64::new(0),
+            posts_succeeded: AtomicU64::new(0),
+            posts_failed: AtomicU64::new(0),
+            queue_depth: AtomicUsize::new(0),
+            connected: AtomicBool::new(false),
+            last_sample: Mutex::new(None),
+            last_fetch: Mutex::new(None),
+            last_post_success: Mutex::new(None),
+            last_post_failure: Mutex::new(None),
+            api_url: Mutex::new(None),
+            link_quality: Mutex::new(None),
+            presence: Mutex::new(BTreeMap::new()),
+            recent_errors: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    pub fn add_discarded_frames(&self, count: u64) -> u64 {
+        self.discarded_frames.fetch_add(count, Ordering::Relaxed) + count
+    }
+
+    pub fn record_sample(&self, sample: &BatteryData) {
+        self.samples_received.fetch_add(1, Ordering::Relaxed);
+        *self.last_sample.lock().unwrap() = Some(sample.clone());
+        *self.last_fetch.lock().unwrap() = Some(Utc::now());
+        if sample.link != LinkQuality::default() {
+            *self.link_quality.lock().unwrap() = Some(sample.link);
+        }
+        status::update(self);
+    }
+
+    pub fn record_connect_failure(&self) {
+        self.connect_failures.fetch_add(1, Ordering::Relaxed);
+        status::update(self);
+    }
+
+    pub fn set_connected(&self, connected: bool) {
+        if self.connected.swap(connected, Ordering::Relaxed) != connected {
+            if !connected {
+                *self.link_quality.lock().unwrap() = None;
+            }
+            status::update(self);
+        }
+    }
+
+    pub fn set_link_quality(&self, link_quality: LinkQuality) {
+        *self.link_quality.lock().unwrap() = Some(link_quality);
+    }
+
+    pub fn presence(&self, address: &str) -> Option<Presence> {
+        self.presence.lock().unwrap().get(address).copied()
+    }
+
+    pub fn set_presence(&self, address: &str, presence: Presence) {
+        let previous = self
+            .presence
+            .lock()
+            .unwrap()
+            .insert(address.to_string(), presence);
+        if previous != Some(presence) {
+            status::update(self);
+        }
+    }
+
+    // Whether every WiCAN listened for was away, so no samples are expected
+    pub fn all_away(&self) -> bool {
+        let presence = self.presence.lock().unwrap();
+        !presence.is_empty() && presence.values().all(Presence::is_away)
+    }
+
+    pub fn record_post(&self, success: bool, queue_depth: usize) {
+        let (counter, timestamp) = if success {
+            (&self.posts_succeeded, &self.last_post_success)
+        } else {
+            (&self.posts_failed, &self.last_post_failure)
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        *timestamp.lock().unwrap() = Some(Utc::now());
+        self.queue_depth.store(queue_depth, Ordering::Relaxed);
+        status::update(self);
+    }
+
+    pub fn record_error(&self, message: &str) {
+        let mut errors = self.recent_errors.lock().unwrap();
+        if errors.len() == RECENT_ERRORS {
+            errors.pop_front();
+        }
+        errors.push_back(RecentError {
+            time: Utc::now(),
+            message: message.to_string(),
+        });
+        drop(errors);
+        status::update(self);
+    }
+
+    pub fn set_api_url(&self, url: &str) {
+        *self.api_url.lock().unwrap() = Some(url.to_string());
+    }
+
+    pub fn set_queue_depth(&self, queue_depth: usize) {
+        self.queue_depth.store(queue_depth, Ordering::Relaxed);
+        status::update(self);
+    }
+
+    // Write the current state to the log
+    pub fn dump(&self, configuration_summary: &str) {
+        let format_time = |time: &Mutex<Option<DateTime<Utc>>>| {
+            time.lock()
+                .unwrap()
+                .map_or("never".to_string(), |time| time.to_rfc3339())
+        };
+
+        info!("Statistics dump requested.");
+        info!("Configuration: {}", configuration_summary);
+        info!(
+            "Connection state: {}",
+            if self.connected.load(Ordering::Relaxed) {
+                "connected"
+            } else {
+                "disconnected"
+            }
+        );
+        if let Some(link_quality) = self.link_quality.lock().unwrap().as_ref() {
+            info!("Link quality: {}", link_quality);
+        }
+        for (address, presence) in self.presence.lock().unwrap().iter() {
+            info!(
+                "Vehicle of {} {}, last heard {}",
+                address,
+                if presence.is_away() {
+                    "away"
+                } else {
+                    "in range"
+                },
+                presence
+                    .last_seen
+                    .map_or("never".to_string(), |time| time.to_rfc3339())
+            );
+        }
+        match self.last_sample.lock().unwrap().as_ref() {
+            Some(sample) => info!(
+                "Last sample at {}: battery {:.1}%, outdoor temperature {}",
+                sample
+                    .timestamp
+                    .map_or("unknown".to_string(), |time| time.to_rfc3339()),
+                sample.battery_level_percentage.unwrap_or_default(),
+                sample
+                    .external_temp_celsius
+                    .map_or("unknown".to_string(), crate::units::display_temperature)
+            ),
+            None => info!("No sample received yet."),
+        }
+        info!(
+            "Samples received: {}, frames discarded: {}, connection failures: {}",
+            self.samples_received.load(Ordering::Relaxed),
+            self.discarded_frames.load(Ordering::Relaxed),
+            self.connect_failures.load(Ordering::Relaxed)
+        );
+        info!(
+            "Posts succeeded: {} (last {}), failed: {} (last {}), queued samples: {}",
+            self.posts_succeeded.load(Ordering::Relaxed),
+            format_time(&self.last_post_success),
+            self.posts_failed.load(Ordering::Relaxed),
+            format_time(&self.last_post_failure),
+            self.queue_depth.load(Ordering::Relaxed)
+        );
+        if let Some(url) = self.api_url.lock().unwrap().as_ref() {
+            info!("Last posted to {}", secrets::redact_url(url));
+        }
+    }
+}
+
+pub static STATS: Statistics = Statistics::new();
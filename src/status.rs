@@ -0,0 +1,185 @@
+use crate::devices::{self, KnownDevice};
+use crate::link::LinkQuality;
+use crate::presence::Presence;
+use crate::secrets;
+use crate::stats::{RecentError, Statistics, STATS};
+use crate::systemd;
+use crate::BatteryData;
+use anyhow::{Context, Result};
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use axum::{Json, Router};
+use chrono::{DateTime, Utc};
+use log::{debug, error, info};
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::sync::OnceLock;
+use std::time::Duration;
+use tokio::net::TcpListener;
+
+// Path of the status file, if enabled
+static STATUS_FILE: OnceLock<PathBuf> = OnceLock::new();
+
+// Last known state, as written to the status file and served by the status
+// API
+#[derive(Serialize)]
+struct Status {
+    updated: DateTime<Utc>,
+    connected: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    device: Option<KnownDevice>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    link_quality: Option<LinkQuality>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    vehicles: BTreeMap<String, Presence>,
+    last_sample: Option<BatteryData>,
+    last_fetch: Option<DateTime<Utc>>,
+    last_post_success: Option<DateTime<Utc>>,
+    last_post_failure: Option<DateTime<Utc>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    api_url: Option<String>,
+    samples_received: u64,
+    discarded_frames: u64,
+    connect_failures: u64,
+    posts_succeeded: u64,
+    posts_failed: u64,
+    queue_depth: usize,
+    recent_errors: Vec<RecentError>,
+}
+
+pub fn set_status_file(path: PathBuf) {
+    let _ = STATUS_FILE.set(path);
+}
+
+// Rewrite the status file from the current statistics, if enabled, and pass
+// the state on to systemd
+pub fn update(stats: &Statistics) {
+    systemd::status(stats);
+    let Some(path) = STATUS_FILE.get() else {
+        return;
+    };
+    if let Err(e) = write_status(path, &current(stats)) {
+        debug!("Failed to update status file: {:#}", e);
+    }
+}
+
+// The current status as JSON, for the control socket
+pub fn snapshot(stats: &Statistics) -> Value {
+    serde_json::to_value(current(stats)).unwrap_or_default()
+}
+
+fn current(stats: &Statistics) -> Status {
+    Status {
+        updated: Utc::now(),
+        connected: stats.connected.load(Ordering::Relaxed),
+        device: devices::current(),
+        link_quality: *stats.link_quality.lock().unwrap(),
+        vehicles: stats.presence.lock().unwrap().clone(),
+        last_sample: stats.last_sample.lock().unwrap().clone(),
+        last_fetch: *stats.last_fetch.lock().unwrap(),
+        last_post_success: *stats.last_post_success.lock().unwrap(),
+        last_post_failure: *stats.last_post_failure.lock().unwrap(),
+        api_url: stats
+            .api_url
+            .lock()
+            .unwrap()
+            .as_deref()
+            .map(secrets::redact_url),
+        samples_received: stats.samples_received.load(Ordering::Relaxed),
+        discarded_frames: stats.discarded_frames.load(Ordering::Relaxed),
+        connect_failures: stats.connect_failures.load(Ordering::Relaxed),
+        posts_succeeded: stats.posts_succeeded.load(Ordering::Relaxed),
+        posts_failed: stats.posts_failed.load(Ordering::Relaxed),
+        queue_depth: stats.queue_depth.load(Ordering::Relaxed),
+        recent_errors: stats
+            .recent_errors
+            .lock()
+            .unwrap()
+            .iter()
+            .cloned()
+            .collect(),
+    }
+}
+
+// Write to a temporary file and rename it, so readers never see a partial file.
+// In a shared directory such as /run only the file itself is ours, so it is
+// rewritten in place instead.
+fn write_status(path: &Path, status: &Status) -> Result<()> {
+    let temporary = path.with_extension("json.tmp");
+    let contents = serde_json::to_vec_pretty(status)?;
+    if std::fs::write(&temporary, &contents).is_err() {
+        return std::fs::write(path, contents)
+            .with_context(|| format!("Failed to write '{}'", path.display()));
+    }
+    std::fs::rename(&temporary, path)
+        .with_context(|| format!("Failed to replace '{}'", path.display()))
+}
+
+#[derive(Clone)]
+struct HealthState {
+    started: DateTime<Utc>,
+    max_sample_age: Duration,
+}
+
+// Bind the status listener, done before dropping privileges so low ports work
+pub async fn bind(address: SocketAddr) -> Result<TcpListener> {
+    TcpListener::bind(address)
+        .await
+        .with_context(|| format!("Failed to listen for status requests on {}", address))
+}
+
+// Serve /health and /status until the process exits. Both are read-only, so
+// unlike the admin API they need no token.
+pub async fn serve(listener: TcpListener, max_sample_age: Duration) {
+    let state = HealthState {
+        started: Utc::now(),
+        max_sample_age,
+    };
+    let app = Router::new()
+        .route("/health", get(health))
+        .route("/status", get(status))
+        .with_state(state);
+
+    if let Ok(address) = listener.local_addr() {
+        info!("Status API listening on {}", address);
+    }
+    if let Err(e) = axum::serve(listener, app).await {
+        error!("Status API stopped: {}", e);
+    }
+}
+
+// 200 while samples keep arriving, 503 once the last one, or the start when
+// there was none yet, is older than the maximum sample age. While every
+// vehicle is away no samples are expected, so it stays 200 with an away status.
+async fn health(State(state): State<HealthState>) -> Response {
+    let last_fetch = *STATS.last_fetch.lock().unwrap();
+    let age = (Utc::now() - last_fetch.unwrap_or(state.started))
+        .to_std()
+        .unwrap_or_default();
+    let away = STATS.all_away();
+    let healthy = age <= state.max_sample_age || away;
+    let body = Json(json!({
+        "status": match (healthy, away) {
+            (true, false) => "ok",
+            (true, true) => "away",
+            (false, _) => "stale",
+        },
+        "last_fetch": last_fetch,
+        "connected": STATS.connected.load(Ordering::Relaxed),
+    }));
+    if healthy {
+        body.into_response()
+    } else {
+        (StatusCode::SERVICE_UNAVAILABLE, body).into_response()
+    }
+}
+
+async fn status() -> Json<Status> {
+    Json(current(&STATS))
+}
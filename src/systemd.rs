@@ -0,0 +1,157 @@
+use crate::stats::Statistics;
+use anyhow::{anyhow, Context, Result};
+use chrono::Local;
+use log::{debug, info, warn};
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::sync::atomic::Ordering;
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+use tokio::time;
+
+// The notification socket, when started by systemd as a Type=notify service
+static NOTIFIER: OnceLock<Notifier> = OnceLock::new();
+
+struct Notifier {
+    socket: UnixDatagram,
+    // How often the watchdog is pinged, half of WatchdogSec=
+    watchdog_interval: Option<Duration>,
+    // Last STATUS= sent, so unchanged states aren't sent again
+    status: Mutex<String>,
+}
+
+impl Notifier {
+    fn from_env() -> Result<Option<Self>> {
+        let Some(path) = std::env::var_os("NOTIFY_SOCKET") else {
+            return Ok(None);
+        };
+        let path = path
+            .into_string()
+            .map_err(|_| anyhow!("NOTIFY_SOCKET is not valid UTF-8"))?;
+        // A leading @ names a socket in the abstract namespace
+        let address = match path.strip_prefix('@') {
+            Some(name) => SocketAddr::from_abstract_name(name),
+            None => SocketAddr::from_pathname(&path),
+        }
+        .with_context(|| format!("Invalid NOTIFY_SOCKET '{}'", path))?;
+        let socket = UnixDatagram::unbound().context("Failed to create notification socket")?;
+        socket
+            .connect_addr(&address)
+            .with_context(|| format!("Failed to connect to NOTIFY_SOCKET '{}'", path))?;
+
+        // The watchdog is meant for another process if WATCHDOG_PID names one
+        let for_us =
+            std::env::var("WATCHDOG_PID").map_or(true, |pid| pid.parse() == Ok(std::process::id()));
+        let watchdog_interval = std::env::var("WATCHDOG_USEC")
+            .ok()
+            .and_then(|usec| usec.parse::<u64>().ok())
+            .filter(|usec| *usec > 0 && for_us)
+            .map(|usec| Duration::from_micros(usec / 2));
+
+        Ok(Some(Self {
+            socket,
+            watchdog_interval,
+            status: Mutex::new(String::new()),
+        }))
+    }
+
+    fn send(&self, message: &str) {
+        if let Err(e) = self.socket.send(message.as_bytes()) {
+            debug!("Failed to notify systemd: {}", e);
+        }
+    }
+}
+
+// Connect to systemd's notification socket, if there is one. Done at startup
+// while privileged and before the sandbox is applied.
+pub fn init() {
+    match Notifier::from_env() {
+        Ok(Some(notifier)) => {
+            if let Some(interval) = notifier.watchdog_interval {
+                info!("Pinging the systemd watchdog every {:?}.", interval);
+            }
+            let _ = NOTIFIER.set(notifier);
+        }
+        Ok(None) => {}
+        Err(e) => warn!("Not notifying systemd: {:#}", e),
+    }
+}
+
+// Tell systemd startup has finished
+pub fn ready() {
+    if let Some(notifier) = NOTIFIER.get() {
+        debug!("Notifying systemd that startup has finished.");
+        notifier.send("READY=1");
+    }
+}
+
+// Tell systemd a stop is under way, so the time spent disconnecting isn't
+// taken for a hang
+pub fn stopping() {
+    if let Some(notifier) = NOTIFIER.get() {
+        notifier.send("STOPPING=1");
+    }
+}
+
+// Tell the watchdog the main loop is still making progress
+pub fn watchdog() {
+    if let Some(notifier) = NOTIFIER.get().filter(|n| n.watchdog_interval.is_some()) {
+        notifier.send("WATCHDOG=1");
+    }
+}
+
+// Keep pinging the watchdog, for as long as the main loop is deliberately
+// waiting rather than stuck. Never finishes, so it's meant as a select! branch.
+pub async fn feed_watchdog() {
+    let Some(interval) = NOTIFIER.get().and_then(|n| n.watchdog_interval) else {
+        return std::future::pending().await;
+    };
+    let mut ticks = time::interval(interval);
+    loop {
+        ticks.tick().await;
+        watchdog();
+    }
+}
+
+// Show the connection state, the last sample and any later error in
+// systemctl status
+pub fn status(stats: &Statistics) {
+    let Some(notifier) = NOTIFIER.get() else {
+        return;
+    };
+
+    let mut status = if stats.connected.load(Ordering::Relaxed) {
+        "Connected".to_string()
+    } else if stats.all_away() {
+        "Vehicle away".to_string()
+    } else {
+        "Not connected".to_string()
+    };
+    let last_fetch = *stats.last_fetch.lock().unwrap();
+    let soc = stats
+        .last_sample
+        .lock()
+        .unwrap()
+        .as_ref()
+        .and_then(|sample| sample.battery_level_percentage);
+    match (last_fetch, soc) {
+        (Some(time), Some(soc)) => status.push_str(&format!(
+            ", battery {:.1}% at {}",
+            soc,
+            time.with_timezone(&Local).format("%H:%M:%S")
+        )),
+        _ => status.push_str(", no sample yet"),
+    }
+    if let Some(error) = stats.recent_errors.lock().unwrap().back() {
+        if last_fetch.is_none_or(|time| error.time > time) {
+            status.push_str(&format!(", last error: {}", error.message));
+        }
+    }
+
+    let mut sent = notifier.status.lock().unwrap();
+    if *sent != status {
+        // Each assignment is one line, so errors spanning several are joined
+        notifier.send(&format!("STATUS={}", status.replace('\n', " ")));
+        *sent = status;
+    }
+}
@@ -0,0 +1,121 @@
+use anyhow::{Context, Result};
+use log::debug;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::charging::{self, ChargingType};
+use crate::de;
+use crate::profile::AutopidKeys;
+
+// Values of an autopid reply, under the AutoPID names listed in the README
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WicanResponse {
+    #[serde(alias = "SOC", deserialize_with = "de::tolerant_f32")]
+    pub soc: f32,
+    #[serde(alias = "SOC_D", default, deserialize_with = "de::tolerant_option_f32")]
+    pub soc_d: Option<f32>,
+    #[serde(alias = "TMP_A", default, deserialize_with = "de::tolerant_option_f32")]
+    pub outdoor_temperature: Option<f32>,
+    #[serde(
+        alias = "PRECOND",
+        default,
+        deserialize_with = "de::tolerant_option_bool"
+    )]
+    pub battery_preconditioning: Option<bool>,
+    #[serde(alias = "PLUG", default, deserialize_with = "de::tolerant_option_bool")]
+    pub plug_inserted: Option<bool>,
+    #[serde(
+        alias = "CHG_PORT",
+        default,
+        deserialize_with = "de::tolerant_option_bool"
+    )]
+    pub charge_port_open: Option<bool>,
+    #[serde(
+        alias = "CHG_TYPE",
+        default,
+        deserialize_with = "charging::tolerant_option_charging_type"
+    )]
+    pub charging_type: Option<ChargingType>,
+    #[serde(alias = "PWR", default, deserialize_with = "de::tolerant_option_f32")]
+    pub battery_power_kw: Option<f32>,
+    #[serde(
+        alias = "CELL_TMIN",
+        default,
+        deserialize_with = "de::tolerant_option_f32"
+    )]
+    pub cell_temperature_min: Option<f32>,
+    #[serde(
+        alias = "CELL_TMAX",
+        default,
+        deserialize_with = "de::tolerant_option_f32"
+    )]
+    pub cell_temperature_max: Option<f32>,
+    #[serde(
+        alias = "COOL_TMP",
+        default,
+        deserialize_with = "de::tolerant_option_f32"
+    )]
+    pub coolant_temperature: Option<f32>,
+    #[serde(
+        alias = "HVAC_PWR",
+        default,
+        deserialize_with = "de::tolerant_option_f32"
+    )]
+    pub hvac_power_kw: Option<f32>,
+    #[serde(alias = "SPEED", default, deserialize_with = "de::tolerant_option_f32")]
+    pub speed_kmh: Option<f32>,
+    #[serde(alias = "SOH", default, deserialize_with = "de::tolerant_option_f32")]
+    pub soh: Option<f32>,
+    #[serde(
+        alias = "CHARGING",
+        default,
+        deserialize_with = "de::tolerant_option_bool"
+    )]
+    pub charging: Option<bool>,
+    #[serde(
+        alias = "BATT_TMP",
+        default,
+        deserialize_with = "de::tolerant_option_f32"
+    )]
+    pub battery_temperature: Option<f32>,
+    #[serde(alias = "ODO", default, deserialize_with = "de::tolerant_option_f32")]
+    pub odometer_km: Option<f32>,
+    #[serde(alias = "AUX_V", default, deserialize_with = "de::tolerant_option_f32")]
+    pub aux_battery_voltage: Option<f32>,
+}
+
+// Decode an autopid reply, first copying fields the vehicle profile reports
+// under other keys to their AutoPID names
+pub fn parse(frame: &[u8], keys: &AutopidKeys) -> Result<WicanResponse> {
+    parse_merged(frame, keys, &Map::new())
+}
+
+// Decode an autopid reply with values read separately, e.g. manufacturer
+// specific PIDs, added under their names and replacing any the reply holds
+pub fn parse_merged(
+    frame: &[u8],
+    keys: &AutopidKeys,
+    extra: &Map<String, Value>,
+) -> Result<WicanResponse> {
+    let response_string = std::str::from_utf8(frame)
+        .context("Failed to decode WiCAN response as string")?
+        .trim_end();
+
+    debug!(
+        "Successfully decoded WiCAN response as string: {}",
+        response_string
+    );
+
+    let mut response: Map<String, Value> =
+        serde_json::from_str(response_string).context("Failed to parse WiCAN response JSON")?;
+    keys.apply(&mut response);
+    response.extend(extra.clone());
+    let wican_response: WicanResponse = serde_json::from_value(Value::Object(response))
+        .context("Failed to parse WiCAN response JSON")?;
+
+    debug!(
+        "Successfully decoded WiCAN response as JSON: {:?}",
+        wican_response
+    );
+    Ok(wican_response)
+}
@@ -0,0 +1,70 @@
+use anyhow::{anyhow, Result};
+use log::debug;
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+use tokio::time::{self, Instant};
+
+// Limits on writes to the dongle, for firmware that silently drops commands
+// arriving in quick succession
+#[derive(Debug, Clone, Copy)]
+pub struct ThrottleOptions {
+    pub min_spacing: Duration,
+    // Most writes per poll, counting the keep-alives that follow it
+    pub budget: Option<u16>,
+}
+
+static OPTIONS: OnceLock<ThrottleOptions> = OnceLock::new();
+
+static STATE: Mutex<State> = Mutex::new(State {
+    next_write: None,
+    written: None,
+});
+
+struct State {
+    // Earliest time the next write may go out
+    next_write: Option<Instant>,
+    // Writes since the current poll started, None outside polling so raw CAN
+    // frames aren't counted
+    written: Option<u16>,
+}
+
+pub fn configure(options: ThrottleOptions) {
+    let _ = OPTIONS.set(options);
+}
+
+// Start counting the writes of a new poll against the budget
+pub fn start_cycle() {
+    STATE.lock().unwrap().written = Some(0);
+}
+
+// Wait until a write may go out, failing when the poll has used its budget
+pub async fn acquire() -> Result<()> {
+    let Some(options) = OPTIONS.get() else {
+        return Ok(());
+    };
+    let wait = {
+        let mut state = STATE.lock().unwrap();
+        if let (Some(written), Some(budget)) = (state.written, options.budget) {
+            if written >= budget {
+                return Err(anyhow!(
+                    "Used the budget of {} write(s) to the dongle for this poll",
+                    budget
+                ));
+            }
+        }
+        let now = Instant::now();
+        let at = state
+            .next_write
+            .map_or(now, |next_write| next_write.max(now));
+        state.next_write = Some(at + options.min_spacing);
+        if let Some(written) = state.written.as_mut() {
+            *written += 1;
+        }
+        at - now
+    };
+    if !wait.is_zero() {
+        debug!("Waiting {:?} before writing to the dongle", wait);
+        time::sleep(wait).await;
+    }
+    Ok(())
+}
@@ -0,0 +1,141 @@
+use anyhow::{anyhow, Result};
+use log::{log_enabled, trace, Level};
+use std::fmt;
+use std::fs::File;
+use std::io::Write;
+use std::os::fd::{FromRawFd, OwnedFd};
+use std::sync::{Mutex, OnceLock};
+
+// Bluetooth HCI socket constants from the kernel's bluetooth headers
+const BTPROTO_HCI: libc::c_int = 1;
+const HCI_DEV_NONE: u16 = 0xffff;
+const HCI_CHANNEL_LOGGING: u16 = 4;
+const LOG_INFO: u8 = 6;
+const MARKER_IDENT: &[u8] = b"aa-proxy-wican\0";
+
+#[repr(C)]
+struct SockaddrHci {
+    hci_family: libc::sa_family_t,
+    hci_dev: u16,
+    hci_channel: u16,
+}
+
+// Kernel logging channel shown by btmon, when markers are enabled
+static BTMON: OnceLock<Mutex<File>> = OnceLock::new();
+
+#[derive(Clone, Copy)]
+pub enum Direction {
+    Sent,
+    Received,
+}
+
+impl fmt::Display for Direction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Direction::Sent => write!(f, "TX"),
+            Direction::Received => write!(f, "RX"),
+        }
+    }
+}
+
+// Open the HCI logging channel so frame markers appear in a btmon capture.
+// Needs CAP_NET_ADMIN.
+pub fn enable_btmon_markers() -> Result<()> {
+    let fd = unsafe {
+        libc::socket(
+            libc::AF_BLUETOOTH,
+            libc::SOCK_RAW | libc::SOCK_CLOEXEC,
+            BTPROTO_HCI,
+        )
+    };
+    if fd < 0 {
+        return Err(anyhow!(
+            "Failed to open HCI socket: {}",
+            std::io::Error::last_os_error()
+        ));
+    }
+    let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+    let address = SockaddrHci {
+        hci_family: libc::AF_BLUETOOTH as libc::sa_family_t,
+        hci_dev: HCI_DEV_NONE,
+        hci_channel: HCI_CHANNEL_LOGGING,
+    };
+    let result = unsafe {
+        libc::bind(
+            std::os::fd::AsRawFd::as_raw_fd(&fd),
+            &address as *const SockaddrHci as *const libc::sockaddr,
+            std::mem::size_of::<SockaddrHci>() as libc::socklen_t,
+        )
+    };
+    if result < 0 {
+        return Err(anyhow!(
+            "Failed to bind HCI logging channel: {}",
+            std::io::Error::last_os_error()
+        ));
+    }
+
+    let _ = BTMON.set(Mutex::new(File::from(fd)));
+    Ok(())
+}
+
+// Seconds on the monotonic clock, matching kernel and btmon timestamps better
+// than wall time, which may jump when the head unit syncs its clock
+fn monotonic_seconds() -> f64 {
+    let mut now = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut now) };
+    now.tv_sec as f64 + now.tv_nsec as f64 / 1e9
+}
+
+// Hex dump a raw frame at trace level and mark it in btmon if enabled
+pub fn frame(direction: Direction, data: &[u8]) {
+    let tracing = log_enabled!(Level::Trace);
+    let btmon = BTMON.get();
+    if !tracing && btmon.is_none() {
+        return;
+    }
+
+    let timestamp = monotonic_seconds();
+    if tracing {
+        trace!(
+            "{} [{:.6}] {} byte(s): {}",
+            direction,
+            timestamp,
+            data.len(),
+            hex_dump(data)
+        );
+    }
+    if let Some(btmon) = btmon {
+        let marker = format!("{} [{:.6}] {} byte(s)", direction, timestamp, data.len());
+        if let Err(e) = write_marker(&mut btmon.lock().unwrap(), &marker) {
+            trace!("Failed to write btmon marker: {}", e);
+        }
+    }
+}
+
+// Write a message in the format bluetoothd uses for the logging channel
+fn write_marker(socket: &mut File, message: &str) -> std::io::Result<()> {
+    let payload_len = 2 + MARKER_IDENT.len() + message.len() + 1;
+
+    let mut packet = Vec::with_capacity(6 + payload_len);
+    packet.extend(0u16.to_le_bytes());
+    packet.extend(HCI_DEV_NONE.to_le_bytes());
+    packet.extend((payload_len as u16).to_le_bytes());
+    packet.push(LOG_INFO);
+    packet.push(MARKER_IDENT.len() as u8);
+    packet.extend(MARKER_IDENT);
+    packet.extend(message.as_bytes());
+    packet.push(0);
+
+    socket.write_all(&packet)
+}
+
+fn hex_dump(data: &[u8]) -> String {
+    data.iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
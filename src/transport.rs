@@ -0,0 +1,345 @@
+use anyhow::{anyhow, Context, Result};
+use bluer::gatt::remote::Characteristic;
+use clap::ValueEnum;
+use futures_util::stream;
+use log::{debug, error, info, warn};
+use rumqttc::{AsyncClient, Event as MqttEvent, Packet, QoS};
+use serde_json::{Map, Value};
+use std::fmt;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::tcp::OwnedWriteHalf;
+use tokio::net::TcpStream;
+use tokio::time;
+
+use crate::adaptive;
+use crate::api::ApiClient;
+use crate::control::CONTROL;
+use crate::dongle::Dongle;
+use crate::events::{self, Event};
+use crate::merge::SampleSource;
+use crate::metadata::SourceMetadata;
+use crate::mqtt::{self, MqttConnectOptions};
+use crate::stats::STATS;
+use crate::vehicle::Vehicle;
+use crate::{elm327, hooks, throttle, trace, BatteryData, Outputs, WicanResponse, WriteType};
+
+// Port of the WiCAN's ELM327 server in Wi-Fi AP and station mode
+pub const DEFAULT_TCP_PORT: u16 = 3333;
+
+const MQTT_CHANNEL_CAPACITY: usize = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Transport {
+    // Bluetooth LE
+    Ble,
+    // The TCP port the WiCAN serves over Wi-Fi
+    Tcp,
+    // AutoPID JSON the WiCAN publishes to an MQTT broker
+    Mqtt,
+}
+
+// Address of a WiCAN reached over Wi-Fi, written HOST[:PORT]
+#[derive(Debug, Clone, PartialEq)]
+pub struct TcpAddress {
+    pub host: String,
+    pub port: u16,
+}
+
+impl FromStr for TcpAddress {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (host, port) = match s.rsplit_once(':') {
+            // Leave bare IPv6 addresses alone, they need brackets with a port
+            Some((host, port)) if !host.contains(':') || host.ends_with(']') => {
+                let port = port
+                    .parse()
+                    .map_err(|_| anyhow!("Invalid port '{}' in '{}'", port, s))?;
+                (host, port)
+            }
+            _ => (s, DEFAULT_TCP_PORT),
+        };
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if host.is_empty() {
+            return Err(anyhow!("'{}' has no host", s));
+        }
+        Ok(Self {
+            host: host.to_string(),
+            port,
+        })
+    }
+}
+
+impl fmt::Display for TcpAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.host.contains(':') {
+            write!(f, "[{}]:{}", self.host, self.port)
+        } else {
+            write!(f, "{}:{}", self.host, self.port)
+        }
+    }
+}
+
+// Where commands for the dongle are written, whichever transport reaches it
+pub enum CommandWriter {
+    Ble {
+        characteristic: Characteristic,
+        write_type: WriteType,
+    },
+    Tcp(OwnedWriteHalf),
+}
+
+impl CommandWriter {
+    pub async fn write(&mut self, command: &[u8]) -> Result<()> {
+        match self {
+            CommandWriter::Ble {
+                characteristic,
+                write_type,
+            } => crate::write_command(characteristic, command, *write_type).await,
+            CommandWriter::Tcp(stream) => {
+                throttle::acquire().await?;
+                trace::frame(trace::Direction::Sent, command);
+                stream
+                    .write_all(command)
+                    .await
+                    .context("Failed to write to the WiCAN over TCP")
+            }
+        }
+    }
+
+    // Write a command too long for one BLE write in pieces of the MTU, which
+    // the dongle joins up again until the line ending
+    pub async fn write_long(&mut self, command: &[u8]) -> Result<()> {
+        let chunk_len = match self {
+            CommandWriter::Ble { characteristic, .. } => characteristic
+                .mtu()
+                .await
+                .context("Failed to read the MTU of the write characteristic")?
+                .max(1),
+            CommandWriter::Tcp(_) => command.len().max(1),
+        };
+        for chunk in command.chunks(chunk_len) {
+            self.write(chunk).await?;
+        }
+        Ok(())
+    }
+}
+
+pub struct TcpOptions {
+    pub address: TcpAddress,
+    pub dongle: Dongle,
+    pub connect_timeout: Duration,
+    pub response_timeout: Duration,
+}
+
+// Poll a WiCAN over TCP at the update frequency, through the same request,
+// parsing and outputs as over Bluetooth
+pub async fn poll_tcp(
+    options: TcpOptions,
+    api: &ApiClient,
+    vehicle: &Vehicle,
+    outputs: &Outputs<'_>,
+    source: Option<SourceMetadata>,
+) -> Result<()> {
+    info!(
+        "Reading battery data from the {} at {} over TCP.",
+        options.dongle, options.address
+    );
+    let address = options.address.to_string();
+    let mut connected = false;
+    let mut control = CONTROL.listen();
+    let mut first_run = true;
+    loop {
+        if !first_run {
+            crate::wait_for_next_update(
+                &mut control,
+                api.retry_after(),
+                None,
+                WriteType::Auto,
+                None,
+                b"",
+            )
+            .await;
+        }
+        first_run = false;
+        control.take_poll_request();
+        // Every poll connects anew
+        CONTROL.take_reconnect_request();
+        hooks::pre_poll().await;
+
+        let battery_data = match fetch_data(&options, vehicle).await {
+            Ok(data) => {
+                if !connected {
+                    events::emit(Event::Connected {
+                        address: address.clone(),
+                    });
+                    connected = true;
+                }
+                STATS.set_connected(true);
+                data
+            }
+            Err(e) => {
+                error!("Failed to fetch data over TCP: {:#}. Will retry...", e);
+                hooks::error(format!("Failed to fetch data over TCP: {:#}", e));
+                STATS.record_connect_failure();
+                STATS.set_connected(false);
+                adaptive::record_failure();
+                if connected {
+                    events::emit(Event::Disconnected {
+                        address: address.clone(),
+                        reason: e.to_string(),
+                    });
+                    connected = false;
+                }
+                continue;
+            }
+        };
+        if let Some(battery_data) = battery_data {
+            let battery_data = BatteryData {
+                source: source.clone(),
+                ..battery_data
+            };
+            outputs.publish(SampleSource::Wican, battery_data).await;
+        } else {
+            adaptive::record_failure();
+        }
+    }
+}
+
+// Connect for a single request, as the WiCAN serves one client at a time
+async fn fetch_data(options: &TcpOptions, vehicle: &Vehicle) -> Result<Option<BatteryData>> {
+    let address = &options.address;
+    let stream = time::timeout(
+        options.connect_timeout,
+        TcpStream::connect((address.host.as_str(), address.port)),
+    )
+    .await
+    .map_err(|_| anyhow!("Timed out connecting to {}", address))?
+    .with_context(|| format!("Could not connect to {}", address))?;
+    stream.set_nodelay(true)?;
+    let (reader, writer) = stream.into_split();
+    let chunks = stream::unfold(reader, |mut reader| async move {
+        let mut buffer = vec![0; 1024];
+        match reader.read(&mut buffer).await {
+            Ok(0) | Err(_) => None,
+            Ok(len) => {
+                buffer.truncate(len);
+                Some((buffer, reader))
+            }
+        }
+    });
+    let mut responses = Box::pin(options.dongle.frames(chunks));
+    let mut writer = CommandWriter::Tcp(writer);
+
+    throttle::start_cycle();
+    if options.dongle.is_elm327() {
+        return elm327::read(
+            responses,
+            writer,
+            options.dongle,
+            vehicle,
+            options.response_timeout,
+        )
+        .await
+        .map(Some);
+    }
+    crate::request_autopid(
+        &mut responses,
+        &mut writer,
+        options.dongle,
+        vehicle,
+        options.response_timeout,
+    )
+    .await
+}
+
+pub struct MqttOptions {
+    pub connection: MqttConnectOptions,
+    pub topic: String,
+}
+
+// Follow the AutoPID JSON a WiCAN publishes to a broker, keeping the latest
+// value of every field as they may arrive in separate messages, and send a
+// sample at the update frequency
+pub async fn subscribe_mqtt(
+    options: MqttOptions,
+    vehicle: &Vehicle,
+    outputs: &Outputs<'_>,
+    source: Option<SourceMetadata>,
+) -> Result<()> {
+    let (client, mut event_loop) = AsyncClient::new(
+        mqtt::options_v311(&options.connection)?,
+        MQTT_CHANNEL_CAPACITY,
+    );
+    let mut fields = Map::new();
+    let mut last_sample: Option<Instant> = None;
+    let mut control = CONTROL.listen();
+    loop {
+        match event_loop.poll().await {
+            Ok(MqttEvent::Incoming(Packet::ConnAck(_))) => {
+                info!(
+                    "Connected to MQTT broker {}, following WiCAN AutoPID data on {}.",
+                    options.connection.url, options.topic
+                );
+                client
+                    .subscribe(&options.topic, QoS::AtLeastOnce)
+                    .await
+                    .context("Failed to subscribe to WiCAN AutoPID data")?;
+                STATS.set_connected(true);
+            }
+            Ok(MqttEvent::Incoming(Packet::Publish(publish))) => {
+                if !mqtt::topic_matches(&options.topic, &publish.topic) {
+                    continue;
+                }
+                trace::frame(trace::Direction::Received, &publish.payload);
+                match serde_json::from_slice::<Map<String, Value>>(&publish.payload) {
+                    Ok(values) => fields.extend(values),
+                    Err(e) => {
+                        warn!(
+                            "Ignoring WiCAN message on {} that is not a JSON object: {}",
+                            publish.topic, e
+                        );
+                        continue;
+                    }
+                }
+
+                let interval = CONTROL.poll_interval();
+                let due = last_sample.is_none_or(|last| last.elapsed() >= interval)
+                    || control.take_poll_request();
+                if !due || CONTROL.is_paused() {
+                    continue;
+                }
+
+                let mut response = fields.clone();
+                vehicle.autopid_keys.apply(&mut response);
+                let response: WicanResponse = match serde_json::from_value(Value::Object(response))
+                {
+                    Ok(response) => response,
+                    Err(e) => {
+                        debug!("No complete AutoPID data from the WiCAN yet: {}", e);
+                        continue;
+                    }
+                };
+                last_sample = Some(Instant::now());
+                let battery_data = BatteryData {
+                    source: source.clone(),
+                    ..crate::battery_data(response, vehicle)
+                };
+                outputs.publish(SampleSource::Wican, battery_data).await;
+            }
+            Ok(event) => debug!("MQTT event: {:?}", event),
+            Err(e) => {
+                warn!(
+                    "MQTT connection to {} failed: {}. Retrying in {:?}...",
+                    options.connection.url,
+                    e,
+                    mqtt::RECONNECT_DELAY
+                );
+                STATS.set_connected(false);
+                time::sleep(mqtt::RECONNECT_DELAY).await;
+            }
+        }
+    }
+}
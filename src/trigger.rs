@@ -0,0 +1,74 @@
+use anyhow::{anyhow, Result};
+use log::{error, info, warn};
+use nix::sys::inotify::AddWatchFlags;
+use std::path::Path;
+
+use crate::control::CONTROL;
+use crate::filewatch::FileWatcher;
+use crate::paths;
+
+// Watches the directory of the trigger file, as the file itself comes and goes
+pub struct TriggerFile {
+    watcher: FileWatcher,
+    // In a directory shared with others, such as /tmp, the service user may
+    // not remove files, so the file is kept and touching it triggers a poll
+    keep: bool,
+}
+
+impl TriggerFile {
+    // Start watching, removing a trigger left behind by an earlier run, or
+    // creating the file to be touched when it is kept
+    pub fn watch(path: &Path, keep: bool) -> Result<Self> {
+        if path.file_name().is_none() {
+            return Err(anyhow!("Invalid poll trigger file {}", path.display()));
+        }
+
+        let watcher = FileWatcher::watch(
+            path,
+            AddWatchFlags::IN_CREATE
+                | AddWatchFlags::IN_MOVED_TO
+                | AddWatchFlags::IN_CLOSE_WRITE
+                | AddWatchFlags::IN_ATTRIB,
+        )?;
+        if keep {
+            paths::touch(path)?;
+        } else {
+            remove(path);
+        }
+
+        Ok(Self { watcher, keep })
+    }
+
+    // Request a poll whenever the trigger file appears, then remove it so it
+    // can be created again, or whenever the kept file is touched
+    pub async fn run(self) {
+        let path = self.watcher.path();
+        info!("Watching {} for poll requests.", path.display());
+        let watched = self
+            .watcher
+            .run(|| {
+                if path.exists() {
+                    if !self.keep {
+                        remove(path);
+                    }
+                    CONTROL.poll_now();
+                }
+            })
+            .await;
+        if let Err(e) = watched {
+            error!("Stopped watching the poll trigger file: {}", e);
+        }
+    }
+}
+
+fn remove(path: &Path) {
+    if let Err(e) = std::fs::remove_file(path) {
+        if e.kind() != std::io::ErrorKind::NotFound {
+            warn!(
+                "Could not remove poll trigger file {}: {}",
+                path.display(),
+                e
+            );
+        }
+    }
+}
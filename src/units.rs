@@ -0,0 +1,57 @@
+use clap::ValueEnum;
+use std::fmt;
+use std::sync::OnceLock;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum TemperatureUnit {
+    Celsius,
+    Fahrenheit,
+}
+
+impl TemperatureUnit {
+    // Convert a temperature in this unit to celsius
+    pub fn to_celsius(self, value: f32) -> f32 {
+        match self {
+            TemperatureUnit::Celsius => value,
+            TemperatureUnit::Fahrenheit => (value - 32.0) * 5.0 / 9.0,
+        }
+    }
+
+    // Convert a temperature in celsius to this unit
+    pub fn celsius_to_unit(self, celsius: f32) -> f32 {
+        match self {
+            TemperatureUnit::Celsius => celsius,
+            TemperatureUnit::Fahrenheit => celsius * 9.0 / 5.0 + 32.0,
+        }
+    }
+
+    // Format a temperature in celsius for display in this unit
+    pub fn display(self, celsius: f32) -> String {
+        format!("{:.1}{}", self.celsius_to_unit(celsius), self)
+    }
+}
+
+impl fmt::Display for TemperatureUnit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TemperatureUnit::Celsius => write!(f, "°C"),
+            TemperatureUnit::Fahrenheit => write!(f, "°F"),
+        }
+    }
+}
+
+static DISPLAY_TEMPERATURE_UNIT: OnceLock<TemperatureUnit> = OnceLock::new();
+
+// Set the unit used for temperatures in logs and other local outputs
+pub fn set_display_temperature_unit(unit: TemperatureUnit) {
+    let _ = DISPLAY_TEMPERATURE_UNIT.set(unit);
+}
+
+// Format a temperature in celsius using the configured display unit
+pub fn display_temperature(celsius: f32) -> String {
+    DISPLAY_TEMPERATURE_UNIT
+        .get()
+        .copied()
+        .unwrap_or(TemperatureUnit::Celsius)
+        .display(celsius)
+}
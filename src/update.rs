@@ -0,0 +1,235 @@
+use anyhow::{anyhow, Context, Result};
+use base64::Engine;
+use log::info;
+use reqwest::Client;
+use ring::signature::{UnparsedPublicKey, ED25519};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::cmp::Ordering;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+
+pub const RELEASES_URL: &str = "https://api.github.com/repos/Ioniq3/aa-proxy-wican/releases/latest";
+
+// Release assets listing the SHA-256 of every binary, as written by
+// sha256sum, with a "version X.Y.Z" line naming the release, and its detached
+// Ed25519 signature
+const CHECKSUMS_ASSET: &str = "SHA256SUMS";
+const SIGNATURE_ASSET: &str = "SHA256SUMS.sig";
+
+// Base64 Ed25519 key releases are signed with, pinned when building release
+// binaries so an update can't be swapped together with its checksums
+const PINNED_PUBLIC_KEY: Option<&str> = option_env!("AA_PROXY_WICAN_RELEASE_PUBLIC_KEY");
+
+pub struct UpdateOptions {
+    pub releases_url: String,
+    // Base64 Ed25519 public key the checksums must be signed with, instead of
+    // the one pinned at build time
+    pub public_key: Option<String>,
+    pub check: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct Release {
+    tag_name: String,
+    assets: Vec<Asset>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Asset {
+    name: String,
+    browser_download_url: String,
+}
+
+impl Release {
+    fn asset(&self, name: &str) -> Result<&Asset> {
+        self.assets
+            .iter()
+            .find(|asset| asset.name == name)
+            .ok_or_else(|| anyhow!("Release {} has no {} asset", self.tag_name, name))
+    }
+}
+
+// Name of the release binary for the platform we're running on
+pub fn asset_name() -> String {
+    format!("aa-proxy-wican-{}-linux", std::env::consts::ARCH)
+}
+
+// Replace the running binary with the latest release when it's newer,
+// or only report whether there is one with check
+pub async fn self_update(options: UpdateOptions) -> Result<()> {
+    let client = Client::builder()
+        .user_agent(concat!("aa-proxy-wican/", env!("CARGO_PKG_VERSION")))
+        .build()
+        .context("Failed to create HTTP client")?;
+    let release: Release = get(&client, &options.releases_url)
+        .await?
+        .json()
+        .await
+        .context("Invalid release")?;
+    let current = env!("CARGO_PKG_VERSION");
+    let latest = release.tag_name.trim_start_matches('v');
+    if compare_versions(latest, current)? != Ordering::Greater {
+        println!("aa-proxy-wican {} is up to date.", current);
+        return Ok(());
+    }
+    let name = asset_name();
+    let asset = release.asset(&name)?;
+    if options.check {
+        println!(
+            "aa-proxy-wican {} is available, running {}: {}",
+            latest, current, asset.browser_download_url
+        );
+        return Ok(());
+    }
+
+    // The checksums come from the same release as the binary, so only the
+    // signature proves where it came from and nothing is installed without it
+    let public_key = options
+        .public_key
+        .as_deref()
+        .or(PINNED_PUBLIC_KEY)
+        .ok_or_else(|| {
+            anyhow!(
+                "This build has no release signing key pinned, so updates can't be verified. Use --update-public-key."
+            )
+        })?;
+    let checksums = download(&client, release.asset(CHECKSUMS_ASSET)?).await?;
+    let signature = download(&client, release.asset(SIGNATURE_ASSET)?).await?;
+    verify_signature(public_key, &checksums, &signature)?;
+    info!("Verified the signature of {}", CHECKSUMS_ASSET);
+    let checksums = String::from_utf8_lossy(&checksums);
+    // The tag isn't signed, so an older signed release relabelled as newer
+    // would otherwise be installed
+    let signed = signed_version(&checksums)?;
+    if compare_versions(signed, latest)? != Ordering::Equal {
+        return Err(anyhow!(
+            "{} is signed for version {}, not {} as the release claims",
+            CHECKSUMS_ASSET,
+            signed,
+            latest
+        ));
+    }
+    let expected = expected_checksum(&checksums, &name)?;
+    info!("Downloading {}", asset.browser_download_url);
+    let binary = download(&client, asset).await?;
+    let actual = hex::encode(Sha256::digest(&binary));
+    if !actual.eq_ignore_ascii_case(&expected) {
+        return Err(anyhow!(
+            "Checksum mismatch for {}: expected {}, got {}",
+            name,
+            expected,
+            actual
+        ));
+    }
+
+    let exe = std::env::current_exe().context("Could not find the running binary")?;
+    replace(&exe, &binary)?;
+    println!(
+        "Updated {} from {} to {}. Restart aa-proxy-wican to run it.",
+        exe.display(),
+        current,
+        latest
+    );
+    Ok(())
+}
+
+async fn get(client: &Client, url: &str) -> Result<reqwest::Response> {
+    let response = client
+        .get(url)
+        .send()
+        .await
+        .with_context(|| format!("Could not reach {}", url))?;
+    let status = response.status();
+    if !status.is_success() {
+        return Err(anyhow!("{} answered {}", url, status));
+    }
+    Ok(response)
+}
+
+async fn download(client: &Client, asset: &Asset) -> Result<Vec<u8>> {
+    let bytes = get(client, &asset.browser_download_url)
+        .await?
+        .bytes()
+        .await
+        .with_context(|| format!("Failed to download {}", asset.name))?;
+    Ok(bytes.to_vec())
+}
+
+// Compare dotted numeric versions, ignoring any -prerelease or +build suffix
+fn compare_versions(a: &str, b: &str) -> Result<Ordering> {
+    fn parse(version: &str) -> Result<Vec<u64>> {
+        version
+            .split(['-', '+'])
+            .next()
+            .unwrap_or_default()
+            .split('.')
+            .map(|part| {
+                part.parse()
+                    .map_err(|_| anyhow!("Invalid version '{}'", version))
+            })
+            .collect()
+    }
+    Ok(parse(a)?.cmp(&parse(b)?))
+}
+
+// Find the checksum of a file in sha256sum output, "HASH  NAME" per line with
+// an optional * before binary file names
+fn expected_checksum(checksums: &str, name: &str) -> Result<String> {
+    checksums
+        .lines()
+        .filter_map(|line| line.split_once(char::is_whitespace))
+        .find(|(_, file)| file.trim().trim_start_matches('*') == name)
+        .map(|(hash, _)| hash.to_string())
+        .ok_or_else(|| anyhow!("{} has no checksum for {}", CHECKSUMS_ASSET, name))
+}
+
+// The version the checksums were signed for, from their "version X.Y.Z" line
+fn signed_version(checksums: &str) -> Result<&str> {
+    checksums
+        .lines()
+        .find_map(|line| line.trim().strip_prefix("version "))
+        .map(|version| version.trim().trim_start_matches('v'))
+        .ok_or_else(|| anyhow!("{} names no version", CHECKSUMS_ASSET))
+}
+
+// The signature may be raw or base64, as written by e.g. openssl pkeyutl or
+// signify-style tools
+fn verify_signature(public_key: &str, message: &[u8], signature: &[u8]) -> Result<()> {
+    let engine = base64::engine::general_purpose::STANDARD;
+    let public_key = engine
+        .decode(public_key.trim())
+        .context("The release signing key must be base64")?;
+    let signature = match engine.decode(String::from_utf8_lossy(signature).trim()) {
+        Ok(decoded) => decoded,
+        Err(_) => signature.to_vec(),
+    };
+    UnparsedPublicKey::new(&ED25519, public_key)
+        .verify(message, &signature)
+        .map_err(|_| anyhow!("Bad signature on {}", CHECKSUMS_ASSET))
+}
+
+// Write the new binary next to the old one and rename it over it, so the
+// binary is never left half written. The running process keeps the old one.
+fn replace(exe: &Path, binary: &[u8]) -> Result<()> {
+    let mut temporary = exe.as_os_str().to_owned();
+    temporary.push(".new");
+    let result = (|| {
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o755)
+            .open(&temporary)
+            .with_context(|| format!("Could not create {:?}", temporary))?;
+        file.write_all(binary)?;
+        file.sync_all()?;
+        fs::rename(&temporary, exe).with_context(|| format!("Could not replace {}", exe.display()))
+    })();
+    if result.is_err() {
+        let _ = fs::remove_file(&temporary);
+    }
+    result
+}
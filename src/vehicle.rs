@@ -0,0 +1,151 @@
+use anyhow::{anyhow, Context, Result};
+use clap::ValueEnum;
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::auxload::DriveModel;
+use crate::passthrough::ExtraPid;
+use crate::pid::{GroupInterval, ObdPid, PidGroup};
+use crate::profile::AutopidKeys;
+use crate::units::TemperatureUnit;
+use crate::wake::WakeStep;
+
+// Vehicle specific settings used when reading and converting WiCAN responses
+#[derive(Debug, Clone)]
+pub struct Vehicle {
+    pub battery_capacity_wh: u32,
+    pub soc_display_curve: Option<SocCurve>,
+    pub temperature_unit: TemperatureUnit,
+    pub soc_pid: ObdPid,
+    pub temperature_pid: Option<ObdPid>,
+    pub cell_temperature_min_pid: Option<ObdPid>,
+    pub cell_temperature_max_pid: Option<ObdPid>,
+    pub coolant_temperature_pid: Option<ObdPid>,
+    pub hvac_power_pid: Option<ObdPid>,
+    pub speed_pid: Option<ObdPid>,
+    pub soh_pid: Option<ObdPid>,
+    pub extra_pids: Vec<ExtraPid>,
+    pub wake_sequence: Vec<WakeStep>,
+    pub pid_group_intervals: Vec<GroupInterval>,
+    pub capacity_basis: CapacityBasis,
+    pub usable_capacity_percentage: u8,
+    pub consumption_wh_per_km: Option<f32>,
+    pub max_ac_charging_kw: f32,
+    pub drive_model: DriveModel,
+    pub autopid_keys: AutopidKeys,
+    // Sent with every sample, set for one of several cars
+    pub tag: Option<String>,
+}
+
+impl Vehicle {
+    // The SOC to display, preferring the vehicle's displayed SOC and otherwise
+    // mapping the raw/BMS SOC through the display curve when one is configured
+    pub fn displayed_soc(&self, soc: f32, soc_d: Option<f32>) -> f32 {
+        match (soc_d, &self.soc_display_curve) {
+            (Some(soc_d), _) => soc_d,
+            (None, Some(curve)) => curve.map(soc),
+            (None, None) => soc,
+        }
+    }
+
+    // How often a group of PIDs is read, None to read it every poll
+    pub fn group_interval(&self, group: PidGroup) -> Option<Duration> {
+        self.pid_group_intervals
+            .iter()
+            .rfind(|interval| interval.group == group)
+            .map(|interval| interval.interval)
+    }
+
+    // The capacity in Wh the energy left is worked out from, the usable share
+    // of the battery scaled down by the state of health when adjusting for it
+    // and the vehicle reports one
+    pub fn capacity_wh(&self, soh: Option<f32>) -> u32 {
+        let usable =
+            self.battery_capacity_wh as f32 * f32::from(self.usable_capacity_percentage) / 100.0;
+        match (self.capacity_basis, soh) {
+            // Some vehicles report a SOH above 100% while the pack is new
+            (CapacityBasis::Adjusted, Some(soh)) if soh > 0.0 => {
+                (usable * soh.min(100.0) / 100.0).round() as u32
+            }
+            _ => usable.round() as u32,
+        }
+    }
+
+    // Distance the energy left lasts at the configured consumption
+    pub fn range_km(&self, energy_wh: f32) -> Option<f32> {
+        self.consumption_wh_per_km
+            .filter(|consumption| *consumption > 0.0)
+            .map(|consumption| (energy_wh / consumption * 10.0).round() / 10.0)
+    }
+}
+
+// Battery capacity selected on the command line
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum CapacityBasis {
+    Nominal,
+    Adjusted,
+}
+
+// Piecewise linear mapping from raw SOC to displayed SOC, written as
+// comma separated raw:displayed points, e.g. "0:0,5:0,97:100,100:100"
+#[derive(Debug, Clone, PartialEq)]
+pub struct SocCurve {
+    points: Vec<(f32, f32)>,
+}
+
+impl SocCurve {
+    pub fn map(&self, raw: f32) -> f32 {
+        let first = self.points[0];
+        let last = self.points[self.points.len() - 1];
+        if raw <= first.0 {
+            return first.1;
+        }
+        if raw >= last.0 {
+            return last.1;
+        }
+
+        for window in self.points.windows(2) {
+            let ((x0, y0), (x1, y1)) = (window[0], window[1]);
+            if raw <= x1 {
+                return y0 + (raw - x0) * (y1 - y0) / (x1 - x0);
+            }
+        }
+
+        last.1
+    }
+}
+
+impl FromStr for SocCurve {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut points = s
+            .split(',')
+            .map(|point| {
+                let (raw, displayed) = point
+                    .split_once(':')
+                    .ok_or_else(|| anyhow!("Point '{}' is not in raw:displayed form", point))?;
+                let raw: f32 = raw
+                    .trim()
+                    .parse()
+                    .with_context(|| format!("Invalid raw SOC in point '{}'", point))?;
+                let displayed: f32 = displayed
+                    .trim()
+                    .parse()
+                    .with_context(|| format!("Invalid displayed SOC in point '{}'", point))?;
+                Ok((raw, displayed))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        if points.len() < 2 {
+            return Err(anyhow!("A SOC curve needs at least two points"));
+        }
+
+        points.sort_by(|a, b| a.0.total_cmp(&b.0));
+        if points.windows(2).any(|w| w[0].0 == w[1].0) {
+            return Err(anyhow!("A SOC curve cannot contain the same raw SOC twice"));
+        }
+
+        Ok(Self { points })
+    }
+}
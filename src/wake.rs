@@ -0,0 +1,74 @@
+use anyhow::{anyhow, Result};
+use futures_util::stream::{Stream, StreamExt};
+use futures_util::FutureExt;
+use log::debug;
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+use tokio::time;
+
+use crate::transport::CommandWriter;
+
+// One command of the sequence sent before requesting data, for vehicles whose
+// ECUs ignore the first request after sleeping, written as
+// COMMAND[@MILLISECONDS] with the time to wait after it
+#[derive(Debug, Clone, PartialEq)]
+pub struct WakeStep {
+    pub command: String,
+    pub delay: Duration,
+}
+
+impl FromStr for WakeStep {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (command, delay) = match s.rsplit_once('@') {
+            Some((command, milliseconds)) => {
+                let milliseconds: u64 = milliseconds
+                    .trim()
+                    .parse()
+                    .map_err(|_| anyhow!("Invalid delay '{}' in '{}'", milliseconds, s))?;
+                (command, Duration::from_millis(milliseconds))
+            }
+            None => (s, Duration::ZERO),
+        };
+        let command = command.trim();
+        if command.is_empty() {
+            return Err(anyhow!("'{}' has no command", s));
+        }
+        Ok(Self {
+            command: command.to_string(),
+            delay,
+        })
+    }
+}
+
+impl fmt::Display for WakeStep {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}@{}", self.command, self.delay.as_millis())
+    }
+}
+
+// Send the sequence to an autopid dongle before the autopid request, dropping
+// whatever it answers so the replies aren't taken for the autopid response
+pub async fn send<S: Stream<Item = Vec<u8>> + Unpin>(
+    writer: &mut CommandWriter,
+    responses: &mut S,
+    steps: &[WakeStep],
+) -> Result<()> {
+    for step in steps {
+        debug!("Sending wake command {}", step);
+        writer
+            .write(format!("{}\n", step.command).as_bytes())
+            .await?;
+        time::sleep(step.delay).await;
+        while let Some(Some(reply)) = responses.next().now_or_never() {
+            debug!(
+                "Dropped reply to wake command '{}': {:?}",
+                step.command,
+                String::from_utf8_lossy(&reply)
+            );
+        }
+    }
+    Ok(())
+}
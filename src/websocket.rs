@@ -0,0 +1,392 @@
+use anyhow::{anyhow, Context, Result};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use log::{debug, info, warn};
+use reqwest::Url;
+use serde::Deserialize;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio::time::{self, Instant};
+use uuid::Uuid;
+
+use crate::control::CONTROL;
+use crate::stats::STATS;
+
+// Delay before reconnecting after the connection failed or was closed
+const RECONNECT_DELAY: Duration = Duration::from_secs(10);
+
+// Samples buffered for the connection task
+const CHANNEL_CAPACITY: usize = 16;
+
+// Interval between pings, the connection counting as lost after two without
+// anything received
+const PING_INTERVAL: Duration = Duration::from_secs(30);
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Largest handshake response and message accepted from the server
+const MAX_HANDSHAKE_LEN: usize = 8 * 1024;
+const MAX_MESSAGE_LEN: usize = 1024 * 1024;
+
+// Appended to the handshake key before hashing, as fixed by RFC 6455
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xa;
+
+// Pushes samples to aa-proxy-rs over a persistent WebSocket, reconnecting in
+// the background, and follows the polling commands the server sends back
+pub struct WebSocketSink {
+    sender: mpsc::Sender<String>,
+    connected: Arc<AtomicBool>,
+}
+
+// Message sent by the server to change polling
+#[derive(Deserialize)]
+#[serde(tag = "command", rename_all = "kebab-case")]
+enum ServerCommand {
+    PollNow,
+    Pause,
+    Resume,
+    SetInterval { minutes: u8 },
+}
+
+struct Frame {
+    fin: bool,
+    opcode: u8,
+    payload: Vec<u8>,
+}
+
+impl WebSocketSink {
+    pub fn new(url: &str) -> Result<Self> {
+        let url = Url::parse(url).with_context(|| format!("Invalid WebSocket url '{}'", url))?;
+        if url.scheme() != "ws" {
+            return Err(anyhow!(
+                "Only ws:// WebSocket urls are supported, not '{}'",
+                url
+            ));
+        }
+        if url.host_str().is_none() {
+            return Err(anyhow!("WebSocket url '{}' has no host", url));
+        }
+
+        let (sender, receiver) = mpsc::channel(CHANNEL_CAPACITY);
+        let connected = Arc::new(AtomicBool::new(false));
+        tokio::spawn(run(url, receiver, connected.clone()));
+        Ok(Self { sender, connected })
+    }
+
+    // Queue a sample payload for the socket, returning false when it isn't
+    // connected so the sample can be posted over HTTP instead
+    pub fn send(&self, payload: String) -> bool {
+        self.connected.load(Ordering::Relaxed) && self.sender.try_send(payload).is_ok()
+    }
+}
+
+async fn run(url: Url, mut receiver: mpsc::Receiver<String>, connected: Arc<AtomicBool>) {
+    loop {
+        match connect(&url).await {
+            Ok(stream) => {
+                info!("WebSocket connected to {}", url);
+                connected.store(true, Ordering::Relaxed);
+                let e = serve(stream, &mut receiver).await;
+                connected.store(false, Ordering::Relaxed);
+                warn!(
+                    "WebSocket connection to {} lost: {:#}. Reconnecting in {:?}...",
+                    url, e, RECONNECT_DELAY
+                );
+            }
+            Err(e) => warn!(
+                "Failed to connect to the WebSocket at {}: {:#}. Retrying in {:?}...",
+                url, e, RECONNECT_DELAY
+            ),
+        }
+        // Samples queued when the connection dropped were lost with it
+        while receiver.try_recv().is_ok() {
+            STATS.record_post(false, 0);
+        }
+        time::sleep(RECONNECT_DELAY).await;
+    }
+}
+
+// Open the TCP connection and perform the opening handshake
+async fn connect(url: &Url) -> Result<TcpStream> {
+    let host = url.host_str().unwrap_or_default();
+    let port = url.port_or_known_default().unwrap_or(80);
+    let mut stream = time::timeout(CONNECT_TIMEOUT, TcpStream::connect((host, port)))
+        .await
+        .map_err(|_| anyhow!("Timed out connecting"))??;
+
+    let key = BASE64.encode(Uuid::new_v4().as_bytes());
+    let path = match url.query() {
+        Some(query) => format!("{}?{}", url.path(), query),
+        None => url.path().to_string(),
+    };
+    let request = format!(
+        "GET {} HTTP/1.1\r\nHost: {}:{}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: {}\r\nSec-WebSocket-Version: 13\r\nUser-Agent: {}/{}\r\n\r\n",
+        path,
+        host,
+        port,
+        key,
+        env!("CARGO_PKG_NAME"),
+        env!("CARGO_PKG_VERSION")
+    );
+    stream.write_all(request.as_bytes()).await?;
+
+    let response = time::timeout(CONNECT_TIMEOUT, read_handshake(&mut stream))
+        .await
+        .map_err(|_| anyhow!("Timed out waiting for the handshake response"))??;
+    let mut lines = response.lines();
+    let status = lines.next().unwrap_or_default();
+    if status.split_whitespace().nth(1) != Some("101") {
+        return Err(anyhow!("Server refused the upgrade: {}", status));
+    }
+    let expected = BASE64.encode(sha1(format!("{}{}", key, ACCEPT_GUID).as_bytes()));
+    let accepted = lines.any(|line| {
+        line.split_once(':').is_some_and(|(name, value)| {
+            name.trim().eq_ignore_ascii_case("sec-websocket-accept") && value.trim() == expected
+        })
+    });
+    if !accepted {
+        return Err(anyhow!("Server did not accept the WebSocket key"));
+    }
+    Ok(stream)
+}
+
+// Read the handshake response up to the blank line ending its headers, a
+// byte at a time so frames the server sends right after it are left unread
+async fn read_handshake(stream: &mut TcpStream) -> Result<String> {
+    let mut response = Vec::new();
+    let mut byte = [0u8; 1];
+    while !response.ends_with(b"\r\n\r\n") {
+        if response.len() >= MAX_HANDSHAKE_LEN {
+            return Err(anyhow!("Handshake response too long"));
+        }
+        if stream.read(&mut byte).await? == 0 {
+            return Err(anyhow!("Connection closed during the handshake"));
+        }
+        response.push(byte[0]);
+    }
+    Ok(String::from_utf8_lossy(&response).to_string())
+}
+
+// Send queued samples and answer the server until the connection fails,
+// returning why it did
+async fn serve(stream: TcpStream, receiver: &mut mpsc::Receiver<String>) -> anyhow::Error {
+    let (reader, mut writer) = stream.into_split();
+    let (frames_sender, mut frames) = mpsc::channel(CHANNEL_CAPACITY);
+    let reading = tokio::spawn(read_frames(reader, frames_sender));
+
+    let mut ping = time::interval(PING_INTERVAL);
+    ping.tick().await;
+    let mut last_received = Instant::now();
+    let mut message = Vec::new();
+    let error = loop {
+        tokio::select! {
+            payload = receiver.recv() => {
+                let Some(payload) = payload else {
+                    break anyhow!("Sink closed");
+                };
+                let result = write_frame(&mut writer, OPCODE_TEXT, payload.as_bytes()).await;
+                STATS.record_post(result.is_ok(), 0);
+                if let Err(e) = result {
+                    break e;
+                }
+                debug!("Sent {} over the WebSocket", payload);
+            }
+            frame = frames.recv() => {
+                let frame = match frame {
+                    Some(Ok(frame)) => frame,
+                    Some(Err(e)) => break e,
+                    None => break anyhow!("Connection closed"),
+                };
+                last_received = Instant::now();
+                match frame.opcode {
+                    OPCODE_PING => {
+                        if let Err(e) = write_frame(&mut writer, OPCODE_PONG, &frame.payload).await {
+                            break e;
+                        }
+                    }
+                    OPCODE_CLOSE => {
+                        let _ = write_frame(&mut writer, OPCODE_CLOSE, &frame.payload).await;
+                        break anyhow!("Closed by the server");
+                    }
+                    OPCODE_TEXT | OPCODE_CONTINUATION => {
+                        message.extend(frame.payload);
+                        if message.len() > MAX_MESSAGE_LEN {
+                            break anyhow!("Message from the server too long");
+                        }
+                        if frame.fin {
+                            handle_message(&String::from_utf8_lossy(&message));
+                            message.clear();
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            _ = ping.tick() => {
+                if last_received.elapsed() > PING_INTERVAL * 2 {
+                    break anyhow!("No response from the server for {:?}", last_received.elapsed());
+                }
+                if let Err(e) = write_frame(&mut writer, OPCODE_PING, &[]).await {
+                    break e;
+                }
+            }
+        }
+    };
+    reading.abort();
+    error
+}
+
+// Follow a polling command pushed by the server
+fn handle_message(message: &str) {
+    match serde_json::from_str::<ServerCommand>(message) {
+        Ok(ServerCommand::PollNow) => {
+            info!("Poll requested over the WebSocket.");
+            CONTROL.poll_now();
+        }
+        Ok(ServerCommand::Pause) => CONTROL.pause(),
+        Ok(ServerCommand::Resume) => CONTROL.resume(),
+        Ok(ServerCommand::SetInterval { minutes }) if minutes > 0 => {
+            if let Err(e) = CONTROL.set_update_frequency_minutes(minutes) {
+                warn!(
+                    "Ignoring a WebSocket request to poll every {} minute(s): {:#}",
+                    minutes, e
+                );
+            }
+        }
+        Ok(ServerCommand::SetInterval { .. }) => {
+            warn!("Ignoring a WebSocket request to poll every 0 minutes.")
+        }
+        Err(_) => debug!("WebSocket message: {}", message),
+    }
+}
+
+async fn read_frames(mut reader: OwnedReadHalf, frames: mpsc::Sender<Result<Frame>>) {
+    loop {
+        let frame = read_frame(&mut reader).await;
+        let failed = frame.is_err();
+        if frames.send(frame).await.is_err() || failed {
+            return;
+        }
+    }
+}
+
+async fn read_frame(reader: &mut OwnedReadHalf) -> Result<Frame> {
+    let mut header = [0u8; 2];
+    reader.read_exact(&mut header).await?;
+    let fin = header[0] & 0x80 != 0;
+    let opcode = header[0] & 0x0f;
+    let masked = header[1] & 0x80 != 0;
+    let length = match header[1] & 0x7f {
+        126 => reader.read_u16().await? as u64,
+        127 => reader.read_u64().await?,
+        length => length as u64,
+    };
+    if length > MAX_MESSAGE_LEN as u64 {
+        return Err(anyhow!("Frame of {} bytes is too long", length));
+    }
+    let mut mask = [0u8; 4];
+    if masked {
+        reader.read_exact(&mut mask).await?;
+    }
+    let mut payload = vec![0u8; length as usize];
+    reader.read_exact(&mut payload).await?;
+    if masked {
+        for (i, byte) in payload.iter_mut().enumerate() {
+            *byte ^= mask[i % 4];
+        }
+    }
+    Ok(Frame {
+        fin,
+        opcode,
+        payload,
+    })
+}
+
+// Write a single unfragmented frame, masked as required of clients
+async fn write_frame(writer: &mut OwnedWriteHalf, opcode: u8, payload: &[u8]) -> Result<()> {
+    let mut frame = vec![0x80 | opcode];
+    match payload.len() {
+        length if length < 126 => frame.push(0x80 | length as u8),
+        length if length <= u16::MAX as usize => {
+            frame.push(0x80 | 126);
+            frame.extend((length as u16).to_be_bytes());
+        }
+        length => {
+            frame.push(0x80 | 127);
+            frame.extend((length as u64).to_be_bytes());
+        }
+    }
+    let mask: [u8; 4] = Uuid::new_v4().as_bytes()[..4].try_into()?;
+    frame.extend(mask);
+    frame.extend(
+        payload
+            .iter()
+            .enumerate()
+            .map(|(i, byte)| byte ^ mask[i % 4]),
+    );
+    writer
+        .write_all(&frame)
+        .await
+        .context("Failed to write to the WebSocket")
+}
+
+// SHA-1, only used for the handshake's Sec-WebSocket-Accept check
+fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut h: [u32; 5] = [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476, 0xc3d2e1f0];
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend((data.len() as u64 * 8).to_be_bytes());
+
+    for chunk in message.chunks_exact(64) {
+        let mut w = [0u32; 80];
+        for (i, word) in chunk.chunks_exact(4).enumerate() {
+            w[i] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+        }
+        for i in 16..80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e] = h;
+        for (i, word) in w.iter().enumerate() {
+            let (f, k) = match i {
+                0..=19 => ((b & c) | (!b & d), 0x5a827999),
+                20..=39 => (b ^ c ^ d, 0x6ed9eba1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8f1bbcdc),
+                _ => (b ^ c ^ d, 0xca62c1d6),
+            };
+            let temp = a
+                .rotate_left(5)
+                .wrapping_add(f)
+                .wrapping_add(e)
+                .wrapping_add(k)
+                .wrapping_add(*word);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = temp;
+        }
+        for (value, added) in h.iter_mut().zip([a, b, c, d, e]) {
+            *value = value.wrapping_add(added);
+        }
+    }
+
+    let mut digest = [0u8; 20];
+    for (bytes, value) in digest.chunks_exact_mut(4).zip(h) {
+        bytes.copy_from_slice(&value.to_be_bytes());
+    }
+    digest
+}
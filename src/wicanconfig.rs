@@ -0,0 +1,132 @@
+use anyhow::{anyhow, Context, Result};
+use futures_util::stream::Stream;
+use log::debug;
+use serde_json::Value;
+use std::path::Path;
+use std::time::Duration;
+
+use crate::dongle::Dongle;
+use crate::passthrough;
+use crate::transport::CommandWriter;
+use crate::WicanConfigCommand;
+
+// Commands of the WiCAN firmware's command interface, overridable for
+// firmware that names them differently
+pub const GET_COMMAND: &str = "autopid -g";
+pub const SET_COMMAND: &str = "autopid -s";
+pub const REBOOT_COMMAND: &str = "reboot";
+
+pub struct ConfigCommands {
+    pub get: String,
+    pub set: String,
+    pub reboot: String,
+}
+
+pub async fn run<S: Stream<Item = Vec<u8>> + Unpin>(
+    command: &WicanConfigCommand,
+    commands: &ConfigCommands,
+    writer: &mut CommandWriter,
+    responses: &mut S,
+    dongle: Dongle,
+    timeout: Duration,
+) -> Result<()> {
+    match command {
+        WicanConfigCommand::Pull { file } => {
+            let config = pull(commands, writer, responses, dongle, timeout).await?;
+            let config = serde_json::to_string_pretty(&config)?;
+            match file {
+                Some(path) => {
+                    std::fs::write(path, format!("{}\n", config))
+                        .with_context(|| format!("Failed to write '{}'", path.display()))?;
+                    println!("Saved the AutoPID configuration to {}.", path.display());
+                }
+                None => println!("{}", config),
+            }
+        }
+        WicanConfigCommand::Push { file, reboot } => {
+            let config = load(file)?;
+            push(commands, &config, writer, responses, dongle, timeout).await?;
+            println!(
+                "Uploaded the AutoPID configuration from {}.",
+                file.display()
+            );
+            if *reboot {
+                restart(commands, writer, dongle).await?;
+                println!("Rebooting the {}.", dongle);
+            }
+        }
+        WicanConfigCommand::Reboot => {
+            restart(commands, writer, dongle).await?;
+            println!("Rebooting the {}.", dongle);
+        }
+    }
+    Ok(())
+}
+
+fn load(path: &Path) -> Result<Value> {
+    let config = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read '{}'", path.display()))?;
+    serde_json::from_str(&config).with_context(|| format!("'{}' is not valid JSON", path.display()))
+}
+
+// Ask the WiCAN for its AutoPID configuration
+async fn pull<S: Stream<Item = Vec<u8>> + Unpin>(
+    commands: &ConfigCommands,
+    writer: &mut CommandWriter,
+    responses: &mut S,
+    dongle: Dongle,
+    timeout: Duration,
+) -> Result<Value> {
+    let reply = passthrough::exchange(writer, responses, &commands.get, dongle, timeout).await?;
+    serde_json::from_str(&reply).with_context(|| {
+        format!(
+            "Expected the AutoPID configuration in reply to '{}', got '{}'",
+            commands.get, reply
+        )
+    })
+}
+
+// Send the configuration on one line after the set command, split into
+// writes the size of the MTU
+async fn push<S: Stream<Item = Vec<u8>> + Unpin>(
+    commands: &ConfigCommands,
+    config: &Value,
+    writer: &mut CommandWriter,
+    responses: &mut S,
+    dongle: Dongle,
+    timeout: Duration,
+) -> Result<()> {
+    let command = format!(
+        "{} {}{}",
+        commands.set,
+        serde_json::to_string(config)?,
+        passthrough::line_ending(dongle)
+    );
+    passthrough::drop_stale(responses);
+    writer
+        .write_long(command.as_bytes())
+        .await
+        .context("Failed to upload the configuration")?;
+    let reply = passthrough::collect_reply(responses, &commands.set, timeout).await?;
+    debug!("{} replied to '{}': {:?}", dongle, commands.set, reply);
+    if reply == "?" || reply.to_ascii_uppercase().starts_with("ERROR") {
+        return Err(anyhow!(
+            "The {} rejected the configuration: {}",
+            dongle,
+            reply
+        ));
+    }
+    Ok(())
+}
+
+// The WiCAN drops the connection as it restarts, so no reply is waited for
+async fn restart(
+    commands: &ConfigCommands,
+    writer: &mut CommandWriter,
+    dongle: Dongle,
+) -> Result<()> {
+    writer
+        .write(format!("{}{}", commands.reboot, passthrough::line_ending(dongle)).as_bytes())
+        .await
+        .context("Failed to send the reboot command")
+}